@@ -0,0 +1,9 @@
+#version 400
+
+uniform sampler2D tex;
+in vec2 frag_tex_coord;
+out vec4 color;
+
+void main() {
+    color = texture(tex, frag_tex_coord);
+}
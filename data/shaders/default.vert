@@ -0,0 +1,14 @@
+#version 400
+
+uniform Matrices {
+    mat4 model_view_proj;
+};
+
+in vec3 position;
+in vec2 tex_coord;
+out vec2 frag_tex_coord;
+
+void main() {
+    frag_tex_coord = tex_coord;
+    gl_Position = model_view_proj * vec4(position, 1.0);
+}
@@ -0,0 +1,10 @@
+#version 400
+
+uniform sampler2D tex;
+in vec2 frag_tex_coord;
+in vec4 frag_tint;
+out vec4 color;
+
+void main() {
+    color = texture(tex, frag_tex_coord) * frag_tint;
+}
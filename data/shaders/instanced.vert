@@ -0,0 +1,24 @@
+#version 400
+
+#include "shared.glsl"
+
+in vec3 position;
+in vec2 tex_coord;
+
+// Per instance, see scene/model_batch.rs.
+in vec4 instance_model_0;
+in vec4 instance_model_1;
+in vec4 instance_model_2;
+in vec4 instance_model_3;
+in vec4 instance_tint;
+
+out vec2 frag_tex_coord;
+out vec4 frag_tint;
+
+void main() {
+    mat4 model = mat4(instance_model_0, instance_model_1, instance_model_2, instance_model_3);
+
+    frag_tex_coord = tex_coord;
+    frag_tint = instance_tint;
+    gl_Position = view_projection * model * vec4(position, 1.0);
+}
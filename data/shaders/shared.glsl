@@ -0,0 +1,16 @@
+#pragma once
+
+// Uniform blocks filled in by the renderer, see renderer/shared_data.rs.
+// They have to stay std140 and match the layout there.
+
+layout(std140) uniform FrameData {
+    float time;
+    float delta_time;
+};
+
+layout(std140) uniform ViewData {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec3 camera_position;
+};
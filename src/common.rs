@@ -6,6 +6,7 @@ pub use cgmath::{
     Vector4,
     Quaternion,
     SquareMatrix,
+    Matrix2,
     Matrix3,
     Matrix4,
     Angle,
@@ -18,7 +19,14 @@ pub type Point3f = Point3<f32>;
 pub type Vec2f = Vector2<f32>;
 pub type Vec3f = Vector3<f32>;
 pub type Vec4f = Vector4<f32>;
+pub type Vec2i = Vector2<i32>;
+pub type Vec3i = Vector3<i32>;
+pub type Vec4i = Vector4<i32>;
+pub type Vec2u = Vector2<u32>;
+pub type Vec3u = Vector3<u32>;
+pub type Vec4u = Vector4<u32>;
 pub type Quatf = Quaternion<f32>;
+pub type Mat2f = Matrix2<f32>;
 pub type Mat3f = Matrix3<f32>;
 pub type Mat4f = Matrix4<f32>;
 pub type Degf = Deg<f32>;
@@ -88,7 +88,8 @@ impl Game {
         gl::load_with(|name| vid_ctx.gl_get_proc_address(name) as *const _);
 
         let renderer_name = determine_best_renderer();
-        let renderer = renderer_factory(&renderer_name).unwrap();
+        let mut renderer = renderer_factory(&renderer_name).unwrap();
+        renderer.add_shader_search_path(&Path::new("data/shaders"));
 
         let mut scene = Scene::new(renderer, width as f32 / height as f32);
         
@@ -96,7 +97,14 @@ impl Game {
         node.borrow_mut().transform_change(&|transform| {
         	transform.position = Vec3f::new(0f32, 0f32, -2f32);		
         });
-        scene.attach_model_component_from_file(&node, &Path::new("data/sphere.obj"));
+        let material = match scene.load_material(&Path::new("data/materials/default.mat")) {
+            Ok(x)  => x,
+            Err(e) => {
+                println!("{}", e);
+                scene.error_material()
+            },
+        };
+        scene.attach_model_component_from_file(&node, &Path::new("data/sphere.obj"), material);
 
         Game {
             running: true,
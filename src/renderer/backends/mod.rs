@@ -1,7 +1,11 @@
 use super::Renderer;
 use self::opengl::OpenGLRenderer;
+use self::software::SoftwareRenderer;
+use self::trace::TraceRenderer;
 
 pub mod opengl;
+pub mod software;
+pub mod trace;
 
 pub fn determine_best_renderer() -> String {
     "OpenGL".to_string()
@@ -9,7 +13,9 @@ pub fn determine_best_renderer() -> String {
 
 pub fn renderer_factory(renderer_name: &str) -> Result<Box<Renderer>, String> {
     match renderer_name {
-        "OpenGL" => Ok(OpenGLRenderer::new()),
-        _        => Err(format!("No renderer by the name {}", renderer_name)),
+        "OpenGL"   => Ok(OpenGLRenderer::new()),
+        "Software" => Ok(SoftwareRenderer::new()),
+        "Trace"    => Ok(TraceRenderer::new()),
+        _          => Err(format!("No renderer by the name {}", renderer_name)),
     }
 }
@@ -4,8 +4,12 @@ use super::super::*;
 
 use std::mem;
 use std::ptr;
-use std::ffi::CString;
+use std::any::Any;
+use std::ffi::{CStr, CString};
 use std::str;
+use std::cmp;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 
@@ -13,42 +17,66 @@ use gl;
 
 use gl::types::*;
 
-use image::{GenericImage, DynamicImage};
 
-type GLHandle = u32;
+use renderer::util::preprocessor::{GlslPreprocessor, PreprocessedSource};
+use renderer::util::handle_pool::{HandlePool, PoolHandle};
 
-type Handle = usize;
+type GLHandle = u32;
 
-type VBOHandle = Handle;
-type VAOHandle = Handle;
-type IBOHandle = Handle;
-type ProgramHandle = Handle;
-type TextureHandle = Handle;
+type VBOHandle = PoolHandle<GLVbo>;
+type VAOHandle = PoolHandle<GLVertexArrayObject>;
+type IBOHandle = PoolHandle<GLIbo>;
+type ProgramHandle = PoolHandle<GLProg>;
+type TextureHandle = PoolHandle<GLTexture>;
+type FramebufferHandle = PoolHandle<GLFramebuffer>;
+type GLMaterialHandle = PoolHandle<GLMaterial>;
+type UBOHandle = PoolHandle<GLuint>;
 
 struct GLVbo {
     id: GLHandle,
+    /// Bytes allocated, which stream buffers keep when given less data.
+    size: usize,
+    usage: BufferUsage,
 }
 
 struct GLIbo {
     id: GLHandle,
     itype: IndexType,
     count: usize,
+    size: usize,
+    usage: BufferUsage,
 }
 
 struct GLUniform {
+    /// Without the `[0]` GL reports for arrays.
     name: String,
     index: GLuint,
     offset: GLint,
     utype: GLenum,
+    /// Number of array elements, 1 when not an array.
     size: GLsizei,
+    is_array: bool,
+    /// Bytes between array elements, and between matrix columns (or rows
+    /// when row major), as laid out by the driver.
+    array_stride: usize,
+    matrix_stride: usize,
+    row_major: bool,
 }
 
 struct GLUniformBlock {
     name: String,
+    index: GLuint,
+    binding: GLuint,
     size: usize,
+    uniforms: Vec<GLUniform>,
+}
+
+/// Storage for one uniform block of a program. Each geometry has its own,
+/// so that geometries sharing a program don't overwrite each other's values.
+struct GLUniformBuffer {
+    handle: UBOHandle,
     buffer: GLuint,
     buffer_data: BufferData,
-    uniforms: Vec<GLUniform>,
 }
 
 impl PartialEq for GLUniformBlock {
@@ -57,29 +85,121 @@ impl PartialEq for GLUniformBlock {
     }
 }
 
-struct GLSampler2D {
+struct GLSamplerUniform {
     uniform_info: GLUniform,
     tex_unit: u32,
-    tex_id: GLuint,
+    /// Texture target the sampler type reads from.
+    target: GLenum,
+    /// Shadow samplers compare against the depth texture they sample.
+    shadow: bool,
+}
+
+/// A uniform outside of any uniform block, set with `glUniform*`.
+struct GLDefaultUniform {
+    uniform_info: GLUniform,
+    /// One per array element.
+    locations: Vec<GLint>,
+}
+
+/// The preprocessed sources of a program, so that identical programs are
+/// only compiled once. Includes are resolved, so a program is compiled
+/// again when a file it includes changes.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ProgramKey {
+    vert_src: String,
+    frag_src: String,
+}
+
+/// Sorts defines by name, so the order they were added in doesn't change
+/// the program. A name given twice keeps its last value, like
+/// `MaterialDescription::add_define`.
+fn sorted_defines(defines: &[(String, String)]) -> Vec<(String, String)> {
+    let mut sorted: Vec<(String, String)> = Vec::with_capacity(defines.len());
+
+    for &(ref name, ref value) in defines.iter() {
+        match sorted.iter().position(|&(ref n, _)| n == name) {
+            Some(i) => sorted[i].1 = value.clone(),
+            None    => sorted.push((name.clone(), value.clone())),
+        }
+    }
+
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+    sorted
 }
 
 struct GLProg {
     id: GLHandle,
+    key: ProgramKey,
+    /// Number of materials and geometries using the program. It is deleted
+    /// when this drops to zero.
+    ref_count: usize,
     uniform_blocks: Vec<GLUniformBlock>,
-    sampler2ds: Vec<GLSampler2D>,
+    sampler_uniforms: Vec<GLSamplerUniform>,
+    default_uniforms: Vec<GLDefaultUniform>,
+    /// Active vertex attributes, besides built-ins such as `gl_VertexID`.
+    attributes: Vec<VertexInput>,
+    /// Location of each of `attributes`, in the same order.
+    attribute_locations: Vec<GLuint>,
+    /// Reflected params that geometries using the program start with.
+    params: ShaderParams,
+    /// For each param group, the index of each param's uniform within its
+    /// block, `default_uniforms` or `sampler_uniforms`. Group order is the uniform
+    /// blocks, then the default block, then the samplers.
+    param_uniforms: Vec<Vec<usize>>,
+    /// Default block uniforms are program state, so geometries sharing the
+    /// program have to upload all of theirs when they weren't the last to.
+    default_uniforms_geometry: Cell<Option<usize>>,
+    /// Files included by the sources.
+    includes: Vec<PathBuf>,
+}
+
+struct GLMaterial {
+    program: ProgramHandle,
+    defines: Vec<(String, String)>,
+    /// Bumped whenever the program is reloaded, so geometries know to move
+    /// over to the new one.
+    generation: u32,
+    /// Params with the material's values already set, cloned for every
+    /// geometry using the material.
+    params: ShaderParams,
+    render_state: RenderState,
+    /// Sampler objects overriding the textures' own, by sampler uniform name.
+    samplers: Vec<(String, usize)>,
 }
 
 struct GLVertexArrayObject {
     id: GLHandle,
 }
 
+struct GLFramebuffer {
+    id: GLHandle,
+    color_textures: Vec<TextureHandle>,
+    depth_texture: Option<TextureHandle>,
+    width: u32,
+    height: u32,
+}
+
 struct GLStateManager {
     prog: GLHandle,
     vao: GLHandle,
     vbo: GLHandle,
-    ibo: GLHandle,
+    /// The element buffer is part of the VAO's state, so this is None
+    /// until one is bound after switching VAOs.
+    ibo: Option<GLHandle>,
     ubo: GLHandle,
-    tex_units: HashMap<u32, GLuint>
+    fbo: GLHandle,
+    /// Target and texture bound to each texture unit.
+    tex_units: HashMap<u32, (GLenum, GLuint)>,
+    /// Sampler object bound to each texture unit.
+    sampler_units: HashMap<u32, GLuint>,
+    ubo_bindings: HashMap<u32, GLuint>,
+    /// `None` until the first state is applied, as the initial GL state
+    /// doesn't match any `DepthStencilState`.
+    depth_stencil: Option<DepthStencilState>,
+    blend: Option<BlendState>,
+    rasterizer: Option<RasterizerState>,
+    /// Restart index, None while primitive restart is disabled.
+    primitive_restart: Option<u32>,
 }
 
 impl GLStateManager {
@@ -88,9 +208,16 @@ impl GLStateManager {
             prog: 0,
             vao: 0,
             vbo: 0,
-            ibo: 0,
+            ibo: None,
             ubo: 0,
+            fbo: 0,
             tex_units: HashMap::new(),
+            sampler_units: HashMap::new(),
+            ubo_bindings: HashMap::new(),
+            depth_stencil: None,
+            blend: None,
+            rasterizer: None,
+            primitive_restart: None,
         }
     }
 
@@ -104,6 +231,7 @@ impl GLStateManager {
     pub fn set_vao(&mut self, vao: GLHandle) {
         if self.vao != vao {
             self.vao = vao;
+            self.ibo = None;
             unsafe { gl::BindVertexArray(vao); }
         }
     }
@@ -115,323 +243,1554 @@ impl GLStateManager {
         }
     }
 
+    /// Attaches the index buffer to the bound VAO.
     pub fn set_ibo(&mut self, ibo: GLHandle) {
-        if self.ibo != ibo {
-            self.ibo = ibo;
+        if self.ibo != Some(ibo) {
+            self.ibo = Some(ibo);
             unsafe { gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ibo); }
         }
     }
 
-    pub fn set_ubo(&mut self, ubo: GLHandle) {
-        if self.ubo != ubo {
-            self.ubo = ubo;
-            unsafe { gl::BindBuffer(gl::UNIFORM_BUFFER, ubo); }
+    pub fn set_primitive_restart(&mut self, restart_index: Option<u32>) {
+        if self.primitive_restart == restart_index {
+            return;
         }
-    }
 
-    pub fn set_tex2d(&mut self, tex_unit_i: u32, tex2d: GLuint) {
         unsafe {
-            let mut bind = false;
-            
-            match self.tex_units.entry(tex_unit_i) {
-                Vacant(entry) => { 
-                    entry.insert(tex2d);
-                    bind = true;
-                },
-                Occupied(mut entry) => {
-                    let oldvalue = entry.insert(tex2d);
-                    if oldvalue != tex2d {
-                        bind = true;
+            match restart_index {
+                Some(index) => {
+                    if self.primitive_restart.is_none() {
+                        gl::Enable(gl::PRIMITIVE_RESTART);
                     }
+                    gl::PrimitiveRestartIndex(index);
                 },
-            }
-            
-            if bind {
-                gl::ActiveTexture(gl::TEXTURE0 + tex_unit_i);
-                gl::BindTexture(gl::TEXTURE_2D, tex2d);
+                None => gl::Disable(gl::PRIMITIVE_RESTART),
             }
         }
-    }
-}
-
-pub struct GLTex2D {
-    id: GLuint,
-}
-
-pub struct OpenGLTexture {
-    texture_handle: TextureHandle,
-    filter_method: FilteringMethod,
-    filter_changed: bool,
-    texture_format: TextureFormat,
-}
 
-impl Texture for OpenGLTexture {
-    fn param_handle(&self) -> TextureParamHandle {
-        self.texture_handle as TextureParamHandle
-    }
-    
-    fn format(&self) -> &TextureFormat {
-        &self.texture_format
-    }
-    
-    fn set_filtering_method(&mut self, method: FilteringMethod) {
-        self.filter_method = method;
-        self.filter_changed = true;
+        self.primitive_restart = restart_index;
     }
-}
 
-pub struct OpenGLGeometry {
-    vbo: VBOHandle,
-    ibo: IBOHandle,
-    vao: VAOHandle,
-    program: ProgramHandle,
-    layout_desc: VertexLayoutDescription,
-    params: ShaderParams,
-}
-
-impl Geometry for OpenGLGeometry {
-    fn get_vertex_layout_description(&self) -> &VertexLayoutDescription {
-        &self.layout_desc
-    }
-    
-    fn get_params(&self) -> &ShaderParams {
-        &self.params
-    }
-    
-    fn get_mut_params(&mut self) -> &mut ShaderParams {
-        &mut self.params
+    pub fn set_ubo(&mut self, ubo: GLHandle) {
+        if self.ubo != ubo {
+            self.ubo = ubo;
+            unsafe { gl::BindBuffer(gl::UNIFORM_BUFFER, ubo); }
+        }
     }
-}
 
-pub struct OpenGLRenderer {
-    vaos: Vec<GLVertexArrayObject>,
-    vbos: Vec<GLVbo>,
-    ibos: Vec<GLIbo>,
-    progs: Vec<GLProg>,
-    tex2ds: Vec<GLTex2D>,
-    state: GLStateManager,
-}
+    pub fn set_ubo_binding(&mut self, binding: u32, ubo: GLHandle) {
+        let bind = match self.ubo_bindings.insert(binding, ubo) {
+            Some(oldvalue) => oldvalue != ubo,
+            None           => true,
+        };
 
-impl OpenGLRenderer {
-    pub fn new() -> Box<OpenGLRenderer> {
-        Box::new(OpenGLRenderer {
-            vaos: Vec::new(),
-            vbos: Vec::new(),
-            ibos: Vec::new(),
-            progs: Vec::new(),
-            tex2ds: Vec::new(),
-            state: GLStateManager::new(),
-        })
+        if bind {
+            // Binding to an indexed target also binds the generic target.
+            self.ubo = ubo;
+            unsafe { gl::BindBufferBase(gl::UNIFORM_BUFFER, binding, ubo); }
+        }
     }
 
-    fn compile_shader(&self, src: &str, shader_type: GLenum) -> GLuint {
-        unsafe {
-            let shader = gl::CreateShader(shader_type);
+    /// Deleting a buffer unbinds it, so the cache has to forget about it
+    /// before the name can be reused.
+    pub fn forget_buffer(&mut self, buffer: GLHandle) {
+        if self.vbo == buffer {
+            self.vbo = 0;
+        }
 
-            let c_str = CString::new(src.as_bytes()).unwrap();
+        if self.ibo == Some(buffer) {
+            self.ibo = Some(0);
+        }
 
-            gl::ShaderSource(shader, 1, &c_str.as_ptr(), ptr::null());
-            gl::CompileShader(shader);
+        if self.ubo == buffer {
+            self.ubo = 0;
+        }
 
-            let mut status = gl::FALSE as GLint;
-            gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut status);
+        for bound in self.ubo_bindings.values_mut() {
+            if *bound == buffer {
+                *bound = 0;
+            }
+        }
+    }
 
+    pub fn forget_vertex_array(&mut self, vao: GLHandle) {
+        if self.vao == vao {
+            self.vao = 0;
+        }
+    }
 
-            if status != (gl::TRUE as GLint) {
-                let mut len = 0;
-                gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
-                let mut buf = Vec::with_capacity(len as usize);
-                buf.set_len((len as usize) - 1);
-                gl::GetShaderInfoLog(shader, len, ptr::null_mut(), buf.as_mut_ptr() as * mut GLchar);
-                panic!("{}", str::from_utf8(&buf).ok().expect("ShaderInfoLog not valid utf8."));
+    /// Deleted textures are unbound from every unit.
+    pub fn forget_texture(&mut self, texture: GLHandle) {
+        for bound in self.tex_units.values_mut() {
+            if bound.1 == texture {
+                bound.1 = 0;
             }
+        }
+    }
 
-            shader
+    pub fn set_fbo(&mut self, fbo: GLHandle) {
+        if self.fbo != fbo {
+            self.fbo = fbo;
+            unsafe { gl::BindFramebuffer(gl::FRAMEBUFFER, fbo); }
         }
     }
 
-    fn create_vertex_array_object(&mut self, desc: &VertexLayoutDescription, vboh: VBOHandle, progh: ProgramHandle) -> Result<VAOHandle, String> {
-        let mut vao = 0;
+    pub fn set_depth_stencil_state(&mut self, state: &DepthStencilState) {
+        let force = self.depth_stencil.is_none();
+        let prev = self.depth_stencil.unwrap_or(*state);
 
         unsafe {
-            gl::GenVertexArrays(1, &mut vao);
-            self.state.set_vao(vao);
+            if force || prev.depth_test != state.depth_test {
+                if state.depth_test {
+                    gl::Enable(gl::DEPTH_TEST);
+                } else {
+                    gl::Disable(gl::DEPTH_TEST);
+                }
+            }
+
+            if force || prev.depth_write != state.depth_write {
+                gl::DepthMask(if state.depth_write { gl::TRUE } else { gl::FALSE });
+            }
 
-            let vboid = self.vbos[vboh].id;
-            self.state.set_vbo(vboid);
+            if force || prev.depth_compare != state.depth_compare {
+                gl::DepthFunc(gl_compare_func(state.depth_compare));
+            }
 
-            let progid = self.progs[progh].id;
+            if force || prev.stencil_test != state.stencil_test {
+                if state.stencil_test {
+                    gl::Enable(gl::STENCIL_TEST);
+                } else {
+                    gl::Disable(gl::STENCIL_TEST);
+                }
+            }
 
-            self.state.set_program(progid);
+            let func_changed = prev.stencil_ref != state.stencil_ref ||
+                               prev.stencil_read_mask != state.stencil_read_mask;
 
-			let mut stride: GLsizei = 0;
-			for elem in desc.elements.iter() {
-				stride += elem.vtype.get_size_of() as i32;
-			}
+            for &(face, prev_face, new_face) in [(gl::FRONT, &prev.stencil_front, &state.stencil_front),
+                                                 (gl::BACK, &prev.stencil_back, &state.stencil_back)].iter() {
+                if force || func_changed || prev_face.compare != new_face.compare {
+                    gl::StencilFuncSeparate(face,
+                                            gl_compare_func(new_face.compare),
+                                            state.stencil_ref as GLint,
+                                            state.stencil_read_mask);
+                }
 
-            for (i,elem) in desc.elements.iter().enumerate() {
-                let index = i as u32;
-                let num_components = elem.vtype.get_num_components();
-                let elem_type = match elem.vtype {
-                    VertexElementType::F32 | VertexElementType::F32F32 |
-                    VertexElementType::F32F32F32 | VertexElementType::F32F32F32F32 => gl::FLOAT,
-                };
-                
-                let attr_name_cstr = CString::new(elem.name.clone()).unwrap().as_ptr();
-                
-                gl::BindAttribLocation(progid, index, attr_name_cstr);
-                
-                gl::EnableVertexAttribArray(index as u32);
-                gl::VertexAttribPointer(index as u32, num_components, elem_type, gl::FALSE, stride, mem::transmute(elem.offset));
+                if force || prev_face.fail_op != new_face.fail_op ||
+                   prev_face.depth_fail_op != new_face.depth_fail_op ||
+                   prev_face.pass_op != new_face.pass_op {
+                    gl::StencilOpSeparate(face,
+                                          gl_stencil_op(new_face.fail_op),
+                                          gl_stencil_op(new_face.depth_fail_op),
+                                          gl_stencil_op(new_face.pass_op));
+                }
             }
-        }
 
-        self.vaos.push(GLVertexArrayObject {
-            id: vao,
-        });
+            if force || prev.stencil_write_mask != state.stencil_write_mask {
+                gl::StencilMask(state.stencil_write_mask);
+            }
+        }
 
-        Ok(self.vaos.len() - 1)
+        self.depth_stencil = Some(*state);
     }
 
-    fn create_vertex_buffer_object(&mut self, data: &BufferData) -> Result<VBOHandle, String> {
-        let mut buf_id = 0;
+    /// glClear respects the depth mask, so clearing has to enable writes.
+    pub fn set_depth_write(&mut self, write: bool) {
+        let changed = match self.depth_stencil {
+            Some(ref mut state) => {
+                let changed = state.depth_write != write;
+                state.depth_write = write;
+                changed
+            },
+            None => true,
+        };
 
-        unsafe {
-            gl::GenBuffers(1, &mut buf_id);
-            self.state.set_vbo(buf_id);
-            gl::BufferData(gl::ARRAY_BUFFER, data.bytes.len() as isize, mem::transmute(&data.bytes[0]), gl::STATIC_DRAW);
+        if changed {
+            unsafe { gl::DepthMask(if write { gl::TRUE } else { gl::FALSE }); }
         }
+    }
 
-        let vbo = GLVbo {
-            id: buf_id,
+    /// glClear respects the stencil mask, so clearing has to enable writes.
+    pub fn set_stencil_write_mask(&mut self, mask: u32) {
+        let changed = match self.depth_stencil {
+            Some(ref mut state) => {
+                let changed = state.stencil_write_mask != mask;
+                state.stencil_write_mask = mask;
+                changed
+            },
+            None => true,
         };
 
-        self.vbos.push(vbo);
-
-        Ok(self.vbos.len() - 1)
+        if changed {
+            unsafe { gl::StencilMask(mask); }
+        }
     }
 
-    fn create_index_buffer_object(&mut self, itype: IndexType, data: &BufferData) -> Result<IBOHandle, String> {
-        let mut buf_id = 0;
+    pub fn set_blend_state(&mut self, state: &BlendState) {
+        let force = self.blend.is_none();
+        let prev = self.blend.unwrap_or(*state);
 
         unsafe {
-            gl::GenBuffers(1, &mut buf_id);
-            self.state.set_ibo(buf_id);
-            gl::BufferData(gl::ELEMENT_ARRAY_BUFFER, data.bytes.len() as isize, mem::transmute(&data.bytes[0]), gl::STATIC_DRAW);
-        }
-
-        let count;
+            if force || prev.enabled != state.enabled {
+                if state.enabled {
+                    gl::Enable(gl::BLEND);
+                } else {
+                    gl::Disable(gl::BLEND);
+                }
+            }
 
-        match itype {
-            IndexType::U32 => count = data.bytes.len() / mem::size_of::<u32>(),
-            IndexType::U16 => count = data.bytes.len() / mem::size_of::<u16>(),
-        }
+            if force || prev.src_color != state.src_color || prev.dst_color != state.dst_color ||
+               prev.src_alpha != state.src_alpha || prev.dst_alpha != state.dst_alpha {
+                gl::BlendFuncSeparate(gl_blend_factor(state.src_color),
+                                      gl_blend_factor(state.dst_color),
+                                      gl_blend_factor(state.src_alpha),
+                                      gl_blend_factor(state.dst_alpha));
+            }
 
-        let ibo = GLIbo {
-            id: buf_id,
-            itype: itype,
-            count: count
-        };
+            if force || prev.color_equation != state.color_equation || prev.alpha_equation != state.alpha_equation {
+                gl::BlendEquationSeparate(gl_blend_equation(state.color_equation),
+                                          gl_blend_equation(state.alpha_equation));
+            }
 
-        self.ibos.push(ibo);
+            if force || prev.constant != state.constant {
+                gl::BlendColor(state.constant[0], state.constant[1], state.constant[2], state.constant[3]);
+            }
+        }
 
-        Ok(self.vbos.len() - 1)
+        self.blend = Some(*state);
     }
 
-    fn create_program(&mut self, vert_src: &str, frag_src: &str) -> Result<ProgramHandle, String> {
-        let vs = self.compile_shader(vert_src, gl::VERTEX_SHADER);
-        let fs = self.compile_shader(frag_src, gl::FRAGMENT_SHADER);
-
-        let program;
+    pub fn set_rasterizer_state(&mut self, state: &RasterizerState) {
+        let force = self.rasterizer.is_none();
+        let prev = self.rasterizer.unwrap_or(*state);
 
         unsafe {
-            program = gl::CreateProgram();
-            gl::AttachShader(program, vs);
-            gl::AttachShader(program, fs);
-            gl::LinkProgram(program);
+            if force || prev.cull_mode != state.cull_mode {
+                match state.cull_mode {
+                    CullMode::None => gl::Disable(gl::CULL_FACE),
+                    mode           => {
+                        gl::Enable(gl::CULL_FACE);
+                        gl::CullFace(match mode {
+                            CullMode::Front => gl::FRONT,
+                            CullMode::Back  => gl::BACK,
+                            _               => gl::FRONT_AND_BACK,
+                        });
+                    },
+                }
+            }
 
-            let mut status = gl::FALSE as GLint;
-            gl::GetProgramiv(program, gl::LINK_STATUS, &mut status);
+            if force || prev.front_face != state.front_face {
+                gl::FrontFace(match state.front_face {
+                    FrontFace::CounterClockwise => gl::CCW,
+                    FrontFace::Clockwise        => gl::CW,
+                });
+            }
 
-            if status != (gl::TRUE as GLint) {
-                let mut len: GLint = 0;
-                gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
-                let mut buf = Vec::with_capacity(len as usize);
-                buf.set_len((len as usize) - 1);
-                gl::GetProgramInfoLog(program, len, ptr::null_mut(), buf.as_mut_ptr() as *mut GLchar);
-                panic!("{}", str::from_utf8(&buf).ok().expect("programinfolog not valid utf8"));
+            if force || prev.polygon_mode != state.polygon_mode {
+                gl::PolygonMode(gl::FRONT_AND_BACK, match state.polygon_mode {
+                    PolygonMode::Fill  => gl::FILL,
+                    PolygonMode::Line  => gl::LINE,
+                    PolygonMode::Point => gl::POINT,
+                });
             }
-            
-            gl::DetachShader(program, vs);
-            gl::DetachShader(program, fs);
-            
-            gl::DeleteShader(vs);
-            gl::DeleteShader(fs);
         }
 
-        let uniform_blocks = self.get_program_uniform_blocks(program);
-        let sampler2ds = self.get_program_samplers(program);
+        if force || prev.scissor != state.scissor {
+            self.apply_scissor(&state.scissor);
+        }
 
-        let prog = GLProg {
-            id: program,
-            uniform_blocks: uniform_blocks,
-            sampler2ds: sampler2ds,
+        self.rasterizer = Some(*state);
+    }
+
+    /// glClear respects the scissor test, so clearing has to disable it.
+    pub fn set_scissor(&mut self, scissor: Option<ScissorRect>) {
+        let changed = match self.rasterizer {
+            Some(ref mut state) => {
+                let changed = state.scissor != scissor;
+                state.scissor = scissor;
+                changed
+            },
+            None => true,
         };
 
-        self.progs.push(prog);
+        if changed {
+            self.apply_scissor(&scissor);
+        }
+    }
 
-        Ok(self.progs.len() - 1)
+    fn apply_scissor(&self, scissor: &Option<ScissorRect>) {
+        unsafe {
+            match *scissor {
+                Some(ref rect) => {
+                    gl::Enable(gl::SCISSOR_TEST);
+                    gl::Scissor(rect.x, rect.y, rect.width, rect.height);
+                },
+                None => gl::Disable(gl::SCISSOR_TEST),
+            }
+        }
     }
-    
-    fn get_shader_params(&self, uniform_blocks: &Vec<GLUniformBlock>, samplers: &Vec<GLSampler2D>) -> ShaderParams {
-        let mut param_groups: Vec<ParamGroup> = Vec::with_capacity(uniform_blocks.len());
 
-        for block in uniform_blocks.iter() {
-            let group_name = block.name.clone();
-            let mut params: Vec<Param> = Vec::with_capacity(block.uniforms.len());
+    pub fn set_texture(&mut self, tex_unit_i: u32, target: GLenum, texture: GLuint) {
+        unsafe {
+            let mut bind = false;
             
-            for uniform in block.uniforms.iter() {
-                let param_value: ParamValue = match uniform.utype {
-                    gl::FLOAT      => ParamValue::F32(0.0),
-                    gl::FLOAT_VEC4 => ParamValue::Vec4(Vec4f::new(0.0, 0.0, 0.0, 0.0)),
-                    gl::FLOAT_MAT3 => ParamValue::Mat3(Mat3f::identity()),
-                    gl::FLOAT_MAT4 => ParamValue::Mat4(Mat4f::identity()),
-                    _              => panic!("Unsupported shader uniform type!"),
-                };
-
-                params.push(Param {
-                    name: uniform.name.clone(),
-                    value: param_value
-                });
+            match self.tex_units.entry(tex_unit_i) {
+                Vacant(entry) => { 
+                    entry.insert((target, texture));
+                    bind = true;
+                },
+                Occupied(mut entry) => {
+                    let (old_target, old_texture) = entry.insert((target, texture));
+                    if old_target != target {
+                        // Don't leave the old texture bound to a target
+                        // that nothing is tracking any more.
+                        gl::ActiveTexture(gl::TEXTURE0 + tex_unit_i);
+                        gl::BindTexture(old_target, 0);
+                        bind = true;
+                    } else if old_texture != texture {
+                        bind = true;
+                    }
+                },
+            }
+            
+            if bind {
+                gl::ActiveTexture(gl::TEXTURE0 + tex_unit_i);
+                gl::BindTexture(target, texture);
             }
+        }
+    }
 
-            param_groups.push(ParamGroup {
-                name: group_name,
-                params: params,
-            });
+    pub fn set_sampler(&mut self, tex_unit_i: u32, sampler: GLuint) {
+        if self.sampler_units.insert(tex_unit_i, sampler) != Some(sampler) {
+            unsafe { gl::BindSampler(tex_unit_i, sampler); }
         }
-        
-        let tex_group_name = "_textures".to_string();
-        let mut tex_params: Vec<Param> = Vec::with_capacity(samplers.len());
-        
-        for sampler in samplers.iter() {
-            tex_params.push(Param {
-                name: sampler.uniform_info.name.clone(),
-                value: ParamValue::Texture2D(0),
-            });
+    }
+}
+
+/// Starting value of a uniform block member's param. Matrices start out as
+/// identity, everything else as zero.
+fn gl_uniform_default_value(utype: GLenum) -> Option<ParamValue> {
+    let value = match utype {
+        gl::FLOAT             => ParamValue::F32(0.0),
+        gl::FLOAT_VEC2        => ParamValue::Vec2(Vec2f::new(0.0, 0.0)),
+        gl::FLOAT_VEC3        => ParamValue::Vec3(Vec3f::new(0.0, 0.0, 0.0)),
+        gl::FLOAT_VEC4        => ParamValue::Vec4(Vec4f::new(0.0, 0.0, 0.0, 0.0)),
+        gl::INT               => ParamValue::I32(0),
+        gl::INT_VEC2          => ParamValue::IVec2(Vec2i::new(0, 0)),
+        gl::INT_VEC3          => ParamValue::IVec3(Vec3i::new(0, 0, 0)),
+        gl::INT_VEC4          => ParamValue::IVec4(Vec4i::new(0, 0, 0, 0)),
+        gl::UNSIGNED_INT      => ParamValue::U32(0),
+        gl::UNSIGNED_INT_VEC2 => ParamValue::UVec2(Vec2u::new(0, 0)),
+        gl::UNSIGNED_INT_VEC3 => ParamValue::UVec3(Vec3u::new(0, 0, 0)),
+        gl::UNSIGNED_INT_VEC4 => ParamValue::UVec4(Vec4u::new(0, 0, 0, 0)),
+        gl::BOOL              => ParamValue::Bool(false),
+        gl::BOOL_VEC2         => ParamValue::BVec2([false; 2]),
+        gl::BOOL_VEC3         => ParamValue::BVec3([false; 3]),
+        gl::BOOL_VEC4         => ParamValue::BVec4([false; 4]),
+        gl::FLOAT_MAT2        => ParamValue::Mat2(Mat2f::identity()),
+        gl::FLOAT_MAT3        => ParamValue::Mat3(Mat3f::identity()),
+        gl::FLOAT_MAT4        => ParamValue::Mat4(Mat4f::identity()),
+        gl::FLOAT_MAT2x3      => ParamValue::Mat2x3([Vec3f::new(1.0, 0.0, 0.0), Vec3f::new(0.0, 1.0, 0.0)]),
+        gl::FLOAT_MAT2x4      => ParamValue::Mat2x4([Vec4f::new(1.0, 0.0, 0.0, 0.0), Vec4f::new(0.0, 1.0, 0.0, 0.0)]),
+        gl::FLOAT_MAT3x2      => ParamValue::Mat3x2([Vec2f::new(1.0, 0.0), Vec2f::new(0.0, 1.0), Vec2f::new(0.0, 0.0)]),
+        gl::FLOAT_MAT3x4      => ParamValue::Mat3x4([Vec4f::new(1.0, 0.0, 0.0, 0.0), Vec4f::new(0.0, 1.0, 0.0, 0.0), Vec4f::new(0.0, 0.0, 1.0, 0.0)]),
+        gl::FLOAT_MAT4x2      => ParamValue::Mat4x2([Vec2f::new(1.0, 0.0), Vec2f::new(0.0, 1.0), Vec2f::new(0.0, 0.0), Vec2f::new(0.0, 0.0)]),
+        gl::FLOAT_MAT4x3      => ParamValue::Mat4x3([Vec3f::new(1.0, 0.0, 0.0), Vec3f::new(0.0, 1.0, 0.0), Vec3f::new(0.0, 0.0, 1.0), Vec3f::new(0.0, 0.0, 0.0)]),
+        _                     => return None,
+    };
+
+    Some(value)
+}
+
+/// Params for reflected uniforms, skipping types that can't be set, along
+/// with the index of the uniform each param is for.
+fn uniform_params<'a, I: Iterator<Item=&'a GLUniform>>(uniforms: I) -> (Vec<Param>, Vec<usize>) {
+    let mut params: Vec<Param> = Vec::new();
+    let mut indices: Vec<usize> = Vec::new();
+
+    for (index, uniform) in uniforms.enumerate() {
+        let mut param_value: ParamValue = match gl_uniform_default_value(uniform.utype) {
+            Some(x) => x,
+            None    => {
+                println!("Skipping uniform {} of unsupported type {:#x}", uniform.name, uniform.utype);
+                continue;
+            },
+        };
+
+        if uniform.is_array {
+            param_value = ParamValue::Array(vec![param_value; uniform.size as usize]);
         }
-        
+
+        params.push(Param {
+            name: uniform.name.clone(),
+            value: param_value
+        });
+        indices.push(index);
+    }
+
+    (params, indices)
+}
+
+/// The params of a program and the uniform each one is for, see
+/// `GLProg::param_uniforms`.
+fn program_shader_params(uniform_blocks: &[GLUniformBlock], default_uniforms: &[GLDefaultUniform], sampler_uniforms: &[GLSamplerUniform]) -> (ShaderParams, Vec<Vec<usize>>) {
+    let mut param_groups: Vec<ParamGroup> = Vec::with_capacity(uniform_blocks.len() + 2);
+    let mut param_uniforms: Vec<Vec<usize>> = Vec::with_capacity(uniform_blocks.len() + 2);
+
+    for block in uniform_blocks.iter() {
+        let (params, indices) = uniform_params(block.uniforms.iter());
         param_groups.push(ParamGroup {
-            name: tex_group_name,
-            params: tex_params, 
+            name: block.name.clone(),
+            params: params,
         });
+        param_uniforms.push(indices);
+    }
+
+    let (default_params, default_indices) = uniform_params(default_uniforms.iter().map(|uniform| &uniform.uniform_info));
+    param_groups.push(ParamGroup {
+        name: "_default".to_string(),
+        params: default_params,
+    });
+    param_uniforms.push(default_indices);
 
-        ShaderParams::new(param_groups)
+    let mut tex_params: Vec<Param> = Vec::with_capacity(sampler_uniforms.len());
+
+    for sampler in sampler_uniforms.iter() {
+        tex_params.push(Param {
+            name: sampler.uniform_info.name.clone(),
+            value: sampler_param_value(sampler.target, sampler.shadow),
+        });
     }
-    
-    fn get_uniform_info(&self, progid: GLuint, uniform_index: u32) -> GLUniform {
-        unsafe {
-            let mut max_uniform_name_len: GLint = 0;
+
+    param_groups.push(ParamGroup {
+        name: "_textures".to_string(),
+        params: tex_params,
+    });
+    param_uniforms.push((0..sampler_uniforms.len()).collect());
+
+    (ShaderParams::new(param_groups), param_uniforms)
+}
+
+fn gl_buffer_usage(usage: BufferUsage) -> GLenum {
+    match usage {
+        BufferUsage::Static  => gl::STATIC_DRAW,
+        BufferUsage::Dynamic => gl::DYNAMIC_DRAW,
+        BufferUsage::Stream  => gl::STREAM_DRAW,
+    }
+}
+
+fn gl_primitive_topology(topology: PrimitiveTopology) -> GLenum {
+    match topology {
+        PrimitiveTopology::Points        => gl::POINTS,
+        PrimitiveTopology::Lines         => gl::LINES,
+        PrimitiveTopology::LineStrip     => gl::LINE_STRIP,
+        PrimitiveTopology::Triangles     => gl::TRIANGLES,
+        PrimitiveTopology::TriangleStrip => gl::TRIANGLE_STRIP,
+        PrimitiveTopology::TriangleFan   => gl::TRIANGLE_FAN,
+    }
+}
+
+fn gl_vertex_element_type(vtype: VertexElementType) -> GLenum {
+    match vtype {
+        VertexElementType::F32 | VertexElementType::F32F32 |
+        VertexElementType::F32F32F32 | VertexElementType::F32F32F32F32 => gl::FLOAT,
+        VertexElementType::F16(_)                                      => gl::HALF_FLOAT,
+        VertexElementType::U8Norm(_) | VertexElementType::U8(_)        => gl::UNSIGNED_BYTE,
+        VertexElementType::I8Norm(_) | VertexElementType::I8(_)        => gl::BYTE,
+        VertexElementType::U16Norm(_) | VertexElementType::U16(_)      => gl::UNSIGNED_SHORT,
+        VertexElementType::I16Norm(_) | VertexElementType::I16(_)      => gl::SHORT,
+        VertexElementType::U32(_)                                      => gl::UNSIGNED_INT,
+        VertexElementType::I32(_)                                      => gl::INT,
+        VertexElementType::I10_10_10_2Norm                             => gl::INT_2_10_10_10_REV,
+        VertexElementType::U10_10_10_2Norm                             => gl::UNSIGNED_INT_2_10_10_10_REV,
+    }
+}
+
+/// Number of whole vertices in `data`.
+fn vertex_count(layout: &VertexLayoutDescription, data: &BufferData) -> usize {
+    match layout.stride() {
+        0      => 0,
+        stride => data.bytes.len() / stride,
+    }
+}
+
+/// Buffers are updated through `COPY_WRITE_BUFFER`, binding an index buffer
+/// to `ELEMENT_ARRAY_BUFFER` would attach it to whichever VAO is bound.
+fn write_buffer_region(buffer: GLuint, offset: usize, bytes: &[u8]) {
+    unsafe {
+        gl::BindBuffer(gl::COPY_WRITE_BUFFER, buffer);
+        gl::BufferSubData(gl::COPY_WRITE_BUFFER, offset as isize, bytes.len() as isize, bytes.as_ptr() as *const GLvoid);
+    }
+}
+
+/// Replaces a buffer's contents and returns its new size. Stream buffers
+/// are orphaned rather than reallocated, and never shrink, so that feeding
+/// them a different amount each frame doesn't stall or churn memory.
+fn replace_buffer_data(buffer: GLuint, size: usize, usage: BufferUsage, bytes: &[u8]) -> usize {
+    unsafe {
+        gl::BindBuffer(gl::COPY_WRITE_BUFFER, buffer);
+
+        if usage == BufferUsage::Stream {
+            let new_size = size.max(bytes.len());
+            gl::BufferData(gl::COPY_WRITE_BUFFER, new_size as isize, ptr::null(), gl::STREAM_DRAW);
+            gl::BufferSubData(gl::COPY_WRITE_BUFFER, 0, bytes.len() as isize, bytes.as_ptr() as *const GLvoid);
+            new_size
+        } else {
+            gl::BufferData(gl::COPY_WRITE_BUFFER, bytes.len() as isize, bytes.as_ptr() as *const GLvoid, gl_buffer_usage(usage));
+            bytes.len()
+        }
+    }
+}
+
+fn gl_attribute_is_integer(atype: GLenum) -> bool {
+    match atype {
+        gl::INT | gl::INT_VEC2 | gl::INT_VEC3 | gl::INT_VEC4 |
+        gl::UNSIGNED_INT | gl::UNSIGNED_INT_VEC2 | gl::UNSIGNED_INT_VEC3 | gl::UNSIGNED_INT_VEC4 => true,
+        _ => false,
+    }
+}
+
+/// Texture target and whether it is a shadow sampler, for sampler uniform
+/// types. None for everything else.
+fn gl_sampler_type(utype: GLenum) -> Option<(GLenum, bool)> {
+    match utype {
+        gl::SAMPLER_2D                   => Some((gl::TEXTURE_2D, false)),
+        gl::SAMPLER_CUBE                 => Some((gl::TEXTURE_CUBE_MAP, false)),
+        gl::SAMPLER_2D_ARRAY             => Some((gl::TEXTURE_2D_ARRAY, false)),
+        gl::SAMPLER_3D                   => Some((gl::TEXTURE_3D, false)),
+        gl::SAMPLER_2D_SHADOW            => Some((gl::TEXTURE_2D, true)),
+        gl::SAMPLER_CUBE_SHADOW          => Some((gl::TEXTURE_CUBE_MAP, true)),
+        gl::SAMPLER_2D_ARRAY_SHADOW      => Some((gl::TEXTURE_2D_ARRAY, true)),
+        _                                => None,
+    }
+}
+
+fn sampler_param_value(target: GLenum, shadow: bool) -> ParamValue {
+    match (target, shadow) {
+        (gl::TEXTURE_CUBE_MAP, false) => ParamValue::TextureCube(0),
+        (gl::TEXTURE_2D_ARRAY, false) => ParamValue::Texture2DArray(0),
+        (gl::TEXTURE_3D, _)           => ParamValue::Texture3D(0),
+        (gl::TEXTURE_2D, true)        => ParamValue::Texture2DShadow(0),
+        (gl::TEXTURE_CUBE_MAP, true)  => ParamValue::TextureCubeShadow(0),
+        (gl::TEXTURE_2D_ARRAY, true)  => ParamValue::Texture2DArrayShadow(0),
+        _                             => ParamValue::Texture2D(0),
+    }
+}
+
+fn gl_texture_target(texture_type: TextureType) -> GLenum {
+    match texture_type {
+        TextureType::Texture2D => gl::TEXTURE_2D,
+        TextureType::Cube      => gl::TEXTURE_CUBE_MAP,
+        TextureType::Array2D   => gl::TEXTURE_2D_ARRAY,
+        TextureType::Texture3D => gl::TEXTURE_3D,
+    }
+}
+
+/// Sets a default block uniform of the current program.
+fn upload_default_uniform(uniform: &GLDefaultUniform, value: &ParamValue) {
+    match *value {
+        ParamValue::Array(ref elements) => {
+            for (element, location) in elements.iter().zip(uniform.locations.iter()) {
+                upload_uniform_value(*location, element);
+            }
+        },
+        _ => upload_uniform_value(uniform.locations[0], value),
+    }
+}
+
+fn upload_uniform_value(location: GLint, value: &ParamValue) {
+    fn b(x: bool) -> GLint { if x { 1 } else { 0 } }
+
+    unsafe {
+        match *value {
+            ParamValue::F32(x)   => gl::Uniform1f(location, x),
+            ParamValue::I32(x)   => gl::Uniform1i(location, x),
+            ParamValue::U32(x)   => gl::Uniform1ui(location, x),
+            ParamValue::Bool(x)  => gl::Uniform1i(location, b(x)),
+            ParamValue::Vec2(v)  => gl::Uniform2f(location, v.x, v.y),
+            ParamValue::Vec3(v)  => gl::Uniform3f(location, v.x, v.y, v.z),
+            ParamValue::Vec4(v)  => gl::Uniform4f(location, v.x, v.y, v.z, v.w),
+            ParamValue::IVec2(v) => gl::Uniform2i(location, v.x, v.y),
+            ParamValue::IVec3(v) => gl::Uniform3i(location, v.x, v.y, v.z),
+            ParamValue::IVec4(v) => gl::Uniform4i(location, v.x, v.y, v.z, v.w),
+            ParamValue::UVec2(v) => gl::Uniform2ui(location, v.x, v.y),
+            ParamValue::UVec3(v) => gl::Uniform3ui(location, v.x, v.y, v.z),
+            ParamValue::UVec4(v) => gl::Uniform4ui(location, v.x, v.y, v.z, v.w),
+            ParamValue::BVec2(v) => gl::Uniform2i(location, b(v[0]), b(v[1])),
+            ParamValue::BVec3(v) => gl::Uniform3i(location, b(v[0]), b(v[1]), b(v[2])),
+            ParamValue::BVec4(v) => gl::Uniform4i(location, b(v[0]), b(v[1]), b(v[2]), b(v[3])),
+            ParamValue::Array(_) => (),
+            _ if value.texture_handle().is_some() => (),
+            _ => {
+                let columns = value.matrix_columns().unwrap();
+                let data: Vec<f32> = columns.iter().flat_map(|column| column.iter().cloned()).collect();
+                let ptr = data.as_ptr();
+
+                match *value {
+                    ParamValue::Mat2(_)   => gl::UniformMatrix2fv(location, 1, gl::FALSE, ptr),
+                    ParamValue::Mat3(_)   => gl::UniformMatrix3fv(location, 1, gl::FALSE, ptr),
+                    ParamValue::Mat4(_)   => gl::UniformMatrix4fv(location, 1, gl::FALSE, ptr),
+                    ParamValue::Mat2x3(_) => gl::UniformMatrix2x3fv(location, 1, gl::FALSE, ptr),
+                    ParamValue::Mat2x4(_) => gl::UniformMatrix2x4fv(location, 1, gl::FALSE, ptr),
+                    ParamValue::Mat3x2(_) => gl::UniformMatrix3x2fv(location, 1, gl::FALSE, ptr),
+                    ParamValue::Mat3x4(_) => gl::UniformMatrix3x4fv(location, 1, gl::FALSE, ptr),
+                    ParamValue::Mat4x2(_) => gl::UniformMatrix4x2fv(location, 1, gl::FALSE, ptr),
+                    _                     => gl::UniformMatrix4x3fv(location, 1, gl::FALSE, ptr),
+                }
+            },
+        }
+    }
+}
+
+/// Writes a param into a uniform block's buffer using the offsets and
+/// strides the driver reported, rather than assuming tight packing.
+fn write_uniform(buffer_data: &mut BufferData, uniform: &GLUniform, value: &ParamValue) -> Result<(), String> {
+    match *value {
+        ParamValue::Array(ref elements) => {
+            for (i, element) in elements.iter().take(uniform.size as usize).enumerate() {
+                try!(write_uniform_element(buffer_data, uniform, uniform.offset as usize + i * uniform.array_stride, element));
+            }
+
+            Ok(())
+        },
+        _ => write_uniform_element(buffer_data, uniform, uniform.offset as usize, value),
+    }
+}
+
+fn write_uniform_element(buffer_data: &mut BufferData, uniform: &GLUniform, offset: usize, value: &ParamValue) -> Result<(), String> {
+    if let Some(components) = value.scalar_components() {
+        for (i, component) in components.iter().enumerate() {
+            try!(buffer_data.update_region(offset + i * 4, component.to_vec()));
+        }
+        return Ok(());
+    }
+
+    if let Some(columns) = value.matrix_columns() {
+        for (col, column) in columns.iter().enumerate() {
+            for (row, x) in column.iter().enumerate() {
+                let element_offset = if uniform.row_major {
+                    offset + row * uniform.matrix_stride + col * 4
+                } else {
+                    offset + col * uniform.matrix_stride + row * 4
+                };
+
+                try!(buffer_data.update_region(element_offset, vec![*x]));
+            }
+        }
+    }
+
+    // Textures don't live in uniform blocks.
+    Ok(())
+}
+
+/// One and two channel formats are stored in red and green, so have them
+/// read back as the channels they stand for. The texture has to be bound.
+fn set_texture_swizzle(target: GLenum, format: &TextureFormat) {
+    let swizzle = match *format {
+        TextureFormat::Alpha          => [gl::ZERO, gl::ZERO, gl::ZERO, gl::RED],
+        TextureFormat::Luminance      => [gl::RED, gl::RED, gl::RED, gl::ONE],
+        TextureFormat::LuminanceAlpha => [gl::RED, gl::RED, gl::RED, gl::GREEN],
+        _                             => return,
+    };
+
+    let swizzle: Vec<GLint> = swizzle.iter().map(|x| *x as GLint).collect();
+    unsafe {
+        gl::TexParameteriv(target, gl::TEXTURE_SWIZZLE_RGBA, swizzle.as_ptr());
+    }
+}
+
+/// Internal format, format and type to upload texture data with. sRGB is
+/// only honoured for 8-bit RGB and RGBA, GL has no other sRGB formats.
+fn gl_texture_upload_format(format: &TextureFormat, component_type: ComponentType, color_space: ColorSpace) -> (GLenum, GLenum, GLenum) {
+    let srgb = color_space == ColorSpace::SRGB;
+
+    let gl_format = match *format {
+        TextureFormat::Alpha | TextureFormat::Luminance => gl::RED,
+        TextureFormat::LuminanceAlpha                   => gl::RG,
+        TextureFormat::RGB                              => gl::RGB,
+        TextureFormat::RGBA                             => gl::RGBA,
+    };
+
+    let internal_format = match (component_type, gl_format) {
+        (ComponentType::U8, gl::RED)           => gl::R8,
+        (ComponentType::U8, gl::RG)            => gl::RG8,
+        (ComponentType::U8, gl::RGB) if srgb   => gl::SRGB8,
+        (ComponentType::U8, gl::RGB)           => gl::RGB8,
+        (ComponentType::U8, _) if srgb         => gl::SRGB8_ALPHA8,
+        (ComponentType::U8, _)                 => gl::RGBA8,
+        (ComponentType::U16, gl::RED)          => gl::R16,
+        (ComponentType::U16, gl::RG)           => gl::RG16,
+        (ComponentType::U16, gl::RGB)          => gl::RGB16,
+        (ComponentType::U16, _)                => gl::RGBA16,
+        (ComponentType::F32, gl::RED)          => gl::R32F,
+        (ComponentType::F32, gl::RG)           => gl::RG32F,
+        (ComponentType::F32, gl::RGB)          => gl::RGB32F,
+        (ComponentType::F32, _)                => gl::RGBA32F,
+    };
+
+    let data_type = match component_type {
+        ComponentType::U8  => gl::UNSIGNED_BYTE,
+        ComponentType::U16 => gl::UNSIGNED_SHORT,
+        ComponentType::F32 => gl::FLOAT,
+    };
+
+    (internal_format, gl_format, data_type)
+}
+
+/// From EXT_texture_filter_anisotropic, which the generated bindings don't
+/// include.
+const TEXTURE_MAX_ANISOTROPY_EXT: GLenum = 0x84FE;
+const MAX_TEXTURE_MAX_ANISOTROPY_EXT: GLenum = 0x84FF;
+
+/// The largest anisotropy samplers may use, None when the context has
+/// neither GL 4.6 nor EXT_texture_filter_anisotropic.
+fn query_max_anisotropy() -> Option<GLfloat> {
+    unsafe {
+        let mut major: GLint = 0;
+        let mut minor: GLint = 0;
+        gl::GetIntegerv(gl::MAJOR_VERSION, &mut major);
+        gl::GetIntegerv(gl::MINOR_VERSION, &mut minor);
+
+        let mut supported = (major, minor) >= (4, 6);
+
+        if !supported {
+            let mut num_extensions: GLint = 0;
+            gl::GetIntegerv(gl::NUM_EXTENSIONS, &mut num_extensions);
+
+            for i in 0..num_extensions {
+                let name = gl::GetStringi(gl::EXTENSIONS, i as GLuint);
+                if !name.is_null() && CStr::from_ptr(name as *const _).to_bytes() == b"GL_EXT_texture_filter_anisotropic" {
+                    supported = true;
+                    break;
+                }
+            }
+        }
+
+        if !supported {
+            return None;
+        }
+
+        let mut max_anisotropy: GLfloat = 1.0;
+        gl::GetFloatv(MAX_TEXTURE_MAX_ANISOTROPY_EXT, &mut max_anisotropy);
+        Some(max_anisotropy)
+    }
+}
+
+fn gl_wrap_mode(wrap: WrapMode) -> GLenum {
+    match wrap {
+        WrapMode::Repeat         => gl::REPEAT,
+        WrapMode::MirroredRepeat => gl::MIRRORED_REPEAT,
+        WrapMode::ClampToEdge    => gl::CLAMP_TO_EDGE,
+        WrapMode::ClampToBorder  => gl::CLAMP_TO_BORDER,
+    }
+}
+
+fn gl_compare_func(func: CompareFunc) -> GLenum {
+    match func {
+        CompareFunc::Never        => gl::NEVER,
+        CompareFunc::Less         => gl::LESS,
+        CompareFunc::Equal        => gl::EQUAL,
+        CompareFunc::LessEqual    => gl::LEQUAL,
+        CompareFunc::Greater      => gl::GREATER,
+        CompareFunc::NotEqual     => gl::NOTEQUAL,
+        CompareFunc::GreaterEqual => gl::GEQUAL,
+        CompareFunc::Always       => gl::ALWAYS,
+    }
+}
+
+fn gl_stencil_op(op: StencilOp) -> GLenum {
+    match op {
+        StencilOp::Keep          => gl::KEEP,
+        StencilOp::Zero          => gl::ZERO,
+        StencilOp::Replace       => gl::REPLACE,
+        StencilOp::Increment     => gl::INCR,
+        StencilOp::IncrementWrap => gl::INCR_WRAP,
+        StencilOp::Decrement     => gl::DECR,
+        StencilOp::DecrementWrap => gl::DECR_WRAP,
+        StencilOp::Invert        => gl::INVERT,
+    }
+}
+
+fn gl_blend_factor(factor: BlendFactor) -> GLenum {
+    match factor {
+        BlendFactor::Zero                  => gl::ZERO,
+        BlendFactor::One                   => gl::ONE,
+        BlendFactor::SrcColor              => gl::SRC_COLOR,
+        BlendFactor::OneMinusSrcColor      => gl::ONE_MINUS_SRC_COLOR,
+        BlendFactor::DstColor              => gl::DST_COLOR,
+        BlendFactor::OneMinusDstColor      => gl::ONE_MINUS_DST_COLOR,
+        BlendFactor::SrcAlpha              => gl::SRC_ALPHA,
+        BlendFactor::OneMinusSrcAlpha      => gl::ONE_MINUS_SRC_ALPHA,
+        BlendFactor::DstAlpha              => gl::DST_ALPHA,
+        BlendFactor::OneMinusDstAlpha      => gl::ONE_MINUS_DST_ALPHA,
+        BlendFactor::ConstantColor         => gl::CONSTANT_COLOR,
+        BlendFactor::OneMinusConstantColor => gl::ONE_MINUS_CONSTANT_COLOR,
+        BlendFactor::ConstantAlpha         => gl::CONSTANT_ALPHA,
+        BlendFactor::OneMinusConstantAlpha => gl::ONE_MINUS_CONSTANT_ALPHA,
+        BlendFactor::SrcAlphaSaturate      => gl::SRC_ALPHA_SATURATE,
+    }
+}
+
+fn gl_blend_equation(equation: BlendEquation) -> GLenum {
+    match equation {
+        BlendEquation::Add             => gl::FUNC_ADD,
+        BlendEquation::Subtract        => gl::FUNC_SUBTRACT,
+        BlendEquation::ReverseSubtract => gl::FUNC_REVERSE_SUBTRACT,
+        BlendEquation::Min             => gl::MIN,
+        BlendEquation::Max             => gl::MAX,
+    }
+}
+
+/// A texture's own sampler, shared with its `OpenGLTexture` so that changes
+/// made through the texture reach the renderer.
+struct GLTextureSampler {
+    desc: SamplerDescription,
+    changed: bool,
+}
+
+pub struct GLTexture {
+    id: GLuint,
+    target: GLenum,
+    has_mipmaps: bool,
+    sampler: Rc<RefCell<GLTextureSampler>>,
+    /// Index into `OpenGLRenderer::samplers` matching `sampler`, updated
+    /// the next time the texture is bound after it changes.
+    sampler_object: usize,
+}
+
+/// Sampler objects are shared by every texture and material sampling the
+/// same way, and live as long as the renderer.
+struct GLSampler {
+    id: GLuint,
+    desc: SamplerDescription,
+}
+
+pub struct OpenGLTexture {
+    texture_handle: TextureHandle,
+    texture_type: TextureType,
+    sampler: Rc<RefCell<GLTextureSampler>>,
+    texture_format: TextureFormat,
+    component_type: ComponentType,
+    color_space: ColorSpace,
+    dead_resources: DeadResources,
+}
+
+impl Drop for OpenGLTexture {
+    fn drop(&mut self) {
+        self.dead_resources.borrow_mut().push(GLDeadResource::Texture(self.texture_handle));
+    }
+}
+
+impl Texture for OpenGLTexture {
+    fn param_handle(&self) -> TextureParamHandle {
+        self.texture_handle.to_bits()
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn texture_type(&self) -> TextureType {
+        self.texture_type
+    }
+    
+    fn format(&self) -> &TextureFormat {
+        &self.texture_format
+    }
+
+    fn component_type(&self) -> ComponentType {
+        self.component_type
+    }
+
+    fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+    
+    fn sampler(&self) -> SamplerDescription {
+        self.sampler.borrow().desc.clone()
+    }
+
+    fn set_sampler(&mut self, desc: &SamplerDescription) {
+        let mut sampler = self.sampler.borrow_mut();
+        sampler.desc = desc.clone();
+        sampler.changed = true;
+    }
+}
+
+pub struct OpenGLRenderTarget {
+    framebuffer_handle: FramebufferHandle,
+    color_textures: Vec<TextureParamHandle>,
+    depth_texture: Option<TextureParamHandle>,
+    width: u32,
+    height: u32,
+    dead_resources: DeadResources,
+}
+
+impl Drop for OpenGLRenderTarget {
+    fn drop(&mut self) {
+        self.dead_resources.borrow_mut().push(GLDeadResource::RenderTarget(self.framebuffer_handle));
+    }
+}
+
+impl RenderTarget for OpenGLRenderTarget {
+    fn handle(&self) -> RenderTargetHandle {
+        self.framebuffer_handle.to_bits()
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn color_texture_count(&self) -> usize {
+        self.color_textures.len()
+    }
+
+    fn color_texture(&self, index: usize) -> Option<TextureParamHandle> {
+        self.color_textures.get(index).cloned()
+    }
+
+    fn depth_texture(&self) -> Option<TextureParamHandle> {
+        self.depth_texture
+    }
+}
+
+pub struct OpenGLGeometry {
+    id: usize,
+    vbo: VBOHandle,
+    ibo: IBOHandle,
+    vao: VAOHandle,
+    program: ProgramHandle,
+    material: MaterialHandle,
+    material_generation: u32,
+    layout_desc: VertexLayoutDescription,
+    params: ShaderParams,
+    render_state: RenderState,
+    draw_state: DrawState,
+    /// Whole vertices in the vertex buffer, drawn when there are no indices.
+    vertex_count: usize,
+    /// One per uniform block of the program, in the same order.
+    uniform_buffers: Vec<GLUniformBuffer>,
+    /// Texture bound to each sampler of the program, in the same order.
+    sampler_textures: Vec<Option<TextureHandle>>,
+    /// The material's sampler object for each sampler of the program, None
+    /// to use the texture's own.
+    sampler_objects: Vec<Option<usize>>,
+    /// VAO with the attributes of the instance buffer the geometry was last
+    /// drawn with as well as its own, rebuilt when drawn with another one.
+    instance_vao: Option<(VAOHandle, VBOHandle)>,
+    /// The instance buffer, if any, the layouts were last found to match
+    /// the program's attributes with. None until the first draw.
+    checked_inputs: Option<Option<VBOHandle>>,
+    dead_resources: DeadResources,
+}
+
+impl Drop for OpenGLGeometry {
+    fn drop(&mut self) {
+        self.dead_resources.borrow_mut().push(GLDeadResource::Geometry {
+            vao: self.vao,
+            vbo: self.vbo,
+            ibo: self.ibo,
+            instance_vao: self.instance_vao.map(|(vao, _)| vao),
+            program: self.program,
+            uniform_buffers: mem::replace(&mut self.uniform_buffers, Vec::new()),
+        });
+    }
+}
+
+impl Geometry for OpenGLGeometry {
+    fn get_vertex_layout_description(&self) -> &VertexLayoutDescription {
+        &self.layout_desc
+    }
+    
+    fn get_params(&self) -> &ShaderParams {
+        &self.params
+    }
+    
+    fn get_mut_params(&mut self) -> &mut ShaderParams {
+        &mut self.params
+    }
+    
+    fn get_render_state(&self) -> &RenderState {
+        &self.render_state
+    }
+    
+    fn get_mut_render_state(&mut self) -> &mut RenderState {
+        &mut self.render_state
+    }
+
+    fn get_draw_state(&self) -> &DrawState {
+        &self.draw_state
+    }
+
+    fn get_mut_draw_state(&mut self) -> &mut DrawState {
+        &mut self.draw_state
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+}
+
+fn gl_geometry(geom: &mut Box<Geometry>) -> Result<&mut OpenGLGeometry, RendererError> {
+    match geom.as_any_mut().downcast_mut::<OpenGLGeometry>() {
+        Some(glgeom) => Ok(glgeom),
+        None         => Err(RendererError::ForeignResource("geometry wasn't created by the OpenGL renderer".to_string())),
+    }
+}
+
+pub struct OpenGLInstanceBuffer {
+    vbo: VBOHandle,
+    layout_desc: VertexLayoutDescription,
+    count: usize,
+    dead_resources: DeadResources,
+}
+
+impl Drop for OpenGLInstanceBuffer {
+    fn drop(&mut self) {
+        self.dead_resources.borrow_mut().push(GLDeadResource::InstanceBuffer(self.vbo));
+    }
+}
+
+impl InstanceBuffer for OpenGLInstanceBuffer {
+    fn layout(&self) -> &VertexLayoutDescription {
+        &self.layout_desc
+    }
+
+    fn instance_count(&self) -> usize {
+        self.count
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+}
+
+fn gl_instance_buffer(instances: &InstanceBuffer) -> Result<&OpenGLInstanceBuffer, RendererError> {
+    match instances.as_any().downcast_ref::<OpenGLInstanceBuffer>() {
+        Some(glinstances) => Ok(glinstances),
+        None              => Err(RendererError::ForeignResource("instance buffer wasn't created by the OpenGL renderer".to_string())),
+    }
+}
+
+/// GL objects whose owning box has been dropped, waiting for the renderer
+/// to free them.
+enum GLDeadResource {
+    Geometry {
+        vao: VAOHandle,
+        vbo: VBOHandle,
+        ibo: IBOHandle,
+        instance_vao: Option<VAOHandle>,
+        program: ProgramHandle,
+        uniform_buffers: Vec<GLUniformBuffer>,
+    },
+    InstanceBuffer(VBOHandle),
+    Texture(TextureHandle),
+    /// Frees the attachment textures along with the framebuffer.
+    RenderTarget(FramebufferHandle),
+}
+
+type DeadResources = Rc<RefCell<Vec<GLDeadResource>>>;
+
+pub struct OpenGLRenderer {
+    vaos: HandlePool<GLVertexArrayObject>,
+    vbos: HandlePool<GLVbo>,
+    ibos: HandlePool<GLIbo>,
+    progs: HandlePool<GLProg>,
+    program_cache: HashMap<ProgramKey, ProgramHandle>,
+    materials: HandlePool<GLMaterial>,
+    textures: HandlePool<GLTexture>,
+    samplers: Vec<GLSampler>,
+    /// None when anisotropic filtering isn't supported.
+    max_anisotropy: Option<GLfloat>,
+    fbos: HandlePool<GLFramebuffer>,
+    ubos: HandlePool<GLuint>,
+    /// Buffers of the shared blocks, 0 until first set.
+    frame_data_ubo: GLuint,
+    view_data_ubo: GLuint,
+    preprocessor: GlslPreprocessor,
+    num_geometries: usize,
+    state: GLStateManager,
+    /// The window's viewport, saved while a render target is bound.
+    window_viewport: Option<[GLint; 4]>,
+    /// Shared with every geometry, texture and render target, which add
+    /// themselves here when dropped.
+    dead_resources: DeadResources,
+    /// Reused by `apply_shader_params` for the params changed since the
+    /// geometry last drew.
+    param_changes: Vec<ParamHandle>,
+}
+
+impl OpenGLRenderer {
+    pub fn new() -> Box<OpenGLRenderer> {
+        Box::new(OpenGLRenderer {
+            vaos: HandlePool::new(),
+            vbos: HandlePool::new(),
+            ibos: HandlePool::new(),
+            progs: HandlePool::new(),
+            program_cache: HashMap::new(),
+            materials: HandlePool::new(),
+            textures: HandlePool::new(),
+            samplers: Vec::new(),
+            max_anisotropy: query_max_anisotropy(),
+            fbos: HandlePool::new(),
+            ubos: HandlePool::new(),
+            frame_data_ubo: 0,
+            view_data_ubo: 0,
+            preprocessor: GlslPreprocessor::new(),
+            num_geometries: 0,
+            state: GLStateManager::new(),
+            window_viewport: None,
+            dead_resources: Rc::new(RefCell::new(Vec::new())),
+            param_changes: Vec::new(),
+        })
+    }
+
+    /// Creates a texture with undefined contents for use as a framebuffer
+    /// attachment.
+    fn create_attachment_texture(&mut self, internal_format: GLenum, format: GLenum, data_type: GLenum, width: u32, height: u32) -> TextureHandle {
+        let mut tex_id: GLHandle = 0;
+
+        unsafe {
+            gl::GenTextures(1, &mut tex_id);
+
+            self.state.set_texture(0, gl::TEXTURE_2D, tex_id);
+
+            gl::TexImage2D(gl::TEXTURE_2D, 0, internal_format as i32, width as i32, height as i32, 0, format, data_type, ptr::null());
+
+        }
+
+        let mut desc = SamplerDescription::default();
+        desc.filtering = FilteringMethod::BiLinear;
+        desc.set_wrap(WrapMode::ClampToEdge);
+
+        self.push_texture(tex_id, gl::TEXTURE_2D, false, desc)
+    }
+
+    fn push_texture(&mut self, tex_id: GLuint, target: GLenum, has_mipmaps: bool, desc: SamplerDescription) -> TextureHandle {
+        let sampler_object = self.acquire_sampler(&desc);
+
+        self.textures.insert(GLTexture {
+            id: tex_id,
+            target: target,
+            has_mipmaps: has_mipmaps,
+            sampler: Rc::new(RefCell::new(GLTextureSampler {
+                desc: desc,
+                changed: false,
+            })),
+            sampler_object: sampler_object,
+        })
+    }
+
+    /// Finds or creates a sampler object for `desc`. Depth comparison is
+    /// enabled when `desc.compare_func` is set.
+    fn acquire_sampler(&mut self, desc: &SamplerDescription) -> usize {
+        if let Some(i) = self.samplers.iter().position(|sampler| sampler.desc == *desc) {
+            return i;
+        }
+
+        let (min_filter, mag_filter) = match desc.filtering {
+            FilteringMethod::Nearest   => (gl::NEAREST, gl::NEAREST),
+            FilteringMethod::BiLinear  => (gl::LINEAR, gl::LINEAR),
+            FilteringMethod::TriLinear |
+            FilteringMethod::Anisotropic => (gl::LINEAR_MIPMAP_LINEAR, gl::LINEAR),
+        };
+
+        let mut id: GLuint = 0;
+
+        unsafe {
+            gl::GenSamplers(1, &mut id);
+
+            gl::SamplerParameteri(id, gl::TEXTURE_MIN_FILTER, min_filter as i32);
+            gl::SamplerParameteri(id, gl::TEXTURE_MAG_FILTER, mag_filter as i32);
+            gl::SamplerParameteri(id, gl::TEXTURE_WRAP_S, gl_wrap_mode(desc.wrap_s) as i32);
+            gl::SamplerParameteri(id, gl::TEXTURE_WRAP_T, gl_wrap_mode(desc.wrap_t) as i32);
+            gl::SamplerParameteri(id, gl::TEXTURE_WRAP_R, gl_wrap_mode(desc.wrap_r) as i32);
+
+            let border_color = [desc.border_color.x, desc.border_color.y, desc.border_color.z, desc.border_color.w];
+            gl::SamplerParameterfv(id, gl::TEXTURE_BORDER_COLOR, border_color.as_ptr());
+            gl::SamplerParameterf(id, gl::TEXTURE_LOD_BIAS, desc.lod_bias);
+
+            // Without the extension anisotropic filtering is plain trilinear.
+            if let (FilteringMethod::Anisotropic, Some(max_anisotropy)) = (desc.filtering, self.max_anisotropy) {
+                gl::SamplerParameterf(id, TEXTURE_MAX_ANISOTROPY_EXT, desc.max_anisotropy.max(1.0).min(max_anisotropy));
+            }
+
+            if let Some(func) = desc.compare_func {
+                gl::SamplerParameteri(id, gl::TEXTURE_COMPARE_MODE, gl::COMPARE_REF_TO_TEXTURE as i32);
+                gl::SamplerParameteri(id, gl::TEXTURE_COMPARE_FUNC, gl_compare_func(func) as i32);
+            }
+        }
+
+        self.samplers.push(GLSampler {
+            id: id,
+            desc: desc.clone(),
+        });
+
+        self.samplers.len() - 1
+    }
+
+    /// The texture's own sampler object, switching to a new one first if the
+    /// texture's sampler has changed.
+    fn texture_sampler_object(&mut self, texh: TextureHandle) -> usize {
+        let changed_desc = {
+            let mut sampler = self.textures[texh].sampler.borrow_mut();
+            if sampler.changed {
+                sampler.changed = false;
+                Some(sampler.desc.clone())
+            } else {
+                None
+            }
+        };
+
+        if let Some(desc) = changed_desc {
+            let sampler_object = self.acquire_sampler(&desc);
+            self.textures[texh].sampler_object = sampler_object;
+        }
+
+        self.textures[texh].sampler_object
+    }
+
+    /// The material's sampler object for each sampler of the program.
+    fn material_sampler_objects(&self, material: MaterialHandle, progh: ProgramHandle) -> Vec<Option<usize>> {
+        let samplers = match self.materials.get(GLMaterialHandle::from_bits(material)) {
+            Some(glmaterial) => &glmaterial.samplers,
+            None                 => return vec![None; self.progs[progh].sampler_uniforms.len()],
+        };
+
+        self.progs[progh].sampler_uniforms.iter().map(|sampler_uniform| {
+            samplers.iter()
+                .find(|&&(ref name, _)| *name == sampler_uniform.uniform_info.name)
+                .map(|&(_, sampler_object)| sampler_object)
+        }).collect()
+    }
+
+    /// Binds the geometry's textures along with the sampler each one is read
+    /// through, generating mipmaps for textures sampled with trilinear or
+    /// anisotropic filtering that don't have them yet. A texture of the
+    /// wrong type for its sampler is left unbound.
+    fn bind_sampler_textures(&mut self, geom: &OpenGLGeometry) {
+        let sampler_uniforms: Vec<(u32, GLenum, bool)> = self.progs[geom.program].sampler_uniforms.iter()
+            .map(|sampler| (sampler.tex_unit, sampler.target, sampler.shadow))
+            .collect();
+
+        for (sampler_idx, (tex_unit, target, shadow)) in sampler_uniforms.into_iter().enumerate() {
+            let texh = match geom.sampler_textures[sampler_idx] {
+                // Textures destroyed since being set are left unbound too.
+                Some(x) if self.textures.get(x).map(|texture| texture.target) == Some(target) => x,
+                _ => {
+                    self.state.set_texture(tex_unit, target, 0);
+                    self.state.set_sampler(tex_unit, 0);
+                    continue;
+                },
+            };
+
+            let mut sampler_object = match geom.sampler_objects[sampler_idx] {
+                Some(x) => x,
+                None    => self.texture_sampler_object(texh),
+            };
+
+            // Shadow samplers always compare, everything else never does.
+            let compare_func = if shadow {
+                Some(self.samplers[sampler_object].desc.compare_func.unwrap_or(CompareFunc::LessEqual))
+            } else {
+                None
+            };
+
+            if self.samplers[sampler_object].desc.compare_func != compare_func {
+                let mut desc = self.samplers[sampler_object].desc.clone();
+                desc.compare_func = compare_func;
+                sampler_object = self.acquire_sampler(&desc);
+            }
+
+            self.state.set_texture(tex_unit, target, self.textures[texh].id);
+            self.state.set_sampler(tex_unit, self.samplers[sampler_object].id);
+
+            if self.samplers[sampler_object].desc.needs_mipmaps() && !self.textures[texh].has_mipmaps {
+                unsafe {
+                    gl::ActiveTexture(gl::TEXTURE0 + tex_unit);
+                    gl::GenerateMipmap(target);
+                }
+                self.textures[texh].has_mipmaps = true;
+            }
+        }
+    }
+
+    fn compile_shader(&self, src: &PreprocessedSource, stage: ShaderStage) -> Result<GLuint, ShaderError> {
+        let shader_type = match stage {
+            ShaderStage::Vertex   => gl::VERTEX_SHADER,
+            ShaderStage::Fragment => gl::FRAGMENT_SHADER,
+        };
+
+        unsafe {
+            let shader = gl::CreateShader(shader_type);
+
+            let c_str = CString::new(src.source.as_bytes()).unwrap();
+
+            gl::ShaderSource(shader, 1, &c_str.as_ptr(), ptr::null());
+            gl::CompileShader(shader);
+
+            let mut status = gl::FALSE as GLint;
+            gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut status);
+
+
+            if status != (gl::TRUE as GLint) {
+                let mut len = 0;
+                gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
+                let mut buf = Vec::with_capacity(len as usize);
+                buf.set_len((len as usize) - 1);
+                gl::GetShaderInfoLog(shader, len, ptr::null_mut(), buf.as_mut_ptr() as * mut GLchar);
+                gl::DeleteShader(shader);
+
+                return Err(ShaderError::compile(stage, &String::from_utf8_lossy(&buf), src));
+            }
+
+            Ok(shader)
+        }
+    }
+
+    fn create_vertex_array_object(&mut self, desc: &VertexLayoutDescription, vboh: VBOHandle, progh: ProgramHandle, vertex_count: usize) -> VAOHandle {
+        let mut vao = 0;
+
+        unsafe {
+            gl::GenVertexArrays(1, &mut vao);
+        }
+
+        self.state.set_vao(vao);
+        self.set_vertex_attributes(desc, vboh, progh, vertex_count);
+
+        self.vaos.insert(GLVertexArrayObject {
+            id: vao,
+        })
+    }
+
+    /// Points the bound VAO's attributes at the buffer, as laid out by
+    /// `desc`, at the locations the program gave the attributes of the same
+    /// name. Elements the program doesn't read are left out. Per-instance
+    /// layouts get a divisor of their step rate.
+    ///
+    /// Streams after the first start further in when there are more
+    /// vertices, so this has to be called again when the count changes.
+    fn set_vertex_attributes(&mut self, desc: &VertexLayoutDescription, vboh: VBOHandle, progh: ProgramHandle, vertex_count: usize) {
+        let vboid = self.vbos[vboh].id;
+        self.state.set_vbo(vboid);
+
+        let prog = &self.progs[progh];
+
+        unsafe {
+            for elem in desc.elements.iter() {
+                let index = match prog.attributes.iter().position(|attr| attr.name == elem.name) {
+                    Some(i) => prog.attribute_locations[i],
+                    None    => continue,
+                };
+
+                let num_components = elem.vtype.get_num_components();
+                let elem_type = gl_vertex_element_type(elem.vtype);
+                let stride = desc.stream_stride(elem.stream) as GLsizei;
+                let offset = desc.element_offset(elem, 0, vertex_count) as *const GLvoid;
+
+                gl::EnableVertexAttribArray(index);
+
+                if elem.vtype.is_integer() {
+                    gl::VertexAttribIPointer(index, num_components, elem_type, stride, offset);
+                } else {
+                    let normalized = if elem.vtype.is_normalized() { gl::TRUE } else { gl::FALSE };
+                    gl::VertexAttribPointer(index, num_components, elem_type, normalized, stride, offset);
+                }
+
+                gl::VertexAttribDivisor(index, desc.step_rate);
+            }
+        }
+    }
+
+    fn create_vertex_buffer_object(&mut self, data: &BufferData, usage: BufferUsage) -> VBOHandle {
+        let mut buf_id = 0;
+
+        unsafe {
+            gl::GenBuffers(1, &mut buf_id);
+            self.state.set_vbo(buf_id);
+            gl::BufferData(gl::ARRAY_BUFFER, data.bytes.len() as isize, data.bytes.as_ptr() as *const GLvoid, gl_buffer_usage(usage));
+        }
+
+        let vbo = GLVbo {
+            id: buf_id,
+            size: data.bytes.len(),
+            usage: usage,
+        };
+
+        self.vbos.insert(vbo)
+    }
+
+    /// Geometry without indices still gets an empty buffer, so that every
+    /// geometry has one.
+    fn create_index_buffer_object(&mut self, itype: IndexType, data: &BufferData, usage: BufferUsage) -> IBOHandle {
+        let mut buf_id = 0;
+
+        let bytes: &[u8] = match itype {
+            IndexType::NoIndices => &[],
+            _                    => &data.bytes,
+        };
+
+        unsafe {
+            gl::GenBuffers(1, &mut buf_id);
+            self.state.set_ibo(buf_id);
+            gl::BufferData(gl::ELEMENT_ARRAY_BUFFER, bytes.len() as isize, bytes.as_ptr() as *const GLvoid, gl_buffer_usage(usage));
+        }
+
+        let ibo = GLIbo {
+            id: buf_id,
+            itype: itype,
+            count: itype.count(data),
+            size: bytes.len(),
+            usage: usage,
+        };
+
+        self.ibos.insert(ibo)
+    }
+
+    /// Returns the cached program for the sources, or compiles a new one.
+    /// Either way the caller holds a reference and must `release_program` it.
+    fn acquire_program(&mut self, vert_src: &str, frag_src: &str, defines: &[(String, String)]) -> Result<ProgramHandle, ShaderError> {
+        let (vert_src, frag_src) = try!(self.preprocessor.preprocess_program(vert_src, frag_src, &sorted_defines(defines)));
+
+        let key = ProgramKey {
+            vert_src: vert_src.source.clone(),
+            frag_src: frag_src.source.clone(),
+        };
+
+        let progh = match self.program_cache.get(&key) {
+            Some(progh) => *progh,
+            None        => {
+                let progh = try!(self.create_program(&key, &vert_src, &frag_src));
+                self.program_cache.insert(key, progh);
+                progh
+            },
+        };
+
+        self.progs[progh].ref_count += 1;
+
+        Ok(progh)
+    }
+
+    fn retain_program(&mut self, progh: ProgramHandle) {
+        self.progs[progh].ref_count += 1;
+    }
+
+    fn release_program(&mut self, progh: ProgramHandle) {
+        self.progs[progh].ref_count -= 1;
+
+        if self.progs[progh].ref_count > 0 {
+            return;
+        }
+
+        let id = self.progs[progh].id;
+        self.program_cache.remove(&self.progs[progh].key);
+
+        if self.state.prog == id {
+            self.state.set_program(0);
+        }
+
+        unsafe { gl::DeleteProgram(id); }
+
+        self.progs.remove(progh);
+    }
+
+    fn create_program(&mut self, key: &ProgramKey, vert_src: &PreprocessedSource, frag_src: &PreprocessedSource) -> Result<ProgramHandle, ShaderError> {
+        let vs = try!(self.compile_shader(vert_src, ShaderStage::Vertex));
+        let fs = match self.compile_shader(frag_src, ShaderStage::Fragment) {
+            Ok(fs) => fs,
+            Err(e) => {
+                unsafe { gl::DeleteShader(vs); }
+                return Err(e);
+            },
+        };
+
+        let program;
+
+        unsafe {
+            program = gl::CreateProgram();
+            gl::AttachShader(program, vs);
+            gl::AttachShader(program, fs);
+            gl::LinkProgram(program);
+
+            let mut status = gl::FALSE as GLint;
+            gl::GetProgramiv(program, gl::LINK_STATUS, &mut status);
+
+            if status != (gl::TRUE as GLint) {
+                let mut len: GLint = 0;
+                gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
+                let mut buf = Vec::with_capacity(len as usize);
+                buf.set_len((len as usize) - 1);
+                gl::GetProgramInfoLog(program, len, ptr::null_mut(), buf.as_mut_ptr() as *mut GLchar);
+
+                gl::DeleteProgram(program);
+                gl::DeleteShader(vs);
+                gl::DeleteShader(fs);
+
+                return Err(ShaderError::link(&String::from_utf8_lossy(&buf)));
+            }
+            
+            gl::DetachShader(program, vs);
+            gl::DetachShader(program, fs);
+            
+            gl::DeleteShader(vs);
+            gl::DeleteShader(fs);
+        }
+
+        let uniform_blocks = self.get_program_uniform_blocks(program);
+        let sampler_uniforms = self.get_program_samplers(program);
+        let default_uniforms = self.get_program_default_uniforms(program);
+        let (attributes, attribute_locations) = self.get_program_attributes(program);
+
+        // Texture units never change, so point the samplers at them once.
+        self.state.set_program(program);
+
+        for sampler in sampler_uniforms.iter() {
+            let name_cstr = CString::new(sampler.uniform_info.name.clone()).unwrap();
+            unsafe {
+                let location = gl::GetUniformLocation(program, name_cstr.as_ptr());
+                gl::Uniform1i(location, sampler.tex_unit as i32);
+            }
+        }
+
+        let (params, param_uniforms) = program_shader_params(&uniform_blocks, &default_uniforms, &sampler_uniforms);
+
+        let prog = GLProg {
+            id: program,
+            key: key.clone(),
+            ref_count: 0,
+            uniform_blocks: uniform_blocks,
+            sampler_uniforms: sampler_uniforms,
+            default_uniforms: default_uniforms,
+            attributes: attributes,
+            attribute_locations: attribute_locations,
+            params: params,
+            param_uniforms: param_uniforms,
+            default_uniforms_geometry: Cell::new(None),
+            includes: GlslPreprocessor::program_includes(vert_src, frag_src),
+        };
+
+        Ok(self.progs.insert(prog))
+    }
+    
+    fn get_uniform_info(&self, progid: GLuint, uniform_index: u32) -> GLUniform {
+        unsafe {
+            let mut max_uniform_name_len: GLint = 0;
             gl::GetProgramiv(progid, gl::ACTIVE_UNIFORM_MAX_LENGTH, &mut max_uniform_name_len);
             
             let mut name_len: GLsizei = 0;
@@ -452,22 +1811,42 @@ impl OpenGLRenderer {
                 uniform_name_bytes.pop();
             }
 
-            let uniform_name: String = str::from_utf8(&uniform_name_bytes).unwrap().to_string();
+            let mut uniform_name: String = str::from_utf8(&uniform_name_bytes).unwrap().to_string();
+
+            let is_array = uniform_name.ends_with("[0]");
+            if is_array {
+                let len = uniform_name.len();
+                uniform_name.truncate(len - 3);
+            }
             
             let mut uniform_offset: GLint = 0;
             gl::GetActiveUniformsiv(progid, 1, &uniform_index, gl::UNIFORM_OFFSET, &mut uniform_offset);
+
+            let mut array_stride: GLint = 0;
+            gl::GetActiveUniformsiv(progid, 1, &uniform_index, gl::UNIFORM_ARRAY_STRIDE, &mut array_stride);
+
+            let mut matrix_stride: GLint = 0;
+            gl::GetActiveUniformsiv(progid, 1, &uniform_index, gl::UNIFORM_MATRIX_STRIDE, &mut matrix_stride);
+
+            let mut row_major: GLint = 0;
+            gl::GetActiveUniformsiv(progid, 1, &uniform_index, gl::UNIFORM_IS_ROW_MAJOR, &mut row_major);
             
+            // Uniforms outside of blocks report -1 for these.
             GLUniform {
                 name: uniform_name,
                 index: uniform_index,
                 offset: uniform_offset,
                 utype: uniform_type,
                 size: uniform_size,
+                is_array: is_array,
+                array_stride: cmp::max(array_stride, 0) as usize,
+                matrix_stride: cmp::max(matrix_stride, 0) as usize,
+                row_major: row_major > 0,
             }
         }
     }
     
-    fn get_program_uniform_blocks(&mut self, progid: GLuint) -> Vec<GLUniformBlock> {
+    fn get_program_uniform_blocks(&self, progid: GLuint) -> Vec<GLUniformBlock> {
         let mut num_blocks: GLint = 0;
         unsafe { gl::GetProgramiv(progid, gl::ACTIVE_UNIFORM_BLOCKS, &mut num_blocks); }
         
@@ -511,21 +1890,26 @@ impl OpenGLRenderer {
                     uniforms.push(uniform);
                 }
 
-                let buffer_data = BufferData::new_zero_initialized(block_size as usize);
+                // Shared blocks are filled by the renderer and always bound
+                // at the same point, they don't get params or buffers of their
+                // own.
+                if let Some(binding) = shared_block_binding(&block_name) {
+                    if shared_block_size(&block_name) != Some(block_size as usize) {
+                        println!("Warning: uniform block {} doesn't match the declaration in shared.glsl", block_name);
+                    }
 
-                let mut ubo: GLuint = 0;
-                gl::GenBuffers(1, &mut ubo);
-                self.state.set_ubo(ubo);
-                gl::BufferData(gl::UNIFORM_BUFFER, buffer_data.bytes.len() as isize, mem::transmute(&buffer_data.bytes[0]), gl::DYNAMIC_DRAW);
-                
-                gl::UniformBlockBinding(progid, i as u32, i as u32);
-                gl::BindBufferBase(gl::UNIFORM_BUFFER, i as u32, ubo);
+                    gl::UniformBlockBinding(progid, i as u32, binding);
+                    continue;
+                }
+
+                let binding = FIRST_OBJECT_BLOCK_BINDING + uniform_blocks.len() as u32;
+                gl::UniformBlockBinding(progid, i as u32, binding);
                 
                 uniform_blocks.push(GLUniformBlock {
                     name: block_name,
+                    index: i as u32,
+                    binding: binding,
                     size: block_size as usize,
-                    buffer: ubo,
-                    buffer_data: BufferData::new_zero_initialized(block_size as usize),
                     uniforms: uniforms,
                 });
             }
@@ -534,7 +1918,55 @@ impl OpenGLRenderer {
         }
     }
     
-    fn get_program_samplers(&self, progid: GLHandle) -> Vec<GLSampler2D> {
+    fn get_program_default_uniforms(&self, progid: GLHandle) -> Vec<GLDefaultUniform> {
+        let mut num_uniforms: GLint = 0;
+        
+        unsafe {
+            gl::GetProgramiv(progid, gl::ACTIVE_UNIFORMS, &mut num_uniforms);
+        }
+
+        let mut default_uniforms = Vec::new();
+
+        for i in 0..num_uniforms {
+            let mut block_index: GLint = 0;
+            let mut utype: GLint = 0;
+
+            unsafe {
+                gl::GetActiveUniformsiv(progid, 1, &(i as u32) as *const u32, gl::UNIFORM_BLOCK_INDEX, &mut block_index);
+                gl::GetActiveUniformsiv(progid, 1, &(i as u32) as *const u32, gl::UNIFORM_TYPE, &mut utype);
+            }
+
+            if block_index != -1 || gl_sampler_type(utype as u32).is_some() {
+                continue;
+            }
+
+            let uniform_info = self.get_uniform_info(progid, i as u32);
+
+            if uniform_info.name.starts_with("gl_") {
+                continue;
+            }
+
+            let element_names = if uniform_info.is_array {
+                (0..uniform_info.size).map(|i| format!("{}[{}]", uniform_info.name, i)).collect()
+            } else {
+                vec![uniform_info.name.clone()]
+            };
+
+            let locations = element_names.into_iter().map(|name| {
+                let name_cstr = CString::new(name).unwrap();
+                unsafe { gl::GetUniformLocation(progid, name_cstr.as_ptr()) }
+            }).collect();
+
+            default_uniforms.push(GLDefaultUniform {
+                uniform_info: uniform_info,
+                locations: locations,
+            });
+        }
+
+        default_uniforms
+    }
+
+    fn get_program_samplers(&self, progid: GLHandle) -> Vec<GLSamplerUniform> {
         let mut num_uniforms: GLint = 0;
         
         unsafe {
@@ -550,276 +1982,1013 @@ impl OpenGLRenderer {
                 gl::GetActiveUniformsiv(progid, 1, &(i as u32) as *const u32, gl::UNIFORM_TYPE, &mut utype);
             }
             
-            match utype as u32 {
-                gl::SAMPLER_2D => {
+            match gl_sampler_type(utype as u32) {
+                Some((target, shadow)) => {
                     let uniform_info = self.get_uniform_info(progid, i as u32);
                     let tex_unit = samplers.len() as u32;
                     
-                    samplers.push(GLSampler2D {
+                    samplers.push(GLSamplerUniform {
                        uniform_info: uniform_info,
                        tex_unit: tex_unit,
-                       tex_id: 0, 
+                       target: target,
+                       shadow: shadow,
                     });
                 },
-                _ => continue,
+                None => continue,
+            }
+        }
+        
+        samplers
+    }
+
+    fn get_program_attributes(&self, progid: GLHandle) -> (Vec<VertexInput>, Vec<GLuint>) {
+        let mut num_attributes: GLint = 0;
+        let mut max_name_len: GLint = 0;
+
+        unsafe {
+            gl::GetProgramiv(progid, gl::ACTIVE_ATTRIBUTES, &mut num_attributes);
+            gl::GetProgramiv(progid, gl::ACTIVE_ATTRIBUTE_MAX_LENGTH, &mut max_name_len);
+        }
+
+        let mut attributes = Vec::new();
+        let mut locations = Vec::new();
+
+        for i in 0..num_attributes {
+            let mut name_len: GLsizei = 0;
+            let mut name_bytes: Vec<u8> = vec![0; max_name_len as usize];
+            let mut atype: GLenum = 0;
+            let mut size: GLint = 0;
+
+            let location = unsafe {
+                gl::GetActiveAttrib(progid,
+                                    i as u32,
+                                    max_name_len,
+                                    &mut name_len,
+                                    &mut size,
+                                    &mut atype,
+                                    name_bytes.as_mut_ptr() as *mut GLchar);
+
+                name_bytes.truncate(name_len as usize);
+                let name_cstr = CString::new(name_bytes.clone()).unwrap();
+                gl::GetAttribLocation(progid, name_cstr.as_ptr())
+            };
+
+            // Built-ins have no location.
+            if location < 0 {
+                continue;
+            }
+
+            attributes.push(VertexInput {
+                name: String::from_utf8_lossy(&name_bytes).into_owned(),
+                integer: gl_attribute_is_integer(atype),
+            });
+            locations.push(location as GLuint);
+        }
+
+        (attributes, locations)
+    }
+
+    /// Names the first of the geometry's objects that doesn't exist in this
+    /// renderer, as happens with geometry created by another renderer.
+    fn check_geometry_handles(&self, geom: &OpenGLGeometry) -> Result<(), RendererError> {
+        let missing = if !self.vbos.contains(geom.vbo) {
+            format!("vertex buffer {:?}", geom.vbo)
+        } else if !self.vaos.contains(geom.vao) {
+            format!("vertex array {:?}", geom.vao)
+        } else if !self.ibos.contains(geom.ibo) {
+            format!("index buffer {:?}", geom.ibo)
+        } else if !self.progs.contains(geom.program) {
+            format!("program {:?}", geom.program)
+        } else {
+            return Ok(());
+        };
+
+        Err(RendererError::ForeignResource(format!("the geometry's {} doesn't exist in this renderer", missing)))
+    }
+
+    /// The first element and number of elements to draw, indices or
+    /// vertices for geometry without them.
+    fn geometry_draw_range(&self, geom: &OpenGLGeometry) -> Result<(usize, usize), RendererError> {
+        let total = match self.ibos[geom.ibo].itype {
+            IndexType::NoIndices => geom.vertex_count,
+            _                    => self.ibos[geom.ibo].count,
+        };
+
+        geom.draw_state.resolve_range(total).map_err(RendererError::Buffer)
+    }
+
+    /// Draws `count` elements from `first` on, `instances` times if given
+    /// and once otherwise.
+    fn draw_vertex_arrays(&mut self, geom: &OpenGLGeometry, vaoh: VAOHandle, first: usize, count: usize, instances: Option<usize>) {
+        let ibo = &self.ibos[geom.ibo];
+
+        self.state.set_program(self.progs[geom.program].id);
+        self.state.set_vbo(self.vbos[geom.vbo].id);
+        // The index buffer is bound after the VAO, otherwise it would be
+        // attached to whichever VAO was bound before.
+        self.state.set_vao(self.vaos[vaoh].id);
+        self.state.set_ibo(ibo.id);
+
+        let mode = gl_primitive_topology(geom.draw_state.topology);
+
+        let gl_itype = match ibo.itype {
+            IndexType::U16       => gl::UNSIGNED_SHORT,
+            IndexType::U32       => gl::UNSIGNED_INT,
+            IndexType::NoIndices => {
+                unsafe {
+                    match instances {
+                        Some(instances) => gl::DrawArraysInstanced(mode, first as GLint, count as GLsizei, instances as GLsizei),
+                        None            => gl::DrawArrays(mode, first as GLint, count as GLsizei),
+                    }
+                }
+                return;
+            },
+        };
+
+        if geom.draw_state.primitive_restart {
+            self.state.set_primitive_restart(Some(ibo.itype.restart_index()));
+        } else {
+            self.state.set_primitive_restart(None);
+        }
+
+        let base_vertex = geom.draw_state.range.map_or(0, |range| range.base_vertex);
+        let offset = (first * ibo.itype.size()) as *const GLvoid;
+
+        unsafe {
+            match instances {
+                Some(instances) => gl::DrawElementsInstancedBaseVertex(mode, count as GLsizei, gl_itype, offset, instances as GLsizei, base_vertex),
+                None            => gl::DrawElementsBaseVertex(mode, count as GLsizei, gl_itype, offset, base_vertex),
+            }
+        }
+    }
+
+    fn create_uniform_buffers(&mut self, progh: ProgramHandle) -> Vec<GLUniformBuffer> {
+        let mut uniform_buffers: Vec<GLUniformBuffer> = Vec::with_capacity(self.progs[progh].uniform_blocks.len());
+
+        for i in 0..self.progs[progh].uniform_blocks.len() {
+            let buffer_data = BufferData::new_zero_initialized(self.progs[progh].uniform_blocks[i].size);
+
+            let mut ubo: GLuint = 0;
+
+            unsafe {
+                gl::GenBuffers(1, &mut ubo);
+                self.state.set_ubo(ubo);
+                gl::BufferData(gl::UNIFORM_BUFFER, buffer_data.bytes.len() as isize, mem::transmute(&buffer_data.bytes[0]), gl::DYNAMIC_DRAW);
+            }
+
+            uniform_buffers.push(GLUniformBuffer {
+                handle: self.ubos.insert(ubo),
+                buffer: ubo,
+                buffer_data: buffer_data,
+            });
+        }
+
+        uniform_buffers
+    }
+
+    /// Uploads a shared block, creating its buffer if `ubo` is 0, and binds
+    /// it at its fixed binding point. Returns the buffer.
+    fn upload_shared_block(&mut self, ubo: GLuint, binding: u32, buffer_data: &BufferData) -> GLuint {
+        let mut ubo = ubo;
+
+        unsafe {
+            if ubo == 0 {
+                gl::GenBuffers(1, &mut ubo);
+                self.state.set_ubo(ubo);
+                gl::BufferData(gl::UNIFORM_BUFFER, buffer_data.bytes.len() as isize, mem::transmute(&buffer_data.bytes[0]), gl::DYNAMIC_DRAW);
+                self.ubos.insert(ubo);
+            } else {
+                self.state.set_ubo(ubo);
+                gl::BufferSubData(gl::UNIFORM_BUFFER, 0, buffer_data.bytes.len() as isize, mem::transmute(&buffer_data.bytes[0]));
+            }
+        }
+
+        self.state.set_ubo_binding(binding, ubo);
+        ubo
+    }
+
+    fn drop_uniform_buffers(&mut self, uniform_buffers: &Vec<GLUniformBuffer>) {
+        let buffers: Vec<GLuint> = uniform_buffers.iter().filter_map(|ubo| self.ubos.remove(ubo.handle)).collect();
+
+        for buffer in buffers.iter() {
+            self.state.forget_buffer(*buffer);
+        }
+
+        self.drop_buffers(buffers);
+    }
+
+    /// Moves the geometry over to its material's current program if it has
+    /// been reloaded since the geometry last drew, keeping param values by name.
+    ///
+    /// The new program may put the attributes elsewhere, so the VAOs are
+    /// rebuilt and the layout checked again.
+    fn update_geometry_program(&mut self, geom: &mut OpenGLGeometry) {
+        let (prog, generation, mut params) = match self.materials.get(GLMaterialHandle::from_bits(geom.material)) {
+            Some(glmaterial) if glmaterial.generation != geom.material_generation => {
+                (glmaterial.program, glmaterial.generation, glmaterial.params.clone())
+            },
+            _ => return,
+        };
+
+        params.copy_values_from(&geom.params);
+        // The new uniform buffers start out zeroed.
+        params.mark_all_changed();
+
+        let old_uniform_buffers = mem::replace(&mut geom.uniform_buffers, Vec::new());
+        self.drop_uniform_buffers(&old_uniform_buffers);
+
+        self.retain_program(prog);
+        let old_prog = geom.program;
+        self.release_program(old_prog);
+
+        geom.program = prog;
+        geom.material_generation = generation;
+        geom.params = params;
+        geom.uniform_buffers = self.create_uniform_buffers(prog);
+        geom.sampler_textures = vec![None; self.progs[prog].sampler_uniforms.len()];
+        geom.sampler_objects = self.material_sampler_objects(geom.material, prog);
+
+        let mut old_vaos = vec![geom.vao];
+        if let Some((vao, _)) = geom.instance_vao.take() {
+            old_vaos.push(vao);
+        }
+
+        self.drop_vertex_array_objects(old_vaos.clone());
+        for vao in old_vaos {
+            self.vaos.remove(vao);
+        }
+
+        geom.vao = self.create_vertex_array_object(&geom.layout_desc, geom.vbo, prog, geom.vertex_count);
+        geom.checked_inputs = None;
+    }
+
+    /// Checks the program's attributes against the geometry's layout, and
+    /// the layout of `instances` when drawing instanced. Passing checks are
+    /// remembered until the program or instance buffer changes.
+    fn check_vertex_inputs(&self, geom: &mut OpenGLGeometry, instances: Option<&OpenGLInstanceBuffer>) -> Result<(), RendererError> {
+        let instance_vbo = instances.map(|x| x.vbo);
+
+        if geom.checked_inputs == Some(instance_vbo) {
+            return Ok(());
+        }
+
+        let mut layouts = vec![&geom.layout_desc];
+        if let Some(instances) = instances {
+            layouts.push(&instances.layout_desc);
+        }
+
+        try!(check_vertex_inputs(&self.progs[geom.program].attributes, &layouts).map_err(RendererError::VertexLayout));
+
+        geom.checked_inputs = Some(instance_vbo);
+        Ok(())
+    }
+
+    fn apply_shader_params(&mut self, geom: &mut OpenGLGeometry) {
+        let prog: &GLProg = &self.progs[geom.program];
+        let num_blocks = prog.uniform_blocks.len();
+        let default_group = num_blocks;
+        let textures_group = num_blocks + 1;
+
+        // The blocks come first in the groups, so a dirty group below
+        // `num_blocks` is a uniform buffer that needs uploading.
+        let affected_blocks: Vec<usize> = (0..num_blocks).filter(|&g| geom.params.is_group_dirty(g)).collect();
+        geom.params.flush_changes(&mut self.param_changes);
+
+        let mut changed_defaults: Vec<ParamHandle> = Vec::new();
+
+        for &handle in self.param_changes.iter() {
+            let group = handle.group();
+            let uniform_idx = prog.param_uniforms[group][handle.index()];
+
+            if group == textures_group {
+                if let Some(tex_handle) = geom.params.get_by_handle(handle).texture_handle() {
+                    geom.sampler_textures[uniform_idx] = Some(TextureHandle::from_bits(tex_handle));
+                }
+            } else if group == default_group {
+                changed_defaults.push(handle);
+            } else {
+                let uniform = &prog.uniform_blocks[group].uniforms[uniform_idx];
+                if let Err(e) = write_uniform(&mut geom.uniform_buffers[group].buffer_data, uniform, geom.params.get_by_handle(handle)) {
+                    println!("Failed to write uniform {}: {}", uniform.name, e);
+                }
+            }
+        }
+
+        let upload_all_defaults = prog.default_uniforms_geometry.get() != Some(geom.id);
+
+        if upload_all_defaults || !changed_defaults.is_empty() {
+            self.state.set_program(prog.id);
+
+            if upload_all_defaults {
+                let params = &geom.params.groups()[default_group].params;
+                for (param, &uniform_idx) in params.iter().zip(prog.param_uniforms[default_group].iter()) {
+                    upload_default_uniform(&prog.default_uniforms[uniform_idx], &param.value);
+                }
+            } else {
+                for handle in changed_defaults {
+                    let uniform_idx = prog.param_uniforms[default_group][handle.index()];
+                    upload_default_uniform(&prog.default_uniforms[uniform_idx], geom.params.get_by_handle(handle));
+                }
+            }
+
+            prog.default_uniforms_geometry.set(Some(geom.id));
+        }
+
+        for block_idx in affected_blocks {
+            let ubo = &geom.uniform_buffers[block_idx];
+            unsafe {
+                self.state.set_ubo(ubo.buffer);
+                gl::BufferSubData(gl::UNIFORM_BUFFER, 0, ubo.buffer_data.bytes.len() as isize, mem::transmute(&ubo.buffer_data.bytes[0]));
             }
         }
-        
-        samplers
-    }
 
-    fn draw_vertex_arrays(&mut self, vboh: VBOHandle, vaoh: VAOHandle, iboh: IBOHandle, progh: ProgramHandle) {
-        let ibo = &self.ibos[iboh];
-        let prog = &self.progs[progh];
+        // Other geometries may share the program, so the geometry's own
+        // buffers and textures have to be bound for every draw.
+        for (block_idx, block) in prog.uniform_blocks.iter().enumerate() {
+            self.state.set_ubo_binding(block.binding, geom.uniform_buffers[block_idx].buffer);
+        }
+    }
+    
+    /// The geometry's VAO for drawing with `instances`, creating it if the
+    /// geometry was last drawn with a different instance buffer.
+    fn instance_vertex_array(&mut self, geom: &mut OpenGLGeometry, instances: &OpenGLInstanceBuffer) -> VAOHandle {
+        if let Some((vao, vbo)) = geom.instance_vao {
+            if vbo == instances.vbo {
+                return vao;
+            }
 
-        self.state.set_program(self.progs[progh].id);
-        self.state.set_vbo(self.vbos[vboh].id);
-        self.state.set_ibo(self.ibos[iboh].id);
-        self.state.set_vao(self.vaos[vaoh].id);
-        
-        for sampler in prog.sampler2ds.iter() {
-            self.state.set_tex2d(sampler.tex_unit, sampler.tex_id);
+            self.drop_vertex_array_objects(vec![vao]);
+            self.vaos.remove(vao);
         }
 
-        let gl_itype = match ibo.itype {
-            IndexType::U16 => gl::UNSIGNED_SHORT,
-            IndexType::U32 => gl::UNSIGNED_INT,
-        };
+        let vao = self.create_vertex_array_object(&geom.layout_desc, geom.vbo, geom.program, geom.vertex_count);
+        self.set_vertex_attributes(&instances.layout_desc, instances.vbo, geom.program, instances.count);
 
-        unsafe {
-            gl::DrawElements(gl::TRIANGLES, ibo.count as i32, gl_itype, ptr::null());
+        geom.instance_vao = Some((vao, instances.vbo));
+        vao
+    }
+
+    /// Everything `draw_geometry` does up to the draw call itself, once
+    /// the geometry is on its current program and known to be drawable.
+    fn prepare_geometry(&mut self, geom: &mut OpenGLGeometry) {
+        self.apply_shader_params(geom);
+        self.bind_sampler_textures(geom);
+
+        self.state.set_blend_state(&geom.render_state.blend);
+        self.state.set_rasterizer_state(&geom.render_state.rasterizer);
+        self.state.set_depth_stencil_state(&geom.render_state.depth_stencil);
+    }
+
+    fn bind_window_framebuffer(&mut self) {
+        self.state.set_fbo(0);
+
+        if let Some(viewport) = self.window_viewport.take() {
+            unsafe { gl::Viewport(viewport[0], viewport[1], viewport[2], viewport[3]); }
         }
     }
 
-    fn apply_shader_params(&mut self, geom: &mut Box<OpenGLGeometry>) {
-        let changes;
+    /// Frees everything dropped since the last call, reusing the slots.
+    /// Called before creating anything and at the start of every frame.
+    fn free_dead_resources(&mut self) {
+        let dead = mem::replace(&mut *self.dead_resources.borrow_mut(), Vec::new());
 
-        {
-            let mut params = geom.get_mut_params();
-            changes = params.flush_changes();
-        }
-
-        let mut prog: &mut GLProg = self.progs.get_mut(geom.program).unwrap();
-        let mut uniform_blocks: &mut Vec<GLUniformBlock> = &mut prog.uniform_blocks;
-        let mut affected_blocks: Vec<usize> = Vec::new();
-
-		self.state.set_program(prog.id);
-
-        let params = geom.get_mut_params();
-
-        // This is O(scary)
-        // should probably be optimized some time
-        // ShaderParams::flush_changes should return which blocks
-        // are affected as well as the parameters within that where
-        // affected, so that we can avoid this
-        for name in changes.iter() {
-            match *params.get(name) {
-                ParamValue::Texture2D(tex_handle) => {
-                    let gltexid = self.tex2ds[tex_handle as usize].id;
-                    for sampler in prog.sampler2ds.iter_mut() {
-                        if sampler.uniform_info.name == *name {
-                            sampler.tex_id = gltexid;
-                            unsafe { gl::Uniform1i(sampler.uniform_info.index as i32, sampler.tex_unit as i32); }
-                            break;
-                        }
+        for resource in dead {
+            match resource {
+                GLDeadResource::Geometry { vao, vbo, ibo, instance_vao, program, uniform_buffers } => {
+                    let mut vaos = vec![vao];
+                    vaos.extend(instance_vao);
+
+                    self.drop_uniform_buffers(&uniform_buffers);
+                    self.drop_vertex_array_objects(vaos.clone());
+                    self.drop_vertex_buffer_objects(vec![vbo]);
+                    self.drop_index_buffer_objects(vec![ibo]);
+
+                    for vao in vaos {
+                        self.vaos.remove(vao);
                     }
+                    self.vbos.remove(vbo);
+                    self.ibos.remove(ibo);
+
+                    self.release_program(program);
                 },
-                _ => (),
-            }
-            
-            'outer: for (block_idx, block) in uniform_blocks.iter_mut().enumerate() {
-                for uniform in block.uniforms.iter() {
-                    if uniform.name == *name {
-                        if !affected_blocks.contains(&block_idx) {
-                            affected_blocks.push(block_idx);
-                        }
-
-                        let param_value = params.get(name);
-                        match *param_value {
-                            ParamValue::F32(x)  => block.buffer_data.update_region(uniform.offset as usize, vec![x]),
-                            ParamValue::Vec4(x) => block.buffer_data.update_region(uniform.offset as usize, vec![x]),
-                            ParamValue::Mat3(x) => block.buffer_data.update_region(uniform.offset as usize, vec![x]),
-                            ParamValue::Mat4(x) => block.buffer_data.update_region(uniform.offset as usize, vec![x]),
-                            ParamValue::Texture2D(x) => (),
-                        }
-
-                        break 'outer;
+                GLDeadResource::InstanceBuffer(vbo) => {
+                    self.drop_vertex_buffer_objects(vec![vbo]);
+                    self.vbos.remove(vbo);
+                },
+                GLDeadResource::Texture(texh) => {
+                    self.drop_textures(vec![texh]);
+                    self.textures.remove(texh);
+                },
+                GLDeadResource::RenderTarget(fboh) => {
+                    if self.state.fbo == self.fbos[fboh].id {
+                        self.bind_window_framebuffer();
                     }
-                }
-            }
-        }
 
-        for block_idx in affected_blocks {
-            let block = uniform_blocks.get_mut(block_idx).unwrap();
-            unsafe {
-                self.state.set_ubo(block.buffer);
-                gl::BufferSubData(gl::UNIFORM_BUFFER, 0, block.buffer_data.bytes.len() as isize, mem::transmute(&block.buffer_data.bytes[0]));
+                    let mut textures = self.fbos[fboh].color_textures.clone();
+                    textures.extend(self.fbos[fboh].depth_texture);
+
+                    self.drop_framebuffers(vec![fboh]);
+                    self.fbos.remove(fboh);
+
+                    self.drop_textures(textures.clone());
+                    for texh in textures {
+                        self.textures.remove(texh);
+                    }
+                },
             }
         }
     }
-    
+
     fn drop_vertex_array_objects(&mut self, vaos: Vec<VAOHandle>) {
         let vaoids: Vec<GLHandle> = vaos.iter().map(|vao| self.vaos[*vao].id).collect();
+
+        for vaoid in vaoids.iter() {
+            self.state.forget_vertex_array(*vaoid);
+        }
+
         unsafe {
             gl::DeleteVertexArrays(vaoids.len() as i32, vaoids.as_ptr() as *const GLuint);
         }
     }
     
-    fn drop_textures(&mut self, tex2ds: Vec<TextureHandle>) {
-        let texids: Vec<GLHandle> = tex2ds.iter().map(|tex2d| self.tex2ds[*tex2d].id).collect();
+    fn drop_textures(&mut self, handles: Vec<TextureHandle>) {
+        let texids: Vec<GLHandle> = handles.iter().map(|texh| self.textures[*texh].id).collect();
+
+        for texid in texids.iter() {
+            self.state.forget_texture(*texid);
+        }
+
         unsafe {
             gl::DeleteTextures(texids.len() as i32, texids.as_ptr() as *const GLuint);
         }
     }
     
+    fn drop_framebuffers(&mut self, fbos: Vec<FramebufferHandle>) {
+        let fboids: Vec<GLHandle> = fbos.iter().map(|fbo| self.fbos[*fbo].id).collect();
+        unsafe {
+            gl::DeleteFramebuffers(fboids.len() as i32, fboids.as_ptr() as *const GLuint);
+        }
+    }
+    
     fn drop_buffers(&mut self, buffers: Vec<GLuint>) {
         unsafe { gl::DeleteBuffers(buffers.len() as i32, buffers.as_ptr() as *const GLuint); }
     }
     
     fn drop_programs(&mut self, programs: Vec<ProgramHandle>) {
-        let mut ubos: Vec<GLuint> = Vec::new();
-        
-        {
-            let mut progs: Vec<&GLProg> = Vec::new();
-            
-            for progh in programs {
-                progs.push(&self.progs[progh]);
-            }
-            
-            
-            for prog in progs {
-                unsafe {
-                    let buffers: Vec<GLuint> = prog.uniform_blocks.iter().map(|ubo| ubo.buffer).collect();
-                    ubos.extend(buffers);
-                    
-                    gl::DeleteProgram(prog.id);
-                }
-            }
+        for progh in programs {
+            unsafe { gl::DeleteProgram(self.progs[progh].id); }
         }
-        
-        self.drop_buffers(ubos);
     }
     
     fn drop_vertex_buffer_objects(&mut self, vbos: Vec<VBOHandle>) {
         let buffers: Vec<GLuint> = vbos.iter().map(|vbo| self.vbos[*vbo].id).collect();
+
+        for buffer in buffers.iter() {
+            self.state.forget_buffer(*buffer);
+        }
+
         self.drop_buffers(buffers);
     }
     
     fn drop_index_buffer_objects(&mut self, ibos: Vec<IBOHandle>) {
         let buffers: Vec<GLuint> = ibos.iter().map(|ibo| self.ibos[*ibo].id).collect();
+
+        for buffer in buffers.iter() {
+            self.state.forget_buffer(*buffer);
+        }
+
         self.drop_buffers(buffers);
     }
 }
 
 impl Renderer for OpenGLRenderer {
     fn clear(&mut self, r: f32, g: f32, b: f32, a: f32) {
+        self.free_dead_resources();
+        self.state.set_scissor(None);
+        
         unsafe {
             gl::ClearColor(r, g, b, a);
             gl::Clear(gl::COLOR_BUFFER_BIT);
         }
     }
     
-    fn create_texture_from_image(&mut self, image_data: &DynamicImage) -> Box<Texture> {
+    fn clear_depth(&mut self, depth: f32) {
+        self.state.set_scissor(None);
+        self.state.set_depth_write(true);
+        
+        unsafe {
+            gl::ClearDepth(depth as GLdouble);
+            gl::Clear(gl::DEPTH_BUFFER_BIT);
+        }
+    }
+    
+    fn clear_stencil(&mut self, stencil: i32) {
+        self.state.set_scissor(None);
+        self.state.set_stencil_write_mask(0xff);
+        
+        unsafe {
+            gl::ClearStencil(stencil);
+            gl::Clear(gl::STENCIL_BUFFER_BIT);
+        }
+    }
+    
+    fn create_texture_layers(&mut self, texture_type: TextureType, layers: &[&TextureData], options: &TextureOptions) -> Result<Box<Texture>, RendererError> {
+        self.free_dead_resources();
+        try!(texture_type.validate_layers(layers).map_err(RendererError::Texture));
+
+        let first = layers[0];
+        let target = gl_texture_target(texture_type);
+        let (internal_format, format, data_type) = gl_texture_upload_format(&first.format, first.component_type, options.color_space);
+
+        let mut tex_id: GLHandle = 0;
+
         unsafe {
-            let mut tex_id: GLHandle = 0;
             gl::GenTextures(1, &mut tex_id);
             
-            self.state.set_tex2d(0, tex_id);
-            
-            let tex_format = match *image_data {
-                DynamicImage::ImageLuma8(_) => TextureFormat::Luminance,
-                DynamicImage::ImageLumaA8(_) => TextureFormat::LuminanceAlpha,
-                DynamicImage::ImageRgb8(_) => TextureFormat::RGB,
-                DynamicImage::ImageRgba8(_) => TextureFormat::RGBA,
-            };
-            
-            let gl_tex_format = match tex_format {
-                TextureFormat::Luminance => gl::RED,
-                TextureFormat::LuminanceAlpha => gl::RG,
-                TextureFormat::RGB => gl::RGB,
-                TextureFormat::RGBA => gl::RGBA,
-                TextureFormat::Alpha => gl::ALPHA,
-            };
-            
-            let (width, height) = image_data.dimensions();
-            
-            let mut raw_pixels = image_data.raw_pixels();
-            
-            gl::TexImage2D(gl::TEXTURE_2D, 0, gl_tex_format as i32, width as i32, height as i32, 0, gl_tex_format, gl::UNSIGNED_BYTE, raw_pixels.as_mut_ptr() as *mut GLvoid);
-            
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
-            
-            self.tex2ds.push(GLTex2D {
-                id: tex_id,
-            });
-            
-            Box::new(OpenGLTexture {
-               texture_handle: self.tex2ds.len() - 1,
-               filter_method: FilteringMethod::Nearest,
-               filter_changed: false,
-               texture_format: tex_format,
-            })
+            self.state.set_texture(0, target, tex_id);
+
+            // Rows are tightly packed, the default of 4 would skew RGB
+            // images with odd widths.
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+
+            match texture_type {
+                TextureType::Texture2D => {
+                    gl::TexImage2D(target, 0, internal_format as i32, first.width as i32, first.height as i32, 0, format, data_type, first.bytes.as_ptr() as *const GLvoid);
+                },
+                TextureType::Cube => {
+                    for (i, face) in layers.iter().enumerate() {
+                        gl::TexImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_X + i as GLenum, 0, internal_format as i32, face.width as i32, face.height as i32, 0, format, data_type, face.bytes.as_ptr() as *const GLvoid);
+                    }
+
+                    // Filter across face edges instead of showing seams.
+                    gl::Enable(gl::TEXTURE_CUBE_MAP_SEAMLESS);
+                },
+                TextureType::Array2D | TextureType::Texture3D => {
+                    let mut bytes = Vec::with_capacity(first.bytes.len() * layers.len());
+                    for layer in layers {
+                        bytes.extend(layer.bytes.iter().cloned());
+                    }
+
+                    gl::TexImage3D(target, 0, internal_format as i32, first.width as i32, first.height as i32, layers.len() as i32, 0, format, data_type, bytes.as_ptr() as *const GLvoid);
+                },
+            }
+
+            set_texture_swizzle(target, &first.format);
+
+            if options.generate_mipmaps {
+                gl::GenerateMipmap(target);
+            }
+        }
+
+        let texture_handle = self.push_texture(tex_id, target, options.generate_mipmaps, options.sampler.clone());
+
+        Ok(Box::new(OpenGLTexture {
+           texture_handle: texture_handle,
+           texture_type: texture_type,
+           sampler: self.textures[texture_handle].sampler.clone(),
+           texture_format: first.format.clone(),
+           component_type: first.component_type,
+           color_space: options.color_space,
+           dead_resources: self.dead_resources.clone(),
+        }))
+    }
+
+    fn create_render_target(&mut self, desc: &RenderTargetDescription) -> Result<Box<RenderTarget>, RendererError> {
+        self.free_dead_resources();
+
+        let mut fbo_id: GLHandle = 0;
+        let previous_fbo = self.state.fbo;
+
+        unsafe {
+            gl::GenFramebuffers(1, &mut fbo_id);
+        }
+
+        self.state.set_fbo(fbo_id);
+
+        let mut color_textures: Vec<TextureHandle> = Vec::with_capacity(desc.color_formats.len());
+        let mut draw_buffers: Vec<GLenum> = Vec::with_capacity(desc.color_formats.len());
+
+        for (i, format) in desc.color_formats.iter().enumerate() {
+            // Alpha and luminance are stored in red and green, like uploaded
+            // textures, as GL_ALPHA can't be rendered to.
+            let (internal_format, gl_format, data_type) = gl_texture_upload_format(format, ComponentType::U8, ColorSpace::Linear);
+            let texh = self.create_attachment_texture(internal_format, gl_format, data_type, desc.width, desc.height);
+            set_texture_swizzle(gl::TEXTURE_2D, format);
+            let attachment = gl::COLOR_ATTACHMENT0 + i as u32;
+
+            unsafe {
+                gl::FramebufferTexture2D(gl::FRAMEBUFFER, attachment, gl::TEXTURE_2D, self.textures[texh].id, 0);
+            }
+
+            color_textures.push(texh);
+            draw_buffers.push(attachment);
+        }
+
+        let depth_texture = match desc.depth_stencil_format {
+            Some(ref format) => {
+                let (internal_format, gl_format, data_type, attachment) = match *format {
+                    DepthStencilFormat::Depth16 => (gl::DEPTH_COMPONENT16, gl::DEPTH_COMPONENT, gl::UNSIGNED_SHORT, gl::DEPTH_ATTACHMENT),
+                    DepthStencilFormat::Depth24 => (gl::DEPTH_COMPONENT24, gl::DEPTH_COMPONENT, gl::UNSIGNED_INT, gl::DEPTH_ATTACHMENT),
+                    DepthStencilFormat::Depth32F => (gl::DEPTH_COMPONENT32F, gl::DEPTH_COMPONENT, gl::FLOAT, gl::DEPTH_ATTACHMENT),
+                    DepthStencilFormat::Depth24Stencil8 => (gl::DEPTH24_STENCIL8, gl::DEPTH_STENCIL, gl::UNSIGNED_INT_24_8, gl::DEPTH_STENCIL_ATTACHMENT),
+                };
+
+                let texh = self.create_attachment_texture(internal_format, gl_format, data_type, desc.width, desc.height);
+
+                unsafe {
+                    gl::FramebufferTexture2D(gl::FRAMEBUFFER, attachment, gl::TEXTURE_2D, self.textures[texh].id, 0);
+                }
+
+                Some(texh)
+            },
+            None => None,
+        };
+
+        let status;
+
+        unsafe {
+            if draw_buffers.is_empty() {
+                // Depth only targets, e.g. shadow maps.
+                gl::DrawBuffer(gl::NONE);
+                gl::ReadBuffer(gl::NONE);
+            } else {
+                gl::DrawBuffers(draw_buffers.len() as i32, draw_buffers.as_ptr());
+            }
+
+            status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+        }
+
+        self.state.set_fbo(previous_fbo);
+
+        let fboh = self.fbos.insert(GLFramebuffer {
+            id: fbo_id,
+            color_textures: color_textures.clone(),
+            depth_texture: depth_texture,
+            width: desc.width,
+            height: desc.height,
+        });
+
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            self.dead_resources.borrow_mut().push(GLDeadResource::RenderTarget(fboh));
+            return Err(RendererError::RenderTarget(format!("Render target is incomplete. Framebuffer status: 0x{:x}", status)));
+        }
+
+        Ok(Box::new(OpenGLRenderTarget {
+            framebuffer_handle: fboh,
+            color_textures: color_textures.iter().map(|texh| texh.to_bits()).collect(),
+            depth_texture: depth_texture.map(|texh| texh.to_bits()),
+            width: desc.width,
+            height: desc.height,
+            dead_resources: self.dead_resources.clone(),
+        }))
+    }
+
+    fn bind_render_target(&mut self, target: Option<&RenderTarget>) -> Result<(), RendererError> {
+        match target {
+            Some(target) => {
+                let fboh = match target.as_any().downcast_ref::<OpenGLRenderTarget>() {
+                    Some(gltarget) => gltarget.framebuffer_handle,
+                    None           => return Err(RendererError::ForeignResource("render target wasn't created by the OpenGL renderer".to_string())),
+                };
+
+                let (fbo_id, width, height) = {
+                    let fbo = &self.fbos[fboh];
+                    (fbo.id, fbo.width, fbo.height)
+                };
+
+                if self.window_viewport.is_none() {
+                    let mut viewport: [GLint; 4] = [0; 4];
+                    unsafe { gl::GetIntegerv(gl::VIEWPORT, viewport.as_mut_ptr()); }
+                    self.window_viewport = Some(viewport);
+                }
+
+                self.state.set_fbo(fbo_id);
+                unsafe { gl::Viewport(0, 0, width as i32, height as i32); }
+            },
+            None => self.bind_window_framebuffer(),
+        }
+
+        Ok(())
+    }
+
+    fn add_shader_search_path(&mut self, path: &Path) {
+        self.preprocessor.add_search_path(path);
+    }
+
+    fn create_material(&mut self, desc: &MaterialDescription) -> Result<MaterialHandle, RendererError> {
+        let prog = try!(self.acquire_program(&desc.vert_src, &desc.frag_src, &desc.defines));
+
+        let mut params = self.progs[prog].params.clone();
+
+        desc.apply_params(&mut params);
+
+        let samplers = desc.samplers.iter().map(|&(ref name, ref sampler)| (name.clone(), self.acquire_sampler(sampler))).collect();
+
+        let handle = self.materials.insert(GLMaterial {
+            program: prog,
+            defines: desc.defines.clone(),
+            generation: 0,
+            params: params,
+            render_state: desc.render_state.clone(),
+            samplers: samplers,
+        });
+
+        Ok(handle.to_bits())
+    }
+
+    fn reload_material(&mut self, material: MaterialHandle, vert_src: &str, frag_src: &str) -> Result<(), RendererError> {
+        let handle = GLMaterialHandle::from_bits(material);
+
+        let (old_prog, defines) = match self.materials.get(handle) {
+            Some(glmaterial) => (glmaterial.program, glmaterial.defines.clone()),
+            None             => return Err(RendererError::Material(format!("material {:?} has been destroyed", handle))),
+        };
+
+        // On failure the material keeps drawing with the old program.
+        let prog = try!(self.acquire_program(vert_src, frag_src, &defines));
+
+        // Neither the sources nor their includes have changed.
+        if prog == old_prog {
+            self.release_program(prog);
+            return Ok(());
+        }
+
+        let mut params = self.progs[prog].params.clone();
+
+        {
+            let glmaterial = &mut self.materials[handle];
+            params.copy_values_from(&glmaterial.params);
+
+            glmaterial.program = prog;
+            glmaterial.params = params;
+            glmaterial.generation += 1;
+        }
+
+        self.release_program(old_prog);
+
+        Ok(())
+    }
+
+    fn material_includes(&self, material: MaterialHandle) -> Vec<PathBuf> {
+        match self.materials.get(GLMaterialHandle::from_bits(material)) {
+            Some(glmaterial) => self.progs[glmaterial.program].includes.clone(),
+            None             => Vec::new(),
         }
     }
 
-    fn create_geometry(&mut self, vertex_data: &BufferData, index_data: &BufferData, layout: &VertexLayoutDescription, index_type: IndexType, vert_src: &str, frag_src: &str) -> Box<Geometry> {
-        let vbo = self.create_vertex_buffer_object(vertex_data).unwrap();
-        let prog = self.create_program(vert_src, frag_src).unwrap();
-        let vao = self.create_vertex_array_object(&layout, vbo, prog).unwrap();
-        let ibo = self.create_index_buffer_object(index_type, index_data).unwrap();
+    fn destroy_material(&mut self, material: MaterialHandle) -> Result<(), RendererError> {
+        let handle = GLMaterialHandle::from_bits(material);
+
+        let prog = match self.materials.remove(handle) {
+            Some(glmaterial) => glmaterial.program,
+            None             => return Err(RendererError::Material(format!("material {:?} has already been destroyed", handle))),
+        };
+
+        // Geometries created with the material hold their own reference, so
+        // they keep drawing.
+        self.release_program(prog);
+
+        Ok(())
+    }
+
+    fn set_frame_data(&mut self, data: &FrameData) {
+        let ubo = self.frame_data_ubo;
+        self.frame_data_ubo = self.upload_shared_block(ubo, FRAME_DATA_BINDING, &data.std140_data());
+    }
+
+    fn set_view_data(&mut self, data: &ViewData) {
+        let ubo = self.view_data_ubo;
+        self.view_data_ubo = self.upload_shared_block(ubo, VIEW_DATA_BINDING, &data.std140_data());
+    }
+
+    fn create_geometry_with_usage(&mut self, vertex_data: &BufferData, index_data: &BufferData, layout: &VertexLayoutDescription, index_type: IndexType, usage: BufferUsage, material: MaterialHandle) -> Result<Box<Geometry>, RendererError> {
+        self.free_dead_resources();
+
+        let handle = GLMaterialHandle::from_bits(material);
+
+        let (prog, generation, params, render_state) = match self.materials.get(handle) {
+            Some(glmaterial) => (glmaterial.program, glmaterial.generation, glmaterial.params.clone(), glmaterial.render_state.clone()),
+            None             => return Err(RendererError::Material(format!("material {:?} has been destroyed", handle))),
+        };
+
+        self.retain_program(prog);
+
+        let vbo = self.create_vertex_buffer_object(vertex_data, usage);
+        let vao = self.create_vertex_array_object(&layout, vbo, prog, vertex_count(layout, vertex_data));
+        let ibo = self.create_index_buffer_object(index_type, index_data, usage);
         
-        let uniform_blocks = &self.progs[prog].uniform_blocks;
-        let samplers = &self.progs[prog].sampler2ds;
-        let params = self.get_shader_params(uniform_blocks, samplers);
+        let uniform_buffers = self.create_uniform_buffers(prog);
+        let sampler_textures = vec![None; self.progs[prog].sampler_uniforms.len()];
+        let sampler_objects = self.material_sampler_objects(material, prog);
+
+        let id = self.num_geometries;
+        self.num_geometries += 1;
 
         let geom = OpenGLGeometry {
+            id: id,
             vbo: vbo,
             vao: vao,
             ibo: ibo,
             program: prog,
+            material: material,
+            material_generation: generation,
+            layout_desc: layout.clone(),
+            params: params,
+            render_state: render_state,
+            draw_state: DrawState::default(),
+            vertex_count: vertex_count(layout, vertex_data),
+            uniform_buffers: uniform_buffers,
+            sampler_textures: sampler_textures,
+            sampler_objects: sampler_objects,
+            instance_vao: None,
+            checked_inputs: None,
+            dead_resources: self.dead_resources.clone(),
+        };
+
+        Ok(Box::new(geom))
+    }
+
+    fn draw_geometry(&mut self, geom: &mut Box<Geometry>) -> Result<(), RendererError> {
+        let glgeom = try!(gl_geometry(geom));
+        try!(self.check_geometry_handles(glgeom));
+        let (first, count) = try!(self.geometry_draw_range(glgeom));
+
+        self.update_geometry_program(glgeom);
+        try!(self.check_vertex_inputs(glgeom, None));
+        self.prepare_geometry(glgeom);
+
+        let vao = glgeom.vao;
+        self.draw_vertex_arrays(glgeom, vao, first, count, None);
+
+        Ok(())
+    }
+
+    fn update_vertex_data(&mut self, geom: &mut Box<Geometry>, offset: usize, data: &BufferData) -> Result<(), RendererError> {
+        let glgeom = try!(gl_geometry(geom));
+        let vbo = &self.vbos[glgeom.vbo];
+
+        try!(check_buffer_region(vbo.size, offset, data.bytes.len()).map_err(RendererError::Buffer));
+        write_buffer_region(vbo.id, offset, &data.bytes);
+
+        Ok(())
+    }
+
+    fn update_index_data(&mut self, geom: &mut Box<Geometry>, offset: usize, data: &BufferData) -> Result<(), RendererError> {
+        let glgeom = try!(gl_geometry(geom));
+        let ibo = &self.ibos[glgeom.ibo];
+
+        // Only the indices being drawn can be updated, the rest of a stream
+        // buffer's storage is left over from bigger data.
+        // Same as `IndexType::used_size` of the data last set.
+        let used_size = ibo.count * ibo.itype.size();
+        try!(check_buffer_region(used_size, offset, data.bytes.len()).map_err(RendererError::Buffer));
+        write_buffer_region(ibo.id, offset, &data.bytes);
+
+        Ok(())
+    }
+
+    fn set_vertex_data(&mut self, geom: &mut Box<Geometry>, data: &BufferData) -> Result<(), RendererError> {
+        let glgeom = try!(gl_geometry(geom));
+        {
+            let vbo = &mut self.vbos[glgeom.vbo];
+            vbo.size = replace_buffer_data(vbo.id, vbo.size, vbo.usage, &data.bytes);
+        }
+
+        let old_vertex_count = glgeom.vertex_count;
+        glgeom.vertex_count = vertex_count(&glgeom.layout_desc, data);
+
+        if glgeom.layout_desc.stream_count() > 1 && glgeom.vertex_count != old_vertex_count {
+            self.state.set_vao(self.vaos[glgeom.vao].id);
+            self.set_vertex_attributes(&glgeom.layout_desc, glgeom.vbo, glgeom.program, glgeom.vertex_count);
+
+            // Rebuilt on the next instanced draw.
+            if let Some((vao, _)) = glgeom.instance_vao.take() {
+                self.drop_vertex_array_objects(vec![vao]);
+                self.vaos.remove(vao);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_index_data(&mut self, geom: &mut Box<Geometry>, data: &BufferData) -> Result<(), RendererError> {
+        let glgeom = try!(gl_geometry(geom));
+        let ibo = &mut self.ibos[glgeom.ibo];
+
+        if ibo.itype == IndexType::NoIndices {
+            return Err(RendererError::Buffer("the geometry was created without indices".to_string()));
+        }
+
+        ibo.size = replace_buffer_data(ibo.id, ibo.size, ibo.usage, &data.bytes);
+        ibo.count = ibo.itype.count(data);
+
+        Ok(())
+    }
+
+    fn create_instance_buffer(&mut self, data: &BufferData, layout: &VertexLayoutDescription, usage: BufferUsage) -> Result<Box<InstanceBuffer>, RendererError> {
+        self.free_dead_resources();
+
+        let count = try!(count_instances(layout, data).map_err(RendererError::Buffer));
+        let vbo = self.create_vertex_buffer_object(data, usage);
+
+        Ok(Box::new(OpenGLInstanceBuffer {
+            vbo: vbo,
             layout_desc: layout.clone(),
-            params: params
+            count: count,
+            dead_resources: self.dead_resources.clone(),
+        }))
+    }
+
+    fn set_instance_data(&mut self, instances: &mut Box<InstanceBuffer>, data: &BufferData) -> Result<(), RendererError> {
+        let glinstances = match instances.as_any_mut().downcast_mut::<OpenGLInstanceBuffer>() {
+            Some(x) => x,
+            None    => return Err(RendererError::ForeignResource("instance buffer wasn't created by the OpenGL renderer".to_string())),
         };
 
-        Box::new(geom)
+        let count = try!(count_instances(&glinstances.layout_desc, data).map_err(RendererError::Buffer));
+        let vbo = &mut self.vbos[glinstances.vbo];
+
+        vbo.size = replace_buffer_data(vbo.id, vbo.size, vbo.usage, &data.bytes);
+        glinstances.count = count;
+
+        Ok(())
     }
 
-    fn draw_geometry(&mut self, geom: &mut Box<Geometry>) {
-        // This is pretty lame. There should be a better way to convert Box<Geometry> to Box<OpenGLGeometry>
-        // Perhaps this is just an unsafe design by nature however.
-        let glgeom: &mut Box<OpenGLGeometry> = unsafe { mem::transmute(geom) };
+    fn draw_geometry_instanced(&mut self, geom: &mut Box<Geometry>, instances: &InstanceBuffer, count: usize) -> Result<(), RendererError> {
+        let glgeom = try!(gl_geometry(geom));
+        let glinstances = try!(gl_instance_buffer(instances));
+        try!(self.check_geometry_handles(glgeom));
+
+        try!(check_instance_count(instances, count).map_err(RendererError::Buffer));
+        let (first, element_count) = try!(self.geometry_draw_range(glgeom));
+
+        if count == 0 {
+            return Ok(());
+        }
+
+        self.update_geometry_program(glgeom);
+        try!(self.check_vertex_inputs(glgeom, Some(glinstances)));
+        self.prepare_geometry(glgeom);
 
-        self.apply_shader_params(glgeom);
+        let vao = self.instance_vertex_array(glgeom, glinstances);
+        self.draw_vertex_arrays(glgeom, vao, first, element_count, Some(count));
 
-        self.draw_vertex_arrays(glgeom.vbo, glgeom.vao, glgeom.ibo, glgeom.program);
+        Ok(())
     }
 }
 
 impl Drop for OpenGLRenderer {
     fn drop(&mut self) {
-        fn vec_indices<T>(vec: &Vec<T>) -> Vec<usize> {
-            let mut result: Vec<usize> = Vec::new();
-            for i in 0..vec.len() {
-                result.push(i as usize);
-            }
-            
-            result
-        }
+        self.free_dead_resources();
+
+        self.state.set_fbo(0);
+        
+        let fbo_handles = self.fbos.handles();
+        self.drop_framebuffers(fbo_handles);
+        
+        let vao_handles = self.vaos.handles();
+        self.drop_vertex_array_objects(vao_handles);
         
-        let vao_indices = vec_indices(&self.vaos);
-        self.drop_vertex_array_objects(vao_indices);
+        let texture_handles = self.textures.handles();
+        self.drop_textures(texture_handles);
         
-        let tex2ds_indices = vec_indices(&self.tex2ds);
-        self.drop_textures(tex2ds_indices);
+        let prog_handles = self.progs.handles();
+        self.drop_programs(prog_handles);
         
-        let prog_indices = vec_indices(&self.progs);
-        self.drop_programs(prog_indices);
+        let ubos: Vec<GLuint> = self.ubos.values().cloned().collect();
+        self.drop_buffers(ubos);
+
+        let samplers: Vec<GLuint> = self.samplers.iter().map(|sampler| sampler.id).collect();
+        unsafe { gl::DeleteSamplers(samplers.len() as i32, samplers.as_ptr()); }
         
-        let vbo_indices = vec_indices(&self.vbos);
-        self.drop_vertex_buffer_objects(vbo_indices);
+        let vbo_handles = self.vbos.handles();
+        self.drop_vertex_buffer_objects(vbo_handles);
         
-        let ibo_indices = vec_indices(&self.ibos);
-        self.drop_index_buffer_objects(ibo_indices);
+        let ibo_handles = self.ibos.handles();
+        self.drop_index_buffer_objects(ibo_handles);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sorted_defines;
+
+    fn defines(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn sorted_defines_ignore_order() {
+        let a = sorted_defines(&defines(&[("A", "1"), ("B", "2")]));
+        let b = sorted_defines(&defines(&[("B", "2"), ("A", "1")]));
+
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn sorted_defines_keep_last_value_of_repeated_define() {
+        let sorted = sorted_defines(&defines(&[("B", "1"), ("A", "1"), ("B", "2")]));
+
+        assert_eq!(sorted, defines(&[("A", "1"), ("B", "2")]));
     }
 }
@@ -0,0 +1,1761 @@
+use common::*;
+
+use super::super::*;
+
+use std::mem;
+use std::usize;
+use std::f32;
+use std::any::Any;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use renderer::util::glsl::{reflect_shader_params, reflect_vertex_inputs};
+use renderer::util::preprocessor::GlslPreprocessor;
+use renderer::util::handle_pool::{HandlePool, PoolHandle};
+
+type Handle = usize;
+
+type TextureHandle = Handle;
+type TargetHandle = Handle;
+
+const DEFAULT_WIDTH: usize = 640;
+const DEFAULT_HEIGHT: usize = 480;
+
+/// Attribute names the rasterizer reads from the vertex layout. These match
+/// the defaults used by `MeshOptions`.
+const POSITION_ATTR_NAME: &'static str = "position";
+const TEX_COORD_ATTR_NAME: &'static str = "tex_coord";
+
+const MVP_PARAM_NAME: &'static str = "model_view_proj";
+
+/// Columns of the per-instance model matrix, as laid out by the scene's
+/// `ModelBatch`. Instanced vertices are transformed by the view data's
+/// `view_projection` and this matrix instead of `model_view_proj`.
+const INSTANCE_MODEL_ATTR_NAMES: [&'static str; 4] = ["instance_model_0", "instance_model_1", "instance_model_2", "instance_model_3"];
+
+/// CPU side color, depth and stencil buffers that the software renderer draws into.
+pub struct SoftwareFramebuffer {
+    width: usize,
+    height: usize,
+    color: Vec<u8>,
+    depth: Vec<f32>,
+    stencil: Vec<u8>,
+}
+
+pub type SoftwareFramebufferRef = Rc<RefCell<SoftwareFramebuffer>>;
+
+impl SoftwareFramebuffer {
+    pub fn new(width: usize, height: usize) -> SoftwareFramebuffer {
+        SoftwareFramebuffer {
+            width: width,
+            height: height,
+            color: vec![0; width * height * 4],
+            depth: vec![1.0; width * height],
+            stencil: vec![0; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Tightly packed RGBA8 pixels, starting at the top left corner.
+    pub fn color(&self) -> &Vec<u8> {
+        &self.color
+    }
+
+    pub fn depth(&self) -> &Vec<f32> {
+        &self.depth
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * self.width + x) * 4;
+        [self.color[i], self.color[i + 1], self.color[i + 2], self.color[i + 3]]
+    }
+
+    pub fn depth_at(&self, x: usize, y: usize) -> f32 {
+        self.depth[y * self.width + x]
+    }
+
+    pub fn stencil(&self) -> &Vec<u8> {
+        &self.stencil
+    }
+
+    pub fn stencil_at(&self, x: usize, y: usize) -> u8 {
+        self.stencil[y * self.width + x]
+    }
+
+    fn clear_color(&mut self, color: [u8; 4]) {
+        for pixel in self.color.chunks_mut(4) {
+            pixel.copy_from_slice(&color);
+        }
+    }
+
+    fn clear_depth(&mut self, depth: f32) {
+        for d in self.depth.iter_mut() {
+            *d = depth;
+        }
+    }
+
+    fn clear_stencil(&mut self, stencil: u8) {
+        for s in self.stencil.iter_mut() {
+            *s = stencil;
+        }
+    }
+
+    fn apply_stencil_op(&mut self, index: usize, op: StencilOp, state: &DepthStencilState) {
+        let stored = self.stencil[index];
+
+        let value = match op {
+            StencilOp::Keep          => stored,
+            StencilOp::Zero          => 0,
+            StencilOp::Replace       => state.stencil_ref as u8,
+            StencilOp::Increment     => stored.saturating_add(1),
+            StencilOp::IncrementWrap => stored.wrapping_add(1),
+            StencilOp::Decrement     => stored.saturating_sub(1),
+            StencilOp::DecrementWrap => stored.wrapping_sub(1),
+            StencilOp::Invert        => !stored,
+        };
+
+        let write_mask = state.stencil_write_mask as u8;
+        self.stencil[index] = (stored & !write_mask) | (value & write_mask);
+    }
+}
+
+struct SoftTex2D {
+    width: usize,
+    height: usize,
+    /// Always expanded to RGBA8 regardless of the source format.
+    pixels: Vec<u8>,
+    /// Shared with the `SoftwareTexture`. Only the wrap modes and border
+    /// color are used, sampling is always nearest.
+    sampler: Rc<RefCell<SamplerDescription>>,
+}
+
+impl SoftTex2D {
+    fn sample(&self, u: f32, v: f32) -> [f32; 4] {
+        let sampler = self.sampler.borrow();
+
+        let (u, v) = match (wrap_coord(u, sampler.wrap_s), wrap_coord(v, sampler.wrap_t)) {
+            (Some(u), Some(v)) => (u, v),
+            _ => {
+                let c = sampler.border_color;
+                return [c.x, c.y, c.z, c.w];
+            },
+        };
+
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+
+        let i = (y * self.width + x) * 4;
+
+        [self.pixels[i] as f32 / 255.0,
+         self.pixels[i + 1] as f32 / 255.0,
+         self.pixels[i + 2] as f32 / 255.0,
+         self.pixels[i + 3] as f32 / 255.0]
+    }
+}
+
+/// Maps a texture coordinate into 0-1, None when it samples the border.
+fn wrap_coord(x: f32, wrap: WrapMode) -> Option<f32> {
+    match wrap {
+        WrapMode::Repeat         => Some(x - x.floor()),
+        WrapMode::MirroredRepeat => {
+            let t = x - (x / 2.0).floor() * 2.0;
+            Some(if t > 1.0 { 2.0 - t } else { t })
+        },
+        WrapMode::ClampToEdge    => Some(x.max(0.0).min(1.0)),
+        WrapMode::ClampToBorder  => if x < 0.0 || x > 1.0 { None } else { Some(x) },
+    }
+}
+
+pub struct SoftwareTexture {
+    texture_handle: TextureHandle,
+    sampler: Rc<RefCell<SamplerDescription>>,
+    texture_format: TextureFormat,
+    component_type: ComponentType,
+    color_space: ColorSpace,
+}
+
+impl Texture for SoftwareTexture {
+    fn param_handle(&self) -> TextureParamHandle {
+        self.texture_handle as TextureParamHandle
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn texture_type(&self) -> TextureType {
+        TextureType::Texture2D
+    }
+
+    fn format(&self) -> &TextureFormat {
+        &self.texture_format
+    }
+
+    fn component_type(&self) -> ComponentType {
+        self.component_type
+    }
+
+    fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    fn sampler(&self) -> SamplerDescription {
+        self.sampler.borrow().clone()
+    }
+
+    fn set_sampler(&mut self, desc: &SamplerDescription) {
+        // Filtering is always nearest, but keep track of the request so
+        // the texture behaves like the other backends.
+        *self.sampler.borrow_mut() = desc.clone();
+    }
+}
+
+struct SoftRenderTarget {
+    framebuffer: SoftwareFramebufferRef,
+    color_texture: Option<TextureHandle>,
+    depth_texture: Option<TextureHandle>,
+}
+
+pub struct SoftwareRenderTarget {
+    target_handle: TargetHandle,
+    color_texture: Option<TextureParamHandle>,
+    depth_texture: Option<TextureParamHandle>,
+    width: u32,
+    height: u32,
+}
+
+impl RenderTarget for SoftwareRenderTarget {
+    fn handle(&self) -> RenderTargetHandle {
+        self.target_handle as RenderTargetHandle
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn color_texture_count(&self) -> usize {
+        if self.color_texture.is_some() { 1 } else { 0 }
+    }
+
+    fn color_texture(&self, index: usize) -> Option<TextureParamHandle> {
+        if index == 0 { self.color_texture } else { None }
+    }
+
+    fn depth_texture(&self) -> Option<TextureParamHandle> {
+        self.depth_texture
+    }
+}
+
+pub struct SoftwareGeometry {
+    vertex_data: BufferData,
+    index_data: BufferData,
+    index_type: IndexType,
+    layout_desc: VertexLayoutDescription,
+    material: MaterialHandle,
+    material_generation: u32,
+    params: ShaderParams,
+    render_state: RenderState,
+    draw_state: DrawState,
+    /// Textures bound to sampler params. Like the GL backend, a sampler
+    /// only has a texture once its param has been set.
+    bound_textures: Vec<(String, TextureHandle)>,
+}
+
+impl Geometry for SoftwareGeometry {
+    fn get_vertex_layout_description(&self) -> &VertexLayoutDescription {
+        &self.layout_desc
+    }
+
+    fn get_params(&self) -> &ShaderParams {
+        &self.params
+    }
+
+    fn get_mut_params(&mut self) -> &mut ShaderParams {
+        &mut self.params
+    }
+
+    fn get_render_state(&self) -> &RenderState {
+        &self.render_state
+    }
+
+    fn get_mut_render_state(&mut self) -> &mut RenderState {
+        &mut self.render_state
+    }
+
+    fn get_draw_state(&self) -> &DrawState {
+        &self.draw_state
+    }
+
+    fn get_mut_draw_state(&mut self) -> &mut DrawState {
+        &mut self.draw_state
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+}
+
+pub struct SoftwareInstanceBuffer {
+    data: BufferData,
+    layout_desc: VertexLayoutDescription,
+    count: usize,
+}
+
+impl InstanceBuffer for SoftwareInstanceBuffer {
+    fn layout(&self) -> &VertexLayoutDescription {
+        &self.layout_desc
+    }
+
+    fn instance_count(&self) -> usize {
+        self.count
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+}
+
+fn software_geometry(geom: &mut Box<Geometry>) -> Result<&mut SoftwareGeometry, RendererError> {
+    match geom.as_any_mut().downcast_mut::<SoftwareGeometry>() {
+        Some(x) => Ok(x),
+        None    => Err(RendererError::ForeignResource("geometry wasn't created by the software renderer".to_string())),
+    }
+}
+
+/// A vertex after transformation into clip space.
+#[derive(Clone, Copy)]
+struct ClipVertex {
+    position: Vec4f,
+    tex_coord: Vec2f,
+}
+
+impl ClipVertex {
+    fn lerp(&self, other: &ClipVertex, t: f32) -> ClipVertex {
+        ClipVertex {
+            position: self.position + (other.position - self.position) * t,
+            tex_coord: self.tex_coord + (other.tex_coord - self.tex_coord) * t,
+        }
+    }
+}
+
+/// A vertex after perspective division and viewport transformation.
+struct ScreenVertex {
+    x: f32,
+    y: f32,
+    z: f32,
+    inv_w: f32,
+    tex_coord: Vec2f,
+}
+
+struct SoftMaterial {
+    defines: Vec<(String, String)>,
+    /// Bumped whenever the material is reloaded, like on the GL backend.
+    generation: u32,
+    params: ShaderParams,
+    render_state: RenderState,
+    /// Reflected from the vertex shader to check geometries' layouts against.
+    inputs: Vec<VertexInput>,
+    includes: Vec<PathBuf>,
+}
+
+/// Renders on the CPU into a `SoftwareFramebuffer`.
+///
+/// Shaders can't be executed, so the vertex stage is fixed to transforming the
+/// `position` attribute by the `model_view_proj` param, and the fragment stage
+/// outputs the first bound texture sampled at `tex_coord`, or white when there
+/// is none. The GLSL sources are still scanned for uniforms so geometries get
+/// the same `ShaderParams` they would on the GL backend.
+///
+/// Render targets are resolved into their textures when they are unbound.
+pub struct SoftwareRenderer {
+    framebuffer: SoftwareFramebufferRef,
+    tex2ds: Vec<SoftTex2D>,
+    materials: HandlePool<SoftMaterial>,
+    preprocessor: GlslPreprocessor,
+    targets: Vec<SoftRenderTarget>,
+    /// Either `framebuffer` or the framebuffer of the bound render target.
+    current_framebuffer: SoftwareFramebufferRef,
+    bound_target: Option<TargetHandle>,
+    /// From the last `set_view_data`, for instanced draws.
+    view_projection: Mat4f,
+    /// Reused by `apply_shader_params` for the params changed since the
+    /// geometry last drew.
+    param_changes: Vec<ParamHandle>,
+}
+
+impl SoftwareRenderer {
+    pub fn new() -> Box<SoftwareRenderer> {
+        SoftwareRenderer::with_size(DEFAULT_WIDTH, DEFAULT_HEIGHT)
+    }
+
+    pub fn with_size(width: usize, height: usize) -> Box<SoftwareRenderer> {
+        let framebuffer = Rc::new(RefCell::new(SoftwareFramebuffer::new(width, height)));
+
+        Box::new(SoftwareRenderer {
+            framebuffer: framebuffer.clone(),
+            tex2ds: Vec::new(),
+            materials: HandlePool::new(),
+            preprocessor: GlslPreprocessor::new(),
+            targets: Vec::new(),
+            current_framebuffer: framebuffer,
+            bound_target: None,
+            view_projection: Mat4f::identity(),
+            param_changes: Vec::new(),
+        })
+    }
+
+    /// Shared reference to the framebuffer, so that the results can still be
+    /// inspected after the renderer has been handed off to a `Scene`.
+    pub fn framebuffer(&self) -> SoftwareFramebufferRef {
+        self.framebuffer.clone()
+    }
+
+    /// Copies what has been drawn into a render target to its textures, so
+    /// it can be sampled once the target is unbound.
+    fn resolve_target(&mut self, targeth: TargetHandle) {
+        let target = &self.targets[targeth];
+        let fb = target.framebuffer.borrow();
+
+        if let Some(texh) = target.color_texture {
+            self.tex2ds[texh].pixels.copy_from_slice(&fb.color);
+        }
+
+        if let Some(texh) = target.depth_texture {
+            let pixels = &mut self.tex2ds[texh].pixels;
+            for (i, depth) in fb.depth.iter().enumerate() {
+                let d = (depth.max(0.0).min(1.0) * 255.0) as u8;
+                pixels[i * 4] = d;
+                pixels[i * 4 + 1] = d;
+                pixels[i * 4 + 2] = d;
+                pixels[i * 4 + 3] = 255;
+            }
+        }
+    }
+
+    fn create_blank_texture(&mut self, width: usize, height: usize) -> TextureHandle {
+        let mut sampler = SamplerDescription::default();
+        sampler.set_wrap(WrapMode::ClampToEdge);
+
+        self.tex2ds.push(SoftTex2D {
+            width: width,
+            height: height,
+            pixels: vec![0; width * height * 4],
+            sampler: Rc::new(RefCell::new(sampler)),
+        });
+
+        self.tex2ds.len() - 1
+    }
+
+    fn read_f32(bytes: &[u8], offset: usize) -> f32 {
+        let raw = [bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]];
+        unsafe { mem::transmute(raw) }
+    }
+
+    fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+        let raw = [bytes[offset], bytes[offset + 1]];
+        unsafe { mem::transmute(raw) }
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        let raw = [bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]];
+        unsafe { mem::transmute(raw) }
+    }
+
+    fn half_to_f32(half: u16) -> f32 {
+        let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+        let exponent = ((half >> 10) & 0x1F) as i32;
+        let mantissa = (half & 0x3FF) as f32;
+
+        match exponent {
+            0  => sign * mantissa * 2f32.powi(-24),
+            31 => if mantissa == 0.0 { sign * f32::INFINITY } else { f32::NAN },
+            _  => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+        }
+    }
+
+    /// The `c`th component of an element starting at `offset`, as the
+    /// shader would see it. Integer attributes are just converted.
+    fn read_component(bytes: &[u8], offset: usize, vtype: VertexElementType, c: usize) -> f32 {
+        let at = offset + c * vtype.component_size();
+
+        match vtype {
+            VertexElementType::F32 | VertexElementType::F32F32 |
+            VertexElementType::F32F32F32 | VertexElementType::F32F32F32F32 => SoftwareRenderer::read_f32(bytes, at),
+            VertexElementType::F16(_)     => SoftwareRenderer::half_to_f32(SoftwareRenderer::read_u16(bytes, at)),
+            VertexElementType::U8Norm(_)  => bytes[at] as f32 / 255.0,
+            VertexElementType::I8Norm(_)  => (bytes[at] as i8 as f32 / 127.0).max(-1.0),
+            VertexElementType::U16Norm(_) => SoftwareRenderer::read_u16(bytes, at) as f32 / 65535.0,
+            VertexElementType::I16Norm(_) => (SoftwareRenderer::read_u16(bytes, at) as i16 as f32 / 32767.0).max(-1.0),
+            VertexElementType::U8(_)      => bytes[at] as f32,
+            VertexElementType::I8(_)      => bytes[at] as i8 as f32,
+            VertexElementType::U16(_)     => SoftwareRenderer::read_u16(bytes, at) as f32,
+            VertexElementType::I16(_)     => SoftwareRenderer::read_u16(bytes, at) as i16 as f32,
+            VertexElementType::U32(_)     => SoftwareRenderer::read_u32(bytes, at) as f32,
+            VertexElementType::I32(_)     => SoftwareRenderer::read_u32(bytes, at) as i32 as f32,
+            VertexElementType::U10_10_10_2Norm => {
+                let packed = SoftwareRenderer::read_u32(bytes, offset);
+                let bits = if c == 3 { 2 } else { 10 };
+                let max = ((1 << bits) - 1) as u32;
+                ((packed >> (c * 10)) & max) as f32 / max as f32
+            },
+            VertexElementType::I10_10_10_2Norm => {
+                let packed = SoftwareRenderer::read_u32(bytes, offset);
+                let bits = if c == 3 { 2 } else { 10 };
+                // Shifting the field up to the top and back sign extends it.
+                let value = ((packed << (32 - bits - c * 10)) as i32) >> (32 - bits);
+                let max = ((1 << (bits - 1)) - 1) as f32;
+                (value as f32 / max).max(-1.0)
+            },
+        }
+    }
+
+    fn read_index(data: &BufferData, itype: &IndexType, i: usize) -> usize {
+        let bytes = &data.bytes;
+        match *itype {
+            IndexType::U16 => {
+                let raw = [bytes[i * 2], bytes[i * 2 + 1]];
+                let index: u16 = unsafe { mem::transmute(raw) };
+                index as usize
+            },
+            IndexType::U32 => {
+                let raw = [bytes[i * 4], bytes[i * 4 + 1], bytes[i * 4 + 2], bytes[i * 4 + 3]];
+                let index: u32 = unsafe { mem::transmute(raw) };
+                index as usize
+            },
+            // Vertices are drawn in order.
+            IndexType::NoIndices => i,
+        }
+    }
+
+    /// Indices to draw from, or vertices without indices.
+    fn element_count(geom: &SoftwareGeometry) -> usize {
+        match geom.index_type {
+            IndexType::NoIndices => match geom.layout_desc.stride() {
+                0      => 0,
+                stride => geom.vertex_data.bytes.len() / stride,
+            },
+            itype => itype.count(&geom.index_data),
+        }
+    }
+
+    /// Vertex numbers of the `count` elements from `first` on, split into
+    /// runs at primitive restarts. Vertices before the first one, which a
+    /// negative base vertex can give, are `usize::MAX` so they are never found.
+    fn element_runs(geom: &SoftwareGeometry, first: usize, count: usize) -> Vec<Vec<usize>> {
+        let draw = &geom.draw_state;
+        let base_vertex = draw.range.map_or(0, |range| range.base_vertex) as isize;
+        let restart = match geom.index_type {
+            IndexType::NoIndices => None,
+            itype                => if draw.primitive_restart { Some(itype.restart_index() as usize) } else { None },
+        };
+
+        let mut runs = vec![Vec::new()];
+
+        for i in first..(first + count) {
+            let index = SoftwareRenderer::read_index(&geom.index_data, &geom.index_type, i);
+
+            if Some(index) == restart {
+                runs.push(Vec::new());
+                continue;
+            }
+
+            let vertex = match geom.index_type {
+                IndexType::NoIndices => index as isize,
+                _                    => index as isize + base_vertex,
+            };
+
+            runs.last_mut().unwrap().push(if vertex < 0 { usize::MAX } else { vertex as usize });
+        }
+
+        runs
+    }
+
+    /// Splits a run of elements into primitives, given as positions in the
+    /// run. Every other triangle of a strip is flipped to keep the winding.
+    fn assemble_primitives(topology: PrimitiveTopology, len: usize) -> Vec<Vec<usize>> {
+        let mut result = Vec::new();
+
+        match topology {
+            PrimitiveTopology::Points => {
+                for i in 0..len {
+                    result.push(vec![i]);
+                }
+            },
+            PrimitiveTopology::Lines => {
+                for i in 0..(len / 2) {
+                    result.push(vec![i * 2, i * 2 + 1]);
+                }
+            },
+            PrimitiveTopology::LineStrip => {
+                for i in 1..len {
+                    result.push(vec![i - 1, i]);
+                }
+            },
+            PrimitiveTopology::Triangles => {
+                for i in 0..(len / 3) {
+                    result.push(vec![i * 3, i * 3 + 1, i * 3 + 2]);
+                }
+            },
+            PrimitiveTopology::TriangleStrip => {
+                for i in 2..len {
+                    if i % 2 == 0 {
+                        result.push(vec![i - 2, i - 1, i]);
+                    } else {
+                        result.push(vec![i - 1, i - 2, i]);
+                    }
+                }
+            },
+            PrimitiveTopology::TriangleFan => {
+                for i in 2..len {
+                    result.push(vec![0, i - 1, i]);
+                }
+            },
+        }
+
+        result
+    }
+
+    /// Reads up to four float components of an element, filling the rest
+    /// in the same way GL does for missing attribute components.
+    fn read_element(bytes: &[u8], offset: usize, elem: &VertexElement) -> Vec4f {
+        let mut components = [0.0, 0.0, 0.0, 1.0];
+        for c in 0..elem.vtype.get_num_components() as usize {
+            components[c] = SoftwareRenderer::read_component(bytes, offset, elem.vtype, c);
+        }
+
+        Vec4f::new(components[0], components[1], components[2], components[3])
+    }
+
+    fn find_element<'a>(desc: &'a VertexLayoutDescription, name: &str) -> Option<&'a VertexElement> {
+        desc.elements.iter().find(|elem| elem.name == name)
+    }
+
+    /// Picks up the material's params if it has been reloaded since the
+    /// geometry last drew, keeping values by name.
+    fn update_geometry_material(&self, geom: &mut SoftwareGeometry) {
+        let (generation, mut params) = match self.materials.get(PoolHandle::from_bits(geom.material)) {
+            Some(mat) if mat.generation != geom.material_generation => (mat.generation, mat.params.clone()),
+            _ => return,
+        };
+
+        params.copy_values_from(&geom.params);
+
+        geom.bound_textures.retain(|&(ref name, _)| params.contains(name));
+        geom.params = params;
+        geom.material_generation = generation;
+    }
+
+    /// Checks the geometry's layout, and the instances' when drawing
+    /// instanced, against its material's vertex shader like the GL backend.
+    fn check_vertex_inputs(&self, geom: &SoftwareGeometry, instances: Option<&VertexLayoutDescription>) -> Result<(), RendererError> {
+        let mat = match self.materials.get(PoolHandle::from_bits(geom.material)) {
+            Some(mat) => mat,
+            None          => return Ok(()),
+        };
+
+        let mut layouts = vec![&geom.layout_desc];
+        if let Some(instances) = instances {
+            layouts.push(instances);
+        }
+
+        check_vertex_inputs(&mat.inputs, &layouts).map_err(RendererError::VertexLayout)
+    }
+
+    fn apply_shader_params(&mut self, geom: &mut SoftwareGeometry) {
+        geom.params.flush_changes(&mut self.param_changes);
+
+        for &handle in self.param_changes.iter() {
+            let param = geom.params.param_by_handle(handle);
+
+            if let ParamValue::Texture2D(tex_handle) = param.value {
+                let tex_handle = tex_handle as TextureHandle;
+
+                match geom.bound_textures.iter().position(|&(ref n, _)| *n == param.name) {
+                    Some(i) => geom.bound_textures[i].1 = tex_handle,
+                    None    => geom.bound_textures.push((param.name.clone(), tex_handle)),
+                }
+            }
+        }
+    }
+
+    /// The geometry's `model_view_proj` param.
+    fn geometry_mvp(geom: &SoftwareGeometry) -> Mat4f {
+        match geom.params.get(MVP_PARAM_NAME) {
+            Ok(&ParamValue::Mat4(x)) => x,
+            _                        => Mat4f::identity(),
+        }
+    }
+
+    /// The transform of the `instance`th instance, None if the layout has
+    /// no model matrix.
+    fn instance_mvp(&self, instances: &SoftwareInstanceBuffer, instance: usize) -> Option<Mat4f> {
+        let desc = &instances.layout_desc;
+        let element = instance / desc.step_rate as usize;
+
+        let mut columns = [Vec4f::new(0.0, 0.0, 0.0, 0.0); 4];
+        for (column, name) in columns.iter_mut().zip(INSTANCE_MODEL_ATTR_NAMES.iter()) {
+            match SoftwareRenderer::find_element(desc, name) {
+                Some(elem) => {
+                    let offset = desc.element_offset(elem, element, instances.count);
+                    *column = SoftwareRenderer::read_element(&instances.data.bytes, offset, elem);
+                },
+                None       => return None,
+            }
+        }
+
+        let model = Mat4f::from_cols(columns[0], columns[1], columns[2], columns[3]);
+        Some(self.view_projection * model)
+    }
+
+    fn transform_vertices(geom: &SoftwareGeometry, mvp: &Mat4f) -> Vec<ClipVertex> {
+        let desc = &geom.layout_desc;
+        let stride = desc.stride();
+
+        if stride == 0 {
+            return Vec::new();
+        }
+
+        let position_elem = match SoftwareRenderer::find_element(desc, POSITION_ATTR_NAME) {
+            Some(x) => x,
+            None    => match desc.elements.first() {
+                Some(x) => x,
+                None    => return Vec::new(),
+            },
+        };
+
+        let tex_coord_elem = SoftwareRenderer::find_element(desc, TEX_COORD_ATTR_NAME);
+
+        let bytes = &geom.vertex_data.bytes;
+        let num_vertices = bytes.len() / stride;
+
+        let mut result = Vec::with_capacity(num_vertices);
+
+        for i in 0..num_vertices {
+            let position_offset = desc.element_offset(position_elem, i, num_vertices);
+            let position = SoftwareRenderer::read_element(bytes, position_offset, position_elem);
+
+            let tex_coord = match tex_coord_elem {
+                Some(elem) => {
+                    let coord = SoftwareRenderer::read_element(bytes, desc.element_offset(elem, i, num_vertices), elem);
+                    Vec2f::new(coord.x, coord.y)
+                },
+                None       => Vec2f::new(0.0, 0.0),
+            };
+
+            result.push(ClipVertex {
+                position: *mvp * position,
+                tex_coord: tex_coord,
+            });
+        }
+
+        result
+    }
+
+    /// Clips a triangle against the near plane (z >= -w). Everything else is
+    /// handled by the scissoring done during rasterization.
+    fn clip_triangle(tri: [ClipVertex; 3]) -> Vec<ClipVertex> {
+        let mut result = Vec::with_capacity(4);
+
+        for i in 0..3 {
+            let cur = &tri[i];
+            let next = &tri[(i + 1) % 3];
+
+            let cur_dist = cur.position.z + cur.position.w;
+            let next_dist = next.position.z + next.position.w;
+
+            if cur_dist >= 0.0 {
+                result.push(*cur);
+            }
+
+            if (cur_dist >= 0.0) != (next_dist >= 0.0) {
+                let t = cur_dist / (cur_dist - next_dist);
+                result.push(cur.lerp(next, t));
+            }
+        }
+
+        result
+    }
+
+    fn to_screen(&self, vertex: &ClipVertex, width: usize, height: usize) -> ScreenVertex {
+        let inv_w = 1.0 / vertex.position.w;
+        let ndc_x = vertex.position.x * inv_w;
+        let ndc_y = vertex.position.y * inv_w;
+        let ndc_z = vertex.position.z * inv_w;
+
+        ScreenVertex {
+            x: (ndc_x + 1.0) * 0.5 * width as f32,
+            // Row 0 of the framebuffer is the top of the screen.
+            y: (1.0 - ndc_y) * 0.5 * height as f32,
+            z: ndc_z * 0.5 + 0.5,
+            inv_w: inv_w,
+            tex_coord: vertex.tex_coord * inv_w,
+        }
+    }
+
+    fn edge(ax: f32, ay: f32, bx: f32, by: f32, px: f32, py: f32) -> f32 {
+        (bx - ax) * (py - ay) - (by - ay) * (px - ax)
+    }
+
+    fn blend_factor(factor: BlendFactor, src: &[f32; 4], dst: &[f32; 4], constant: &[f32; 4], c: usize) -> f32 {
+        match factor {
+            BlendFactor::Zero                  => 0.0,
+            BlendFactor::One                   => 1.0,
+            BlendFactor::SrcColor              => src[c],
+            BlendFactor::OneMinusSrcColor      => 1.0 - src[c],
+            BlendFactor::DstColor              => dst[c],
+            BlendFactor::OneMinusDstColor      => 1.0 - dst[c],
+            BlendFactor::SrcAlpha              => src[3],
+            BlendFactor::OneMinusSrcAlpha      => 1.0 - src[3],
+            BlendFactor::DstAlpha              => dst[3],
+            BlendFactor::OneMinusDstAlpha      => 1.0 - dst[3],
+            BlendFactor::ConstantColor         => constant[c],
+            BlendFactor::OneMinusConstantColor => 1.0 - constant[c],
+            BlendFactor::ConstantAlpha         => constant[3],
+            BlendFactor::OneMinusConstantAlpha => 1.0 - constant[3],
+            BlendFactor::SrcAlphaSaturate      => if c == 3 { 1.0 } else { src[3].min(1.0 - dst[3]) },
+        }
+    }
+
+    fn blend(state: &BlendState, src: [f32; 4], dst: [f32; 4]) -> [f32; 4] {
+        let mut result = [0.0; 4];
+
+        for c in 0..4 {
+            let (src_factor, dst_factor, equation) = if c < 3 {
+                (state.src_color, state.dst_color, state.color_equation)
+            } else {
+                (state.src_alpha, state.dst_alpha, state.alpha_equation)
+            };
+
+            let s = src[c] * SoftwareRenderer::blend_factor(src_factor, &src, &dst, &state.constant, c);
+            let d = dst[c] * SoftwareRenderer::blend_factor(dst_factor, &src, &dst, &state.constant, c);
+
+            // Like GL, min and max ignore the blend factors.
+            result[c] = match equation {
+                BlendEquation::Add             => s + d,
+                BlendEquation::Subtract        => s - d,
+                BlendEquation::ReverseSubtract => d - s,
+                BlendEquation::Min             => src[c].min(dst[c]),
+                BlendEquation::Max             => src[c].max(dst[c]),
+            };
+        }
+
+        result
+    }
+
+    /// Runs the per fragment tests, shading and blending for a single pixel,
+    /// in the same order as GL. The vertex attributes are interpolated with
+    /// `weights`, one per vertex.
+    fn shade_fragment(fb: &mut SoftwareFramebuffer, x: usize, y: usize, vertices: &[&ScreenVertex], weights: &[f32], front_facing: bool, texture: Option<&SoftTex2D>, state: &RenderState) {
+        if let Some(ref rect) = state.rasterizer.scissor {
+            // The scissor rectangle has its origin at the bottom left.
+            let scissor_x = x as i32;
+            let scissor_y = (fb.height - 1 - y) as i32;
+
+            if scissor_x < rect.x || scissor_x >= rect.x + rect.width ||
+               scissor_y < rect.y || scissor_y >= rect.y + rect.height {
+                return;
+            }
+        }
+
+        let mut depth = 0.0;
+        let mut inv_w = 0.0;
+        let mut tex_coord = Vec2f::new(0.0, 0.0);
+
+        for (vertex, weight) in vertices.iter().zip(weights.iter()) {
+            depth += vertex.z * *weight;
+            inv_w += vertex.inv_w * *weight;
+            tex_coord = tex_coord + vertex.tex_coord * *weight;
+        }
+
+        if depth < 0.0 || depth > 1.0 {
+            return;
+        }
+
+        let ds = &state.depth_stencil;
+        let stencil_face = if front_facing { &ds.stencil_front } else { &ds.stencil_back };
+        let index = y * fb.width + x;
+
+        if ds.stencil_test {
+            let masked_ref = ds.stencil_ref & ds.stencil_read_mask;
+            let masked_stored = fb.stencil[index] as u32 & ds.stencil_read_mask;
+
+            if !stencil_face.compare.compare(masked_ref, masked_stored) {
+                fb.apply_stencil_op(index, stencil_face.fail_op, ds);
+                return;
+            }
+        }
+
+        if ds.depth_test && !ds.depth_compare.compare(depth, fb.depth[index]) {
+            if ds.stencil_test {
+                fb.apply_stencil_op(index, stencil_face.depth_fail_op, ds);
+            }
+            return;
+        }
+
+        if ds.stencil_test {
+            fb.apply_stencil_op(index, stencil_face.pass_op, ds);
+        }
+
+        // Like GL, depth is only written while depth testing.
+        if ds.depth_test && ds.depth_write {
+            fb.depth[index] = depth;
+        }
+
+        let src = match texture {
+            Some(tex) => {
+                let coord = tex_coord / inv_w;
+                tex.sample(coord.x, coord.y)
+            },
+            None      => [1.0, 1.0, 1.0, 1.0],
+        };
+
+        let color_index = index * 4;
+
+        let color = if state.blend.enabled {
+            let mut dst = [0.0; 4];
+            for c in 0..4 {
+                dst[c] = fb.color[color_index + c] as f32 / 255.0;
+            }
+
+            SoftwareRenderer::blend(&state.blend, src, dst)
+        } else {
+            src
+        };
+
+        for c in 0..4 {
+            fb.color[color_index + c] = (color[c].max(0.0).min(1.0) * 255.0) as u8;
+        }
+    }
+
+    fn rasterize_point(fb: &mut SoftwareFramebuffer, v: &ScreenVertex, front_facing: bool, texture: Option<&SoftTex2D>, state: &RenderState) {
+        if v.x < 0.0 || v.y < 0.0 || v.x >= fb.width as f32 || v.y >= fb.height as f32 {
+            return;
+        }
+
+        SoftwareRenderer::shade_fragment(fb, v.x as usize, v.y as usize, &[v], &[1.0], front_facing, texture, state);
+    }
+
+    fn rasterize_line(fb: &mut SoftwareFramebuffer, a: &ScreenVertex, b: &ScreenVertex, front_facing: bool, texture: Option<&SoftTex2D>, state: &RenderState) {
+        let dx = b.x - a.x;
+        let dy = b.y - a.y;
+        let steps = dx.abs().max(dy.abs()).ceil().max(1.0) as usize;
+
+        for i in 0..(steps + 1) {
+            let t = i as f32 / steps as f32;
+            let x = a.x + dx * t;
+            let y = a.y + dy * t;
+
+            if x < 0.0 || y < 0.0 || x >= fb.width as f32 || y >= fb.height as f32 {
+                continue;
+            }
+
+            SoftwareRenderer::shade_fragment(fb, x as usize, y as usize, &[a, b], &[1.0 - t, t], front_facing, texture, state);
+        }
+    }
+
+    fn rasterize_triangle(fb: &mut SoftwareFramebuffer, v: [&ScreenVertex; 3], texture: Option<&SoftTex2D>, state: &RenderState) {
+        let area = SoftwareRenderer::edge(v[0].x, v[0].y, v[1].x, v[1].y, v[2].x, v[2].y);
+
+        if area == 0.0 {
+            return;
+        }
+
+        // Counter clockwise in NDC is clockwise on screen as y is flipped.
+        let counter_clockwise = area < 0.0;
+
+        let front_facing = match state.rasterizer.front_face {
+            FrontFace::CounterClockwise => counter_clockwise,
+            FrontFace::Clockwise        => !counter_clockwise,
+        };
+
+        let culled = match state.rasterizer.cull_mode {
+            CullMode::None         => false,
+            CullMode::Front        => front_facing,
+            CullMode::Back         => !front_facing,
+            CullMode::FrontAndBack => true,
+        };
+
+        if culled {
+            return;
+        }
+
+        match state.rasterizer.polygon_mode {
+            PolygonMode::Fill  => (),
+            PolygonMode::Line  => {
+                for i in 0..3 {
+                    SoftwareRenderer::rasterize_line(fb, v[i], v[(i + 1) % 3], front_facing, texture, state);
+                }
+                return;
+            },
+            PolygonMode::Point => {
+                for i in 0..3 {
+                    SoftwareRenderer::rasterize_point(fb, v[i], front_facing, texture, state);
+                }
+                return;
+            },
+        }
+
+        let min_x = v[0].x.min(v[1].x).min(v[2].x).floor().max(0.0) as usize;
+        let min_y = v[0].y.min(v[1].y).min(v[2].y).floor().max(0.0) as usize;
+        let max_x = v[0].x.max(v[1].x).max(v[2].x).ceil().min(fb.width as f32) as usize;
+        let max_y = v[0].y.max(v[1].y).max(v[2].y).ceil().min(fb.height as f32) as usize;
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let px = x as f32 + 0.5;
+                let py = y as f32 + 0.5;
+
+                // Dividing by the signed area makes the weights positive
+                // inside the triangle for either winding order.
+                let w0 = SoftwareRenderer::edge(v[1].x, v[1].y, v[2].x, v[2].y, px, py) / area;
+                let w1 = SoftwareRenderer::edge(v[2].x, v[2].y, v[0].x, v[0].y, px, py) / area;
+                let w2 = SoftwareRenderer::edge(v[0].x, v[0].y, v[1].x, v[1].y, px, py) / area;
+
+                if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                    continue;
+                }
+
+                SoftwareRenderer::shade_fragment(fb, x, y, &v, &[w0, w1, w2], front_facing, texture, state);
+            }
+        }
+    }
+
+    /// Draws `count` elements from `first` on, see `element_runs`.
+    fn draw_primitives(&self, geom: &SoftwareGeometry, mvp: &Mat4f, first: usize, count: usize) {
+        let vertices = SoftwareRenderer::transform_vertices(geom, mvp);
+
+        if vertices.is_empty() {
+            return;
+        }
+
+        let texture = geom.bound_textures.first().and_then(|&(_, handle)| self.tex2ds.get(handle));
+
+        let mut fb = self.current_framebuffer.borrow_mut();
+        let (width, height) = (fb.width, fb.height);
+
+        for run in SoftwareRenderer::element_runs(geom, first, count) {
+            for primitive in SoftwareRenderer::assemble_primitives(geom.draw_state.topology, run.len()) {
+                let corners: Option<Vec<ClipVertex>> = primitive.iter().map(|&i| vertices.get(run[i]).cloned()).collect();
+
+                let corners = match corners {
+                    Some(x) => x,
+                    None    => continue,
+                };
+
+                if corners.len() < 3 {
+                    // Points and lines are dropped rather than clipped when
+                    // they cross the near plane. Like in GL they are always
+                    // front facing.
+                    if corners.iter().any(|v| v.position.z + v.position.w < 0.0) {
+                        continue;
+                    }
+
+                    let screen: Vec<ScreenVertex> = corners.iter().map(|v| self.to_screen(v, width, height)).collect();
+
+                    if screen.len() == 1 {
+                        SoftwareRenderer::rasterize_point(&mut fb, &screen[0], true, texture, &geom.render_state);
+                    } else {
+                        SoftwareRenderer::rasterize_line(&mut fb, &screen[0], &screen[1], true, texture, &geom.render_state);
+                    }
+
+                    continue;
+                }
+
+                let clipped = SoftwareRenderer::clip_triangle([corners[0], corners[1], corners[2]]);
+
+                if clipped.len() < 3 {
+                    continue;
+                }
+
+                let screen: Vec<ScreenVertex> = clipped.iter().map(|v| self.to_screen(v, width, height)).collect();
+
+                // The clipped polygon is convex, so a fan covers it.
+                for i in 1..(screen.len() - 1) {
+                    SoftwareRenderer::rasterize_triangle(&mut fb, [&screen[0], &screen[i], &screen[i + 1]], texture, &geom.render_state);
+                }
+            }
+        }
+    }
+}
+
+impl Renderer for SoftwareRenderer {
+    fn clear(&mut self, r: f32, g: f32, b: f32, a: f32) {
+        fn to_byte(x: f32) -> u8 {
+            (x.max(0.0).min(1.0) * 255.0) as u8
+        }
+
+        let mut fb = self.current_framebuffer.borrow_mut();
+        fb.clear_color([to_byte(r), to_byte(g), to_byte(b), to_byte(a)]);
+    }
+
+    fn clear_depth(&mut self, depth: f32) {
+        self.current_framebuffer.borrow_mut().clear_depth(depth);
+    }
+
+    fn clear_stencil(&mut self, stencil: i32) {
+        self.current_framebuffer.borrow_mut().clear_stencil(stencil as u8);
+    }
+
+    fn create_texture_layers(&mut self, texture_type: TextureType, layers: &[&TextureData], options: &TextureOptions) -> Result<Box<Texture>, RendererError> {
+        if texture_type != TextureType::Texture2D {
+            return Err(RendererError::Texture(format!("The software renderer only supports 2D textures, not {:?}.", texture_type)));
+        }
+
+        try!(texture_type.validate_layers(layers).map_err(RendererError::Texture));
+
+        let data = layers[0];
+
+        // Everything is sampled as 8-bit RGBA, without mipmaps or sRGB
+        // decoding.
+        let sampler = Rc::new(RefCell::new(options.sampler.clone()));
+
+        self.tex2ds.push(SoftTex2D {
+            width: data.width as usize,
+            height: data.height as usize,
+            pixels: data.to_rgba8(),
+            sampler: sampler.clone(),
+        });
+
+        Ok(Box::new(SoftwareTexture {
+            texture_handle: self.tex2ds.len() - 1,
+            sampler: sampler,
+            texture_format: data.format.clone(),
+            component_type: data.component_type,
+            color_space: options.color_space,
+        }))
+    }
+
+    fn create_render_target(&mut self, desc: &RenderTargetDescription) -> Result<Box<RenderTarget>, RendererError> {
+        // Every color format is stored as RGBA8, and since the fragment
+        // stage is fixed there is only ever one color output.
+        if desc.color_formats.len() > 1 {
+            return Err(RendererError::RenderTarget("The software renderer only supports a single color attachment.".to_string()));
+        }
+
+        let width = desc.width as usize;
+        let height = desc.height as usize;
+
+        let color_texture = if desc.color_formats.is_empty() {
+            None
+        } else {
+            Some(self.create_blank_texture(width, height))
+        };
+
+        // The framebuffer always has a depth buffer, it is only exposed
+        // for sampling when asked for.
+        let depth_texture = match desc.depth_stencil_format {
+            Some(_) => Some(self.create_blank_texture(width, height)),
+            None    => None,
+        };
+
+        self.targets.push(SoftRenderTarget {
+            framebuffer: Rc::new(RefCell::new(SoftwareFramebuffer::new(width, height))),
+            color_texture: color_texture,
+            depth_texture: depth_texture,
+        });
+
+        Ok(Box::new(SoftwareRenderTarget {
+            target_handle: self.targets.len() - 1,
+            color_texture: color_texture.map(|texh| texh as TextureParamHandle),
+            depth_texture: depth_texture.map(|texh| texh as TextureParamHandle),
+            width: desc.width,
+            height: desc.height,
+        }))
+    }
+
+    fn bind_render_target(&mut self, target: Option<&RenderTarget>) -> Result<(), RendererError> {
+        if let Some(previous) = self.bound_target.take() {
+            self.resolve_target(previous);
+        }
+
+        match target {
+            Some(target) => {
+                let targeth = match target.as_any().downcast_ref::<SoftwareRenderTarget>() {
+                    Some(softtarget) => softtarget.target_handle,
+                    None             => return Err(RendererError::ForeignResource("render target wasn't created by the software renderer".to_string())),
+                };
+
+                self.current_framebuffer = self.targets[targeth].framebuffer.clone();
+                self.bound_target = Some(targeth);
+            },
+            None => {
+                self.current_framebuffer = self.framebuffer.clone();
+            },
+        }
+
+        Ok(())
+    }
+
+    fn add_shader_search_path(&mut self, path: &Path) {
+        self.preprocessor.add_search_path(path);
+    }
+
+    fn create_material(&mut self, desc: &MaterialDescription) -> Result<MaterialHandle, RendererError> {
+        let (vert_src, frag_src) = try!(self.preprocessor.preprocess_program(&desc.vert_src, &desc.frag_src, &desc.defines));
+        let mut params = reflect_shader_params(&[&vert_src.source, &frag_src.source]);
+        desc.apply_params(&mut params);
+
+        let handle = self.materials.insert(SoftMaterial {
+            defines: desc.defines.clone(),
+            generation: 0,
+            params: params,
+            render_state: desc.render_state.clone(),
+            inputs: reflect_vertex_inputs(&vert_src.source),
+            includes: GlslPreprocessor::program_includes(&vert_src, &frag_src),
+        });
+
+        Ok(handle.to_bits())
+    }
+
+    fn reload_material(&mut self, material: MaterialHandle, vert_src: &str, frag_src: &str) -> Result<(), RendererError> {
+        let handle = PoolHandle::from_bits(material);
+
+        let defines = match self.materials.get(handle) {
+            Some(mat) => mat.defines.clone(),
+            None      => return Err(RendererError::Material(format!("material {:?} has been destroyed", handle))),
+        };
+
+        let (preprocessed_vert, preprocessed_frag) = try!(self.preprocessor.preprocess_program(vert_src, frag_src, &defines));
+        let mut params = reflect_shader_params(&[&preprocessed_vert.source, &preprocessed_frag.source]);
+
+        let mat = &mut self.materials[handle];
+
+        params.copy_values_from(&mat.params);
+
+        mat.params = params;
+        mat.inputs = reflect_vertex_inputs(&preprocessed_vert.source);
+        mat.includes = GlslPreprocessor::program_includes(&preprocessed_vert, &preprocessed_frag);
+        mat.generation += 1;
+
+        Ok(())
+    }
+
+    fn material_includes(&self, material: MaterialHandle) -> Vec<PathBuf> {
+        match self.materials.get(PoolHandle::from_bits(material)) {
+            Some(mat) => mat.includes.clone(),
+            None      => Vec::new(),
+        }
+    }
+
+    fn destroy_material(&mut self, material: MaterialHandle) -> Result<(), RendererError> {
+        let handle = PoolHandle::from_bits(material);
+
+        match self.materials.remove(handle) {
+            Some(_) => Ok(()),
+            None    => Err(RendererError::Material(format!("material {:?} has already been destroyed", handle))),
+        }
+    }
+
+    // Vertices are only transformed by model_view_proj, or the view
+    // projection for instances, so the rest of the shared blocks have
+    // nothing to feed.
+    fn set_frame_data(&mut self, _: &FrameData) {
+    }
+
+    fn set_view_data(&mut self, data: &ViewData) {
+        self.view_projection = data.view_projection;
+    }
+
+    // Everything lives in memory, so usage makes no difference.
+    fn create_geometry_with_usage(&mut self, vertex_data: &BufferData, index_data: &BufferData, layout: &VertexLayoutDescription, index_type: IndexType, _usage: BufferUsage, material: MaterialHandle) -> Result<Box<Geometry>, RendererError> {
+        let handle = PoolHandle::from_bits(material);
+
+        let (generation, params, render_state) = match self.materials.get(handle) {
+            Some(mat) => (mat.generation, mat.params.clone(), mat.render_state.clone()),
+            None      => return Err(RendererError::Material(format!("material {:?} has been destroyed", handle))),
+        };
+
+        Ok(Box::new(SoftwareGeometry {
+            vertex_data: BufferData { bytes: vertex_data.bytes.clone() },
+            index_data: BufferData { bytes: index_data.bytes.clone() },
+            index_type: index_type,
+            layout_desc: layout.clone(),
+            material: material,
+            material_generation: generation,
+            params: params,
+            render_state: render_state,
+            draw_state: DrawState::default(),
+            bound_textures: Vec::new(),
+        }))
+    }
+
+    fn draw_geometry(&mut self, geom: &mut Box<Geometry>) -> Result<(), RendererError> {
+        let softgeom = try!(software_geometry(geom));
+        let total = SoftwareRenderer::element_count(softgeom);
+        let (first, count) = try!(softgeom.draw_state.resolve_range(total).map_err(RendererError::Buffer));
+
+        try!(self.check_vertex_inputs(softgeom, None));
+        self.update_geometry_material(softgeom);
+        self.apply_shader_params(softgeom);
+
+        let mvp = SoftwareRenderer::geometry_mvp(softgeom);
+        self.draw_primitives(softgeom, &mvp, first, count);
+
+        Ok(())
+    }
+
+    fn update_vertex_data(&mut self, geom: &mut Box<Geometry>, offset: usize, data: &BufferData) -> Result<(), RendererError> {
+        let softgeom = try!(software_geometry(geom));
+
+        try!(softgeom.vertex_data.update_region(offset, data.bytes.clone()).map_err(RendererError::Buffer));
+
+        Ok(())
+    }
+
+    fn update_index_data(&mut self, geom: &mut Box<Geometry>, offset: usize, data: &BufferData) -> Result<(), RendererError> {
+        let softgeom = try!(software_geometry(geom));
+
+        let used_size = softgeom.index_type.used_size(softgeom.index_data.bytes.len());
+        try!(check_buffer_region(used_size, offset, data.bytes.len()).map_err(RendererError::Buffer));
+        try!(softgeom.index_data.update_region(offset, data.bytes.clone()).map_err(RendererError::Buffer));
+
+        Ok(())
+    }
+
+    fn set_vertex_data(&mut self, geom: &mut Box<Geometry>, data: &BufferData) -> Result<(), RendererError> {
+        let softgeom = try!(software_geometry(geom));
+        softgeom.vertex_data.bytes = data.bytes.clone();
+
+        Ok(())
+    }
+
+    fn set_index_data(&mut self, geom: &mut Box<Geometry>, data: &BufferData) -> Result<(), RendererError> {
+        let softgeom = try!(software_geometry(geom));
+
+        if softgeom.index_type == IndexType::NoIndices {
+            return Err(RendererError::Buffer("the geometry was created without indices".to_string()));
+        }
+
+        softgeom.index_data.bytes = data.bytes.clone();
+
+        Ok(())
+    }
+
+    fn create_instance_buffer(&mut self, data: &BufferData, layout: &VertexLayoutDescription, _usage: BufferUsage) -> Result<Box<InstanceBuffer>, RendererError> {
+        let count = try!(count_instances(layout, data).map_err(RendererError::Buffer));
+
+        Ok(Box::new(SoftwareInstanceBuffer {
+            data: BufferData { bytes: data.bytes.clone() },
+            layout_desc: layout.clone(),
+            count: count,
+        }))
+    }
+
+    fn set_instance_data(&mut self, instances: &mut Box<InstanceBuffer>, data: &BufferData) -> Result<(), RendererError> {
+        let softinstances = match instances.as_any_mut().downcast_mut::<SoftwareInstanceBuffer>() {
+            Some(x) => x,
+            None    => return Err(RendererError::ForeignResource("instance buffer wasn't created by the software renderer".to_string())),
+        };
+
+        softinstances.count = try!(count_instances(&softinstances.layout_desc, data).map_err(RendererError::Buffer));
+        softinstances.data.bytes = data.bytes.clone();
+
+        Ok(())
+    }
+
+    // Each instance is drawn on its own, other instance attributes such as
+    // tints are ignored like any other attribute besides the position and
+    // texture coordinates.
+    fn draw_geometry_instanced(&mut self, geom: &mut Box<Geometry>, instances: &InstanceBuffer, count: usize) -> Result<(), RendererError> {
+        let softgeom = try!(software_geometry(geom));
+        let softinstances = match instances.as_any().downcast_ref::<SoftwareInstanceBuffer>() {
+            Some(x) => x,
+            None    => return Err(RendererError::ForeignResource("instance buffer wasn't created by the software renderer".to_string())),
+        };
+
+        try!(check_instance_count(instances, count).map_err(RendererError::Buffer));
+        let total = SoftwareRenderer::element_count(softgeom);
+        let (first, element_count) = try!(softgeom.draw_state.resolve_range(total).map_err(RendererError::Buffer));
+
+        try!(self.check_vertex_inputs(softgeom, Some(&softinstances.layout_desc)));
+        self.update_geometry_material(softgeom);
+        self.apply_shader_params(softgeom);
+
+        let geometry_mvp = SoftwareRenderer::geometry_mvp(softgeom);
+
+        for instance in 0..count {
+            let mvp = self.instance_mvp(softinstances, instance).unwrap_or(geometry_mvp);
+            self.draw_primitives(softgeom, &mvp, first, element_count);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use renderer::backends::trace::TraceRenderer;
+
+    const VERT_SRC: &'static str = "#version 400
+uniform mat4 model_view_proj;
+in vec3 position;
+void main() {
+    gl_Position = model_view_proj * vec4(position, 1.0);
+}
+";
+
+    const FRAG_SRC: &'static str = "#version 400
+out vec4 color;
+void main() {
+    color = vec4(1.0);
+}
+";
+
+    #[test]
+    fn draws_triangle_into_framebuffer() {
+        let software = SoftwareRenderer::with_size(8, 8);
+        let framebuffer = software.framebuffer();
+        let mut renderer: Box<Renderer> = software;
+
+        let material = renderer.create_material(&MaterialDescription::new(VERT_SRC, FRAG_SRC)).unwrap();
+
+        let mut layout = VertexLayoutDescription::new();
+        layout.add_element("position".to_string(), VertexElementType::F32F32F32).unwrap();
+
+        // The lower left half of the screen.
+        let vertices = BufferData::new_initialized(vec![-1.0f32, -1.0, 0.0, 1.0, -1.0, 0.0, -1.0, 1.0, 0.0]);
+        let indices = BufferData::new_initialized(vec![0u32, 1, 2]);
+        let mut geom = renderer.create_geometry(&vertices, &indices, &layout, IndexType::U32, material).unwrap();
+
+        renderer.clear(0.0, 0.0, 0.0, 1.0);
+        renderer.draw_geometry(&mut geom).unwrap();
+
+        let fb = framebuffer.borrow();
+        assert_eq!(fb.pixel(0, 7), [255, 255, 255, 255]);
+        assert_eq!(fb.pixel(7, 0), [0, 0, 0, 255]);
+    }
+
+    const TEXTURED_FRAG_SRC: &'static str = "#version 400
+uniform sampler2D tex;
+out vec4 color;
+void main() {
+    color = texture(tex, vec2(0.0));
+}
+";
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+
+    /// A triangle at depth `z` covering the whole framebuffer, counter
+    /// clockwise unless `clockwise`.
+    fn fullscreen_triangle(renderer: &mut Box<Renderer>, material: MaterialHandle, z: f32, clockwise: bool) -> Box<Geometry> {
+        let mut layout = VertexLayoutDescription::new();
+        layout.add_element("position".to_string(), VertexElementType::F32F32F32).unwrap();
+
+        let vertices = BufferData::new_initialized(vec![-1.0f32, -1.0, z, 3.0, -1.0, z, -1.0, 3.0, z]);
+        let indices = if clockwise { vec![0u32, 2, 1] } else { vec![0u32, 1, 2] };
+        renderer.create_geometry(&vertices, &BufferData::new_initialized(indices), &layout, IndexType::U32, material).unwrap()
+    }
+
+    /// A fullscreen triangle drawing in a flat color, and the texture
+    /// holding that color.
+    fn colored_triangle(renderer: &mut Box<Renderer>, material: MaterialHandle, z: f32, color: [u8; 4]) -> (Box<Geometry>, Box<Texture>) {
+        let data = TextureData::new(1, 1, TextureFormat::RGBA, ComponentType::U8, color.to_vec()).unwrap();
+        let texture = renderer.create_texture(&data, &TextureOptions::default()).unwrap();
+
+        let mut geom = fullscreen_triangle(renderer, material, z, false);
+        geom.get_mut_params().set("tex", ParamValue::Texture2D(texture.param_handle())).unwrap();
+
+        (geom, texture)
+    }
+
+    #[test]
+    fn depth_test_keeps_the_nearest_fragment() {
+        let software = SoftwareRenderer::with_size(8, 8);
+        let framebuffer = software.framebuffer();
+        let mut renderer: Box<Renderer> = software;
+
+        let material = renderer.create_material(&MaterialDescription::new(VERT_SRC, TEXTURED_FRAG_SRC)).unwrap();
+        let (mut near, _near_texture) = colored_triangle(&mut renderer, material, -0.5, RED);
+        let (mut far, _far_texture) = colored_triangle(&mut renderer, material, 0.5, GREEN);
+
+        renderer.clear(0.0, 0.0, 0.0, 1.0);
+        renderer.clear_depth(1.0);
+
+        // Less by default, the far triangle is hidden behind the near one.
+        renderer.draw_geometry(&mut near).unwrap();
+        renderer.draw_geometry(&mut far).unwrap();
+        assert_eq!(framebuffer.borrow().pixel(3, 3), RED);
+        assert_eq!(framebuffer.borrow().depth_at(3, 3), 0.25);
+
+        far.get_mut_render_state().depth_stencil.depth_compare = CompareFunc::Greater;
+        renderer.draw_geometry(&mut far).unwrap();
+        assert_eq!(framebuffer.borrow().pixel(3, 3), GREEN);
+        assert_eq!(framebuffer.borrow().depth_at(3, 3), 0.75);
+
+        // Passing fragments leave the depth alone without depth writes.
+        near.get_mut_render_state().depth_stencil.depth_write = false;
+        renderer.draw_geometry(&mut near).unwrap();
+        assert_eq!(framebuffer.borrow().pixel(3, 3), RED);
+        assert_eq!(framebuffer.borrow().depth_at(3, 3), 0.75);
+    }
+
+    #[test]
+    fn culls_faces_by_winding() {
+        let software = SoftwareRenderer::with_size(8, 8);
+        let framebuffer = software.framebuffer();
+        let mut renderer: Box<Renderer> = software;
+
+        let material = renderer.create_material(&MaterialDescription::new(VERT_SRC, FRAG_SRC)).unwrap();
+        let mut front = fullscreen_triangle(&mut renderer, material, 0.0, false);
+        let mut back = fullscreen_triangle(&mut renderer, material, 0.0, true);
+
+        for geom in [&mut front, &mut back].iter_mut() {
+            geom.get_mut_render_state().rasterizer.cull_mode = CullMode::Back;
+            geom.get_mut_render_state().depth_stencil = DepthStencilState::disabled();
+        }
+
+        renderer.clear(0.0, 0.0, 0.0, 1.0);
+        renderer.draw_geometry(&mut back).unwrap();
+        assert_eq!(framebuffer.borrow().pixel(3, 3), BLACK);
+
+        renderer.draw_geometry(&mut front).unwrap();
+        assert_eq!(framebuffer.borrow().pixel(3, 3), WHITE);
+
+        // With clockwise front faces the other triangle is the one culled.
+        front.get_mut_render_state().rasterizer.front_face = FrontFace::Clockwise;
+        back.get_mut_render_state().rasterizer.front_face = FrontFace::Clockwise;
+
+        renderer.clear(0.0, 0.0, 0.0, 1.0);
+        renderer.draw_geometry(&mut front).unwrap();
+        assert_eq!(framebuffer.borrow().pixel(3, 3), BLACK);
+
+        renderer.draw_geometry(&mut back).unwrap();
+        assert_eq!(framebuffer.borrow().pixel(3, 3), WHITE);
+
+        renderer.clear(0.0, 0.0, 0.0, 1.0);
+        front.get_mut_render_state().rasterizer.cull_mode = CullMode::FrontAndBack;
+        renderer.draw_geometry(&mut front).unwrap();
+        assert_eq!(framebuffer.borrow().pixel(3, 3), BLACK);
+    }
+
+    #[test]
+    fn scissor_clips_fragments() {
+        let software = SoftwareRenderer::with_size(8, 8);
+        let framebuffer = software.framebuffer();
+        let mut renderer: Box<Renderer> = software;
+
+        let material = renderer.create_material(&MaterialDescription::new(VERT_SRC, FRAG_SRC)).unwrap();
+        let mut geom = fullscreen_triangle(&mut renderer, material, 0.0, false);
+        geom.get_mut_render_state().rasterizer.scissor = Some(ScissorRect {
+            x: 1,
+            y: 2,
+            width: 3,
+            height: 4,
+        });
+
+        renderer.clear(0.0, 0.0, 0.0, 1.0);
+        renderer.draw_geometry(&mut geom).unwrap();
+
+        // The scissor's origin is the bottom left, pixels start at the top.
+        let fb = framebuffer.borrow();
+        for y in 0..8 {
+            for x in 0..8 {
+                let inside = x >= 1 && x < 4 && y >= 2 && y < 6;
+                let expected = if inside { WHITE } else { BLACK };
+                assert_eq!(fb.pixel(x, 7 - y), expected, "pixel {}, {} from the bottom left", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn equirectangular_cube_rejects_bad_images() {
+        let mut renderer: Box<Renderer> = SoftwareRenderer::new();
+        let options = TextureOptions::default();
+
+        let empty = TextureData::new(0, 0, TextureFormat::RGBA, ComponentType::U8, Vec::new()).unwrap();
+        assert!(renderer.create_cube_texture_from_equirectangular(&empty, 1, &options).is_err());
+
+        let short = TextureData {
+            width: 4,
+            height: 2,
+            format: TextureFormat::RGBA,
+            component_type: ComponentType::U8,
+            bytes: vec![0; 4],
+        };
+        assert!(renderer.create_cube_texture_from_equirectangular(&short, 1, &options).is_err());
+
+        let image = TextureData::new(4, 2, TextureFormat::RGBA, ComponentType::U8, vec![0; 32]).unwrap();
+        assert!(renderer.create_cube_texture_from_equirectangular(&image, 0, &options).is_err());
+    }
+
+    #[test]
+    fn assembles_primitives_for_every_topology() {
+        assert_eq!(SoftwareRenderer::assemble_primitives(PrimitiveTopology::Points, 2), vec![vec![0], vec![1]]);
+        assert_eq!(SoftwareRenderer::assemble_primitives(PrimitiveTopology::Lines, 5), vec![vec![0, 1], vec![2, 3]]);
+        assert_eq!(SoftwareRenderer::assemble_primitives(PrimitiveTopology::LineStrip, 3), vec![vec![0, 1], vec![1, 2]]);
+        assert_eq!(SoftwareRenderer::assemble_primitives(PrimitiveTopology::Triangles, 7), vec![vec![0, 1, 2], vec![3, 4, 5]]);
+        assert_eq!(SoftwareRenderer::assemble_primitives(PrimitiveTopology::TriangleStrip, 5),
+                   vec![vec![0, 1, 2], vec![2, 1, 3], vec![2, 3, 4]]);
+        assert_eq!(SoftwareRenderer::assemble_primitives(PrimitiveTopology::TriangleFan, 4), vec![vec![0, 1, 2], vec![0, 2, 3]]);
+        assert!(SoftwareRenderer::assemble_primitives(PrimitiveTopology::TriangleStrip, 2).is_empty());
+    }
+
+    #[test]
+    fn set_index_data_fails_without_indices() {
+        let mut renderer: Box<Renderer> = SoftwareRenderer::new();
+        let material = renderer.create_material(&MaterialDescription::new(VERT_SRC, FRAG_SRC)).unwrap();
+
+        let mut layout = VertexLayoutDescription::new();
+        layout.add_element("position".to_string(), VertexElementType::F32F32F32).unwrap();
+
+        let vertices = BufferData::new_initialized(vec![0.0f32; 9]);
+        let mut geom = renderer.create_geometry(&vertices, &BufferData::new(), &layout, IndexType::NoIndices, material).unwrap();
+
+        let indices = BufferData::new_initialized(vec![0u32, 1, 2]);
+        assert!(renderer.set_index_data(&mut geom, &indices).is_err());
+    }
+
+    #[test]
+    fn render_targets_with_many_color_attachments_fail() {
+        let mut renderer: Box<Renderer> = SoftwareRenderer::new();
+
+        let mut desc = RenderTargetDescription::new(4, 4);
+        desc.color_formats = vec![TextureFormat::RGBA, TextureFormat::RGBA];
+
+        match renderer.create_render_target(&desc) {
+            Err(RendererError::RenderTarget(_)) => (),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_)  => panic!("created a render target with two color attachments"),
+        }
+    }
+
+    #[test]
+    fn destroyed_material_handles_fail() {
+        let mut renderer: Box<Renderer> = SoftwareRenderer::new();
+        let desc = MaterialDescription::new(VERT_SRC, FRAG_SRC);
+
+        let stale = renderer.create_material(&desc).unwrap();
+        renderer.destroy_material(stale).unwrap();
+
+        // Takes over the destroyed material's slot.
+        let live = renderer.create_material(&desc).unwrap();
+        assert!(live != stale);
+
+        let mut layout = VertexLayoutDescription::new();
+        layout.add_element("position".to_string(), VertexElementType::F32F32F32).unwrap();
+        let vertices = BufferData::new_initialized(vec![0.0f32; 9]);
+
+        match renderer.create_geometry(&vertices, &BufferData::new(), &layout, IndexType::NoIndices, stale) {
+            Err(RendererError::Material(_)) => (),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_)  => panic!("created a geometry with a destroyed material"),
+        }
+
+        assert!(renderer.reload_material(stale, VERT_SRC, FRAG_SRC).is_err());
+        assert!(renderer.material_includes(stale).is_empty());
+        assert!(renderer.destroy_material(stale).is_err());
+
+        assert!(renderer.create_geometry(&vertices, &BufferData::new(), &layout, IndexType::NoIndices, live).is_ok());
+        assert!(renderer.destroy_material(live).is_ok());
+    }
+
+    fn assert_foreign(result: Result<(), RendererError>) {
+        match result {
+            Err(RendererError::ForeignResource(_)) => (),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_)  => panic!("used a resource created by another backend"),
+        }
+    }
+
+    #[test]
+    fn rejects_resources_from_other_backends() {
+        let mut other: Box<Renderer> = TraceRenderer::new();
+        let mut renderer: Box<Renderer> = SoftwareRenderer::new();
+
+        let mut layout = VertexLayoutDescription::new();
+        layout.add_element("position".to_string(), VertexElementType::F32F32F32).unwrap();
+        let vertices = BufferData::new_initialized(vec![0.0f32; 9]);
+        let indices = BufferData::new_initialized(vec![0u32, 1, 2]);
+
+        let mut instance_layout = VertexLayoutDescription::new_per_instance(1);
+        instance_layout.add_element("instance_tint".to_string(), VertexElementType::F32F32F32F32).unwrap();
+        let instance_data = BufferData::new_initialized(vec![1.0f32; 4]);
+
+        let other_material = other.create_material(&MaterialDescription::new(VERT_SRC, FRAG_SRC)).unwrap();
+        let mut foreign_geom = other.create_geometry(&vertices, &indices, &layout, IndexType::U32, other_material).unwrap();
+        let mut foreign_instances = other.create_instance_buffer(&instance_data, &instance_layout, BufferUsage::Static).unwrap();
+        let foreign_target = other.create_render_target(&RenderTargetDescription::new(4, 4)).unwrap();
+
+        let material = renderer.create_material(&MaterialDescription::new(VERT_SRC, FRAG_SRC)).unwrap();
+        let mut geom = renderer.create_geometry(&vertices, &indices, &layout, IndexType::U32, material).unwrap();
+
+        assert_foreign(renderer.draw_geometry(&mut foreign_geom));
+        assert_foreign(renderer.update_vertex_data(&mut foreign_geom, 0, &vertices));
+        assert_foreign(renderer.set_instance_data(&mut foreign_instances, &instance_data));
+        assert_foreign(renderer.draw_geometry_instanced(&mut geom, &*foreign_instances, 1));
+        assert_foreign(renderer.bind_render_target(Some(&*foreign_target)));
+    }
+
+    fn packed_bytes(packed: u32) -> Vec<u8> {
+        let bytes: [u8; 4] = unsafe { mem::transmute(packed) };
+        bytes.to_vec()
+    }
+
+    #[test]
+    fn converts_half_floats() {
+        assert_eq!(SoftwareRenderer::half_to_f32(0x3C00), 1.0);
+        assert_eq!(SoftwareRenderer::half_to_f32(0xC000), -2.0);
+        assert_eq!(SoftwareRenderer::half_to_f32(0x3555), 0.333251953125);
+        assert_eq!(SoftwareRenderer::half_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(SoftwareRenderer::half_to_f32(0xFC00), f32::NEG_INFINITY);
+        assert!(SoftwareRenderer::half_to_f32(0x7E00).is_nan());
+    }
+
+    #[test]
+    fn reads_normalized_and_integer_components() {
+        let bytes = vec![255, 0x80, 0xFF, 0xFF];
+
+        assert_eq!(SoftwareRenderer::read_component(&bytes, 0, VertexElementType::U8Norm(2), 0), 1.0);
+        assert_eq!(SoftwareRenderer::read_component(&bytes, 0, VertexElementType::I8Norm(2), 1), -1.0);
+        assert_eq!(SoftwareRenderer::read_component(&bytes, 0, VertexElementType::I8(2), 1), -128.0);
+        assert_eq!(SoftwareRenderer::read_component(&bytes, 2, VertexElementType::U16Norm(1), 0), 1.0);
+        assert_eq!(SoftwareRenderer::read_component(&bytes, 2, VertexElementType::I16(1), 0), -1.0);
+        assert_eq!(SoftwareRenderer::read_component(&bytes, 0, VertexElementType::F16(1), 0),
+                   SoftwareRenderer::half_to_f32(0x80FF));
+    }
+
+    #[test]
+    fn reads_packed_10_10_10_2_components() {
+        let unsigned = packed_bytes(1023 | (0 << 10) | (512 << 20) | (3 << 30));
+        let read_unsigned = |c| SoftwareRenderer::read_component(&unsigned, 0, VertexElementType::U10_10_10_2Norm, c);
+        assert_eq!(read_unsigned(0), 1.0);
+        assert_eq!(read_unsigned(1), 0.0);
+        assert_eq!(read_unsigned(2), 512.0 / 1023.0);
+        assert_eq!(read_unsigned(3), 1.0);
+
+        // x = 511, y = -512, z = 0, w = -1
+        let signed = packed_bytes(0x1FF | (0x200 << 10) | (0 << 20) | (0x3 << 30));
+        let read_signed = |c| SoftwareRenderer::read_component(&signed, 0, VertexElementType::I10_10_10_2Norm, c);
+        assert_eq!(read_signed(0), 1.0);
+        assert_eq!(read_signed(1), -1.0);
+        assert_eq!(read_signed(2), 0.0);
+        assert_eq!(read_signed(3), -1.0);
+    }
+}
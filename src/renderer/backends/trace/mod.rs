@@ -0,0 +1,849 @@
+use super::super::*;
+
+use renderer::util::glsl::{reflect_shader_params, reflect_vertex_inputs};
+use renderer::util::preprocessor::GlslPreprocessor;
+use renderer::util::handle_pool::{HandlePool, PoolHandle};
+
+use std::any::Any;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+pub type TraceGeometryId = usize;
+pub type TraceInstanceBufferId = usize;
+
+/// A single call made on a `TraceRenderer`.
+#[derive(Clone, Debug)]
+pub enum TraceCommand {
+    Clear {
+        r: f32,
+        g: f32,
+        b: f32,
+        a: f32,
+    },
+    ClearDepth {
+        depth: f32,
+    },
+    ClearStencil {
+        stencil: i32,
+    },
+    CreateTexture {
+        texture: TextureParamHandle,
+        texture_type: TextureType,
+        width: u32,
+        height: u32,
+        layers: usize,
+        format: TextureFormat,
+        component_type: ComponentType,
+        options: TextureOptions,
+    },
+    SetTextureSampler {
+        texture: TextureParamHandle,
+        sampler: SamplerDescription,
+    },
+    CreateRenderTarget {
+        target: RenderTargetHandle,
+        desc: RenderTargetDescription,
+    },
+    BindRenderTarget {
+        target: Option<RenderTargetHandle>,
+    },
+    CreateMaterial {
+        material: MaterialHandle,
+        desc: MaterialDescription,
+    },
+    ReloadMaterial {
+        material: MaterialHandle,
+        vert_src: String,
+        frag_src: String,
+    },
+    DestroyMaterial {
+        material: MaterialHandle,
+    },
+    SetFrameData {
+        data: FrameData,
+    },
+    SetViewData {
+        data: ViewData,
+    },
+    CreateGeometry {
+        geometry: TraceGeometryId,
+        vertex_bytes: usize,
+        index_bytes: usize,
+        index_type: IndexType,
+        usage: BufferUsage,
+        layout: VertexLayoutDescription,
+        material: MaterialHandle,
+    },
+    UpdateVertexData {
+        geometry: TraceGeometryId,
+        offset: usize,
+        bytes: usize,
+    },
+    UpdateIndexData {
+        geometry: TraceGeometryId,
+        offset: usize,
+        bytes: usize,
+    },
+    SetVertexData {
+        geometry: TraceGeometryId,
+        bytes: usize,
+    },
+    SetIndexData {
+        geometry: TraceGeometryId,
+        bytes: usize,
+    },
+    DrawGeometry {
+        geometry: TraceGeometryId,
+        /// Every param value of the geometry at the time of the draw.
+        params: Vec<(String, ParamValue)>,
+        render_state: RenderState,
+        draw_state: DrawState,
+    },
+    CreateInstanceBuffer {
+        instances: TraceInstanceBufferId,
+        bytes: usize,
+        usage: BufferUsage,
+        layout: VertexLayoutDescription,
+    },
+    SetInstanceData {
+        instances: TraceInstanceBufferId,
+        bytes: usize,
+    },
+    DrawGeometryInstanced {
+        geometry: TraceGeometryId,
+        instances: TraceInstanceBufferId,
+        count: usize,
+        params: Vec<(String, ParamValue)>,
+        render_state: RenderState,
+        draw_state: DrawState,
+    },
+}
+
+pub type TraceLog = Rc<RefCell<Vec<TraceCommand>>>;
+
+pub struct TraceTexture {
+    texture_handle: TextureParamHandle,
+    texture_type: TextureType,
+    sampler: SamplerDescription,
+    /// So that sampler changes show up in the trace too.
+    log: TraceLog,
+    texture_format: TextureFormat,
+    component_type: ComponentType,
+    color_space: ColorSpace,
+}
+
+impl Texture for TraceTexture {
+    fn param_handle(&self) -> TextureParamHandle {
+        self.texture_handle
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn texture_type(&self) -> TextureType {
+        self.texture_type
+    }
+
+    fn format(&self) -> &TextureFormat {
+        &self.texture_format
+    }
+
+    fn component_type(&self) -> ComponentType {
+        self.component_type
+    }
+
+    fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    fn sampler(&self) -> SamplerDescription {
+        self.sampler.clone()
+    }
+
+    fn set_sampler(&mut self, desc: &SamplerDescription) {
+        self.sampler = desc.clone();
+        self.log.borrow_mut().push(TraceCommand::SetTextureSampler {
+            texture: self.texture_handle,
+            sampler: desc.clone(),
+        });
+    }
+}
+
+pub struct TraceRenderTarget {
+    target_handle: RenderTargetHandle,
+    color_textures: Vec<TextureParamHandle>,
+    depth_texture: Option<TextureParamHandle>,
+    width: u32,
+    height: u32,
+}
+
+impl RenderTarget for TraceRenderTarget {
+    fn handle(&self) -> RenderTargetHandle {
+        self.target_handle
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn color_texture_count(&self) -> usize {
+        self.color_textures.len()
+    }
+
+    fn color_texture(&self, index: usize) -> Option<TextureParamHandle> {
+        self.color_textures.get(index).cloned()
+    }
+
+    fn depth_texture(&self) -> Option<TextureParamHandle> {
+        self.depth_texture
+    }
+}
+
+pub struct TraceGeometry {
+    id: TraceGeometryId,
+    /// Sizes of the data the geometry would have, to check updates against.
+    vertex_bytes: usize,
+    index_bytes: usize,
+    index_type: IndexType,
+    layout_desc: VertexLayoutDescription,
+    material: MaterialHandle,
+    material_generation: u32,
+    params: ShaderParams,
+    render_state: RenderState,
+    draw_state: DrawState,
+}
+
+impl TraceGeometry {
+    pub fn id(&self) -> TraceGeometryId {
+        self.id
+    }
+
+    /// Checks the draw range against the indices, or the vertices without
+    /// indices, like the other backends do.
+    fn check_draw_range(&self) -> Result<(), RendererError> {
+        let total = match self.index_type {
+            IndexType::NoIndices => match self.layout_desc.stride() {
+                0      => 0,
+                stride => self.vertex_bytes / stride,
+            },
+            itype => self.index_bytes / itype.size(),
+        };
+
+        try!(self.draw_state.resolve_range(total).map_err(RendererError::Buffer));
+        Ok(())
+    }
+}
+
+impl Geometry for TraceGeometry {
+    fn get_vertex_layout_description(&self) -> &VertexLayoutDescription {
+        &self.layout_desc
+    }
+
+    fn get_params(&self) -> &ShaderParams {
+        &self.params
+    }
+
+    fn get_mut_params(&mut self) -> &mut ShaderParams {
+        &mut self.params
+    }
+
+    fn get_render_state(&self) -> &RenderState {
+        &self.render_state
+    }
+
+    fn get_mut_render_state(&mut self) -> &mut RenderState {
+        &mut self.render_state
+    }
+
+    fn get_draw_state(&self) -> &DrawState {
+        &self.draw_state
+    }
+
+    fn get_mut_draw_state(&mut self) -> &mut DrawState {
+        &mut self.draw_state
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+}
+
+fn trace_geometry(geom: &mut Box<Geometry>) -> Result<&mut TraceGeometry, RendererError> {
+    match geom.as_any_mut().downcast_mut::<TraceGeometry>() {
+        Some(x) => Ok(x),
+        None    => Err(RendererError::ForeignResource("geometry wasn't created by the trace renderer".to_string())),
+    }
+}
+
+pub struct TraceInstanceBuffer {
+    id: TraceInstanceBufferId,
+    layout_desc: VertexLayoutDescription,
+    count: usize,
+}
+
+impl TraceInstanceBuffer {
+    pub fn id(&self) -> TraceInstanceBufferId {
+        self.id
+    }
+}
+
+impl InstanceBuffer for TraceInstanceBuffer {
+    fn layout(&self) -> &VertexLayoutDescription {
+        &self.layout_desc
+    }
+
+    fn instance_count(&self) -> usize {
+        self.count
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+}
+
+struct TraceMaterial {
+    defines: Vec<(String, String)>,
+    generation: u32,
+    params: ShaderParams,
+    render_state: RenderState,
+    /// Reflected from the vertex shader to check geometries' layouts against.
+    inputs: Vec<VertexInput>,
+    includes: Vec<PathBuf>,
+}
+
+/// Renders nothing, but records every call into a `TraceLog` so tests can
+/// assert on what a scene asked the renderer to do.
+///
+/// Shader params are reflected from the GLSL sources in the same way as the
+/// software renderer, so geometries accept the same params as on the GL
+/// backend.
+pub struct TraceRenderer {
+    log: TraceLog,
+    materials: HandlePool<TraceMaterial>,
+    preprocessor: GlslPreprocessor,
+    num_geometries: usize,
+    num_instance_buffers: usize,
+    num_textures: usize,
+    num_render_targets: usize,
+    /// Where `prepare_geometry` flushes param changes to, reused across
+    /// draws.
+    param_changes: Vec<ParamHandle>,
+}
+
+impl TraceRenderer {
+    pub fn new() -> Box<TraceRenderer> {
+        Box::new(TraceRenderer {
+            log: Rc::new(RefCell::new(Vec::new())),
+            materials: HandlePool::new(),
+            preprocessor: GlslPreprocessor::new(),
+            num_geometries: 0,
+            num_instance_buffers: 0,
+            num_textures: 0,
+            num_render_targets: 0,
+            param_changes: Vec::new(),
+        })
+    }
+
+    /// Shared reference to the command log, so that it can still be
+    /// inspected after the renderer has been handed off to a `Scene`.
+    pub fn log(&self) -> TraceLog {
+        self.log.clone()
+    }
+
+    fn next_texture_handle(&mut self) -> TextureParamHandle {
+        let handle = self.num_textures as TextureParamHandle;
+        self.num_textures += 1;
+        handle
+    }
+
+    fn record(&mut self, command: TraceCommand) {
+        self.log.borrow_mut().push(command);
+    }
+
+    /// Picks up the material's params if it has been reloaded, keeping
+    /// values by name like the GL backend.
+    fn prepare_geometry(&mut self, geom: &mut TraceGeometry) {
+        if let Some(mat) = self.materials.get(PoolHandle::from_bits(geom.material)) {
+            if mat.generation != geom.material_generation {
+                let mut params = mat.params.clone();
+                params.copy_values_from(&geom.params);
+
+                geom.params = params;
+                geom.material_generation = mat.generation;
+            }
+        }
+
+        // Nothing is uploaded anywhere, clear the changes like the other
+        // backends do.
+        geom.params.flush_changes(&mut self.param_changes);
+    }
+
+    /// Checks the geometry's layout, and the instances' when drawing
+    /// instanced, against its material's vertex shader like the GL backend.
+    fn check_vertex_inputs(&self, geom: &TraceGeometry, instances: Option<&VertexLayoutDescription>) -> Result<(), RendererError> {
+        let mat = match self.materials.get(PoolHandle::from_bits(geom.material)) {
+            Some(mat) => mat,
+            None          => return Ok(()),
+        };
+
+        let mut layouts = vec![&geom.layout_desc];
+        if let Some(instances) = instances {
+            layouts.push(instances);
+        }
+
+        check_vertex_inputs(&mat.inputs, &layouts).map_err(RendererError::VertexLayout)
+    }
+
+    fn snapshot_params(params: &ShaderParams) -> Vec<(String, ParamValue)> {
+        let mut result = Vec::new();
+
+        for group in params.groups().iter() {
+            for param in group.params.iter() {
+                result.push((param.name.clone(), param.value.clone()));
+            }
+        }
+
+        result
+    }
+}
+
+impl Renderer for TraceRenderer {
+    fn clear(&mut self, r: f32, g: f32, b: f32, a: f32) {
+        self.record(TraceCommand::Clear {
+            r: r,
+            g: g,
+            b: b,
+            a: a,
+        });
+    }
+
+    fn clear_depth(&mut self, depth: f32) {
+        self.record(TraceCommand::ClearDepth {
+            depth: depth,
+        });
+    }
+
+    fn clear_stencil(&mut self, stencil: i32) {
+        self.record(TraceCommand::ClearStencil {
+            stencil: stencil,
+        });
+    }
+
+    fn create_texture_layers(&mut self, texture_type: TextureType, layers: &[&TextureData], options: &TextureOptions) -> Result<Box<Texture>, RendererError> {
+        try!(texture_type.validate_layers(layers).map_err(RendererError::Texture));
+
+        let data = layers[0];
+        let handle = self.next_texture_handle();
+
+        self.record(TraceCommand::CreateTexture {
+            texture: handle,
+            texture_type: texture_type,
+            width: data.width,
+            height: data.height,
+            layers: layers.len(),
+            format: data.format.clone(),
+            component_type: data.component_type,
+            options: options.clone(),
+        });
+
+        Ok(Box::new(TraceTexture {
+            texture_handle: handle,
+            texture_type: texture_type,
+            sampler: options.sampler.clone(),
+            log: self.log.clone(),
+            texture_format: data.format.clone(),
+            component_type: data.component_type,
+            color_space: options.color_space,
+        }))
+    }
+
+    fn create_render_target(&mut self, desc: &RenderTargetDescription) -> Result<Box<RenderTarget>, RendererError> {
+        let handle = self.num_render_targets as RenderTargetHandle;
+        self.num_render_targets += 1;
+
+        let mut color_textures = Vec::with_capacity(desc.color_formats.len());
+        for _ in desc.color_formats.iter() {
+            color_textures.push(self.next_texture_handle());
+        }
+
+        let depth_texture = match desc.depth_stencil_format {
+            Some(_) => Some(self.next_texture_handle()),
+            None    => None,
+        };
+
+        self.record(TraceCommand::CreateRenderTarget {
+            target: handle,
+            desc: desc.clone(),
+        });
+
+        Ok(Box::new(TraceRenderTarget {
+            target_handle: handle,
+            color_textures: color_textures,
+            depth_texture: depth_texture,
+            width: desc.width,
+            height: desc.height,
+        }))
+    }
+
+    fn bind_render_target(&mut self, target: Option<&RenderTarget>) -> Result<(), RendererError> {
+        let handle = match target {
+            Some(target) => match target.as_any().downcast_ref::<TraceRenderTarget>() {
+                Some(tracetarget) => Some(tracetarget.target_handle),
+                None              => return Err(RendererError::ForeignResource("render target wasn't created by the trace renderer".to_string())),
+            },
+            None => None,
+        };
+
+        self.record(TraceCommand::BindRenderTarget {
+            target: handle,
+        });
+
+        Ok(())
+    }
+
+    fn add_shader_search_path(&mut self, path: &Path) {
+        self.preprocessor.add_search_path(path);
+    }
+
+    fn create_material(&mut self, desc: &MaterialDescription) -> Result<MaterialHandle, RendererError> {
+        let (vert_src, frag_src) = try!(self.preprocessor.preprocess_program(&desc.vert_src, &desc.frag_src, &desc.defines));
+        let mut params = reflect_shader_params(&[&vert_src.source, &frag_src.source]);
+        desc.apply_params(&mut params);
+
+        let handle = self.materials.insert(TraceMaterial {
+            defines: desc.defines.clone(),
+            generation: 0,
+            params: params,
+            render_state: desc.render_state.clone(),
+            inputs: reflect_vertex_inputs(&vert_src.source),
+            includes: GlslPreprocessor::program_includes(&vert_src, &frag_src),
+        }).to_bits();
+
+        self.record(TraceCommand::CreateMaterial {
+            material: handle,
+            desc: desc.clone(),
+        });
+
+        Ok(handle)
+    }
+
+    fn reload_material(&mut self, material: MaterialHandle, vert_src: &str, frag_src: &str) -> Result<(), RendererError> {
+        let handle = PoolHandle::from_bits(material);
+
+        let defines = match self.materials.get(handle) {
+            Some(mat) => mat.defines.clone(),
+            None      => return Err(RendererError::Material(format!("material {:?} has been destroyed", handle))),
+        };
+
+        let (preprocessed_vert, preprocessed_frag) = try!(self.preprocessor.preprocess_program(vert_src, frag_src, &defines));
+        let mut params = reflect_shader_params(&[&preprocessed_vert.source, &preprocessed_frag.source]);
+
+        {
+            let mat = &mut self.materials[handle];
+
+            params.copy_values_from(&mat.params);
+
+            mat.params = params;
+            mat.inputs = reflect_vertex_inputs(&preprocessed_vert.source);
+            mat.includes = GlslPreprocessor::program_includes(&preprocessed_vert, &preprocessed_frag);
+            mat.generation += 1;
+        }
+
+        self.record(TraceCommand::ReloadMaterial {
+            material: material,
+            vert_src: vert_src.to_string(),
+            frag_src: frag_src.to_string(),
+        });
+
+        Ok(())
+    }
+
+    fn material_includes(&self, material: MaterialHandle) -> Vec<PathBuf> {
+        match self.materials.get(PoolHandle::from_bits(material)) {
+            Some(mat) => mat.includes.clone(),
+            None      => Vec::new(),
+        }
+    }
+
+    fn destroy_material(&mut self, material: MaterialHandle) -> Result<(), RendererError> {
+        let handle = PoolHandle::from_bits(material);
+
+        if self.materials.remove(handle).is_none() {
+            return Err(RendererError::Material(format!("material {:?} has already been destroyed", handle)));
+        }
+
+        self.record(TraceCommand::DestroyMaterial {
+            material: material,
+        });
+
+        Ok(())
+    }
+
+    fn set_frame_data(&mut self, data: &FrameData) {
+        self.record(TraceCommand::SetFrameData {
+            data: *data,
+        });
+    }
+
+    fn set_view_data(&mut self, data: &ViewData) {
+        self.record(TraceCommand::SetViewData {
+            data: *data,
+        });
+    }
+
+    fn create_geometry_with_usage(&mut self, vertex_data: &BufferData, index_data: &BufferData, layout: &VertexLayoutDescription, index_type: IndexType, usage: BufferUsage, material: MaterialHandle) -> Result<Box<Geometry>, RendererError> {
+        let handle = PoolHandle::from_bits(material);
+
+        let (generation, params, render_state) = match self.materials.get(handle) {
+            Some(mat) => (mat.generation, mat.params.clone(), mat.render_state.clone()),
+            None      => return Err(RendererError::Material(format!("material {:?} has been destroyed", handle))),
+        };
+
+        let id = self.num_geometries;
+        self.num_geometries += 1;
+
+        self.record(TraceCommand::CreateGeometry {
+            geometry: id,
+            vertex_bytes: vertex_data.bytes.len(),
+            index_bytes: index_data.bytes.len(),
+            index_type: index_type,
+            usage: usage,
+            layout: layout.clone(),
+            material: material,
+        });
+
+        Ok(Box::new(TraceGeometry {
+            id: id,
+            vertex_bytes: vertex_data.bytes.len(),
+            index_bytes: index_data.bytes.len(),
+            index_type: index_type,
+            layout_desc: layout.clone(),
+            material: material,
+            material_generation: generation,
+            params: params,
+            render_state: render_state,
+            draw_state: DrawState::default(),
+        }))
+    }
+
+    fn draw_geometry(&mut self, geom: &mut Box<Geometry>) -> Result<(), RendererError> {
+        let tracegeom = try!(trace_geometry(geom));
+        try!(tracegeom.check_draw_range());
+        try!(self.check_vertex_inputs(tracegeom, None));
+
+        self.prepare_geometry(tracegeom);
+
+        let params = TraceRenderer::snapshot_params(&tracegeom.params);
+        let id = tracegeom.id;
+        let render_state = tracegeom.render_state.clone();
+        let draw_state = tracegeom.draw_state.clone();
+
+        self.record(TraceCommand::DrawGeometry {
+            geometry: id,
+            params: params,
+            render_state: render_state,
+            draw_state: draw_state,
+        });
+
+        Ok(())
+    }
+
+    fn update_vertex_data(&mut self, geom: &mut Box<Geometry>, offset: usize, data: &BufferData) -> Result<(), RendererError> {
+        let tracegeom = try!(trace_geometry(geom));
+
+        try!(check_buffer_region(tracegeom.vertex_bytes, offset, data.bytes.len()).map_err(RendererError::Buffer));
+
+        self.record(TraceCommand::UpdateVertexData {
+            geometry: tracegeom.id,
+            offset: offset,
+            bytes: data.bytes.len(),
+        });
+
+        Ok(())
+    }
+
+    fn update_index_data(&mut self, geom: &mut Box<Geometry>, offset: usize, data: &BufferData) -> Result<(), RendererError> {
+        let tracegeom = try!(trace_geometry(geom));
+
+        let used_size = tracegeom.index_type.used_size(tracegeom.index_bytes);
+        try!(check_buffer_region(used_size, offset, data.bytes.len()).map_err(RendererError::Buffer));
+
+        self.record(TraceCommand::UpdateIndexData {
+            geometry: tracegeom.id,
+            offset: offset,
+            bytes: data.bytes.len(),
+        });
+
+        Ok(())
+    }
+
+    fn set_vertex_data(&mut self, geom: &mut Box<Geometry>, data: &BufferData) -> Result<(), RendererError> {
+        let tracegeom = try!(trace_geometry(geom));
+        tracegeom.vertex_bytes = data.bytes.len();
+
+        self.record(TraceCommand::SetVertexData {
+            geometry: tracegeom.id,
+            bytes: data.bytes.len(),
+        });
+
+        Ok(())
+    }
+
+    fn set_index_data(&mut self, geom: &mut Box<Geometry>, data: &BufferData) -> Result<(), RendererError> {
+        let tracegeom = try!(trace_geometry(geom));
+
+        if tracegeom.index_type == IndexType::NoIndices {
+            return Err(RendererError::Buffer("the geometry was created without indices".to_string()));
+        }
+
+        tracegeom.index_bytes = data.bytes.len();
+
+        self.record(TraceCommand::SetIndexData {
+            geometry: tracegeom.id,
+            bytes: data.bytes.len(),
+        });
+
+        Ok(())
+    }
+
+    fn create_instance_buffer(&mut self, data: &BufferData, layout: &VertexLayoutDescription, usage: BufferUsage) -> Result<Box<InstanceBuffer>, RendererError> {
+        let count = try!(count_instances(layout, data).map_err(RendererError::Buffer));
+
+        let id = self.num_instance_buffers;
+        self.num_instance_buffers += 1;
+
+        self.record(TraceCommand::CreateInstanceBuffer {
+            instances: id,
+            bytes: data.bytes.len(),
+            usage: usage,
+            layout: layout.clone(),
+        });
+
+        Ok(Box::new(TraceInstanceBuffer {
+            id: id,
+            layout_desc: layout.clone(),
+            count: count,
+        }))
+    }
+
+    fn set_instance_data(&mut self, instances: &mut Box<InstanceBuffer>, data: &BufferData) -> Result<(), RendererError> {
+        let traceinstances = match instances.as_any_mut().downcast_mut::<TraceInstanceBuffer>() {
+            Some(x) => x,
+            None    => return Err(RendererError::ForeignResource("instance buffer wasn't created by the trace renderer".to_string())),
+        };
+
+        traceinstances.count = try!(count_instances(&traceinstances.layout_desc, data).map_err(RendererError::Buffer));
+
+        self.record(TraceCommand::SetInstanceData {
+            instances: traceinstances.id,
+            bytes: data.bytes.len(),
+        });
+
+        Ok(())
+    }
+
+    fn draw_geometry_instanced(&mut self, geom: &mut Box<Geometry>, instances: &InstanceBuffer, count: usize) -> Result<(), RendererError> {
+        let tracegeom = try!(trace_geometry(geom));
+        let instances_id = match instances.as_any().downcast_ref::<TraceInstanceBuffer>() {
+            Some(x) => x.id,
+            None    => return Err(RendererError::ForeignResource("instance buffer wasn't created by the trace renderer".to_string())),
+        };
+
+        try!(check_instance_count(instances, count).map_err(RendererError::Buffer));
+        try!(tracegeom.check_draw_range());
+        try!(self.check_vertex_inputs(tracegeom, Some(instances.layout())));
+
+        self.prepare_geometry(tracegeom);
+
+        let params = TraceRenderer::snapshot_params(&tracegeom.params);
+        let id = tracegeom.id;
+        let render_state = tracegeom.render_state.clone();
+        let draw_state = tracegeom.draw_state.clone();
+
+        self.record(TraceCommand::DrawGeometryInstanced {
+            geometry: id,
+            instances: instances_id,
+            count: count,
+            params: params,
+            render_state: render_state,
+            draw_state: draw_state,
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use renderer::backends::software::SoftwareRenderer;
+
+    const VERT_SRC: &'static str = "#version 400
+uniform mat4 model_view_proj;
+in vec3 position;
+void main() {
+    gl_Position = model_view_proj * vec4(position, 1.0);
+}
+";
+
+    const FRAG_SRC: &'static str = "#version 400
+out vec4 color;
+void main() {
+    color = vec4(1.0);
+}
+";
+
+    fn assert_foreign(result: Result<(), RendererError>) {
+        match result {
+            Err(RendererError::ForeignResource(_)) => (),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_)  => panic!("used a resource created by another backend"),
+        }
+    }
+
+    #[test]
+    fn rejects_resources_from_other_backends() {
+        let mut other: Box<Renderer> = SoftwareRenderer::new();
+        let mut renderer: Box<Renderer> = TraceRenderer::new();
+
+        let mut layout = VertexLayoutDescription::new();
+        layout.add_element("position".to_string(), VertexElementType::F32F32F32).unwrap();
+        let vertices = BufferData::new_initialized(vec![0.0f32; 9]);
+        let indices = BufferData::new_initialized(vec![0u32, 1, 2]);
+
+        let mut instance_layout = VertexLayoutDescription::new_per_instance(1);
+        instance_layout.add_element("instance_tint".to_string(), VertexElementType::F32F32F32F32).unwrap();
+        let instance_data = BufferData::new_initialized(vec![1.0f32; 4]);
+
+        let other_material = other.create_material(&MaterialDescription::new(VERT_SRC, FRAG_SRC)).unwrap();
+        let mut foreign_geom = other.create_geometry(&vertices, &indices, &layout, IndexType::U32, other_material).unwrap();
+        let mut foreign_instances = other.create_instance_buffer(&instance_data, &instance_layout, BufferUsage::Static).unwrap();
+        let foreign_target = other.create_render_target(&RenderTargetDescription::new(4, 4)).unwrap();
+
+        let material = renderer.create_material(&MaterialDescription::new(VERT_SRC, FRAG_SRC)).unwrap();
+        let mut geom = renderer.create_geometry(&vertices, &indices, &layout, IndexType::U32, material).unwrap();
+
+        assert_foreign(renderer.draw_geometry(&mut foreign_geom));
+        assert_foreign(renderer.update_vertex_data(&mut foreign_geom, 0, &vertices));
+        assert_foreign(renderer.set_instance_data(&mut foreign_instances, &instance_data));
+        assert_foreign(renderer.draw_geometry_instanced(&mut geom, &*foreign_instances, 1));
+        assert_foreign(renderer.bind_render_target(Some(&*foreign_target)));
+    }
+}
@@ -1,5 +1,27 @@
 use std::mem;
 
+/// How often a geometry's buffers are expected to change.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BufferUsage {
+    /// Written once at creation.
+    Static,
+    /// Updated every now and then, such as UI that changes with its layout.
+    Dynamic,
+    /// Rewritten every frame, such as particles or debug lines. Replacing
+    /// the data orphans the old storage so the GPU can keep drawing from it.
+    Stream,
+}
+
+/// Checks that `len` bytes written `offset` bytes into a buffer of `size`
+/// bytes stay inside it.
+pub fn check_buffer_region(size: usize, offset: usize, len: usize) -> Result<(), String> {
+    if offset > size || len > size - offset {
+        return Err(format!("{} bytes at offset {} don't fit in a buffer of {} bytes", len, offset, size));
+    }
+
+    Ok(())
+}
+
 pub struct BufferData {
     pub bytes: Vec<u8>
 }
@@ -50,11 +72,43 @@ impl BufferData {
         self.bytes.extend(bytes);
     }
 
-    pub fn update_region<T>(&mut self, start: usize, data: Vec<T>) {
+    /// Overwrites the bytes from `start` on, failing if the data doesn't
+    /// fit in the buffer.
+    pub fn update_region<T>(&mut self, start: usize, data: Vec<T>) -> Result<(), String> {
         let inserting_bytes = BufferData::convert_to_bytes(data);
+        try!(check_buffer_region(self.bytes.len(), start, inserting_bytes.len()));
 
-        for (i, byte) in inserting_bytes.iter().enumerate() {
-            self.bytes[start + i] = *byte;
-        }
+        self.bytes[start..(start + inserting_bytes.len())].copy_from_slice(&inserting_bytes);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_region_overwrites_bytes() {
+        let mut data = BufferData::new_zero_initialized(4);
+        data.update_region(1, vec![7u8, 8]).unwrap();
+
+        assert_eq!(data.bytes, vec![0, 7, 8, 0]);
+    }
+
+    #[test]
+    fn update_region_rejects_data_past_the_end() {
+        let mut data = BufferData::new_zero_initialized(4);
+
+        assert!(data.update_region(3, vec![1u8, 2]).is_err());
+        assert!(data.update_region(5, Vec::<u8>::new()).is_err());
+        assert_eq!(data.bytes, vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn check_buffer_region_allows_regions_up_to_the_end() {
+        assert!(check_buffer_region(4, 0, 4).is_ok());
+        assert!(check_buffer_region(4, 4, 0).is_ok());
+        assert!(check_buffer_region(4, 2, 3).is_err());
     }
 }
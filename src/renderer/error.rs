@@ -0,0 +1,252 @@
+use std::fmt;
+
+use super::util::preprocessor::PreprocessedSource;
+
+/// Number of source lines shown either side of a line with an error.
+const CONTEXT_LINES: usize = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+}
+
+impl fmt::Display for ShaderStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ShaderStage::Vertex   => write!(f, "vertex shader"),
+            ShaderStage::Fragment => write!(f, "fragment shader"),
+        }
+    }
+}
+
+/// One message of an info log that refers to a line of the source.
+#[derive(Clone, Debug)]
+pub struct ShaderDiagnostic {
+    /// The file the line is in, after mapping back through includes.
+    pub file: String,
+    /// 1 based, within `file`.
+    pub line: usize,
+    pub message: String,
+    /// The line itself and the lines around it, with their line numbers.
+    pub context: Vec<(usize, String)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ShaderError {
+    /// None when the program failed to link.
+    pub stage: Option<ShaderStage>,
+    /// The info log exactly as the driver returned it.
+    pub info_log: String,
+    pub diagnostics: Vec<ShaderDiagnostic>,
+}
+
+impl ShaderError {
+    pub fn compile(stage: ShaderStage, info_log: &str, src: &PreprocessedSource) -> ShaderError {
+        let mut diagnostics = Vec::new();
+
+        for log_line in info_log.lines() {
+            let line = match parse_info_log_line_number(log_line) {
+                Some(x) => x,
+                None    => continue,
+            };
+
+            let (file, original_line) = match src.location(line) {
+                Some(x) => x,
+                None    => continue,
+            };
+
+            diagnostics.push(ShaderDiagnostic {
+                file: file.to_string(),
+                line: original_line,
+                message: log_line.trim().to_string(),
+                context: src.context(line, CONTEXT_LINES),
+            });
+        }
+
+        ShaderError {
+            stage: Some(stage),
+            info_log: info_log.to_string(),
+            diagnostics: diagnostics,
+        }
+    }
+
+    /// An include couldn't be resolved or read.
+    pub fn preprocess(stage: ShaderStage, message: &str) -> ShaderError {
+        ShaderError {
+            stage: Some(stage),
+            info_log: message.to_string(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn link(info_log: &str) -> ShaderError {
+        ShaderError {
+            stage: None,
+            info_log: info_log.to_string(),
+            diagnostics: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.stage {
+            Some(stage) => try!(writeln!(f, "failed to compile {}:", stage)),
+            None        => try!(writeln!(f, "failed to link program:")),
+        }
+
+        if self.diagnostics.is_empty() {
+            return write!(f, "{}", self.info_log.trim_right());
+        }
+
+        for diagnostic in self.diagnostics.iter() {
+            try!(writeln!(f, "{}:{}: {}", diagnostic.file, diagnostic.line, diagnostic.message));
+
+            for &(line_num, ref text) in diagnostic.context.iter() {
+                let marker = if line_num == diagnostic.line { ">" } else { " " };
+                try!(writeln!(f, "{} {:4} | {}", marker, line_num, text));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum RendererError {
+    Shader(ShaderError),
+    /// A material file couldn't be read or parsed.
+    Material(String),
+    /// A texture couldn't be loaded or has a format the backend can't use.
+    Texture(String),
+    /// A buffer update didn't fit in the buffer.
+    Buffer(String),
+    /// A geometry or render target created by a different backend, or by
+    /// another renderer of the same one.
+    ForeignResource(String),
+    /// A geometry's vertex layout doesn't match the inputs of its shader.
+    VertexLayout(String),
+    /// A render target description the backend can't create.
+    RenderTarget(String),
+}
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RendererError::Shader(ref e)   => write!(f, "{}", e),
+            RendererError::Material(ref e) => write!(f, "{}", e),
+            RendererError::Texture(ref e)  => write!(f, "{}", e),
+            RendererError::Buffer(ref e)   => write!(f, "{}", e),
+            RendererError::ForeignResource(ref e) => write!(f, "{}", e),
+            RendererError::VertexLayout(ref e) => write!(f, "{}", e),
+            RendererError::RenderTarget(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ShaderError> for RendererError {
+    fn from(e: ShaderError) -> RendererError {
+        RendererError::Shader(e)
+    }
+}
+
+/// Pulls the line number out of an info log line. Every vendor formats these
+/// differently:
+///
+/// ```text
+/// 0(12) : error C0000: ...       NVIDIA
+/// 0:12(5): error: ...            Mesa
+/// ERROR: 0:12: ...               AMD, Intel on Windows
+/// ```
+fn parse_info_log_line_number(log_line: &str) -> Option<usize> {
+    let mut s = log_line.trim();
+
+    for prefix in ["ERROR:", "WARNING:"].iter() {
+        if s.starts_with(prefix) {
+            s = s[prefix.len()..].trim_left();
+        }
+    }
+
+    // Skip the source string number.
+    let digits = s.chars().take_while(|c| c.is_digit(10)).count();
+    if digits == 0 {
+        return None;
+    }
+    s = &s[digits..];
+
+    if !(s.starts_with(":") || s.starts_with("(")) {
+        return None;
+    }
+    s = &s[1..];
+
+    let digits = s.chars().take_while(|c| c.is_digit(10)).count();
+    if digits == 0 {
+        return None;
+    }
+
+    s[..digits].parse::<usize>().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use renderer::util::preprocessor::GlslPreprocessor;
+
+    #[test]
+    fn parses_line_numbers_of_every_vendor() {
+        assert_eq!(parse_info_log_line_number("0(12) : error C0000: syntax error"), Some(12));
+        assert_eq!(parse_info_log_line_number("0:12(5): error: `foo' undeclared"), Some(12));
+        assert_eq!(parse_info_log_line_number("ERROR: 0:12: 'foo' : undeclared identifier"), Some(12));
+        assert_eq!(parse_info_log_line_number("WARNING: 0:7: extension not supported"), Some(7));
+    }
+
+    #[test]
+    fn ignores_lines_without_line_numbers() {
+        assert_eq!(parse_info_log_line_number("ERROR: 1 compilation errors.  No code generated."), None);
+        assert_eq!(parse_info_log_line_number("Vertex info"), None);
+        assert_eq!(parse_info_log_line_number(""), None);
+    }
+
+    #[test]
+    fn maps_diagnostics_back_through_the_preprocessor() {
+        let defines = vec![("FOO".to_string(), "1".to_string())];
+        let src = GlslPreprocessor::new()
+            .preprocess("#version 400\nvoid main() {\n    foo;\n}\n", "shader.vert", &defines)
+            .unwrap();
+
+        // Line 4 of the preprocessed source is line 3 of the file, after the
+        // injected define.
+        let error = ShaderError::compile(ShaderStage::Vertex, "0(4) : error C1008: undefined variable \"foo\"\n", &src);
+
+        assert_eq!(error.diagnostics.len(), 1);
+        let diagnostic = &error.diagnostics[0];
+        assert_eq!(diagnostic.file, "shader.vert");
+        assert_eq!(diagnostic.line, 3);
+        // The define is from another file, so it isn't part of the context.
+        assert_eq!(diagnostic.context, vec![
+            (2, "void main() {".to_string()),
+            (3, "    foo;".to_string()),
+            (4, "}".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn displays_context_lines_with_a_marker() {
+        let src = GlslPreprocessor::new().preprocess("void main() {\n    foo;\n}\n", "shader.frag", &[]).unwrap();
+        let error = ShaderError::compile(ShaderStage::Fragment, "ERROR: 0:2: 'foo' : undeclared identifier", &src);
+
+        assert_eq!(error.to_string(), "failed to compile fragment shader:\n\
+                                       shader.frag:2: ERROR: 0:2: 'foo' : undeclared identifier\n\
+                                       \x20    1 | void main() {\n\
+                                       >    2 |     foo;\n\
+                                       \x20    3 | }\n");
+    }
+
+    #[test]
+    fn falls_back_to_the_info_log() {
+        let error = ShaderError::link("error: vertex shader lacks `main'\n");
+        assert_eq!(error.to_string(), "failed to link program:\nerror: vertex shader lacks `main'");
+    }
+}
@@ -1,16 +1,133 @@
 use super::vertex_layout::VertexLayoutDescription;
 use super::shader_params::ShaderParams;
+use super::render_state::RenderState;
 
+use std::any::Any;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PrimitiveTopology {
+    Points,
+    Lines,
+    LineStrip,
+    Triangles,
+    TriangleStrip,
+    TriangleFan,
+}
+
+/// Part of a geometry to draw, so that many meshes can share one big
+/// vertex and index buffer.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DrawRange {
+    /// First index, or first vertex for geometry without indices.
+    pub first: usize,
+    pub count: usize,
+    /// Added to every index before its vertex is fetched. Unused without
+    /// indices.
+    pub base_vertex: i32,
+}
+
+/// How a geometry's vertices are put together into primitives.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DrawState {
+    pub topology: PrimitiveTopology,
+    /// None draws every index, or every vertex without indices.
+    pub range: Option<DrawRange>,
+    /// Starts a new strip or fan at every index with the largest value of
+    /// the index type, 0xFFFF or 0xFFFFFFFF. Only used with indices.
+    pub primitive_restart: bool,
+}
+
+impl DrawState {
+    pub fn default() -> DrawState {
+        DrawState {
+            topology: PrimitiveTopology::Triangles,
+            range: None,
+            primitive_restart: false,
+        }
+    }
+
+    /// The first element and number of elements to draw out of `total`
+    /// indices, or vertices without indices.
+    pub fn resolve_range(&self, total: usize) -> Result<(usize, usize), String> {
+        match self.range {
+            Some(range) => {
+                if range.first > total || range.count > total - range.first {
+                    return Err(format!("draw range of {} from {} is outside of the {} elements", range.count, range.first, total));
+                }
+
+                Ok((range.first, range.count))
+            },
+            None => Ok((0, total)),
+        }
+    }
+}
+
+/// Dropping a geometry frees its buffers the next time the renderer clears
+/// or creates something.
 pub trait Geometry {
     fn get_vertex_layout_description(&self) -> &VertexLayoutDescription;
     fn get_params(&self) -> &ShaderParams;
     fn get_mut_params(&mut self) -> &mut ShaderParams;
+    fn get_render_state(&self) -> &RenderState;
+    fn get_mut_render_state(&mut self) -> &mut RenderState;
+    fn get_draw_state(&self) -> &DrawState;
+    fn get_mut_draw_state(&mut self) -> &mut DrawState;
+
+    /// Lets a backend get its own geometry type back, and refuse geometries
+    /// created by another backend.
+    fn as_any(&self) -> &Any;
+    fn as_any_mut(&mut self) -> &mut Any;
     
     /// Convenience function for updating the parameters using a closure.
     /// Example:
-    /// `geometry.update_params(&|params| { params.set("whatever", ParamValue::F32(1.0)) });
+    /// `geometry.update_params(&|params| { params.set("whatever", ParamValue::F32(1.0)).unwrap(); });`
     fn update_params(&mut self, closure: &Fn(&mut ShaderParams)) {
         let params = self.get_mut_params();
         closure(params);
     }
+
+    /// Same as `update_params`, but for the render state.
+    fn update_render_state(&mut self, closure: &Fn(&mut RenderState)) {
+        let state = self.get_mut_render_state();
+        closure(state);
+    }
+
+    /// Same as `update_params`, but for the draw state.
+    fn update_draw_state(&mut self, closure: &Fn(&mut DrawState)) {
+        let state = self.get_mut_draw_state();
+        closure(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_range(first: usize, count: usize) -> DrawState {
+        let mut state = DrawState::default();
+        state.range = Some(DrawRange {
+            first: first,
+            count: count,
+            base_vertex: 0,
+        });
+        state
+    }
+
+    #[test]
+    fn resolve_range_defaults_to_everything() {
+        assert_eq!(DrawState::default().resolve_range(6), Ok((0, 6)));
+    }
+
+    #[test]
+    fn resolve_range_allows_ranges_up_to_the_end() {
+        assert_eq!(with_range(2, 4).resolve_range(6), Ok((2, 4)));
+        assert_eq!(with_range(6, 0).resolve_range(6), Ok((6, 0)));
+    }
+
+    #[test]
+    fn resolve_range_rejects_ranges_past_the_end() {
+        assert!(with_range(4, 3).resolve_range(6).is_err());
+        assert!(with_range(7, 0).resolve_range(6).is_err());
+        assert!(with_range(1, usize::max_value()).resolve_range(6).is_err());
+    }
 }
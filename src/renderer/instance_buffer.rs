@@ -0,0 +1,55 @@
+use super::vertex_layout::VertexLayoutDescription;
+use super::buffer::BufferData;
+
+use std::any::Any;
+
+/// Per-instance attributes for `Renderer::draw_geometry_instanced`, such as
+/// a transform and a tint for every copy of a mesh. The layout's elements
+/// follow the geometry's own, and are advanced every `step_rate` instances.
+///
+/// Dropping an instance buffer frees it like a geometry.
+pub trait InstanceBuffer {
+    fn layout(&self) -> &VertexLayoutDescription;
+
+    /// How many elements the buffer holds. Up to `instance_count() *
+    /// layout().step_rate` instances can be drawn with it.
+    fn instance_count(&self) -> usize;
+
+    /// See `Geometry::as_any`.
+    fn as_any(&self) -> &Any;
+    fn as_any_mut(&mut self) -> &mut Any;
+}
+
+/// Number of elements in `data` for a per-instance `layout`.
+pub fn count_instances(layout: &VertexLayoutDescription, data: &BufferData) -> Result<usize, String> {
+    if !layout.is_per_instance() {
+        return Err("instance buffer layouts need a step rate".to_string());
+    }
+
+    if layout.stream_count() > 1 {
+        return Err("instance buffer layouts can only have one stream".to_string());
+    }
+
+    let stride = layout.stride();
+
+    if stride == 0 {
+        return Err("instance buffer layouts need at least one element".to_string());
+    }
+
+    if data.bytes.len() % stride != 0 {
+        return Err(format!("{} bytes of instance data isn't a whole number of {} byte instances", data.bytes.len(), stride));
+    }
+
+    Ok(data.bytes.len() / stride)
+}
+
+/// Checks that `count` instances can be drawn from `instances`.
+pub fn check_instance_count(instances: &InstanceBuffer, count: usize) -> Result<(), String> {
+    let available = instances.instance_count() * instances.layout().step_rate as usize;
+
+    if count > available {
+        return Err(format!("drawing {} instances, but the instance buffer only has data for {}", count, available));
+    }
+
+    Ok(())
+}
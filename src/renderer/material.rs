@@ -0,0 +1,685 @@
+use common::*;
+
+use super::Renderer;
+use super::shader_params::{ParamValue, ParamError, ShaderParams};
+use super::texture::{Texture, TextureOptions, SamplerDescription, FilteringMethod, WrapMode, TextureType, ColorSpace};
+use super::render_state::*;
+use super::error::RendererError;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Packed like `TextureParamHandle`, so a handle to a destroyed material is
+/// recognised even after its slot has been reused.
+pub type MaterialHandle = u64;
+
+const ERROR_VERT_SRC: &'static str = r#"
+#version 400
+
+uniform Matrices {
+    mat4 model_view_proj;
+};
+
+in vec3 position;
+
+void main() {
+    gl_Position = model_view_proj * vec4(position, 1.0);
+}
+"#;
+
+const ERROR_FRAG_SRC: &'static str = r#"
+#version 400
+
+out vec4 color;
+
+void main() {
+    color = vec4(1.0, 0.0, 1.0, 1.0);
+}
+"#;
+
+/// Everything needed to create a material on a renderer.
+#[derive(Clone, Debug)]
+pub struct MaterialDescription {
+    pub vert_src: String,
+    pub frag_src: String,
+    /// `#define`s injected into both sources. Materials with the same sources
+    /// and defines share a program on backends that compile them.
+    pub defines: Vec<(String, String)>,
+    /// Initial param values for every geometry created with the material.
+    /// Textures are given as params of their type, e.g.
+    /// `ParamValue::TextureCube`, holding the texture's `param_handle`.
+    pub params: Vec<(String, ParamValue)>,
+    /// Initial render state for every geometry created with the material.
+    pub render_state: RenderState,
+    /// Samplers to use instead of the textures' own, by sampler uniform name.
+    pub samplers: Vec<(String, SamplerDescription)>,
+}
+
+impl MaterialDescription {
+    pub fn new(vert_src: &str, frag_src: &str) -> MaterialDescription {
+        MaterialDescription {
+            vert_src: vert_src.to_string(),
+            frag_src: frag_src.to_string(),
+            defines: Vec::new(),
+            params: Vec::new(),
+            render_state: RenderState::default(),
+            samplers: Vec::new(),
+        }
+    }
+
+    /// A built-in flat magenta material, to draw with when a material fails
+    /// to load so the broken geometry is easy to spot.
+    pub fn error() -> MaterialDescription {
+        MaterialDescription::new(ERROR_VERT_SRC, ERROR_FRAG_SRC)
+    }
+
+    pub fn set_param(&mut self, name: &str, value: ParamValue) {
+        match self.params.iter().position(|&(ref n, _)| n == name) {
+            Some(i) => self.params[i].1 = value,
+            None    => self.params.push((name.to_string(), value)),
+        }
+    }
+
+    /// Samples whatever texture is given to the `name` sampler uniform with
+    /// `desc`, instead of the texture's own sampler.
+    pub fn set_sampler(&mut self, name: &str, desc: &SamplerDescription) {
+        match self.samplers.iter().position(|&(ref n, _)| n == name) {
+            Some(i) => self.samplers[i].1 = desc.clone(),
+            None    => self.samplers.push((name.to_string(), desc.clone())),
+        }
+    }
+
+    /// The sampler set for `name`, if any.
+    pub fn sampler(&self, name: &str) -> Option<&SamplerDescription> {
+        self.samplers.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref desc)| desc)
+    }
+
+    pub fn add_define(&mut self, name: &str, value: &str) {
+        match self.defines.iter().position(|&(ref n, _)| n == name) {
+            Some(i) => self.defines[i].1 = value.to_string(),
+            None    => self.defines.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    /// Sets the description's params on the reflected params of its program.
+    /// The compiler strips unused uniforms, so params the program doesn't
+    /// have are skipped rather than treated as an error.
+    pub fn apply_params(&self, params: &mut ShaderParams) {
+        for &(ref name, ref value) in self.params.iter() {
+            match params.set(name, value.clone()) {
+                Ok(()) | Err(ParamError::UnknownParam(_)) => (),
+                Err(e) => println!("Warning: material param {}", e),
+            }
+        }
+    }
+}
+
+/// A material created on a renderer, along with the textures it uses so that
+/// they live as long as it does.
+pub struct Material {
+    handle: MaterialHandle,
+    textures: Vec<Box<Texture>>,
+    /// Vertex and fragment shader files, when loaded from a file.
+    shader_paths: Option<(PathBuf, PathBuf)>,
+}
+
+impl Material {
+    pub fn new(handle: MaterialHandle, textures: Vec<Box<Texture>>) -> Material {
+        Material {
+            handle: handle,
+            textures: textures,
+            shader_paths: None,
+        }
+    }
+
+    pub fn handle(&self) -> MaterialHandle {
+        self.handle
+    }
+
+    pub fn textures(&self) -> &Vec<Box<Texture>> {
+        &self.textures
+    }
+
+    pub fn shader_paths(&self) -> Option<(&Path, &Path)> {
+        self.shader_paths.as_ref().map(|&(ref vert_path, ref frag_path)| (vert_path.as_path(), frag_path.as_path()))
+    }
+
+    /// Reads the shader files again and recompiles the material with them.
+    pub fn reload_shaders(&self, renderer: &mut Box<Renderer>) -> Result<(), RendererError> {
+        let (vert_path, frag_path) = match self.shader_paths() {
+            Some(x) => x,
+            None    => return Ok(()),
+        };
+
+        let vert_src = try!(read_source(vert_path).map_err(RendererError::Material));
+        let frag_src = try!(read_source(frag_path).map_err(RendererError::Material));
+
+        renderer.reload_material(self.handle, &vert_src, &frag_src)
+    }
+
+    /// Loads a material file. Each line is a `key = value` pair, and blank
+    /// lines or lines starting with `#` are ignored. Paths are relative to
+    /// the material file.
+    ///
+    /// ```text
+    /// vertex_shader = ../shaders/textured.vert
+    /// fragment_shader = ../shaders/textured.frag
+    /// texture.tex = ../test.bmp
+    /// texture.albedo = ../albedo.png
+    /// texture.albedo.color_space = srgb
+    /// texture_cube.sky = sky_px.png sky_nx.png sky_py.png sky_ny.png sky_pz.png sky_nz.png
+    /// texture_cube.env = environment.png
+    /// texture_array.layers = grass.png rock.png sand.png
+    /// sampler.tex.filter = anisotropic
+    /// sampler.tex.wrap = clamp_to_edge
+    /// param.tint = vec4 1.0 0.5 0.5 1.0
+    /// define.USE_FOG = 1
+    /// blend = alpha
+    /// cull = back
+    /// ```
+    pub fn load_from_file(renderer: &mut Box<Renderer>, path: &Path) -> Result<Material, RendererError> {
+        let f = try!(File::open(path).map_err(|e| RendererError::Material(format!("Failed to open material {}: {}", path.display(), e))));
+        let reader = BufReader::new(&f);
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        let mut desc = MaterialDescription::new("", "");
+        let mut textures: Vec<Box<Texture>> = Vec::new();
+        let mut vert_path = PathBuf::new();
+        let mut frag_path = PathBuf::new();
+        let mut pending_textures: Vec<(usize, String, String)> = Vec::new();
+        let mut color_spaces: HashMap<String, ColorSpace> = HashMap::new();
+
+        for (line_num, line) in reader.lines().enumerate() {
+            let line = try!(line.map_err(|e| RendererError::Material(e.to_string())));
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            let tokens: Vec<&str> = line.splitn(2, "=").collect();
+
+            if tokens.len() != 2 {
+                return Err(RendererError::Material(format!("{}:{}: expected key = value", path.display(), line_num + 1)));
+            }
+
+            let key = tokens[0].trim();
+            let value = tokens[1].trim();
+
+            let result = match key {
+                "vertex_shader" => {
+                    vert_path = dir.join(value);
+                    read_source(&vert_path).map(|src| desc.vert_src = src)
+                },
+                "fragment_shader" => {
+                    frag_path = dir.join(value);
+                    read_source(&frag_path).map(|src| desc.frag_src = src)
+                },
+                // texture.<name>.color_space = srgb, textures are linear otherwise.
+                _ if is_texture_key(key) && key.ends_with(".color_space") => {
+                    let name = &key[(key.find('.').unwrap() + 1)..(key.len() - ".color_space".len())];
+                    parse_color_space(value).map(|color_space| { color_spaces.insert(name.to_string(), color_space); })
+                },
+                // Loaded once every key is read, so that settings may come
+                // after the texture.
+                _ if is_texture_key(key) => {
+                    pending_textures.push((line_num, key.to_string(), value.to_string()));
+                    Ok(())
+                },
+                _ if key.starts_with("define.") => {
+                    desc.add_define(&key["define.".len()..], value);
+                    Ok(())
+                },
+                _ if key.starts_with("param.") => {
+                    parse_param_value(value).map(|param_value| desc.set_param(&key["param.".len()..], param_value))
+                },
+                // sampler.<uniform>.<setting> = value
+                _ if key.starts_with("sampler.") => {
+                    let rest = &key["sampler.".len()..];
+                    match rest.rfind('.') {
+                        Some(i) => {
+                            let name = &rest[..i];
+                            let mut sampler = desc.sampler(name).cloned().unwrap_or(SamplerDescription::default());
+                            parse_sampler_setting(&mut sampler, &rest[(i + 1)..], value).map(|_| desc.set_sampler(name, &sampler))
+                        },
+                        None => Err(format!("expected sampler.<name>.<setting>, got {}", key)),
+                    }
+                },
+                _ => parse_render_state(&mut desc.render_state, key, value),
+            };
+
+            if let Err(e) = result {
+                return Err(RendererError::Material(format!("{}:{}: {}", path.display(), line_num + 1, e)));
+            }
+        }
+
+        for (line_num, key, value) in pending_textures {
+            let i = key.find('.').unwrap();
+            let name = &key[(i + 1)..];
+
+            let mut options = TextureOptions::default();
+            if let Some(&color_space) = color_spaces.get(name) {
+                options.color_space = color_space;
+            }
+
+            // Space separated layers, or a single equirectangular image for cube maps.
+            let paths: Vec<PathBuf> = value.split_whitespace().map(|p| dir.join(p)).collect();
+            let path_refs: Vec<&Path> = paths.iter().map(|p| p.as_path()).collect();
+
+            let result = match &key[..i] {
+                "texture"                          => renderer.load_texture(&dir.join(&value), &options),
+                "texture_cube" if paths.len() == 1 => renderer.load_cube_texture_from_equirectangular(&paths[0], &options),
+                "texture_cube"                     => renderer.load_texture_layers(TextureType::Cube, &path_refs, &options),
+                "texture_array"                    => renderer.load_texture_layers(TextureType::Array2D, &path_refs, &options),
+                _                                  => renderer.load_texture_layers(TextureType::Texture3D, &path_refs, &options),
+            };
+
+            let texture = try!(result.map_err(|e| RendererError::Material(format!("{}:{}: {}", path.display(), line_num + 1, e))));
+
+            let handle = texture.param_handle();
+            let param_value = match texture.texture_type() {
+                TextureType::Texture2D => ParamValue::Texture2D(handle),
+                TextureType::Cube      => ParamValue::TextureCube(handle),
+                TextureType::Array2D   => ParamValue::Texture2DArray(handle),
+                TextureType::Texture3D => ParamValue::Texture3D(handle),
+            };
+            desc.set_param(name, param_value);
+            textures.push(texture);
+        }
+
+        if desc.vert_src.is_empty() || desc.frag_src.is_empty() {
+            return Err(RendererError::Material(format!("{}: both vertex_shader and fragment_shader must be given", path.display())));
+        }
+
+        let handle = try!(renderer.create_material(&desc));
+
+        let mut material = Material::new(handle, textures);
+        material.shader_paths = Some((vert_path, frag_path));
+
+        Ok(material)
+    }
+}
+
+fn read_source(path: &Path) -> Result<String, String> {
+    let mut f = try!(File::open(path).map_err(|e| format!("failed to open {}: {}", path.display(), e)));
+    let mut src = String::new();
+    try!(f.read_to_string(&mut src).map_err(|e| format!("failed to read {}: {}", path.display(), e)));
+    Ok(src)
+}
+
+/// Parses `<glsl type> <components...>`, e.g. `vec4 1.0 0.0 0.0 1.0`.
+/// Matrices are given in column major order, bools as `true` or `false`.
+fn parse_param_value(value: &str) -> Result<ParamValue, String> {
+    let mut tokens = value.split_whitespace();
+
+    let ptype = match tokens.next() {
+        Some(x) => x,
+        None    => return Err("missing param type".to_string()),
+    };
+
+    let components: Vec<&str> = tokens.collect();
+
+    let expected = match ptype {
+        "float" | "int" | "uint" | "bool"   => 1,
+        "vec2" | "ivec2" | "uvec2" | "bvec2" => 2,
+        "vec3" | "ivec3" | "uvec3" | "bvec3" => 3,
+        "vec4" | "ivec4" | "uvec4" | "bvec4" | "mat2" => 4,
+        "mat2x3" | "mat3x2" => 6,
+        "mat2x4" | "mat4x2" => 8,
+        "mat3" => 9,
+        "mat3x4" | "mat4x3" => 12,
+        "mat4" => 16,
+        _ => return Err(format!("unsupported param type {}", ptype)),
+    };
+
+    if components.len() != expected {
+        return Err(format!("{} needs {} components, got {}", ptype, expected, components.len()));
+    }
+
+    fn parse_all<T: FromStr>(components: &Vec<&str>) -> Result<Vec<T>, String> {
+        let mut result = Vec::with_capacity(components.len());
+        for component in components.iter() {
+            match component.parse::<T>() {
+                Ok(x)  => result.push(x),
+                Err(_) => return Err(format!("invalid value {}", component)),
+            }
+        }
+        Ok(result)
+    }
+
+    if ptype.starts_with("int") || ptype.starts_with("ivec") {
+        let c: Vec<i32> = try!(parse_all(&components));
+        return Ok(match ptype {
+            "int"   => ParamValue::I32(c[0]),
+            "ivec2" => ParamValue::IVec2(Vec2i::new(c[0], c[1])),
+            "ivec3" => ParamValue::IVec3(Vec3i::new(c[0], c[1], c[2])),
+            _       => ParamValue::IVec4(Vec4i::new(c[0], c[1], c[2], c[3])),
+        });
+    }
+
+    if ptype.starts_with("uint") || ptype.starts_with("uvec") {
+        let c: Vec<u32> = try!(parse_all(&components));
+        return Ok(match ptype {
+            "uint"  => ParamValue::U32(c[0]),
+            "uvec2" => ParamValue::UVec2(Vec2u::new(c[0], c[1])),
+            "uvec3" => ParamValue::UVec3(Vec3u::new(c[0], c[1], c[2])),
+            _       => ParamValue::UVec4(Vec4u::new(c[0], c[1], c[2], c[3])),
+        });
+    }
+
+    if ptype.starts_with("bool") || ptype.starts_with("bvec") {
+        let c: Vec<bool> = try!(parse_all(&components));
+        return Ok(match ptype {
+            "bool"  => ParamValue::Bool(c[0]),
+            "bvec2" => ParamValue::BVec2([c[0], c[1]]),
+            "bvec3" => ParamValue::BVec3([c[0], c[1], c[2]]),
+            _       => ParamValue::BVec4([c[0], c[1], c[2], c[3]]),
+        });
+    }
+
+    let c: Vec<f32> = try!(parse_all(&components));
+
+    let v2 = |i: usize| Vec2f::new(c[i], c[i + 1]);
+    let v3 = |i: usize| Vec3f::new(c[i], c[i + 1], c[i + 2]);
+    let v4 = |i: usize| Vec4f::new(c[i], c[i + 1], c[i + 2], c[i + 3]);
+
+    Ok(match ptype {
+        "float"  => ParamValue::F32(c[0]),
+        "vec2"   => ParamValue::Vec2(v2(0)),
+        "vec3"   => ParamValue::Vec3(v3(0)),
+        "vec4"   => ParamValue::Vec4(v4(0)),
+        "mat2"   => ParamValue::Mat2(Mat2f::from_cols(v2(0), v2(2))),
+        "mat3"   => ParamValue::Mat3(Mat3f::from_cols(v3(0), v3(3), v3(6))),
+        "mat4"   => ParamValue::Mat4(Mat4f::from_cols(v4(0), v4(4), v4(8), v4(12))),
+        "mat2x3" => ParamValue::Mat2x3([v3(0), v3(3)]),
+        "mat2x4" => ParamValue::Mat2x4([v4(0), v4(4)]),
+        "mat3x2" => ParamValue::Mat3x2([v2(0), v2(2), v2(4)]),
+        "mat3x4" => ParamValue::Mat3x4([v4(0), v4(4), v4(8)]),
+        "mat4x2" => ParamValue::Mat4x2([v2(0), v2(2), v2(4), v2(6)]),
+        _        => ParamValue::Mat4x3([v3(0), v3(3), v3(6), v3(9)]),
+    })
+}
+
+fn parse_sampler_setting(sampler: &mut SamplerDescription, setting: &str, value: &str) -> Result<(), String> {
+    fn parse_f32(value: &str) -> Result<f32, String> {
+        value.parse::<f32>().map_err(|_| format!("invalid value {}", value))
+    }
+
+    match setting {
+        "filter" => {
+            sampler.filtering = match value {
+                "nearest"     => FilteringMethod::Nearest,
+                "bilinear"    => FilteringMethod::BiLinear,
+                "trilinear"   => FilteringMethod::TriLinear,
+                "anisotropic" => FilteringMethod::Anisotropic,
+                _             => return Err(format!("unknown filtering method {}", value)),
+            };
+        },
+        "wrap"   => sampler.set_wrap(try!(parse_wrap_mode(value))),
+        "wrap_s" => sampler.wrap_s = try!(parse_wrap_mode(value)),
+        "wrap_t" => sampler.wrap_t = try!(parse_wrap_mode(value)),
+        "wrap_r" => sampler.wrap_r = try!(parse_wrap_mode(value)),
+        "border_color" => {
+            sampler.border_color = match try!(parse_param_value(&format!("vec4 {}", value))) {
+                ParamValue::Vec4(color) => color,
+                _                       => unreachable!(),
+            };
+        },
+        "lod_bias"   => sampler.lod_bias = try!(parse_f32(value)),
+        "anisotropy" => sampler.max_anisotropy = try!(parse_f32(value)),
+        "compare"    => sampler.compare_func = Some(try!(parse_compare_func(value))),
+        _ => return Err(format!("unknown sampler setting {}", setting)),
+    }
+
+    Ok(())
+}
+
+fn is_texture_key(key: &str) -> bool {
+    ["texture.", "texture_cube.", "texture_array.", "texture_3d."].iter().any(|prefix| key.starts_with(prefix))
+}
+
+fn parse_color_space(value: &str) -> Result<ColorSpace, String> {
+    match value {
+        "srgb"   => Ok(ColorSpace::SRGB),
+        "linear" => Ok(ColorSpace::Linear),
+        _        => Err(format!("unknown color space {}", value)),
+    }
+}
+
+fn parse_wrap_mode(value: &str) -> Result<WrapMode, String> {
+    match value {
+        "repeat"          => Ok(WrapMode::Repeat),
+        "mirrored_repeat" => Ok(WrapMode::MirroredRepeat),
+        "clamp_to_edge"   => Ok(WrapMode::ClampToEdge),
+        "clamp_to_border" => Ok(WrapMode::ClampToBorder),
+        _                 => Err(format!("unknown wrap mode {}", value)),
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true"  => Ok(true),
+        "false" => Ok(false),
+        _       => Err(format!("expected true or false, got {}", value)),
+    }
+}
+
+fn parse_compare_func(value: &str) -> Result<CompareFunc, String> {
+    match value {
+        "never"         => Ok(CompareFunc::Never),
+        "less"          => Ok(CompareFunc::Less),
+        "equal"         => Ok(CompareFunc::Equal),
+        "less_equal"    => Ok(CompareFunc::LessEqual),
+        "greater"       => Ok(CompareFunc::Greater),
+        "not_equal"     => Ok(CompareFunc::NotEqual),
+        "greater_equal" => Ok(CompareFunc::GreaterEqual),
+        "always"        => Ok(CompareFunc::Always),
+        _               => Err(format!("unknown compare function {}", value)),
+    }
+}
+
+fn parse_render_state(state: &mut RenderState, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "blend" => {
+            state.blend = match value {
+                "none"     => BlendState::default(),
+                "alpha"    => BlendState::alpha(),
+                "additive" => BlendState::additive(),
+                _          => return Err(format!("unknown blend mode {}", value)),
+            };
+        },
+        "cull" => {
+            state.rasterizer.cull_mode = match value {
+                "none"           => CullMode::None,
+                "front"          => CullMode::Front,
+                "back"           => CullMode::Back,
+                "front_and_back" => CullMode::FrontAndBack,
+                _                => return Err(format!("unknown cull mode {}", value)),
+            };
+        },
+        "front_face" => {
+            state.rasterizer.front_face = match value {
+                "ccw" => FrontFace::CounterClockwise,
+                "cw"  => FrontFace::Clockwise,
+                _     => return Err(format!("unknown front face {}", value)),
+            };
+        },
+        "polygon_mode" => {
+            state.rasterizer.polygon_mode = match value {
+                "fill"  => PolygonMode::Fill,
+                "line"  => PolygonMode::Line,
+                "point" => PolygonMode::Point,
+                _       => return Err(format!("unknown polygon mode {}", value)),
+            };
+        },
+        "depth_test" => state.depth_stencil.depth_test = try!(parse_bool(value)),
+        "depth_write" => state.depth_stencil.depth_write = try!(parse_bool(value)),
+        "depth_compare" => state.depth_stencil.depth_compare = try!(parse_compare_func(value)),
+        _ => return Err(format!("unknown material key {}", key)),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use renderer::backends::trace::{TraceRenderer, TraceCommand};
+    use std::env;
+    use std::fs;
+
+    const VERT_SRC: &'static str = "#version 400
+uniform mat4 model_view_proj;
+in vec3 position;
+void main() {
+    gl_Position = model_view_proj * vec4(position, 1.0);
+}
+";
+
+    const FRAG_SRC: &'static str = "#version 400
+uniform vec4 tint;
+uniform sampler2D albedo;
+uniform sampler2D normals;
+uniform sampler2D mask;
+out vec4 color;
+void main() {
+    color = tint * texture(albedo, vec2(0.0)) * texture(normals, vec2(0.0)) * texture(mask, vec2(0.0));
+}
+";
+
+    /// A 1x1 white RGBA image.
+    const PIXEL_PNG: [u8; 68] = [
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+        0x89, 0x00, 0x00, 0x00, 0x0b, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0xf8, 0x0f, 0x04, 0x00,
+        0x09, 0xfb, 0x03, 0xfd, 0x68, 0xfa, 0x1c, 0xcc, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44,
+        0xae, 0x42, 0x60, 0x82,
+    ];
+
+    /// An empty directory with the shaders and an image, for a test's
+    /// material file.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rusto_material_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        write_file(&dir, "test.vert", VERT_SRC.as_bytes());
+        write_file(&dir, "test.frag", FRAG_SRC.as_bytes());
+        write_file(&dir, "pixel.png", &PIXEL_PNG);
+        dir
+    }
+
+    fn write_file(dir: &Path, name: &str, contents: &[u8]) {
+        let mut f = File::create(dir.join(name)).unwrap();
+        f.write_all(contents).unwrap();
+    }
+
+    /// Loads `contents` as a material file with the trace renderer, along
+    /// with the description the material was created from.
+    fn load(name: &str, contents: &str) -> Result<(Material, MaterialDescription), RendererError> {
+        let dir = test_dir(name);
+        write_file(&dir, "test.mat", contents.as_bytes());
+
+        let trace = TraceRenderer::new();
+        let log = trace.log();
+        let mut renderer: Box<Renderer> = trace;
+
+        let material = try!(Material::load_from_file(&mut renderer, &dir.join("test.mat")));
+
+        let desc = log.borrow().iter().filter_map(|command| match *command {
+            TraceCommand::CreateMaterial { ref desc, .. } => Some(desc.clone()),
+            _                                              => None,
+        }).next().unwrap();
+
+        Ok((material, desc))
+    }
+
+    fn load_error(name: &str, contents: &str) -> String {
+        match load(name, contents) {
+            Ok(_)  => panic!("loaded an invalid material"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn loads_every_kind_of_key() {
+        let (material, desc) = load("keys", "# A comment, then a blank line.
+
+vertex_shader = test.vert
+fragment_shader = test.frag
+param.tint = vec4 1.0 0.5 0.25 1.0
+define.USE_FOG = 1
+sampler.albedo.filter = nearest
+sampler.albedo.wrap = clamp_to_edge
+blend = alpha
+cull = front
+depth_write = false
+depth_compare = less_equal
+").unwrap();
+
+        assert_eq!(desc.vert_src, VERT_SRC);
+        assert_eq!(desc.frag_src, FRAG_SRC);
+        assert_eq!(desc.params, vec![("tint".to_string(), ParamValue::Vec4(Vec4f::new(1.0, 0.5, 0.25, 1.0)))]);
+        assert_eq!(desc.defines, vec![("USE_FOG".to_string(), "1".to_string())]);
+
+        let mut sampler = SamplerDescription::default();
+        sampler.filtering = FilteringMethod::Nearest;
+        sampler.set_wrap(WrapMode::ClampToEdge);
+        assert_eq!(desc.sampler("albedo"), Some(&sampler));
+
+        let mut render_state = RenderState::default();
+        render_state.blend = BlendState::alpha();
+        render_state.rasterizer.cull_mode = CullMode::Front;
+        render_state.depth_stencil.depth_write = false;
+        render_state.depth_stencil.depth_compare = CompareFunc::LessEqual;
+        assert_eq!(desc.render_state, render_state);
+
+        let (vert_path, frag_path) = material.shader_paths().unwrap();
+        assert!(vert_path.ends_with("test.vert"));
+        assert!(frag_path.ends_with("test.frag"));
+    }
+
+    #[test]
+    fn color_spaces_apply_wherever_they_are_given() {
+        let (material, desc) = load("color_spaces", "texture.normals.color_space = srgb
+texture.albedo = pixel.png
+texture.normals = pixel.png
+texture.mask = pixel.png
+texture.albedo.color_space = srgb
+vertex_shader = test.vert
+fragment_shader = test.frag
+").unwrap();
+
+        let textures = material.textures();
+        assert_eq!(textures.len(), 3);
+        assert_eq!(textures[0].color_space(), ColorSpace::SRGB);
+        assert_eq!(textures[1].color_space(), ColorSpace::SRGB);
+        assert_eq!(textures[2].color_space(), ColorSpace::Linear);
+
+        let texture_params: Vec<(String, ParamValue)> = ["albedo", "normals", "mask"].iter().zip(textures.iter())
+            .map(|(name, texture)| (name.to_string(), ParamValue::Texture2D(texture.param_handle())))
+            .collect();
+        assert_eq!(desc.params, texture_params);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let shaders = "vertex_shader = test.vert\nfragment_shader = test.frag\n";
+
+        assert!(load_error("no_equals", &format!("{}\nblend alpha\n", shaders)).contains("test.mat:4: expected key = value"));
+        assert!(load_error("unknown_key", &format!("{}cull = back\nshininess = 1\n", shaders)).contains("test.mat:4: unknown material key shininess"));
+        assert!(load_error("bad_param", &format!("param.tint = vec4 1.0\n{}", shaders)).contains("test.mat:1: vec4 needs 4 components, got 1"));
+        assert!(load_error("bad_sampler", &format!("{}sampler.albedo = nearest\n", shaders)).contains("test.mat:3: expected sampler.<name>.<setting>"));
+        assert!(load_error("bad_color_space", &format!("texture.albedo = pixel.png\ntexture.albedo.color_space = gamma\n{}", shaders)).contains("test.mat:2: unknown color space gamma"));
+
+        // Textures load after every other key, but keep their own line.
+        assert!(load_error("missing_texture", &format!("texture.albedo = missing.png\n{}", shaders)).contains("test.mat:1: "));
+
+        assert!(load_error("no_shaders", "cull = back\n").contains("both vertex_shader and fragment_shader must be given"));
+    }
+}
@@ -1,30 +1,249 @@
 pub mod vertex_layout;
 pub mod buffer;
 pub mod geometry;
+pub mod instance_buffer;
 pub mod texture;
 pub mod shader_params;
+pub mod shared_data;
+pub mod render_target;
+pub mod render_state;
+pub mod material;
+pub mod error;
 pub mod util;
 
 pub use self::vertex_layout::*;
 pub use self::buffer::*;
 pub use self::geometry::*;
+pub use self::instance_buffer::*;
 pub use self::texture::*;
 pub use self::shader_params::*;
+pub use self::shared_data::*;
+pub use self::render_target::*;
+pub use self::render_state::*;
+pub use self::material::*;
+pub use self::error::*;
 
 use image::DynamicImage;
 
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum IndexType {
     U16,
     U32,
+    /// No index buffer, vertices are drawn in order. The index data passed
+    /// along with it is ignored.
+    NoIndices,
+}
+
+impl IndexType {
+    pub fn size(&self) -> usize {
+        match *self {
+            IndexType::U16       => 2,
+            IndexType::U32       => 4,
+            IndexType::NoIndices => 0,
+        }
+    }
+
+    /// Number of indices in `data`.
+    pub fn count(&self, data: &BufferData) -> usize {
+        match self.size() {
+            0    => 0,
+            size => data.bytes.len() / size,
+        }
+    }
+
+    /// Bytes taken by the whole indices in `len` bytes of index data, which
+    /// is how much of it `Renderer::update_index_data` may write to.
+    /// Trailing bytes of a partial index don't count.
+    pub fn used_size(&self, len: usize) -> usize {
+        match self.size() {
+            0    => 0,
+            size => len / size * size,
+        }
+    }
+
+    /// The index that restarts primitives when `DrawState::primitive_restart`
+    /// is set.
+    pub fn restart_index(&self) -> u32 {
+        match *self {
+            IndexType::U16       => 0xFFFF,
+            IndexType::U32       => 0xFFFFFFFF,
+            IndexType::NoIndices => 0,
+        }
+    }
 }
 
 pub trait Renderer {
     fn clear(&mut self, r: f32, g: f32, b: f32, a: f32);
+    fn clear_depth(&mut self, depth: f32);
+    fn clear_stencil(&mut self, stencil: i32);
+
+    /// Uploads texture data as is, `options.flip_vertically` is only
+    /// applied by the loading functions below.
+    ///
+    /// 2D textures take one layer, cube maps six faces in +X, -X, +Y, -Y,
+    /// +Z, -Z order, arrays one layer per element and 3D textures one layer
+    /// per slice. Every layer must have the same size and format.
+    fn create_texture_layers(&mut self, texture_type: TextureType, layers: &[&TextureData], options: &TextureOptions) -> Result<Box<Texture>, RendererError>;
+
+    fn create_texture(&mut self, data: &TextureData, options: &TextureOptions) -> Result<Box<Texture>, RendererError> {
+        self.create_texture_layers(TextureType::Texture2D, &[data], options)
+    }
+
+    fn create_texture_from_image(&mut self, image_data: &DynamicImage, options: &TextureOptions) -> Result<Box<Texture>, RendererError> {
+        let mut data = util::texture_loader::texture_data_from_image(image_data);
+
+        if options.flip_vertically {
+            data.flip_vertically();
+        }
+
+        self.create_texture(&data, options)
+    }
+
+    /// Builds a cube map out of an equirectangular image, with faces of
+    /// `face_size` texels.
+    fn create_cube_texture_from_equirectangular(&mut self, data: &TextureData, face_size: u32, options: &TextureOptions) -> Result<Box<Texture>, RendererError> {
+        try!(TextureType::Texture2D.validate_layers(&[data]).map_err(RendererError::Texture));
+
+        if data.width == 0 || data.height == 0 {
+            return Err(RendererError::Texture(format!("equirectangular image is {}x{}", data.width, data.height)));
+        }
+
+        if face_size == 0 {
+            return Err(RendererError::Texture("cube faces must be at least 1x1".to_string()));
+        }
+
+        let faces = util::texture_loader::equirectangular_to_cube_faces(data, face_size);
+        let face_refs: Vec<&TextureData> = faces.iter().collect();
+        self.create_texture_layers(TextureType::Cube, &face_refs, options)
+    }
+
+    /// Loads a texture from an image file in any format the image crate
+    /// reads, keeping 16-bit precision for PNG and TIFF files.
+    fn load_texture(&mut self, path: &Path, options: &TextureOptions) -> Result<Box<Texture>, RendererError> {
+        let mut data = try!(util::texture_loader::load_texture_data(path).map_err(RendererError::Texture));
+
+        if options.flip_vertically {
+            data.flip_vertically();
+        }
+
+        self.create_texture(&data, options)
+    }
+
+    /// Loads each layer of a texture from its own file, see
+    /// `create_texture_layers` for the order.
+    fn load_texture_layers(&mut self, texture_type: TextureType, paths: &[&Path], options: &TextureOptions) -> Result<Box<Texture>, RendererError> {
+        let mut layers = Vec::with_capacity(paths.len());
+
+        for path in paths {
+            let mut data = try!(util::texture_loader::load_texture_data(path).map_err(RendererError::Texture));
+
+            if options.flip_vertically {
+                data.flip_vertically();
+            }
+
+            layers.push(data);
+        }
+
+        let layer_refs: Vec<&TextureData> = layers.iter().collect();
+        self.create_texture_layers(texture_type, &layer_refs, options)
+    }
+
+    /// Loads an equirectangular image as a cube map with faces half as tall
+    /// as the image.
+    fn load_cube_texture_from_equirectangular(&mut self, path: &Path, options: &TextureOptions) -> Result<Box<Texture>, RendererError> {
+        let mut data = try!(util::texture_loader::load_texture_data(path).map_err(RendererError::Texture));
+
+        if options.flip_vertically {
+            data.flip_vertically();
+        }
+
+        let face_size = (data.height / 2).max(1);
+        self.create_cube_texture_from_equirectangular(&data, face_size, options)
+    }
+
+    fn create_render_target(&mut self, desc: &RenderTargetDescription) -> Result<Box<RenderTarget>, RendererError>;
+
+    /// Redirects subsequent clears and draws into `target`. Passing `None`
+    /// goes back to drawing into the window.
+    fn bind_render_target(&mut self, target: Option<&RenderTarget>) -> Result<(), RendererError>;
+
+    /// Adds a directory to look in for `#include`d shader files, after the
+    /// directory of the including file.
+    fn add_shader_search_path(&mut self, path: &Path);
+
+    /// Compiles the material's shaders. The returned handle can be shared
+    /// by any number of geometries.
+    fn create_material(&mut self, desc: &MaterialDescription) -> Result<MaterialHandle, RendererError>;
+
+    /// Recompiles the material with new shader sources. Geometries using it
+    /// switch over on their next draw, keeping param values that still exist.
+    /// On failure the material carries on with its previous shaders. Fails
+    /// if the material has been destroyed.
+    fn reload_material(&mut self, material: MaterialHandle, vert_src: &str, frag_src: &str) -> Result<(), RendererError>;
+
+    /// Files `#include`d by the material's current shaders, so they can be
+    /// watched for changes along with the shaders themselves. Empty once the
+    /// material has been destroyed.
+    fn material_includes(&self, material: MaterialHandle) -> Vec<PathBuf>;
+
+    /// Geometries already created with the material are unaffected. Fails
+    /// if the material has already been destroyed.
+    fn destroy_material(&mut self, material: MaterialHandle) -> Result<(), RendererError>;
+
+    /// Sets the `FrameData` block seen by every program.
+    fn set_frame_data(&mut self, data: &FrameData);
+
+    /// Sets the `ViewData` block seen by every program, normally from the
+    /// camera before drawing anything with it.
+    fn set_view_data(&mut self, data: &ViewData);
+
+    fn create_geometry(&mut self, vertex_data: &BufferData, index_data: &BufferData, layout_desc: &VertexLayoutDescription, index_type: IndexType, material: MaterialHandle) -> Result<Box<Geometry>, RendererError> {
+        self.create_geometry_with_usage(vertex_data, index_data, layout_desc, index_type, BufferUsage::Static, material)
+    }
+
+    /// Like `create_geometry`, with buffers meant to be changed afterwards
+    /// through the functions below. The data may start out empty. Fails if
+    /// the material has been destroyed.
+    fn create_geometry_with_usage(&mut self, vertex_data: &BufferData, index_data: &BufferData, layout_desc: &VertexLayoutDescription, index_type: IndexType, usage: BufferUsage, material: MaterialHandle) -> Result<Box<Geometry>, RendererError>;
+
+    /// Overwrites part of the geometry's vertex data, starting `offset` bytes
+    /// in. Fails if the data runs past the end of the buffer.
+    fn update_vertex_data(&mut self, geom: &mut Box<Geometry>, offset: usize, data: &BufferData) -> Result<(), RendererError>;
+
+    /// Same as `update_vertex_data` for the index buffer. The number of
+    /// indices drawn doesn't change.
+    fn update_index_data(&mut self, geom: &mut Box<Geometry>, offset: usize, data: &BufferData) -> Result<(), RendererError>;
+
+    /// Replaces all of the geometry's vertex data, resizing the buffer if
+    /// needed.
+    fn set_vertex_data(&mut self, geom: &mut Box<Geometry>, data: &BufferData) -> Result<(), RendererError>;
+
+    /// Replaces all of the geometry's indices, after which it draws every
+    /// index in `data`. Fails for geometry created with
+    /// `IndexType::NoIndices`.
+    fn set_index_data(&mut self, geom: &mut Box<Geometry>, data: &BufferData) -> Result<(), RendererError>;
+
+    /// Fails if the geometry was created by a different renderer, as do the
+    /// other functions taking geometries or render targets. Also fails if
+    /// the vertex shader has inputs the layout doesn't feed, see
+    /// `check_vertex_inputs`.
+    fn draw_geometry(&mut self, geom: &mut Box<Geometry>) -> Result<(), RendererError>;
+
+    /// Creates a buffer of per-instance attributes. `layout` needs a step
+    /// rate, and `data` a whole number of its elements.
+    fn create_instance_buffer(&mut self, data: &BufferData, layout: &VertexLayoutDescription, usage: BufferUsage) -> Result<Box<InstanceBuffer>, RendererError>;
 
-    fn create_texture_from_image(&mut self, image_data: &DynamicImage) -> Box<Texture>;
+    /// Replaces all of the instance data, resizing the buffer if needed.
+    fn set_instance_data(&mut self, instances: &mut Box<InstanceBuffer>, data: &BufferData) -> Result<(), RendererError>;
 
-    fn create_geometry(&mut self, vertex_data: &BufferData, index_data: &BufferData, layout_desc: &VertexLayoutDescription, index_type: IndexType, vert_src: &str, frag_src: &str) -> Box<Geometry>;
-    fn draw_geometry(&mut self, geom: &mut Box<Geometry>);
+    /// Draws `count` copies of the geometry in one call, feeding the
+    /// instance buffer's attributes to the vertex shader along with the
+    /// geometry's own. Fails if the buffer doesn't have data for `count`
+    /// instances, or if the two layouts between them don't feed every
+    /// input of the vertex shader.
+    fn draw_geometry_instanced(&mut self, geom: &mut Box<Geometry>, instances: &InstanceBuffer, count: usize) -> Result<(), RendererError>;
 }
 
 pub mod backends;
@@ -0,0 +1,252 @@
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CompareFunc {
+    Never,
+    Less,
+    Equal,
+    LessEqual,
+    Greater,
+    NotEqual,
+    GreaterEqual,
+    Always,
+}
+
+impl CompareFunc {
+    pub fn compare<T: PartialOrd>(&self, a: T, b: T) -> bool {
+        match *self {
+            CompareFunc::Never        => false,
+            CompareFunc::Less         => a < b,
+            CompareFunc::Equal        => a == b,
+            CompareFunc::LessEqual    => a <= b,
+            CompareFunc::Greater      => a > b,
+            CompareFunc::NotEqual     => a != b,
+            CompareFunc::GreaterEqual => a >= b,
+            CompareFunc::Always       => true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StencilOp {
+    Keep,
+    Zero,
+    Replace,
+    Increment,
+    IncrementWrap,
+    Decrement,
+    DecrementWrap,
+    Invert,
+}
+
+/// Stencil behaviour for one facing of a triangle.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct StencilFaceState {
+    pub compare: CompareFunc,
+    /// Applied when the stencil test fails.
+    pub fail_op: StencilOp,
+    /// Applied when the stencil test passes but the depth test fails.
+    pub depth_fail_op: StencilOp,
+    /// Applied when both tests pass.
+    pub pass_op: StencilOp,
+}
+
+impl StencilFaceState {
+    pub fn default() -> StencilFaceState {
+        StencilFaceState {
+            compare: CompareFunc::Always,
+            fail_op: StencilOp::Keep,
+            depth_fail_op: StencilOp::Keep,
+            pass_op: StencilOp::Keep,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DepthStencilState {
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_compare: CompareFunc,
+    pub stencil_test: bool,
+    pub stencil_front: StencilFaceState,
+    pub stencil_back: StencilFaceState,
+    pub stencil_ref: u32,
+    pub stencil_read_mask: u32,
+    pub stencil_write_mask: u32,
+}
+
+impl DepthStencilState {
+    /// Depth tested and written with `Less`, stencil disabled.
+    pub fn default() -> DepthStencilState {
+        DepthStencilState {
+            depth_test: true,
+            depth_write: true,
+            depth_compare: CompareFunc::Less,
+            stencil_test: false,
+            stencil_front: StencilFaceState::default(),
+            stencil_back: StencilFaceState::default(),
+            stencil_ref: 0,
+            stencil_read_mask: 0xff,
+            stencil_write_mask: 0xff,
+        }
+    }
+
+    /// Neither depth nor stencil tested or written.
+    pub fn disabled() -> DepthStencilState {
+        DepthStencilState {
+            depth_test: false,
+            depth_write: false,
+            .. DepthStencilState::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlendFactor {
+    Zero,
+    One,
+    SrcColor,
+    OneMinusSrcColor,
+    DstColor,
+    OneMinusDstColor,
+    SrcAlpha,
+    OneMinusSrcAlpha,
+    DstAlpha,
+    OneMinusDstAlpha,
+    ConstantColor,
+    OneMinusConstantColor,
+    ConstantAlpha,
+    OneMinusConstantAlpha,
+    SrcAlphaSaturate,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlendEquation {
+    Add,
+    Subtract,
+    ReverseSubtract,
+    Min,
+    Max,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BlendState {
+    pub enabled: bool,
+    pub src_color: BlendFactor,
+    pub dst_color: BlendFactor,
+    pub color_equation: BlendEquation,
+    pub src_alpha: BlendFactor,
+    pub dst_alpha: BlendFactor,
+    pub alpha_equation: BlendEquation,
+    /// Used by the `Constant*` factors.
+    pub constant: [f32; 4],
+}
+
+impl BlendState {
+    /// Blending disabled, fragments replace what is in the framebuffer.
+    pub fn default() -> BlendState {
+        BlendState {
+            enabled: false,
+            src_color: BlendFactor::One,
+            dst_color: BlendFactor::Zero,
+            color_equation: BlendEquation::Add,
+            src_alpha: BlendFactor::One,
+            dst_alpha: BlendFactor::Zero,
+            alpha_equation: BlendEquation::Add,
+            constant: [0.0, 0.0, 0.0, 0.0],
+        }
+    }
+
+    /// Regular transparency, `src * src.a + dst * (1 - src.a)`.
+    pub fn alpha() -> BlendState {
+        BlendState {
+            enabled: true,
+            src_color: BlendFactor::SrcAlpha,
+            dst_color: BlendFactor::OneMinusSrcAlpha,
+            src_alpha: BlendFactor::One,
+            dst_alpha: BlendFactor::OneMinusSrcAlpha,
+            .. BlendState::default()
+        }
+    }
+
+    /// `src * src.a + dst`, for glows and particles.
+    pub fn additive() -> BlendState {
+        BlendState {
+            enabled: true,
+            src_color: BlendFactor::SrcAlpha,
+            dst_color: BlendFactor::One,
+            src_alpha: BlendFactor::One,
+            dst_alpha: BlendFactor::One,
+            .. BlendState::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CullMode {
+    None,
+    Front,
+    Back,
+    FrontAndBack,
+}
+
+/// Winding order of front facing triangles.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrontFace {
+    CounterClockwise,
+    Clockwise,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PolygonMode {
+    Fill,
+    Line,
+    Point,
+}
+
+/// Rectangle in pixels with the origin at the bottom left of the target.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ScissorRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RasterizerState {
+    pub cull_mode: CullMode,
+    pub front_face: FrontFace,
+    pub polygon_mode: PolygonMode,
+    /// Fragments outside the rectangle are discarded. `None` disables the
+    /// scissor test.
+    pub scissor: Option<ScissorRect>,
+}
+
+impl RasterizerState {
+    /// Filled, counter clockwise front faces, no culling or scissor.
+    pub fn default() -> RasterizerState {
+        RasterizerState {
+            cull_mode: CullMode::None,
+            front_face: FrontFace::CounterClockwise,
+            polygon_mode: PolygonMode::Fill,
+            scissor: None,
+        }
+    }
+}
+
+/// Fixed function state applied when a geometry is drawn.
+#[derive(Clone, PartialEq, Debug)]
+pub struct RenderState {
+    pub blend: BlendState,
+    pub rasterizer: RasterizerState,
+    pub depth_stencil: DepthStencilState,
+}
+
+impl RenderState {
+    pub fn default() -> RenderState {
+        RenderState {
+            blend: BlendState::default(),
+            rasterizer: RasterizerState::default(),
+            depth_stencil: DepthStencilState::default(),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use super::texture::{TextureFormat, TextureParamHandle};
+
+use std::any::Any;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum DepthStencilFormat {
+    Depth16,
+    Depth24,
+    Depth32F,
+    Depth24Stencil8,
+}
+
+#[derive(Clone, Debug)]
+pub struct RenderTargetDescription {
+    pub width: u32,
+    pub height: u32,
+    pub color_formats: Vec<TextureFormat>,
+    pub depth_stencil_format: Option<DepthStencilFormat>,
+}
+
+impl RenderTargetDescription {
+    pub fn new(width: u32, height: u32) -> RenderTargetDescription {
+        RenderTargetDescription {
+            width: width,
+            height: height,
+            color_formats: Vec::new(),
+            depth_stencil_format: None,
+        }
+    }
+
+    /// Color attachments are numbered in the order they are added, which is
+    /// also the fragment shader output location they are written from.
+    pub fn add_color_attachment(&mut self, format: TextureFormat) {
+        self.color_formats.push(format);
+    }
+
+    pub fn set_depth_stencil_attachment(&mut self, format: DepthStencilFormat) {
+        self.depth_stencil_format = Some(format);
+    }
+}
+
+pub type RenderTargetHandle = u64;
+
+/// Dropping a render target frees its attachments along with it, unbinding
+/// it first if it is bound.
+pub trait RenderTarget {
+    fn handle(&self) -> RenderTargetHandle;
+    /// See `Geometry::as_any`.
+    fn as_any(&self) -> &Any;
+
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+
+    fn color_texture_count(&self) -> usize;
+
+    /// Handle for sampling a color attachment through `ParamValue::Texture2D`.
+    fn color_texture(&self, index: usize) -> Option<TextureParamHandle>;
+
+    /// Handle for sampling the depth attachment, if the backend exposes it.
+    fn depth_texture(&self) -> Option<TextureParamHandle>;
+}
@@ -2,82 +2,526 @@ use common::*;
 
 use super::texture::TextureParamHandle;
 
+use std::fmt;
 use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
+static NEXT_LAYOUT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A value for every GLSL uniform type that can go in a uniform block.
+///
+/// Non-square matrices are named like GLSL, `Mat2x3` has 2 columns of 3 rows,
+/// and are stored as their columns. Struct members are separate params named
+/// `light.color` or `lights[1].color`, the same as GL reports them.
+#[derive(Clone, PartialEq, Debug)]
 pub enum ParamValue {
     F32(f32),
+    I32(i32),
+    U32(u32),
+    Bool(bool),
+    Vec2(Vec2f),
+    Vec3(Vec3f),
     Vec4(Vec4f),
+    IVec2(Vec2i),
+    IVec3(Vec3i),
+    IVec4(Vec4i),
+    UVec2(Vec2u),
+    UVec3(Vec3u),
+    UVec4(Vec4u),
+    BVec2([bool; 2]),
+    BVec3([bool; 3]),
+    BVec4([bool; 4]),
+    Mat2(Mat2f),
     Mat3(Mat3f),
     Mat4(Mat4f),
+    Mat2x3([Vec3f; 2]),
+    Mat2x4([Vec4f; 2]),
+    Mat3x2([Vec2f; 3]),
+    Mat3x4([Vec4f; 3]),
+    Mat4x2([Vec2f; 4]),
+    Mat4x3([Vec3f; 4]),
     Texture2D(TextureParamHandle),
+    TextureCube(TextureParamHandle),
+    Texture2DArray(TextureParamHandle),
+    Texture3D(TextureParamHandle),
+    /// Depth textures sampled with a depth comparison, for shadow maps.
+    Texture2DShadow(TextureParamHandle),
+    TextureCubeShadow(TextureParamHandle),
+    Texture2DArrayShadow(TextureParamHandle),
+    /// An array uniform, every element has the same type. Named without the
+    /// `[0]` GL adds to array uniforms.
+    Array(Vec<ParamValue>),
+}
+
+impl ParamValue {
+    /// Whether the values could be given to the same uniform. Arrays also
+    /// need the same length.
+    pub fn same_type(&self, other: &ParamValue) -> bool {
+        match (self, other) {
+            (&ParamValue::Array(ref a), &ParamValue::Array(ref b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.same_type(y))
+            },
+            _ => self.kind() == other.kind(),
+        }
+    }
+
+    fn kind(&self) -> u32 {
+        match *self {
+            ParamValue::F32(_)       => 0,
+            ParamValue::I32(_)       => 1,
+            ParamValue::U32(_)       => 2,
+            ParamValue::Bool(_)      => 3,
+            ParamValue::Vec2(_)      => 4,
+            ParamValue::Vec3(_)      => 5,
+            ParamValue::Vec4(_)      => 6,
+            ParamValue::IVec2(_)     => 7,
+            ParamValue::IVec3(_)     => 8,
+            ParamValue::IVec4(_)     => 9,
+            ParamValue::UVec2(_)     => 10,
+            ParamValue::UVec3(_)     => 11,
+            ParamValue::UVec4(_)     => 12,
+            ParamValue::BVec2(_)     => 13,
+            ParamValue::BVec3(_)     => 14,
+            ParamValue::BVec4(_)     => 15,
+            ParamValue::Mat2(_)      => 16,
+            ParamValue::Mat3(_)      => 17,
+            ParamValue::Mat4(_)      => 18,
+            ParamValue::Mat2x3(_)    => 19,
+            ParamValue::Mat2x4(_)    => 20,
+            ParamValue::Mat3x2(_)    => 21,
+            ParamValue::Mat3x4(_)    => 22,
+            ParamValue::Mat4x2(_)    => 23,
+            ParamValue::Mat4x3(_)    => 24,
+            ParamValue::Texture2D(_) => 25,
+            ParamValue::Array(_)     => 26,
+            ParamValue::TextureCube(_)          => 27,
+            ParamValue::Texture2DArray(_)       => 28,
+            ParamValue::Texture3D(_)            => 29,
+            ParamValue::Texture2DShadow(_)      => 30,
+            ParamValue::TextureCubeShadow(_)    => 31,
+            ParamValue::Texture2DArrayShadow(_) => 32,
+        }
+    }
+
+    /// The texture of any of the texture variants.
+    pub fn texture_handle(&self) -> Option<TextureParamHandle> {
+        match *self {
+            ParamValue::Texture2D(x)            |
+            ParamValue::TextureCube(x)          |
+            ParamValue::Texture2DArray(x)       |
+            ParamValue::Texture3D(x)            |
+            ParamValue::Texture2DShadow(x)      |
+            ParamValue::TextureCubeShadow(x)    |
+            ParamValue::Texture2DArrayShadow(x) => Some(x),
+            _                                   => None,
+        }
+    }
+
+    /// The columns of a matrix value, each as a list of rows.
+    pub fn matrix_columns(&self) -> Option<Vec<Vec<f32>>> {
+        fn v2(v: &Vec2f) -> Vec<f32> { vec![v.x, v.y] }
+        fn v3(v: &Vec3f) -> Vec<f32> { vec![v.x, v.y, v.z] }
+        fn v4(v: &Vec4f) -> Vec<f32> { vec![v.x, v.y, v.z, v.w] }
+
+        match *self {
+            ParamValue::Mat2(ref m)    => Some(vec![v2(&m.x), v2(&m.y)]),
+            ParamValue::Mat3(ref m)    => Some(vec![v3(&m.x), v3(&m.y), v3(&m.z)]),
+            ParamValue::Mat4(ref m)    => Some(vec![v4(&m.x), v4(&m.y), v4(&m.z), v4(&m.w)]),
+            ParamValue::Mat2x3(ref c)  => Some(c.iter().map(v3).collect()),
+            ParamValue::Mat2x4(ref c)  => Some(c.iter().map(v4).collect()),
+            ParamValue::Mat3x2(ref c)  => Some(c.iter().map(v2).collect()),
+            ParamValue::Mat3x4(ref c)  => Some(c.iter().map(v4).collect()),
+            ParamValue::Mat4x2(ref c)  => Some(c.iter().map(v2).collect()),
+            ParamValue::Mat4x3(ref c)  => Some(c.iter().map(v3).collect()),
+            _                          => None,
+        }
+    }
+
+    /// The raw 4 byte components of a scalar or vector value, bools as 0 or
+    /// 1 like GLSL stores them.
+    pub fn scalar_components(&self) -> Option<Vec<[u8; 4]>> {
+        fn f(x: f32) -> [u8; 4] { unsafe { mem::transmute(x) } }
+        fn i(x: i32) -> [u8; 4] { unsafe { mem::transmute(x) } }
+        fn u(x: u32) -> [u8; 4] { unsafe { mem::transmute(x) } }
+        fn b(x: bool) -> [u8; 4] { u(if x { 1 } else { 0 }) }
+
+        match *self {
+            ParamValue::F32(x)       => Some(vec![f(x)]),
+            ParamValue::I32(x)       => Some(vec![i(x)]),
+            ParamValue::U32(x)       => Some(vec![u(x)]),
+            ParamValue::Bool(x)      => Some(vec![b(x)]),
+            ParamValue::Vec2(v)      => Some(vec![f(v.x), f(v.y)]),
+            ParamValue::Vec3(v)      => Some(vec![f(v.x), f(v.y), f(v.z)]),
+            ParamValue::Vec4(v)      => Some(vec![f(v.x), f(v.y), f(v.z), f(v.w)]),
+            ParamValue::IVec2(v)     => Some(vec![i(v.x), i(v.y)]),
+            ParamValue::IVec3(v)     => Some(vec![i(v.x), i(v.y), i(v.z)]),
+            ParamValue::IVec4(v)     => Some(vec![i(v.x), i(v.y), i(v.z), i(v.w)]),
+            ParamValue::UVec2(v)     => Some(vec![u(v.x), u(v.y)]),
+            ParamValue::UVec3(v)     => Some(vec![u(v.x), u(v.y), u(v.z)]),
+            ParamValue::UVec4(v)     => Some(vec![u(v.x), u(v.y), u(v.z), u(v.w)]),
+            ParamValue::BVec2(v)     => Some(v.iter().map(|x| b(*x)).collect()),
+            ParamValue::BVec3(v)     => Some(v.iter().map(|x| b(*x)).collect()),
+            ParamValue::BVec4(v)     => Some(v.iter().map(|x| b(*x)).collect()),
+            _                        => None,
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct Param {
     pub name: String,
     pub value: ParamValue,
 }
 
+#[derive(Clone)]
 pub struct ParamGroup {
     pub name: String,
     pub params: Vec<Param>,
 }
 
+/// Refers to a param by position instead of by name. Get one with
+/// `ShaderParams::handle` once and use it every frame to skip the name lookup.
+///
+/// Handles are only valid for the `ShaderParams` they came from, or a clone
+/// of it. Params reflected again after a shader reload reject old handles
+/// with `ParamError::InvalidHandle`, resolve them again when that happens.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ParamHandle {
+    layout: usize,
+    group: usize,
+    index: usize,
+}
+
+impl ParamHandle {
+    /// Index of the group in `ShaderParams::groups`.
+    pub fn group(&self) -> usize {
+        self.group
+    }
+
+    /// Index of the param within its group.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ParamError {
+    /// The shader doesn't have a param with this name.
+    UnknownParam(String),
+    /// The handle is from a different `ShaderParams`.
+    InvalidHandle(ParamHandle),
+    /// The value given isn't the type of the param.
+    TypeMismatch {
+        name: String,
+        expected: ParamValue,
+        found: ParamValue,
+    },
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParamError::UnknownParam(ref name) => write!(f, "no shader param named {}", name),
+            ParamError::InvalidHandle(_)       => write!(f, "shader param handle is from different params"),
+            ParamError::TypeMismatch { ref name, ref expected, ref found } => {
+                write!(f, "shader param {} is {:?}, can't set it to {:?}", name, expected, found)
+            },
+        }
+    }
+}
+
+/// One bit per param of a group.
+#[derive(Clone)]
+struct DirtyBits {
+    words: Vec<u64>,
+    any: bool,
+}
+
+impl DirtyBits {
+    fn new(len: usize) -> DirtyBits {
+        DirtyBits {
+            words: vec![0; (len + 63) / 64],
+            any: false,
+        }
+    }
+
+    fn set(&mut self, i: usize) {
+        self.words[i / 64] |= 1 << (i % 64);
+        self.any = true;
+    }
+
+    fn set_all(&mut self, len: usize) {
+        for i in 0..len {
+            self.set(i);
+        }
+    }
+
+    /// Appends the set bits to `out` and clears them.
+    fn drain(&mut self, layout: usize, group: usize, out: &mut Vec<ParamHandle>) {
+        if !self.any {
+            return;
+        }
+
+        for (w, word) in self.words.iter_mut().enumerate() {
+            let mut bits = *word;
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                out.push(ParamHandle {
+                    layout: layout,
+                    group: group,
+                    index: w * 64 + bit,
+                });
+                bits &= bits - 1;
+            }
+            *word = 0;
+        }
+
+        self.any = false;
+    }
+}
+
+#[derive(Clone)]
 pub struct ShaderParams {
+    /// Unique per `new`, shared by clones. Stamped on handles so that handles
+    /// into other params are caught.
+    layout: usize,
     groups: Vec<ParamGroup>,
-    changes: Vec<String>, 
+    /// Which params have been set since the last `flush_changes`, per group.
+    dirty: Vec<DirtyBits>,
 }
 
 impl ShaderParams {
     pub fn new(groups: Vec<ParamGroup>) -> ShaderParams {
+        let dirty = groups.iter().map(|group| DirtyBits::new(group.params.len())).collect();
+
         return ShaderParams {
+            layout: NEXT_LAYOUT.fetch_add(1, Ordering::Relaxed),
             groups: groups,
-            changes: Vec::new(),
+            dirty: dirty,
         }
     }
     
-    fn find_mut_param(&mut self, name: &str) -> Option<&mut Param> {
-        let mut result: Option<&mut Param> = None;
-        for group in self.groups.iter_mut() {
-            for param in group.params.iter_mut() {
+    pub fn groups(&self) -> &Vec<ParamGroup> {
+        &self.groups
+    }
+
+    /// Looks up a param by name. Resolve handles once up front, not per
+    /// frame.
+    pub fn handle(&self, name: &str) -> Result<ParamHandle, ParamError> {
+        for (g, group) in self.groups.iter().enumerate() {
+            for (i, param) in group.params.iter().enumerate() {
                 if param.name == name {
-                    result = Some(&mut *param);
-                    break;
+                    return Ok(ParamHandle {
+                        layout: self.layout,
+                        group: g,
+                        index: i,
+                    });
                 }
             }
         }
-        
-        result
+
+        Err(ParamError::UnknownParam(name.to_string()))
+    }
+
+    /// Sets a param by name. Prefer `set_by_handle` for params that are set
+    /// every frame.
+    pub fn set(&mut self, name: &str, value: ParamValue) -> Result<(), ParamError> {
+        let handle = try!(self.handle(name));
+        self.set_by_handle(handle, value)
+    }
+
+    /// Sets a param, failing if `value` isn't the param's type.
+    pub fn set_by_handle(&mut self, handle: ParamHandle, value: ParamValue) -> Result<(), ParamError> {
+        if handle.layout != self.layout {
+            return Err(ParamError::InvalidHandle(handle));
+        }
+
+        {
+            let param = &mut self.groups[handle.group].params[handle.index];
+
+            if !param.value.same_type(&value) {
+                return Err(ParamError::TypeMismatch {
+                    name: param.name.clone(),
+                    expected: param.value.clone(),
+                    found: value,
+                });
+            }
+
+            param.value = value;
+        }
+
+        self.dirty[handle.group].set(handle.index);
+        Ok(())
     }
 
-    fn find_param(&self, name: &str) -> Option<&Param> {
-        let mut result: Option<&Param> = None;
-        for group in self.groups.iter() {
+    pub fn contains(&self, name: &str) -> bool {
+        self.handle(name).is_ok()
+    }
+
+    pub fn get(&self, name: &str) -> Result<&ParamValue, ParamError> {
+        let handle = try!(self.handle(name));
+        Ok(self.get_by_handle(handle))
+    }
+
+    /// Panics if the handle is from a different `ShaderParams`.
+    pub fn get_by_handle(&self, handle: ParamHandle) -> &ParamValue {
+        &self.param_by_handle(handle).value
+    }
+
+    /// Panics if the handle is from a different `ShaderParams`.
+    pub fn param_by_handle(&self, handle: ParamHandle) -> &Param {
+        assert!(handle.layout == self.layout, "shader param handle is from different params");
+        &self.groups[handle.group].params[handle.index]
+    }
+    
+    /// Copies over the value of every param in `other` that has a param of the
+    /// same name and type here. Used to keep values when a shader is reloaded.
+    pub fn copy_values_from(&mut self, other: &ShaderParams) {
+        for group in other.groups.iter() {
             for param in group.params.iter() {
-                if param.name == name {
-                    result = Some(param);
-                    break;
+                if let Ok(handle) = self.handle(&param.name) {
+                    // Params that changed type keep their default.
+                    let _ = self.set_by_handle(handle, param.value.clone());
                 }
             }
         }
-        
-        result
     }
-    
-    pub fn set(&mut self, name: &str, value: ParamValue) {
-        {
-            let param = self.find_mut_param(name).unwrap();
-            param.value = value;
+
+    /// Flags every param as changed, so that all of them get uploaded.
+    pub fn mark_all_changed(&mut self) {
+        for (group, dirty) in self.groups.iter().zip(self.dirty.iter_mut()) {
+            dirty.set_all(group.params.len());
         }
-        self.changes.push(name.clone().to_string());
     }
 
-    pub fn get(&self, name: &str) -> &ParamValue {
-        let param = self.find_param(name).unwrap();
-        &param.value
+    /// Whether any param of the group has been set since the last
+    /// `flush_changes`.
+    pub fn is_group_dirty(&self, group: usize) -> bool {
+        self.dirty[group].any
     }
-    
-    pub fn flush_changes(&mut self) -> Vec<String> {
-        mem::replace(&mut self.changes, Vec::new())
+
+    /// Replaces the contents of `changes` with the params set since the last
+    /// call, ordered by group then index. Backends keep one buffer for this
+    /// so that drawing doesn't allocate.
+    pub fn flush_changes(&mut self, changes: &mut Vec<ParamHandle>) {
+        changes.clear();
+
+        for (g, dirty) in self.dirty.iter_mut().enumerate() {
+            dirty.drain(self.layout, g, changes);
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(name: &str, params: Vec<(&str, ParamValue)>) -> ParamGroup {
+        ParamGroup {
+            name: name.to_string(),
+            params: params.into_iter().map(|(name, value)| Param { name: name.to_string(), value: value }).collect(),
+        }
+    }
+
+    fn test_params() -> ShaderParams {
+        ShaderParams::new(vec![
+            group("Material", vec![("roughness", ParamValue::F32(0.0)), ("weights", ParamValue::Array(vec![ParamValue::F32(0.0); 3]))]),
+            group("", vec![("count", ParamValue::I32(0))]),
+        ])
+    }
+
+    #[test]
+    fn sets_params_by_handle() {
+        let mut params = test_params();
+
+        let handle = params.handle("count").unwrap();
+        assert_eq!(handle.group(), 1);
+        assert_eq!(handle.index(), 0);
+
+        params.set_by_handle(handle, ParamValue::I32(4)).unwrap();
+        assert_eq!(*params.get_by_handle(handle), ParamValue::I32(4));
+        assert_eq!(*params.get("count").unwrap(), ParamValue::I32(4));
+
+        match params.handle("missing") {
+            Err(ParamError::UnknownParam(ref name)) if name == "missing" => (),
+            _ => panic!("found a handle for a param that doesn't exist"),
+        }
+    }
+
+    #[test]
+    fn rejects_handles_from_other_params() {
+        let other = test_params();
+        let mut params = test_params();
+
+        let foreign = other.handle("roughness").unwrap();
+        match params.set_by_handle(foreign, ParamValue::F32(1.0)) {
+            Err(ParamError::InvalidHandle(handle)) => assert_eq!(handle, foreign),
+            _ => panic!("accepted a handle from other params"),
+        }
+
+        // Clones share the layout, so their handles stay valid.
+        let mut clone = other.clone();
+        assert!(clone.set_by_handle(foreign, ParamValue::F32(1.0)).is_ok());
+    }
+
+    #[test]
+    fn rejects_values_of_the_wrong_type() {
+        let mut params = test_params();
+
+        match params.set("roughness", ParamValue::I32(1)) {
+            Err(ParamError::TypeMismatch { ref name, .. }) => assert_eq!(name, "roughness"),
+            _ => panic!("set an f32 param to an i32"),
+        }
+
+        assert!(params.set("weights", ParamValue::Array(vec![ParamValue::F32(1.0); 3])).is_ok());
+        assert!(params.set("weights", ParamValue::Array(vec![ParamValue::F32(1.0); 2])).is_err());
+        assert!(params.set("weights", ParamValue::Array(vec![ParamValue::I32(1); 3])).is_err());
+        assert_eq!(*params.get("weights").unwrap(), ParamValue::Array(vec![ParamValue::F32(1.0); 3]));
+
+        // Failed sets don't count as changes.
+        let mut changes = Vec::new();
+        params.flush_changes(&mut changes);
+        assert_eq!(changes, vec![params.handle("weights").unwrap()]);
+    }
+
+    #[test]
+    fn flushes_changes_past_64_params() {
+        let names: Vec<String> = (0..70).map(|i| format!("p{}", i)).collect();
+        let mut params = ShaderParams::new(vec![
+            group("", names.iter().map(|name| (&name[..], ParamValue::F32(0.0))).collect()),
+        ]);
+
+        for &i in [69, 3, 64, 63].iter() {
+            params.set(&names[i], ParamValue::F32(1.0)).unwrap();
+        }
+
+        let mut changes = Vec::new();
+        params.flush_changes(&mut changes);
+        let indices: Vec<usize> = changes.iter().map(|handle| handle.index()).collect();
+        assert_eq!(indices, vec![3, 63, 64, 69]);
+
+        params.flush_changes(&mut changes);
+        assert!(changes.is_empty());
+
+        params.mark_all_changed();
+        params.flush_changes(&mut changes);
+        assert_eq!(changes.len(), 70);
+    }
+
+    #[test]
+    fn tracks_dirty_groups_until_flushed() {
+        let mut params = test_params();
+        assert!(!params.is_group_dirty(0));
+        assert!(!params.is_group_dirty(1));
+
+        params.set("count", ParamValue::I32(1)).unwrap();
+        assert!(!params.is_group_dirty(0));
+        assert!(params.is_group_dirty(1));
+
+        // Leftovers in the buffer are replaced.
+        let mut changes = vec![params.handle("roughness").unwrap()];
+        params.flush_changes(&mut changes);
+        assert_eq!(changes, vec![params.handle("count").unwrap()]);
+        assert!(!params.is_group_dirty(1));
+    }
+}
@@ -0,0 +1,106 @@
+//! Uniform blocks owned by the renderer rather than by materials. They are
+//! set once per frame or view, every program sees the same buffer and they
+//! never show up in a geometry's params.
+//!
+//! Shaders declare them by including `shared.glsl`, which uses the std140
+//! layout so that the buffers can be filled without asking GL for offsets:
+//!
+//! ```glsl
+//! layout(std140) uniform FrameData {
+//!     float time;
+//!     float delta_time;
+//! };
+//!
+//! layout(std140) uniform ViewData {
+//!     mat4 view;
+//!     mat4 projection;
+//!     mat4 view_projection;
+//!     vec3 camera_position;
+//! };
+//! ```
+
+use common::*;
+
+use super::buffer::BufferData;
+
+pub const FRAME_DATA_BLOCK_NAME: &'static str = "FrameData";
+pub const VIEW_DATA_BLOCK_NAME: &'static str = "ViewData";
+
+pub const FRAME_DATA_BINDING: u32 = 0;
+pub const VIEW_DATA_BINDING: u32 = 1;
+
+/// Per object blocks are bound from here on, so that they never displace a
+/// shared block.
+pub const FIRST_OBJECT_BLOCK_BINDING: u32 = 2;
+
+/// The fixed binding point of a shared block, None for per object blocks.
+pub fn shared_block_binding(block_name: &str) -> Option<u32> {
+    match block_name {
+        FRAME_DATA_BLOCK_NAME => Some(FRAME_DATA_BINDING),
+        VIEW_DATA_BLOCK_NAME  => Some(VIEW_DATA_BINDING),
+        _                     => None,
+    }
+}
+
+/// Size of the std140 layout of a shared block.
+pub fn shared_block_size(block_name: &str) -> Option<usize> {
+    match block_name {
+        FRAME_DATA_BLOCK_NAME => Some(FRAME_DATA_SIZE),
+        VIEW_DATA_BLOCK_NAME  => Some(VIEW_DATA_SIZE),
+        _                     => None,
+    }
+}
+
+const FRAME_DATA_SIZE: usize = 16;
+const VIEW_DATA_SIZE: usize = 208;
+
+#[derive(Clone, Copy, Debug)]
+pub struct FrameData {
+    /// Seconds since the scene was created.
+    pub time: f32,
+    /// Seconds since the previous frame.
+    pub delta_time: f32,
+}
+
+impl FrameData {
+    pub fn std140_data(&self) -> BufferData {
+        let mut data = BufferData::new_zero_initialized(FRAME_DATA_SIZE);
+        data.update_region(0, vec![self.time, self.delta_time]).unwrap();
+        data
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ViewData {
+    pub view: Mat4f,
+    pub projection: Mat4f,
+    pub view_projection: Mat4f,
+    pub camera_position: Vec3f,
+}
+
+impl ViewData {
+    pub fn new(view: Mat4f, projection: Mat4f, camera_position: Vec3f) -> ViewData {
+        ViewData {
+            view: view,
+            projection: projection,
+            view_projection: projection * view,
+            camera_position: camera_position,
+        }
+    }
+
+    pub fn std140_data(&self) -> BufferData {
+        let mut data = BufferData::new_zero_initialized(VIEW_DATA_SIZE);
+        write_mat4(&mut data, 0, &self.view);
+        write_mat4(&mut data, 64, &self.projection);
+        write_mat4(&mut data, 128, &self.view_projection);
+        data.update_region(192, vec![self.camera_position.x, self.camera_position.y, self.camera_position.z]).unwrap();
+        data
+    }
+}
+
+// The blocks have a fixed layout, so everything written here fits.
+fn write_mat4(data: &mut BufferData, offset: usize, m: &Mat4f) {
+    for (i, column) in [m.x, m.y, m.z, m.w].iter().enumerate() {
+        data.update_region(offset + i * 16, vec![column.x, column.y, column.z, column.w]).unwrap();
+    }
+}
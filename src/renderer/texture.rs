@@ -1,10 +1,77 @@
+use common::*;
+
+use super::render_state::CompareFunc;
+
+use std::mem;
+use std::any::Any;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum FilteringMethod {
     Nearest,
     BiLinear,
+    /// Needs mipmaps, which are generated the first time the texture is
+    /// sampled this way if it was created without them.
     TriLinear,
+    /// Trilinear plus `SamplerDescription::max_anisotropy`.
     Anisotropic,
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WrapMode {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+    /// Outside of 0-1 is `SamplerDescription::border_color`.
+    ClampToBorder,
+}
+
+/// How a texture is sampled. Every texture has one of its own, and
+/// materials can override it per sampler uniform.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SamplerDescription {
+    pub filtering: FilteringMethod,
+    pub wrap_s: WrapMode,
+    pub wrap_t: WrapMode,
+    pub wrap_r: WrapMode,
+    pub border_color: Vec4f,
+    pub lod_bias: f32,
+    /// Only used by `FilteringMethod::Anisotropic`, and clamped to what the
+    /// driver supports.
+    pub max_anisotropy: f32,
+    /// Depth comparison done by shadow samplers, which use `LessEqual`
+    /// when this is None. Ignored by every other sampler.
+    pub compare_func: Option<CompareFunc>,
+}
+
+impl SamplerDescription {
+    pub fn default() -> SamplerDescription {
+        SamplerDescription {
+            filtering: FilteringMethod::TriLinear,
+            wrap_s: WrapMode::Repeat,
+            wrap_t: WrapMode::Repeat,
+            wrap_r: WrapMode::Repeat,
+            border_color: Vec4f::new(0.0, 0.0, 0.0, 0.0),
+            lod_bias: 0.0,
+            max_anisotropy: 16.0,
+            compare_func: None,
+        }
+    }
+
+    pub fn set_wrap(&mut self, wrap: WrapMode) {
+        self.wrap_s = wrap;
+        self.wrap_t = wrap;
+        self.wrap_r = wrap;
+    }
+
+    pub fn needs_mipmaps(&self) -> bool {
+        match self.filtering {
+            FilteringMethod::TriLinear | FilteringMethod::Anisotropic => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub enum TextureFormat {
     RGB,
     RGBA,
@@ -13,11 +80,288 @@ pub enum TextureFormat {
     LuminanceAlpha,
 }
 
-pub type TextureParamHandle = u32;
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TextureType {
+    Texture2D,
+    /// Six square faces in +X, -X, +Y, -Y, +Z, -Z order.
+    Cube,
+    /// Layers of the same size, sampled with `sampler2DArray`.
+    Array2D,
+    /// Slices from front to back.
+    Texture3D,
+}
+
+impl TextureType {
+    /// Checks that `layers` can make up a texture of this type.
+    pub fn validate_layers(&self, layers: &[&TextureData]) -> Result<(), String> {
+        let first = match layers.first() {
+            Some(x) => x,
+            None    => return Err("a texture needs at least one layer".to_string()),
+        };
+
+        match *self {
+            TextureType::Texture2D if layers.len() != 1 => {
+                return Err(format!("2D textures have a single layer, got {}", layers.len()));
+            },
+            TextureType::Cube if layers.len() != 6 => {
+                return Err(format!("cube textures need 6 faces, got {}", layers.len()));
+            },
+            TextureType::Cube if first.width != first.height => {
+                return Err(format!("cube faces must be square, got {}x{}", first.width, first.height));
+            },
+            _ => (),
+        }
+
+        for (i, layer) in layers.iter().enumerate() {
+            if layer.width != first.width || layer.height != first.height {
+                return Err(format!("layer {} is {}x{}, layer 0 is {}x{}", i, layer.width, layer.height, first.width, first.height));
+            }
+
+            if layer.format != first.format || layer.component_type != first.component_type {
+                return Err(format!("layer {} has a different format to layer 0", i));
+            }
+
+            if layer.bytes.len() != layer.row_size() * layer.height as usize {
+                return Err(format!("layer {} has the wrong amount of data", i));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Type of each channel of a texel.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ComponentType {
+    U8,
+    U16,
+    F32,
+}
+
+impl ComponentType {
+    pub fn size(&self) -> usize {
+        match *self {
+            ComponentType::U8  => 1,
+            ComponentType::U16 => 2,
+            ComponentType::F32 => 4,
+        }
+    }
+}
+
+/// How color values are encoded. Samplers decode sRGB textures to linear.
+///
+/// Only 8-bit RGB and RGBA can be decoded by the GPU, any other texture is
+/// sampled as linear whatever its color space says.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColorSpace {
+    Linear,
+    SRGB,
+}
+
+impl TextureFormat {
+    pub fn channel_count(&self) -> usize {
+        match *self {
+            TextureFormat::Alpha | TextureFormat::Luminance => 1,
+            TextureFormat::LuminanceAlpha                   => 2,
+            TextureFormat::RGB                              => 3,
+            TextureFormat::RGBA                             => 4,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TextureOptions {
+    /// Linear by default. Color textures are usually authored in sRGB, but
+    /// nothing encodes the framebuffer back to sRGB yet, so decoding them
+    /// would only darken them.
+    pub color_space: ColorSpace,
+    /// Flips images loaded by `Renderer::load_texture` and
+    /// `Renderer::create_texture_from_image` so that the first row is the
+    /// bottom one, like GL expects.
+    pub flip_vertically: bool,
+    pub generate_mipmaps: bool,
+    /// The texture's own sampler, see `Texture::set_sampler`.
+    pub sampler: SamplerDescription,
+}
+
+impl TextureOptions {
+    pub fn default() -> TextureOptions {
+        TextureOptions {
+            color_space: ColorSpace::Linear,
+            flip_vertically: false,
+            generate_mipmaps: true,
+            sampler: SamplerDescription::default(),
+        }
+    }
+}
 
+/// Texels ready to upload. Rows are tightly packed with no padding and
+/// components are native endian.
+#[derive(Clone)]
+pub struct TextureData {
+    pub width: u32,
+    pub height: u32,
+    pub format: TextureFormat,
+    pub component_type: ComponentType,
+    pub bytes: Vec<u8>,
+}
+
+impl TextureData {
+    pub fn new(width: u32, height: u32, format: TextureFormat, component_type: ComponentType, bytes: Vec<u8>) -> Result<TextureData, String> {
+        let data = TextureData {
+            width: width,
+            height: height,
+            format: format,
+            component_type: component_type,
+            bytes: bytes,
+        };
+
+        let expected = data.row_size() * height as usize;
+        if data.bytes.len() != expected {
+            return Err(format!("{}x{} texture data should be {} bytes, not {}", width, height, expected, data.bytes.len()));
+        }
+
+        Ok(data)
+    }
+
+    pub fn texel_size(&self) -> usize {
+        self.format.channel_count() * self.component_type.size()
+    }
+
+    pub fn row_size(&self) -> usize {
+        self.texel_size() * self.width as usize
+    }
+
+    pub fn flip_vertically(&mut self) {
+        let row_size = self.row_size();
+        let height = self.height as usize;
+
+        for y in 0..(height / 2) {
+            let top = y * row_size;
+            let bottom = (height - y - 1) * row_size;
+
+            for x in 0..row_size {
+                self.bytes.swap(top + x, bottom + x);
+            }
+        }
+    }
+
+    /// Converts to 8-bit RGBA, for backends that only sample that.
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        let channels = self.format.channel_count();
+        let texels = (self.width * self.height) as usize;
+        let mut result = Vec::with_capacity(texels * 4);
+
+        for i in 0..texels {
+            let mut c = [0u8; 4];
+            for ch in 0..channels {
+                c[ch] = self.component_u8(i * channels + ch);
+            }
+
+            let rgba = match self.format {
+                TextureFormat::Alpha          => [0, 0, 0, c[0]],
+                TextureFormat::Luminance      => [c[0], c[0], c[0], 255],
+                TextureFormat::LuminanceAlpha => [c[0], c[0], c[0], c[1]],
+                TextureFormat::RGB            => [c[0], c[1], c[2], 255],
+                TextureFormat::RGBA           => c,
+            };
+
+            result.extend(rgba.iter().cloned());
+        }
+
+        result
+    }
+
+    /// The `index`th component scaled to 0-255.
+    fn component_u8(&self, index: usize) -> u8 {
+        let offset = index * self.component_type.size();
+        let b = &self.bytes[offset..];
+
+        match self.component_type {
+            ComponentType::U8  => b[0],
+            ComponentType::U16 => {
+                let x: u16 = unsafe { mem::transmute([b[0], b[1]]) };
+                (x >> 8) as u8
+            },
+            ComponentType::F32 => {
+                let x: f32 = unsafe { mem::transmute([b[0], b[1], b[2], b[3]]) };
+                (x.max(0.0).min(1.0) * 255.0).round() as u8
+            },
+        }
+    }
+}
+
+pub type TextureParamHandle = u64;
+
+/// Dropping a texture frees it. Params still set to it sample nothing.
 pub trait Texture {
     fn param_handle(&self) -> TextureParamHandle;
+    /// See `Geometry::as_any`.
+    fn as_any(&self) -> &Any;
+    fn texture_type(&self) -> TextureType;
     fn format(&self) -> &TextureFormat;
+    fn component_type(&self) -> ComponentType;
+    fn color_space(&self) -> ColorSpace;
     
-    fn set_filtering_method(&mut self, method: FilteringMethod);
+    /// How the texture is sampled by materials that don't override it.
+    fn sampler(&self) -> SamplerDescription;
+    fn set_sampler(&mut self, desc: &SamplerDescription);
+
+    fn set_filtering_method(&mut self, method: FilteringMethod) {
+        let mut desc = self.sampler();
+        desc.filtering = method;
+        self.set_sampler(&desc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn textures_are_linear_by_default() {
+        assert_eq!(TextureOptions::default().color_space, ColorSpace::Linear);
+    }
+
+    #[test]
+    fn flip_vertically_reverses_rows() {
+        let bytes = vec![
+            1, 2, 3,  4, 5, 6,
+            7, 8, 9,  10, 11, 12,
+            13, 14, 15,  16, 17, 18,
+        ];
+        let mut data = TextureData::new(2, 3, TextureFormat::RGB, ComponentType::U8, bytes).unwrap();
+        data.flip_vertically();
+
+        assert_eq!(data.bytes, vec![
+            13, 14, 15,  16, 17, 18,
+            7, 8, 9,  10, 11, 12,
+            1, 2, 3,  4, 5, 6,
+        ]);
+    }
+
+    #[test]
+    fn to_rgba8_expands_channels() {
+        let luminance_alpha = TextureData::new(2, 1, TextureFormat::LuminanceAlpha, ComponentType::U8, vec![10, 20, 30, 40]).unwrap();
+        assert_eq!(luminance_alpha.to_rgba8(), vec![10, 10, 10, 20, 30, 30, 30, 40]);
+
+        let alpha = TextureData::new(1, 1, TextureFormat::Alpha, ComponentType::U8, vec![128]).unwrap();
+        assert_eq!(alpha.to_rgba8(), vec![0, 0, 0, 128]);
+
+        let rgb = TextureData::new(1, 1, TextureFormat::RGB, ComponentType::U8, vec![1, 2, 3]).unwrap();
+        assert_eq!(rgb.to_rgba8(), vec![1, 2, 3, 255]);
+    }
+
+    #[test]
+    fn to_rgba8_scales_wider_components() {
+        let texel: [u16; 1] = [0xff00];
+        let bytes: [u8; 2] = unsafe { mem::transmute(texel) };
+        let luminance = TextureData::new(1, 1, TextureFormat::Luminance, ComponentType::U16, bytes.to_vec()).unwrap();
+        assert_eq!(luminance.to_rgba8(), vec![255, 255, 255, 255]);
+
+        let texel: [f32; 4] = [0.0, 1.0, 2.0, -1.0];
+        let bytes: [u8; 16] = unsafe { mem::transmute(texel) };
+        let rgba = TextureData::new(1, 1, TextureFormat::RGBA, ComponentType::F32, bytes.to_vec()).unwrap();
+        assert_eq!(rgba.to_rgba8(), vec![0, 255, 255, 0]);
+    }
 }
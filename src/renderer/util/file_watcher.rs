@@ -0,0 +1,68 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+struct WatchedFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+/// Polls the modification times of a set of files.
+pub struct FileWatcher {
+    files: Vec<WatchedFile>,
+    poll_interval: Duration,
+    last_poll: Instant,
+}
+
+impl FileWatcher {
+    pub fn new(poll_interval: Duration) -> FileWatcher {
+        FileWatcher {
+            files: Vec::new(),
+            poll_interval: poll_interval,
+            last_poll: Instant::now(),
+        }
+    }
+
+    pub fn watch(&mut self, path: &Path) {
+        if self.files.iter().any(|file| file.path == path) {
+            return;
+        }
+
+        self.files.push(WatchedFile {
+            path: path.to_path_buf(),
+            modified: modified_time(path),
+        });
+    }
+
+    /// Returns the files modified since the last poll. Checks at most once
+    /// per poll interval, returning nothing in between.
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        let mut changed = Vec::new();
+
+        if self.last_poll.elapsed() < self.poll_interval {
+            return changed;
+        }
+
+        self.last_poll = Instant::now();
+
+        for file in self.files.iter_mut() {
+            // Editors often replace the file when saving, so it can be
+            // missing for a moment. Wait until it is back.
+            let modified = match modified_time(&file.path) {
+                Some(x) => Some(x),
+                None    => continue,
+            };
+
+            if modified != file.modified {
+                file.modified = modified;
+                changed.push(file.path.clone());
+            }
+        }
+
+        changed
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
@@ -0,0 +1,885 @@
+use common::*;
+
+use renderer::shader_params::{ShaderParams, ParamGroup, Param, ParamValue};
+use renderer::vertex_layout::VertexInput;
+use renderer::shared_data::shared_block_binding;
+
+use std::mem;
+use std::cmp;
+use std::collections::HashMap;
+
+/// A member of a uniform block or struct, `type name[array_size];`.
+struct Member {
+    utype: String,
+    name: String,
+    array_size: Option<usize>,
+}
+
+/// Builds `ShaderParams` from the uniform declarations in GLSL sources,
+/// grouped the same way the GL backend reflects them: one group per uniform
+/// block, then a `_default` group for uniforms outside of blocks and a
+/// `_textures` group holding the samplers.
+///
+/// Struct members are flattened to `light.color` and `lights[1].color` params
+/// like GL does. Array sizes may be literals or `#define`d constants, and
+/// uniforms in `#ifdef`/`#if` branches that aren't compiled are left out.
+pub fn reflect_shader_params(sources: &[&str]) -> ShaderParams {
+    let mut param_groups: Vec<ParamGroup> = Vec::new();
+    let mut default_params: Vec<Param> = Vec::new();
+    let mut tex_params: Vec<Param> = Vec::new();
+
+    for src in sources {
+        let tokens = tokenize_glsl(src);
+        let constants = collect_defines(src);
+        let mut structs: HashMap<String, Vec<Member>> = HashMap::new();
+        let mut i = 0;
+
+        while i < tokens.len() {
+            // struct Name { type name; ... };
+            if tokens[i] == "struct" && i + 2 < tokens.len() && tokens[i + 2] == "{" {
+                let struct_name = tokens[i + 1].clone();
+                i += 3;
+
+                let members = parse_members(&tokens, &mut i, &constants);
+                structs.insert(struct_name, members);
+                continue;
+            }
+
+            if tokens[i] != "uniform" {
+                i += 1;
+                continue;
+            }
+
+            i += 1;
+
+            // uniform BlockName { type name; ... };
+            if i + 1 < tokens.len() && tokens[i + 1] == "{" {
+                let block_name = tokens[i].clone();
+                i += 2;
+
+                let members = parse_members(&tokens, &mut i, &constants);
+
+                let mut params: Vec<Param> = Vec::new();
+                for member in members.iter() {
+                    expand_member(&structs, member, "", &mut params);
+                }
+
+                // Shared blocks are filled by the renderer, not through params.
+                if shared_block_binding(&block_name).is_some() {
+                    continue;
+                }
+
+                if !param_groups.iter().any(|group| group.name == block_name) {
+                    param_groups.push(ParamGroup {
+                        name: block_name,
+                        params: params,
+                    });
+                }
+
+                continue;
+            }
+
+            // uniform sampler2D name;
+            let sampler_value = if i + 1 < tokens.len() { sampler_param_value(&tokens[i]) } else { None };
+            if let Some(value) = sampler_value {
+                let name = tokens[i + 1].clone();
+
+                if !tex_params.iter().any(|param| param.name == name) {
+                    tex_params.push(Param {
+                        name: name,
+                        value: value,
+                    });
+                }
+
+                continue;
+            }
+
+            // uniform type name;
+            let mut params: Vec<Param> = Vec::new();
+            for member in parse_declaration(&tokens, &mut i, &constants).iter() {
+                expand_member(&structs, member, "", &mut params);
+            }
+
+            for param in params {
+                if !default_params.iter().any(|p| p.name == param.name) {
+                    default_params.push(param);
+                }
+            }
+        }
+    }
+
+    param_groups.push(ParamGroup {
+        name: "_default".to_string(),
+        params: default_params,
+    });
+
+    param_groups.push(ParamGroup {
+        name: "_textures".to_string(),
+        params: tex_params,
+    });
+
+    ShaderParams::new(param_groups)
+}
+
+/// The inputs of a vertex shader, from its `in type name;` declarations
+/// outside of functions, or `attribute` ones in older GLSL. Declarations in
+/// `#ifdef`/`#if` branches that aren't compiled are left out, so reflect the
+/// preprocessed source to go by the material's defines.
+pub fn reflect_vertex_inputs(src: &str) -> Vec<VertexInput> {
+    let tokens = tokenize_glsl(src);
+    let mut inputs: Vec<VertexInput> = Vec::new();
+    // Function parameters can be `in` too.
+    let mut depth = 0;
+    let mut i = 0;
+
+    while i < tokens.len() {
+        match &tokens[i][..] {
+            "{" | "(" => depth += 1,
+            "}" | ")" => depth -= 1,
+            "in" | "attribute" if depth == 0 => {
+                i += 1;
+
+                while i < tokens.len() && (is_qualifier(&tokens[i]) || is_interpolation_qualifier(&tokens[i])) {
+                    i += 1;
+                }
+
+                if i >= tokens.len() {
+                    break;
+                }
+
+                let integer = is_integer_type(&tokens[i]);
+                i += 1;
+
+                // in type a, b;
+                let mut expect_name = true;
+                while i < tokens.len() && tokens[i] != ";" {
+                    if expect_name {
+                        if !inputs.iter().any(|input| input.name == tokens[i]) {
+                            inputs.push(VertexInput {
+                                name: tokens[i].clone(),
+                                integer: integer,
+                            });
+                        }
+
+                        expect_name = false;
+                    } else if tokens[i] == "," {
+                        expect_name = true;
+                    }
+
+                    i += 1;
+                }
+            },
+            _ => {},
+        }
+
+        i += 1;
+    }
+
+    inputs
+}
+
+/// Parses member declarations up to and past the closing `}`. `i` starts
+/// just after the opening `{`.
+fn parse_members(tokens: &Vec<String>, i: &mut usize, constants: &HashMap<String, String>) -> Vec<Member> {
+    let mut members = Vec::new();
+
+    while *i < tokens.len() && tokens[*i] != "}" {
+        members.extend(parse_declaration(tokens, i, constants));
+    }
+
+    // Past the } and the ; after it.
+    *i += 1;
+    if *i < tokens.len() && tokens[*i] == ";" {
+        *i += 1;
+    }
+
+    members
+}
+
+/// Parses `type name, other[size];` up to and past the `;`, stopping early at
+/// a `}`.
+fn parse_declaration(tokens: &Vec<String>, i: &mut usize, constants: &HashMap<String, String>) -> Vec<Member> {
+    let mut members = Vec::new();
+
+    // Skip qualifiers, layout(...) included.
+    while *i < tokens.len() && is_qualifier(&tokens[*i]) {
+        if tokens[*i] == "layout" {
+            while *i < tokens.len() && tokens[*i] != ")" {
+                *i += 1;
+            }
+        }
+        *i += 1;
+    }
+
+    if *i >= tokens.len() || tokens[*i] == "}" {
+        return members;
+    }
+
+    let utype = tokens[*i].clone();
+    *i += 1;
+
+    while *i < tokens.len() && tokens[*i] != ";" && tokens[*i] != "}" {
+        if tokens[*i] == "," {
+            *i += 1;
+            continue;
+        }
+
+        let name = tokens[*i].clone();
+        *i += 1;
+
+        let mut array_size = None;
+
+        // name[size]
+        if *i + 2 < tokens.len() && tokens[*i] == "[" && tokens[*i + 2] == "]" {
+            let size_token = &tokens[*i + 1];
+            let size = constants.get(size_token).unwrap_or(size_token);
+            array_size = size.parse::<usize>().ok();
+            *i += 3;
+        }
+
+        // Skip initializers, `= value`.
+        if *i < tokens.len() && tokens[*i] == "=" {
+            let mut depth = 0;
+            while *i < tokens.len() && !(depth == 0 && (tokens[*i] == "," || tokens[*i] == ";")) {
+                match tokens[*i].as_str() {
+                    "(" => depth += 1,
+                    ")" => depth -= 1,
+                    _   => (),
+                }
+                *i += 1;
+            }
+        }
+
+        members.push(Member {
+            utype: utype.clone(),
+            name: name,
+            array_size: array_size,
+        });
+    }
+
+    if *i < tokens.len() && tokens[*i] == ";" {
+        *i += 1;
+    }
+
+    members
+}
+
+fn expand_member(structs: &HashMap<String, Vec<Member>>, member: &Member, prefix: &str, params: &mut Vec<Param>) {
+    let name = format!("{}{}", prefix, member.name);
+
+    if let Some(struct_members) = structs.get(&member.utype) {
+        let element_prefixes = match member.array_size {
+            Some(size) => (0..size).map(|i| format!("{}[{}].", name, i)).collect(),
+            None       => vec![format!("{}.", name)],
+        };
+
+        for element_prefix in element_prefixes.iter() {
+            for struct_member in struct_members.iter() {
+                expand_member(structs, struct_member, element_prefix, params);
+            }
+        }
+
+        return;
+    }
+
+    let value = match default_param_value(&member.utype) {
+        Some(x) => x,
+        None    => return,
+    };
+
+    params.push(Param {
+        name: name,
+        value: match member.array_size {
+            Some(size) => ParamValue::Array(vec![value; size]),
+            None       => value,
+        },
+    });
+}
+
+fn is_qualifier(token: &str) -> bool {
+    match token {
+        "layout" | "highp" | "mediump" | "lowp" | "row_major" | "column_major" | "precise" => true,
+        _ => false,
+    }
+}
+
+fn is_interpolation_qualifier(token: &str) -> bool {
+    match token {
+        "flat" | "smooth" | "noperspective" | "centroid" | "invariant" => true,
+        _ => false,
+    }
+}
+
+fn is_integer_type(utype: &str) -> bool {
+    utype == "int" || utype == "uint" || utype.starts_with("ivec") || utype.starts_with("uvec")
+}
+
+/// `#define NAME value` lines, for array sizes.
+fn collect_defines(src: &str) -> HashMap<String, String> {
+    let mut defines = HashMap::new();
+
+    for line in active_lines(src) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        if words.len() == 3 && words[0] == "#define" {
+            defines.insert(words[1].to_string(), words[2].to_string());
+        }
+    }
+
+    defines
+}
+
+fn default_param_value(utype: &str) -> Option<ParamValue> {
+    let value = match utype {
+        "float"  => ParamValue::F32(0.0),
+        "vec2"   => ParamValue::Vec2(Vec2f::new(0.0, 0.0)),
+        "vec3"   => ParamValue::Vec3(Vec3f::new(0.0, 0.0, 0.0)),
+        "vec4"   => ParamValue::Vec4(Vec4f::new(0.0, 0.0, 0.0, 0.0)),
+        "int"    => ParamValue::I32(0),
+        "ivec2"  => ParamValue::IVec2(Vec2i::new(0, 0)),
+        "ivec3"  => ParamValue::IVec3(Vec3i::new(0, 0, 0)),
+        "ivec4"  => ParamValue::IVec4(Vec4i::new(0, 0, 0, 0)),
+        "uint"   => ParamValue::U32(0),
+        "uvec2"  => ParamValue::UVec2(Vec2u::new(0, 0)),
+        "uvec3"  => ParamValue::UVec3(Vec3u::new(0, 0, 0)),
+        "uvec4"  => ParamValue::UVec4(Vec4u::new(0, 0, 0, 0)),
+        "bool"   => ParamValue::Bool(false),
+        "bvec2"  => ParamValue::BVec2([false; 2]),
+        "bvec3"  => ParamValue::BVec3([false; 3]),
+        "bvec4"  => ParamValue::BVec4([false; 4]),
+        "mat2" | "mat2x2" => ParamValue::Mat2(Mat2f::identity()),
+        "mat3" | "mat3x3" => ParamValue::Mat3(Mat3f::identity()),
+        "mat4" | "mat4x4" => ParamValue::Mat4(Mat4f::identity()),
+        "mat2x3" => ParamValue::Mat2x3([Vec3f::new(1.0, 0.0, 0.0), Vec3f::new(0.0, 1.0, 0.0)]),
+        "mat2x4" => ParamValue::Mat2x4([Vec4f::new(1.0, 0.0, 0.0, 0.0), Vec4f::new(0.0, 1.0, 0.0, 0.0)]),
+        "mat3x2" => ParamValue::Mat3x2([Vec2f::new(1.0, 0.0), Vec2f::new(0.0, 1.0), Vec2f::new(0.0, 0.0)]),
+        "mat3x4" => ParamValue::Mat3x4([Vec4f::new(1.0, 0.0, 0.0, 0.0), Vec4f::new(0.0, 1.0, 0.0, 0.0), Vec4f::new(0.0, 0.0, 1.0, 0.0)]),
+        "mat4x2" => ParamValue::Mat4x2([Vec2f::new(1.0, 0.0), Vec2f::new(0.0, 1.0), Vec2f::new(0.0, 0.0), Vec2f::new(0.0, 0.0)]),
+        "mat4x3" => ParamValue::Mat4x3([Vec3f::new(1.0, 0.0, 0.0), Vec3f::new(0.0, 1.0, 0.0), Vec3f::new(0.0, 0.0, 1.0), Vec3f::new(0.0, 0.0, 0.0)]),
+        _        => return None,
+    };
+
+    Some(value)
+}
+
+fn sampler_param_value(utype: &str) -> Option<ParamValue> {
+    let value = match utype {
+        "sampler2D"            => ParamValue::Texture2D(0),
+        "samplerCube"          => ParamValue::TextureCube(0),
+        "sampler2DArray"       => ParamValue::Texture2DArray(0),
+        "sampler3D"            => ParamValue::Texture3D(0),
+        "sampler2DShadow"      => ParamValue::Texture2DShadow(0),
+        "samplerCubeShadow"    => ParamValue::TextureCubeShadow(0),
+        "sampler2DArrayShadow" => ParamValue::Texture2DArrayShadow(0),
+        _                      => return None,
+    };
+
+    Some(value)
+}
+
+/// An `#if`, `#ifdef` or `#ifndef` being read.
+struct Conditional {
+    /// Whether the lines around the conditional are kept.
+    parent_active: bool,
+    /// Whether the lines of the current branch are kept.
+    active: bool,
+    /// Whether an earlier branch was kept, which rules out the rest.
+    taken: bool,
+}
+
+/// The lines of `src` outside of `#if`/`#ifdef`/`#ifndef` branches that
+/// aren't compiled, going by the `#define`s above them. Shaders see the
+/// material's defines as `#define` lines added by the preprocessor, so this
+/// leaves the inputs and uniforms GL would see. Conditions that can't be
+/// evaluated count as true.
+fn active_lines(src: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut defines: HashMap<String, String> = HashMap::new();
+    let mut conditionals: Vec<Conditional> = Vec::new();
+
+    for line in src.lines() {
+        let active = conditionals.last().map_or(true, |c| c.active);
+        let trimmed = line.trim_left();
+
+        if !trimmed.starts_with("#") {
+            if active {
+                lines.push(line);
+            }
+            continue;
+        }
+
+        let directive = match trimmed[1..].find("//") {
+            Some(x) => trimmed[1..][..x].trim(),
+            None    => trimmed[1..].trim(),
+        };
+        let (keyword, rest) = match directive.find(char::is_whitespace) {
+            Some(x) => (&directive[..x], directive[x..].trim()),
+            None    => (directive, ""),
+        };
+
+        match keyword {
+            "ifdef" | "ifndef" | "if" => {
+                let condition = match keyword {
+                    "ifdef"  => defines.contains_key(rest),
+                    "ifndef" => !defines.contains_key(rest),
+                    _        => eval_condition(rest, &defines),
+                };
+
+                conditionals.push(Conditional {
+                    parent_active: active,
+                    active: active && condition,
+                    taken: condition,
+                });
+            },
+            "elif" => {
+                if let Some(c) = conditionals.last_mut() {
+                    let condition = !c.taken && eval_condition(rest, &defines);
+                    c.active = c.parent_active && condition;
+                    c.taken = c.taken || condition;
+                }
+            },
+            "else" => {
+                if let Some(c) = conditionals.last_mut() {
+                    c.active = c.parent_active && !c.taken;
+                    c.taken = true;
+                }
+            },
+            "endif" => {
+                conditionals.pop();
+            },
+            "define" if active => {
+                let name_end = rest.find(|c: char| c.is_whitespace() || c == '(').unwrap_or(rest.len());
+                defines.insert(rest[..name_end].to_string(), rest[name_end..].trim().to_string());
+                lines.push(line);
+            },
+            "undef" if active => {
+                defines.remove(rest);
+                lines.push(line);
+            },
+            _ => if active {
+                lines.push(line);
+            },
+        }
+    }
+
+    lines
+}
+
+/// Evaluates an `#if` expression made of integers, defines, `defined`,
+/// `!`, comparisons, `&&`, `||` and parentheses.
+fn eval_condition(expr: &str, defines: &HashMap<String, String>) -> bool {
+    let tokens = tokenize_condition(expr);
+    let mut i = 0;
+
+    match parse_or(&tokens, &mut i, defines, 0) {
+        Ok(value) if i == tokens.len() => value != 0,
+        _ => true,
+    }
+}
+
+fn tokenize_condition(expr: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    let chars: Vec<char> = expr.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().cloned().collect());
+        } else {
+            let pair: String = chars[i..cmp::min(i + 2, chars.len())].iter().cloned().collect();
+            match &pair[..] {
+                "&&" | "||" | "==" | "!=" | "<=" | ">=" => {
+                    tokens.push(pair);
+                    i += 2;
+                },
+                _ => {
+                    tokens.push(c.to_string());
+                    i += 1;
+                },
+            }
+        }
+    }
+
+    tokens
+}
+
+/// Defines may expand to other defines, but not forever.
+const MAX_CONDITION_DEPTH: usize = 16;
+
+fn parse_or(tokens: &[String], i: &mut usize, defines: &HashMap<String, String>, depth: usize) -> Result<i64, String> {
+    let mut value = try!(parse_and(tokens, i, defines, depth));
+
+    while *i < tokens.len() && tokens[*i] == "||" {
+        *i += 1;
+        let rhs = try!(parse_and(tokens, i, defines, depth));
+        value = ((value != 0) || (rhs != 0)) as i64;
+    }
+
+    Ok(value)
+}
+
+fn parse_and(tokens: &[String], i: &mut usize, defines: &HashMap<String, String>, depth: usize) -> Result<i64, String> {
+    let mut value = try!(parse_comparison(tokens, i, defines, depth));
+
+    while *i < tokens.len() && tokens[*i] == "&&" {
+        *i += 1;
+        let rhs = try!(parse_comparison(tokens, i, defines, depth));
+        value = ((value != 0) && (rhs != 0)) as i64;
+    }
+
+    Ok(value)
+}
+
+fn parse_comparison(tokens: &[String], i: &mut usize, defines: &HashMap<String, String>, depth: usize) -> Result<i64, String> {
+    let lhs = try!(parse_unary(tokens, i, defines, depth));
+
+    if *i >= tokens.len() {
+        return Ok(lhs);
+    }
+
+    let op = tokens[*i].clone();
+    match &op[..] {
+        "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+            *i += 1;
+            let rhs = try!(parse_unary(tokens, i, defines, depth));
+            let result = match &op[..] {
+                "==" => lhs == rhs,
+                "!=" => lhs != rhs,
+                "<"  => lhs < rhs,
+                ">"  => lhs > rhs,
+                "<=" => lhs <= rhs,
+                _    => lhs >= rhs,
+            };
+            Ok(result as i64)
+        },
+        _ => Ok(lhs),
+    }
+}
+
+fn parse_unary(tokens: &[String], i: &mut usize, defines: &HashMap<String, String>, depth: usize) -> Result<i64, String> {
+    if *i >= tokens.len() {
+        return Err("unexpected end of condition".to_string());
+    }
+
+    let token = tokens[*i].clone();
+    *i += 1;
+
+    match &token[..] {
+        "!" => parse_unary(tokens, i, defines, depth).map(|value| (value == 0) as i64),
+        "-" => parse_unary(tokens, i, defines, depth).map(|value| -value),
+        "(" => {
+            let value = try!(parse_or(tokens, i, defines, depth));
+            if *i < tokens.len() && tokens[*i] == ")" {
+                *i += 1;
+                Ok(value)
+            } else {
+                Err("missing )".to_string())
+            }
+        },
+        // defined NAME or defined(NAME)
+        "defined" => {
+            let parens = *i < tokens.len() && tokens[*i] == "(";
+            if parens {
+                *i += 1;
+            }
+
+            if *i >= tokens.len() {
+                return Err("defined needs a name".to_string());
+            }
+
+            let value = defines.contains_key(&tokens[*i]) as i64;
+            *i += 1;
+
+            if parens {
+                if *i < tokens.len() && tokens[*i] == ")" {
+                    *i += 1;
+                } else {
+                    return Err("missing ) after defined".to_string());
+                }
+            }
+
+            Ok(value)
+        },
+        _ if token.starts_with(|c: char| c.is_digit(10)) => parse_integer(&token),
+        _ if token.starts_with(|c: char| c.is_alphabetic() || c == '_') => {
+            // Undefined names are 0, like in C.
+            match defines.get(&token) {
+                Some(value) if depth < MAX_CONDITION_DEPTH => {
+                    let value_tokens = tokenize_condition(value);
+                    let mut j = 0;
+                    let value = try!(parse_or(&value_tokens, &mut j, defines, depth + 1));
+                    if j != value_tokens.len() {
+                        return Err(format!("{} isn't a number", token));
+                    }
+                    Ok(value)
+                },
+                Some(_) => Err(format!("{} expands too deep", token)),
+                None    => Ok(0),
+            }
+        },
+        _ => Err(format!("unexpected {}", token)),
+    }
+}
+
+fn parse_integer(token: &str) -> Result<i64, String> {
+    let digits = token.trim_right_matches(|c| c == 'u' || c == 'U' || c == 'l' || c == 'L');
+
+    let result = if digits.starts_with("0x") || digits.starts_with("0X") {
+        i64::from_str_radix(&digits[2..], 16)
+    } else {
+        digits.parse::<i64>()
+    };
+
+    result.map_err(|_| format!("invalid number {}", token))
+}
+
+/// Splits GLSL into identifiers/numbers and single character punctuation,
+/// dropping comments, preprocessor lines and branches that aren't compiled.
+fn tokenize_glsl(src: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+
+    for line in active_lines(src) {
+        let line = match line.find("//") {
+            Some(x) => &line[..x],
+            None    => line,
+        };
+
+        if line.trim_left().starts_with("#") {
+            continue;
+        }
+
+        let mut current = String::new();
+
+        for c in line.chars() {
+            if c.is_alphanumeric() || c == '_' || c == '.' {
+                current.push(c);
+                continue;
+            }
+
+            if !current.is_empty() {
+                tokens.push(mem::replace(&mut current, String::new()));
+            }
+
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        }
+
+        if !current.is_empty() {
+            tokens.push(current);
+        }
+    }
+
+    // Strip block comments now that everything is tokenized.
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut in_comment = false;
+    let mut i = 0;
+
+    while i < tokens.len() {
+        if !in_comment && tokens[i] == "/" && i + 1 < tokens.len() && tokens[i + 1] == "*" {
+            in_comment = true;
+            i += 2;
+            continue;
+        }
+
+        if in_comment && tokens[i] == "*" && i + 1 < tokens.len() && tokens[i + 1] == "/" {
+            in_comment = false;
+            i += 2;
+            continue;
+        }
+
+        if !in_comment {
+            result.push(tokens[i].clone());
+        }
+
+        i += 1;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group_names(params: &ShaderParams) -> Vec<String> {
+        params.groups().iter().map(|group| group.name.clone()).collect()
+    }
+
+    fn param_names(params: &ShaderParams, group: &str) -> Vec<String> {
+        let group = params.groups().iter().find(|g| g.name == group).unwrap();
+        group.params.iter().map(|param| param.name.clone()).collect()
+    }
+
+    #[test]
+    fn reflects_uniform_blocks_defaults_and_samplers() {
+        let src = "#version 400
+uniform Material {
+    vec4 tint;
+    layout(row_major) mat4 transform;
+};
+uniform float intensity = 1.0;
+uniform sampler2D albedo;
+uniform samplerCube sky;
+void main() {}
+";
+        let params = reflect_shader_params(&[src]);
+
+        assert_eq!(group_names(&params), vec!["Material", "_default", "_textures"]);
+        assert_eq!(param_names(&params, "Material"), vec!["tint", "transform"]);
+        assert_eq!(param_names(&params, "_default"), vec!["intensity"]);
+        assert_eq!(param_names(&params, "_textures"), vec!["albedo", "sky"]);
+
+        assert!(params.get("tint").unwrap().same_type(&ParamValue::Vec4(Vec4f::new(0.0, 0.0, 0.0, 0.0))));
+        assert!(params.get("sky").unwrap().same_type(&ParamValue::TextureCube(0)));
+    }
+
+    #[test]
+    fn flattens_structs_and_arrays() {
+        let src = "#define NUM_LIGHTS 2
+struct Light {
+    vec3 color;
+    float radius;
+};
+uniform Lights {
+    Light lights[NUM_LIGHTS];
+    Light sun;
+    float weights[3];
+};
+";
+        let params = reflect_shader_params(&[src]);
+
+        assert_eq!(param_names(&params, "Lights"), vec![
+            "lights[0].color", "lights[0].radius",
+            "lights[1].color", "lights[1].radius",
+            "sun.color", "sun.radius",
+            "weights",
+        ]);
+
+        match *params.get("weights").unwrap() {
+            ParamValue::Array(ref elements) => assert_eq!(elements.len(), 3),
+            ref value => panic!("weights reflected as {:?}", value),
+        }
+    }
+
+    #[test]
+    fn skips_shared_blocks_comments_and_duplicates() {
+        let vert = "uniform ViewData {
+    mat4 view;
+};
+// uniform float commented;
+/* uniform float
+   block_commented; */
+uniform float shared_value;
+uniform sampler2D tex;
+";
+        let frag = "uniform float shared_value;
+uniform sampler2D tex;
+";
+        let params = reflect_shader_params(&[vert, frag]);
+
+        assert_eq!(group_names(&params), vec!["_default", "_textures"]);
+        assert_eq!(param_names(&params, "_default"), vec!["shared_value"]);
+        assert_eq!(param_names(&params, "_textures"), vec!["tex"]);
+    }
+
+    fn input(name: &str, integer: bool) -> VertexInput {
+        VertexInput {
+            name: name.to_string(),
+            integer: integer,
+        }
+    }
+
+    #[test]
+    fn reflects_vertex_inputs() {
+        let src = "#version 400
+layout(location = 0) in vec3 position;
+flat in ivec4 bone_ids;
+in vec4 weights, color;
+attribute vec2 uv;
+out vec3 normal;
+uniform mat4 model;
+vec3 transform(in vec3 v, in mat4 m) {
+    return (m * vec4(v, 1.0)).xyz;
+}
+void main() {}
+";
+        assert_eq!(reflect_vertex_inputs(src), vec![
+            input("position", false),
+            input("bone_ids", true),
+            input("weights", false),
+            input("color", false),
+            input("uv", false),
+        ]);
+    }
+
+    #[test]
+    fn vertex_inputs_follow_conditionals() {
+        let src = "#version 400
+#define USE_SKINNING 1
+#define NUM_UVS 1
+in vec3 position;
+#ifdef USE_NORMALS
+in vec3 normal;
+#endif
+#ifndef USE_NORMALS
+in vec3 flat_normal;
+#endif
+#if defined(USE_SKINNING) && USE_SKINNING
+in uvec4 bone_ids;
+#endif
+#if NUM_UVS > 1
+in vec2 uv1;
+#elif NUM_UVS == 1
+in vec2 uv0;
+#else
+in vec2 no_uv;
+#endif
+";
+        assert_eq!(reflect_vertex_inputs(src), vec![
+            input("position", false),
+            input("flat_normal", false),
+            input("bone_ids", true),
+            input("uv0", false),
+        ]);
+    }
+
+    #[test]
+    fn uniforms_follow_conditionals() {
+        let src = "#ifdef USE_FOG
+uniform vec4 fog_color;
+#else
+uniform float brightness;
+#endif
+";
+        let params = reflect_shader_params(&[src]);
+        assert_eq!(param_names(&params, "_default"), vec!["brightness"]);
+
+        let src = format!("#define USE_FOG\n{}", src);
+        let params = reflect_shader_params(&[&src]);
+        assert_eq!(param_names(&params, "_default"), vec!["fog_color"]);
+    }
+
+    #[test]
+    fn evaluates_conditions() {
+        let mut defines = HashMap::new();
+        defines.insert("A".to_string(), "2".to_string());
+        defines.insert("B".to_string(), "A".to_string());
+
+        assert!(eval_condition("defined A && !defined(C)", &defines));
+        assert!(eval_condition("B == 2 || 0", &defines));
+        assert!(eval_condition("(-1 < 0x1) && 2u == A", &defines));
+        assert!(!eval_condition("C", &defines));
+        assert!(!eval_condition("A < 2", &defines));
+        // Unknown syntax keeps the branch.
+        assert!(eval_condition("A +", &defines));
+    }
+}
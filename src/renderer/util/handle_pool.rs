@@ -0,0 +1,246 @@
+use std::ops::{Index, IndexMut};
+use std::marker::PhantomData;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// A slot index plus the generation of the value it was handed out for.
+/// Slots are reused once their value is removed, and the generation is
+/// bumped when that happens, so a handle that outlives its value is
+/// recognised instead of reaching whatever took its place.
+///
+/// Handles are typed by what they point at, so a handle from one pool can't
+/// be used with a pool of something else.
+pub struct PoolHandle<T> {
+    index: u32,
+    generation: u32,
+    marker: PhantomData<fn() -> T>,
+}
+
+// Derived impls would require T to implement these too.
+impl<T> Clone for PoolHandle<T> {
+    fn clone(&self) -> PoolHandle<T> {
+        *self
+    }
+}
+
+impl<T> Copy for PoolHandle<T> {}
+
+impl<T> PartialEq for PoolHandle<T> {
+    fn eq(&self, other: &PoolHandle<T>) -> bool {
+        self.index == other.index && self.generation == other.generation
+    }
+}
+
+impl<T> Eq for PoolHandle<T> {}
+
+impl<T> Hash for PoolHandle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.generation.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for PoolHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PoolHandle({}, generation {})", self.index, self.generation)
+    }
+}
+
+impl<T> PoolHandle<T> {
+    fn new(index: u32, generation: u32) -> PoolHandle<T> {
+        PoolHandle {
+            index: index,
+            generation: generation,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Packs the handle into a single integer, for public handle types
+    /// such as `TextureParamHandle`. Generations start at 1, so 0 never
+    /// refers to a live value.
+    pub fn to_bits(&self) -> u64 {
+        ((self.generation as u64) << 32) | self.index as u64
+    }
+
+    pub fn from_bits(bits: u64) -> PoolHandle<T> {
+        PoolHandle::new(bits as u32, (bits >> 32) as u32)
+    }
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+pub struct HandlePool<T> {
+    slots: Vec<Slot<T>>,
+    free_slots: Vec<u32>,
+}
+
+impl<T> HandlePool<T> {
+    pub fn new() -> HandlePool<T> {
+        HandlePool {
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> PoolHandle<T> {
+        match self.free_slots.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.value = Some(value);
+
+                PoolHandle::new(index, slot.generation)
+            },
+            None => {
+                self.slots.push(Slot {
+                    generation: 1,
+                    value: Some(value),
+                });
+
+                PoolHandle::new((self.slots.len() - 1) as u32, 1)
+            },
+        }
+    }
+
+    /// Takes the value out, freeing its slot for reuse. None if the handle
+    /// is stale.
+    pub fn remove(&mut self, handle: PoolHandle<T>) -> Option<T> {
+        if !self.contains(handle) {
+            return None;
+        }
+
+        let slot = &mut self.slots[handle.index()];
+        slot.generation = slot.generation.wrapping_add(1).max(1);
+        self.free_slots.push(handle.index);
+
+        slot.value.take()
+    }
+
+    pub fn contains(&self, handle: PoolHandle<T>) -> bool {
+        self.get(handle).is_some()
+    }
+
+    pub fn get(&self, handle: PoolHandle<T>) -> Option<&T> {
+        match self.slots.get(handle.index()) {
+            Some(slot) if slot.generation == handle.generation => slot.value.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: PoolHandle<T>) -> Option<&mut T> {
+        match self.slots.get_mut(handle.index()) {
+            Some(slot) if slot.generation == handle.generation => slot.value.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Number of live values.
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free_slots.len()
+    }
+
+    /// Handles of every live value.
+    pub fn handles(&self) -> Vec<PoolHandle<T>> {
+        self.slots.iter().enumerate()
+            .filter(|&(_, slot)| slot.value.is_some())
+            .map(|(i, slot)| PoolHandle::new(i as u32, slot.generation))
+            .collect()
+    }
+
+    pub fn values<'a>(&'a self) -> Box<Iterator<Item=&'a T> + 'a> {
+        Box::new(self.slots.iter().filter_map(|slot| slot.value.as_ref()))
+    }
+}
+
+/// Panics on invalid or stale handles. Use `get` where a handle may have
+/// outlived its value.
+impl<T> Index<PoolHandle<T>> for HandlePool<T> {
+    type Output = T;
+
+    fn index(&self, handle: PoolHandle<T>) -> &T {
+        match self.get(handle) {
+            Some(value) => value,
+            None        => panic!("invalid handle {:?}, its value has been destroyed or never existed", handle),
+        }
+    }
+}
+
+impl<T> IndexMut<PoolHandle<T>> for HandlePool<T> {
+    fn index_mut(&mut self, handle: PoolHandle<T>) -> &mut T {
+        match self.get_mut(handle) {
+            Some(value) => value,
+            None        => panic!("invalid handle {:?}, its value has been destroyed or never existed", handle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_bumps_generation_and_reuses_slot() {
+        let mut pool = HandlePool::new();
+        let first = pool.insert("first");
+        assert_eq!(first.generation(), 1);
+
+        assert_eq!(pool.remove(first), Some("first"));
+        assert_eq!(pool.len(), 0);
+
+        let second = pool.insert("second");
+        assert_eq!(second.index(), first.index());
+        assert_eq!(second.generation(), 2);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn stale_handles_reach_nothing() {
+        let mut pool = HandlePool::new();
+        let stale = pool.insert(1);
+        pool.remove(stale);
+        let live = pool.insert(2);
+
+        assert!(!pool.contains(stale));
+        assert_eq!(pool.get(stale), None);
+        assert_eq!(pool.get_mut(stale), None);
+        assert_eq!(pool.remove(stale), None);
+
+        assert_eq!(pool[live], 2);
+        assert_eq!(pool.handles(), vec![live]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn indexing_with_stale_handle_panics() {
+        let mut pool = HandlePool::new();
+        let handle = pool.insert(1);
+        pool.remove(handle);
+        pool[handle];
+    }
+
+    #[test]
+    fn bits_round_trip() {
+        let mut pool = HandlePool::new();
+        let first = pool.insert(1);
+        pool.remove(first);
+        let handle = pool.insert(2);
+
+        let bits = handle.to_bits();
+        assert!(bits != 0);
+        assert!(bits != first.to_bits());
+
+        let back: PoolHandle<i32> = PoolHandle::from_bits(bits);
+        assert_eq!(back, handle);
+        assert_eq!(pool[back], 2);
+    }
+}
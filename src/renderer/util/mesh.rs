@@ -70,7 +70,7 @@ pub fn load_meshes_from_file(path: &Path, options: &MeshOptions) -> Result<Vec<M
             Vec3f::new(pos.x, pos.y, pos.z)
         }).collect();
 
-        layout.add_element(options.position_attr_name.clone(), VertexElementType::F32F32F32);
+        try!(layout.add_element(options.position_attr_name.clone(), VertexElementType::F32F32F32));
 
         let mut normals: Vec<Vec3f> = Vec::new();
 
@@ -79,7 +79,7 @@ pub fn load_meshes_from_file(path: &Path, options: &MeshOptions) -> Result<Vec<M
                 Vec3f::new(norm.x, norm.y, norm.z)
             }).collect();
 
-            layout.add_element(options.normal_attr_name.clone(), VertexElementType::F32F32F32);
+            try!(layout.add_element(options.normal_attr_name.clone(), VertexElementType::F32F32F32));
         }
 
         let mut tex_coords: Vec<Vec2f> = Vec::new();
@@ -91,7 +91,7 @@ pub fn load_meshes_from_file(path: &Path, options: &MeshOptions) -> Result<Vec<M
                 Vec2f::new(coord.x, coord.y)
             }).collect();
 
-            layout.add_element(options.tex_coord_attr_name.clone(), VertexElementType::F32F32);
+            try!(layout.add_element(options.tex_coord_attr_name.clone(), VertexElementType::F32F32));
         }
 
         let mut tangents: Vec<Vec3f> = Vec::new();
@@ -106,8 +106,8 @@ pub fn load_meshes_from_file(path: &Path, options: &MeshOptions) -> Result<Vec<M
                 Vec3f::new(bitang.x, bitang.y, bitang.z)
             }).collect();
 
-            layout.add_element(options.tangent_attr_name.clone(), VertexElementType::F32F32F32);
-            layout.add_element(options.bitangent_attr_name.clone(), VertexElementType::F32F32F32);
+            try!(layout.add_element(options.tangent_attr_name.clone(), VertexElementType::F32F32F32));
+            try!(layout.add_element(options.bitangent_attr_name.clone(), VertexElementType::F32F32F32));
         }
 
         let mut interleaved: Vec<f32> = Vec::new();
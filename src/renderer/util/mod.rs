@@ -1 +1,6 @@
-pub mod mesh;
\ No newline at end of file
+pub mod mesh;
+pub mod glsl;
+pub mod preprocessor;
+pub mod file_watcher;
+pub mod texture_loader;
+pub mod handle_pool;
@@ -0,0 +1,427 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use renderer::error::{ShaderError, ShaderStage};
+
+/// Includes can't be nested deeper than this.
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+/// Where a line of preprocessed source came from.
+#[derive(Clone, Copy, Debug)]
+pub struct SourceLine {
+    /// Index into `PreprocessedSource::files`.
+    pub file: usize,
+    /// 1 based.
+    pub line: usize,
+}
+
+pub struct PreprocessedSource {
+    pub source: String,
+    /// Name of the original source followed by every included file, and
+    /// `<defines>` for the injected defines.
+    pub files: Vec<String>,
+    /// One entry per line of `source`.
+    pub lines: Vec<SourceLine>,
+    /// Every file that was included, once each.
+    pub includes: Vec<PathBuf>,
+}
+
+impl PreprocessedSource {
+    /// Maps a 1 based line of the preprocessed source back to the file and
+    /// line it came from.
+    pub fn location(&self, line: usize) -> Option<(&str, usize)> {
+        if line == 0 {
+            return None;
+        }
+
+        self.lines.get(line - 1).map(|source_line| (self.files[source_line.file].as_str(), source_line.line))
+    }
+
+    /// The preprocessed lines from the same file as `line`, within `radius`
+    /// lines of it, along with their original line numbers.
+    pub fn context(&self, line: usize, radius: usize) -> Vec<(usize, String)> {
+        let mut context = Vec::new();
+
+        if line == 0 || line > self.lines.len() {
+            return context;
+        }
+
+        let file = self.lines[line - 1].file;
+        let first = if line > radius { line - radius } else { 1 };
+
+        for (i, text) in self.source.lines().enumerate().skip(first - 1).take(line + radius - first + 1) {
+            let source_line = self.lines[i];
+            if source_line.file == file {
+                context.push((source_line.line, text.to_string()));
+            }
+        }
+
+        context
+    }
+}
+
+/// Resolves `#include "file.glsl"` directives and injects `#define`s.
+///
+/// Includes are looked up relative to the including file first, then in each
+/// search path in the order they were added. A file containing `#pragma once`
+/// is only included the first time.
+pub struct GlslPreprocessor {
+    search_paths: Vec<PathBuf>,
+}
+
+struct PreprocessState {
+    output: String,
+    files: Vec<String>,
+    lines: Vec<SourceLine>,
+    /// Files that contained `#pragma once`.
+    once: Vec<PathBuf>,
+    /// Files currently being included, to report cycles.
+    stack: Vec<PathBuf>,
+    includes: Vec<PathBuf>,
+}
+
+impl PreprocessState {
+    fn push_line(&mut self, text: &str, file: usize, line: usize) {
+        self.output.push_str(text);
+        self.output.push('\n');
+        self.lines.push(SourceLine {
+            file: file,
+            line: line,
+        });
+    }
+}
+
+impl GlslPreprocessor {
+    pub fn new() -> GlslPreprocessor {
+        GlslPreprocessor {
+            search_paths: Vec::new(),
+        }
+    }
+
+    pub fn add_search_path(&mut self, path: &Path) {
+        if !self.search_paths.iter().any(|p| p == path) {
+            self.search_paths.push(path.to_path_buf());
+        }
+    }
+
+    /// `name` is used for the original source in the line map.
+    pub fn preprocess(&self, src: &str, name: &str, defines: &[(String, String)]) -> Result<PreprocessedSource, String> {
+        let mut state = PreprocessState {
+            output: String::new(),
+            files: vec![name.to_string()],
+            lines: Vec::new(),
+            once: Vec::new(),
+            stack: Vec::new(),
+            includes: Vec::new(),
+        };
+
+        let src_lines: Vec<&str> = src.lines().collect();
+
+        // #version has to come before anything else, defines included.
+        // Only blank lines and comments may come before it.
+        let mut version_line = None;
+        for (i, line) in src_lines.iter().enumerate() {
+            let trimmed = line.trim();
+
+            if trimmed.starts_with("#version") {
+                version_line = Some(i);
+                break;
+            }
+
+            if !trimmed.is_empty() && !trimmed.starts_with("//") {
+                break;
+            }
+        }
+
+        let body_start = match version_line {
+            Some(i) => {
+                for (j, line) in src_lines[..(i + 1)].iter().enumerate() {
+                    state.push_line(line, 0, j + 1);
+                }
+                i + 1
+            },
+            None => 0,
+        };
+
+        if !defines.is_empty() {
+            state.files.push("<defines>".to_string());
+            let defines_file = state.files.len() - 1;
+
+            for (i, &(ref define_name, ref value)) in defines.iter().enumerate() {
+                state.push_line(&format!("#define {} {}", define_name, value), defines_file, i + 1);
+            }
+        }
+
+        try!(self.process_lines(&mut state, &src_lines[body_start..], body_start, 0, None));
+
+        Ok(PreprocessedSource {
+            source: state.output,
+            files: state.files,
+            lines: state.lines,
+            includes: state.includes,
+        })
+    }
+
+    /// Preprocesses the vertex and fragment sources of a program with the
+    /// same defines.
+    pub fn preprocess_program(&self, vert_src: &str, frag_src: &str, defines: &[(String, String)]) -> Result<(PreprocessedSource, PreprocessedSource), ShaderError> {
+        let vert = try!(self.preprocess(vert_src, "vertex shader", defines)
+            .map_err(|e| ShaderError::preprocess(ShaderStage::Vertex, &e)));
+        let frag = try!(self.preprocess(frag_src, "fragment shader", defines)
+            .map_err(|e| ShaderError::preprocess(ShaderStage::Fragment, &e)));
+
+        Ok((vert, frag))
+    }
+
+    /// The files included by either of a program's sources.
+    pub fn program_includes(vert: &PreprocessedSource, frag: &PreprocessedSource) -> Vec<PathBuf> {
+        let mut includes = vert.includes.clone();
+
+        for path in frag.includes.iter() {
+            if !includes.contains(path) {
+                includes.push(path.clone());
+            }
+        }
+
+        includes
+    }
+
+    fn process_lines(&self, state: &mut PreprocessState, lines: &[&str], first_line: usize, file: usize, dir: Option<&Path>) -> Result<(), String> {
+        for (i, line) in lines.iter().enumerate() {
+            let line_num = first_line + i + 1;
+
+            match parse_directive(line) {
+                Some(Directive::Include(include_name)) => {
+                    let path = match self.resolve_include(include_name, dir) {
+                        Some(x) => x,
+                        None    => return Err(format!("{}:{}: can't find include {}", state.files[file], line_num, include_name)),
+                    };
+
+                    try!(self.include_file(state, &path).map_err(|e| format!("{}:{}: {}", state.files[file], line_num, e)));
+                },
+                Some(Directive::PragmaOnce) => {
+                    // Handled by include_file, the original source can't be
+                    // included again anyway.
+                },
+                None => state.push_line(line, file, line_num),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn include_file(&self, state: &mut PreprocessState, path: &Path) -> Result<(), String> {
+        if state.once.iter().any(|p| p == path) {
+            return Ok(());
+        }
+
+        if state.stack.iter().any(|p| p == path) {
+            return Err(format!("{} includes itself: {}", path.display(), include_chain(&state.stack, path)));
+        }
+
+        if state.stack.len() >= MAX_INCLUDE_DEPTH {
+            return Err(format!("includes are nested more than {} deep: {}", MAX_INCLUDE_DEPTH, include_chain(&state.stack, path)));
+        }
+
+        let mut src = String::new();
+        let mut f = try!(File::open(path).map_err(|e| format!("failed to open {}: {}", path.display(), e)));
+        try!(f.read_to_string(&mut src).map_err(|e| format!("failed to read {}: {}", path.display(), e)));
+
+        let lines: Vec<&str> = src.lines().collect();
+
+        if !state.includes.iter().any(|p| p == path) {
+            state.includes.push(path.to_path_buf());
+        }
+
+        if lines.iter().any(|line| parse_directive(line).map_or(false, |d| d == Directive::PragmaOnce)) {
+            state.once.push(path.to_path_buf());
+        }
+
+        state.files.push(path.display().to_string());
+        let file = state.files.len() - 1;
+
+        state.stack.push(path.to_path_buf());
+        let result = self.process_lines(state, &lines, 0, file, path.parent());
+        state.stack.pop();
+
+        result
+    }
+
+    fn resolve_include(&self, name: &str, dir: Option<&Path>) -> Option<PathBuf> {
+        let candidates = dir.into_iter().map(|d| d.to_path_buf()).chain(self.search_paths.iter().cloned());
+
+        for candidate_dir in candidates {
+            let path = candidate_dir.join(name);
+            if path.is_file() {
+                return Some(path);
+            }
+        }
+
+        None
+    }
+}
+
+/// `a.glsl -> b.glsl -> path`, the files being included on the way to `path`.
+fn include_chain(stack: &[PathBuf], path: &Path) -> String {
+    let mut chain: Vec<String> = stack.iter().map(|p| p.display().to_string()).collect();
+    chain.push(path.display().to_string());
+    chain.join(" -> ")
+}
+
+#[derive(PartialEq)]
+enum Directive<'a> {
+    Include(&'a str),
+    PragmaOnce,
+}
+
+fn parse_directive(line: &str) -> Option<Directive> {
+    let trimmed = line.trim();
+
+    if !trimmed.starts_with("#") {
+        return None;
+    }
+
+    let directive = trimmed[1..].trim_left();
+
+    if directive.starts_with("include") {
+        let rest = directive["include".len()..].trim();
+
+        if rest.len() >= 2 && rest.starts_with("\"") {
+            if let Some(end) = rest[1..].find('"') {
+                return Some(Directive::Include(&rest[1..(end + 1)]));
+            }
+        }
+
+        return None;
+    }
+
+    let words: Vec<&str> = directive.split_whitespace().collect();
+    if words.len() == 2 && words[0] == "pragma" && words[1] == "once" {
+        return Some(Directive::PragmaOnce);
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    /// An empty directory for a test's include files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rusto_preprocessor_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_file(dir: &Path, name: &str, contents: &str) {
+        let mut f = File::create(dir.join(name)).unwrap();
+        f.write_all(contents.as_bytes()).unwrap();
+    }
+
+    fn defines(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn injects_defines_after_version() {
+        let preprocessor = GlslPreprocessor::new();
+        let result = preprocessor.preprocess("// header\n#version 400\nvoid main() {}\n", "test", &defines(&[("FOO", "1")])).unwrap();
+
+        assert_eq!(result.source, "// header\n#version 400\n#define FOO 1\nvoid main() {}\n");
+        assert_eq!(result.location(3), Some(("<defines>", 1)));
+        assert_eq!(result.location(4), Some(("test", 3)));
+    }
+
+    #[test]
+    fn resolves_includes_from_search_paths() {
+        let dir = test_dir("search_paths");
+        write_file(&dir, "common.glsl", "float common;\n");
+
+        let mut preprocessor = GlslPreprocessor::new();
+        preprocessor.add_search_path(&dir);
+
+        let result = preprocessor.preprocess("#version 400\n#include \"common.glsl\"\nvoid main() {}\n", "test", &[]).unwrap();
+        let include_path = dir.join("common.glsl");
+
+        assert_eq!(result.source, "#version 400\nfloat common;\nvoid main() {}\n");
+        assert_eq!(result.location(2), Some((&include_path.display().to_string()[..], 1)));
+        assert_eq!(result.location(3), Some(("test", 3)));
+        assert_eq!(result.includes, vec![include_path]);
+    }
+
+    #[test]
+    fn resolves_nested_includes_relative_to_including_file() {
+        let dir = test_dir("relative");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        write_file(&dir, "lib/outer.glsl", "#include \"inner.glsl\"\nfloat outer;\n");
+        write_file(&dir, "lib/inner.glsl", "float inner;\n");
+
+        let mut preprocessor = GlslPreprocessor::new();
+        preprocessor.add_search_path(&dir);
+
+        let result = preprocessor.preprocess("#include \"lib/outer.glsl\"\n", "test", &[]).unwrap();
+
+        assert_eq!(result.source, "float inner;\nfloat outer;\n");
+        assert_eq!(result.includes, vec![dir.join("lib/outer.glsl"), dir.join("lib/inner.glsl")]);
+    }
+
+    #[test]
+    fn pragma_once_includes_a_file_once() {
+        let dir = test_dir("pragma_once");
+        write_file(&dir, "once.glsl", "#pragma once\nfloat once;\n");
+
+        let mut preprocessor = GlslPreprocessor::new();
+        preprocessor.add_search_path(&dir);
+
+        let result = preprocessor.preprocess("#include \"once.glsl\"\n#include \"once.glsl\"\n", "test", &[]).unwrap();
+
+        assert_eq!(result.source, "float once;\n");
+    }
+
+    #[test]
+    fn reports_missing_includes() {
+        let preprocessor = GlslPreprocessor::new();
+        let err = preprocessor.preprocess("\n#include \"missing.glsl\"\n", "test", &[]).err().unwrap();
+
+        assert_eq!(err, "test:2: can't find include missing.glsl");
+    }
+
+    #[test]
+    fn reports_include_cycles() {
+        let dir = test_dir("cycle");
+        write_file(&dir, "a.glsl", "#include \"b.glsl\"\n");
+        write_file(&dir, "b.glsl", "#include \"a.glsl\"\n");
+
+        let mut preprocessor = GlslPreprocessor::new();
+        preprocessor.add_search_path(&dir);
+
+        let err = preprocessor.preprocess("#include \"a.glsl\"\n", "test", &[]).err().unwrap();
+        let a = dir.join("a.glsl").display().to_string();
+        let b = dir.join("b.glsl").display().to_string();
+
+        assert!(err.ends_with(&format!("{} includes itself: {} -> {} -> {}", a, a, b, a)), "{}", err);
+    }
+
+    #[test]
+    fn reports_include_depth_limit() {
+        let dir = test_dir("depth");
+        for i in 0..(MAX_INCLUDE_DEPTH + 1) {
+            write_file(&dir, &format!("{}.glsl", i), &format!("#include \"{}.glsl\"\n", i + 1));
+        }
+        write_file(&dir, &format!("{}.glsl", MAX_INCLUDE_DEPTH + 1), "float deepest;\n");
+
+        let mut preprocessor = GlslPreprocessor::new();
+        preprocessor.add_search_path(&dir);
+
+        let err = preprocessor.preprocess("#include \"0.glsl\"\n", "test", &[]).err().unwrap();
+
+        assert!(err.contains(&format!("includes are nested more than {} deep: ", MAX_INCLUDE_DEPTH)), "{}", err);
+        assert!(!err.contains("includes itself"), "{}", err);
+    }
+}
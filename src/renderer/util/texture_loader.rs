@@ -0,0 +1,153 @@
+use image;
+use image::{DynamicImage, GenericImage, ImageDecoder, DecodingResult, ColorType};
+use image::png::PNGDecoder;
+use image::tiff::TIFFDecoder;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::mem;
+use std::f32::consts::PI;
+
+use renderer::texture::{TextureData, TextureFormat, ComponentType};
+
+/// Loads an image file into texture data.
+///
+/// PNG and TIFF files are decoded directly so that 16-bit images keep their
+/// precision. Everything else, and anything those decoders can't give us
+/// (palettes, 1, 2 and 4 bit images), goes through `image::open`, which
+/// expands it to 8 bits.
+pub fn load_texture_data(path: &Path) -> Result<TextureData, String> {
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .unwrap_or(String::new());
+
+    let decoded = match extension.as_str() {
+        "png"          => open_file(path).and_then(|f| decode(PNGDecoder::new(f))),
+        "tif" | "tiff" => open_file(path).and_then(|f| TIFFDecoder::new(f).ok()).and_then(decode),
+        _              => None,
+    };
+
+    if let Some(data) = decoded {
+        return Ok(data);
+    }
+
+    match image::open(path) {
+        Ok(image_data) => Ok(texture_data_from_image(&image_data)),
+        Err(e)         => Err(format!("failed to load texture {}: {}", path.display(), e)),
+    }
+}
+
+pub fn texture_data_from_image(image_data: &DynamicImage) -> TextureData {
+    let format = match *image_data {
+        DynamicImage::ImageLuma8(_)  => TextureFormat::Luminance,
+        DynamicImage::ImageLumaA8(_) => TextureFormat::LuminanceAlpha,
+        DynamicImage::ImageRgb8(_)   => TextureFormat::RGB,
+        DynamicImage::ImageRgba8(_)  => TextureFormat::RGBA,
+    };
+
+    let (width, height) = image_data.dimensions();
+
+    TextureData {
+        width: width,
+        height: height,
+        format: format,
+        component_type: ComponentType::U8,
+        bytes: image_data.raw_pixels(),
+    }
+}
+
+/// Resamples an equirectangular (latitude/longitude) image into the six
+/// faces of a cube map, in +X, -X, +Y, -Y, +Z, -Z order. The top row of
+/// the image is straight up. Uses nearest sampling, so `face_size` should
+/// be no more than a quarter of the image width to avoid aliasing.
+pub fn equirectangular_to_cube_faces(data: &TextureData, face_size: u32) -> Vec<TextureData> {
+    let texel_size = data.texel_size();
+    let row_size = data.row_size();
+    let mut faces = Vec::with_capacity(6);
+
+    for face in 0..6 {
+        let mut bytes = Vec::with_capacity(face_size as usize * face_size as usize * texel_size);
+
+        for y in 0..face_size {
+            for x in 0..face_size {
+                let s = 2.0 * (x as f32 + 0.5) / face_size as f32 - 1.0;
+                let t = 2.0 * (y as f32 + 0.5) / face_size as f32 - 1.0;
+
+                let (dx, dy, dz) = match face {
+                    0 => (1.0, -t, -s),
+                    1 => (-1.0, -t, s),
+                    2 => (s, 1.0, t),
+                    3 => (s, -1.0, -t),
+                    4 => (s, -t, 1.0),
+                    _ => (-s, -t, -1.0),
+                };
+
+                let len = (dx * dx + dy * dy + dz * dz).sqrt();
+                let u = 0.5 + dz.atan2(dx) / (2.0 * PI);
+                let v = 0.5 - (dy / len).asin() / PI;
+
+                let src_x = ((u * data.width as f32) as u32).min(data.width - 1) as usize;
+                let src_y = ((v * data.height as f32) as u32).min(data.height - 1) as usize;
+                let start = src_y * row_size + src_x * texel_size;
+
+                bytes.extend(data.bytes[start..(start + texel_size)].iter().cloned());
+            }
+        }
+
+        faces.push(TextureData {
+            width: face_size,
+            height: face_size,
+            format: data.format.clone(),
+            component_type: data.component_type,
+            bytes: bytes,
+        });
+    }
+
+    faces
+}
+
+fn open_file(path: &Path) -> Option<BufReader<File>> {
+    File::open(path).ok().map(BufReader::new)
+}
+
+/// None if the image isn't 8 or 16 bit gray, gray alpha, RGB or RGBA.
+fn decode<D: ImageDecoder>(mut decoder: D) -> Option<TextureData> {
+    let (width, height) = match decoder.dimensions() {
+        Ok(x)  => x,
+        Err(_) => return None,
+    };
+
+    let (format, bit_depth) = match decoder.colortype() {
+        Ok(ColorType::Gray(bits))  => (TextureFormat::Luminance, bits),
+        Ok(ColorType::GrayA(bits)) => (TextureFormat::LuminanceAlpha, bits),
+        Ok(ColorType::RGB(bits))   => (TextureFormat::RGB, bits),
+        Ok(ColorType::RGBA(bits))  => (TextureFormat::RGBA, bits),
+        _                          => return None,
+    };
+
+    let (component_type, bytes) = match (bit_depth, decoder.read_image()) {
+        (8, Ok(DecodingResult::U8(buf)))   => (ComponentType::U8, buf),
+        (16, Ok(DecodingResult::U16(buf))) => (ComponentType::U16, u16_bytes(buf.into_iter())),
+        // Some decoders hand 16-bit images over as big endian bytes.
+        (16, Ok(DecodingResult::U8(buf)))  => {
+            let values = buf.chunks(2).map(|pair| ((pair[0] as u16) << 8) | pair[1] as u16);
+            (ComponentType::U16, u16_bytes(values))
+        },
+        _ => return None,
+    };
+
+    TextureData::new(width, height, format, component_type, bytes).ok()
+}
+
+fn u16_bytes<I: Iterator<Item=u16>>(values: I) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    for value in values {
+        let native: [u8; 2] = unsafe { mem::transmute(value) };
+        bytes.extend(native.iter().cloned());
+    }
+
+    bytes
+}
@@ -1,11 +1,33 @@
 use std::mem::size_of;
 
-#[derive(Clone)]
+/// Type of a vertex attribute. The variants taking a number have that many
+/// components, from 1 to 4.
+///
+/// `Norm` types are integers the shader reads as floats scaled to 0-1, or
+/// -1-1 for signed types, such as packed colors. The plain integer types
+/// are read as `int`/`uint` vectors, for data such as bone indices.
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum VertexElementType {
     F32,
     F32F32,
     F32F32F32,
     F32F32F32F32,
+    /// Half floats.
+    F16(u8),
+    U8Norm(u8),
+    I8Norm(u8),
+    U16Norm(u8),
+    I16Norm(u8),
+    U8(u8),
+    I8(u8),
+    U16(u8),
+    I16(u8),
+    U32(u8),
+    I32(u8),
+    /// Four components packed into 32 bits, 10 bits each for x, y and z
+    /// from the lowest bit up and 2 for w. Good for normals and tangents.
+    I10_10_10_2Norm,
+    U10_10_10_2Norm,
 }
 
 impl VertexElementType {
@@ -15,6 +37,9 @@ impl VertexElementType {
             VertexElementType::F32F32 => size_of::<f32>() * 2,
             VertexElementType::F32F32F32 => size_of::<f32>() * 3,
             VertexElementType::F32F32F32F32 => size_of::<f32>() * 4,
+            VertexElementType::I10_10_10_2Norm |
+            VertexElementType::U10_10_10_2Norm => size_of::<u32>(),
+            _ => vtype.component_size() * vtype.get_num_components() as usize,
         }
     }
 
@@ -28,42 +53,261 @@ impl VertexElementType {
             VertexElementType::F32F32 => 2,
             VertexElementType::F32F32F32 => 3,
             VertexElementType::F32F32F32F32 => 4,
+            VertexElementType::F16(n) |
+            VertexElementType::U8Norm(n) | VertexElementType::I8Norm(n) |
+            VertexElementType::U16Norm(n) | VertexElementType::I16Norm(n) |
+            VertexElementType::U8(n) | VertexElementType::I8(n) |
+            VertexElementType::U16(n) | VertexElementType::I16(n) |
+            VertexElementType::U32(n) | VertexElementType::I32(n) => n as i32,
+            VertexElementType::I10_10_10_2Norm |
+            VertexElementType::U10_10_10_2Norm => 4,
+        }
+    }
+
+    /// Bytes per component, 0 for the packed types.
+    pub fn component_size(&self) -> usize {
+        match *self {
+            VertexElementType::F32 | VertexElementType::F32F32 |
+            VertexElementType::F32F32F32 | VertexElementType::F32F32F32F32 => 4,
+            VertexElementType::U8Norm(_) | VertexElementType::I8Norm(_) |
+            VertexElementType::U8(_) | VertexElementType::I8(_) => 1,
+            VertexElementType::F16(_) |
+            VertexElementType::U16Norm(_) | VertexElementType::I16Norm(_) |
+            VertexElementType::U16(_) | VertexElementType::I16(_) => 2,
+            VertexElementType::U32(_) | VertexElementType::I32(_) => 4,
+            VertexElementType::I10_10_10_2Norm |
+            VertexElementType::U10_10_10_2Norm => 0,
+        }
+    }
+
+    /// Whether shaders read the attribute as integers rather than floats.
+    pub fn is_integer(&self) -> bool {
+        match *self {
+            VertexElementType::U8(_) | VertexElementType::I8(_) |
+            VertexElementType::U16(_) | VertexElementType::I16(_) |
+            VertexElementType::U32(_) | VertexElementType::I32(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_normalized(&self) -> bool {
+        match *self {
+            VertexElementType::U8Norm(_) | VertexElementType::I8Norm(_) |
+            VertexElementType::U16Norm(_) | VertexElementType::I16Norm(_) |
+            VertexElementType::I10_10_10_2Norm |
+            VertexElementType::U10_10_10_2Norm => true,
+            _ => false,
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct VertexElement {
     pub vtype: VertexElementType,
     pub name: String,
+    /// Offset from the start of the vertex within its stream.
     pub offset: usize,
+    /// See `VertexLayoutDescription::add_element_to_stream`.
+    pub stream: usize,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct VertexLayoutDescription {
-    pub elements: Vec<VertexElement>
+    pub elements: Vec<VertexElement>,
+    /// 0 for per-vertex data. Otherwise the data is per instance, and each
+    /// element is used by this many instances before moving on to the next.
+    pub step_rate: u32,
 }
 
 impl VertexLayoutDescription {
     pub fn new() -> VertexLayoutDescription {
         VertexLayoutDescription {
             elements: Vec::new(),
+            step_rate: 0,
         }
     }
 
-    pub fn add_element(&mut self, name: String, vtype: VertexElementType) {
-        let offset: usize;
+    /// A layout for `InstanceBuffer`s, see `step_rate`.
+    pub fn new_per_instance(step_rate: u32) -> VertexLayoutDescription {
+        VertexLayoutDescription {
+            elements: Vec::new(),
+            step_rate: step_rate,
+        }
+    }
+
+    pub fn is_per_instance(&self) -> bool {
+        self.step_rate != 0
+    }
 
-        match self.elements.last() {
-            Some(x) => offset = x.offset + x.vtype.get_size_of(),
-            None    => offset = 0,
+    /// Bytes of data per vertex (or instance), over every stream.
+    pub fn stride(&self) -> usize {
+        self.elements.iter().map(|elem| elem.vtype.get_size_of()).sum()
+    }
+
+    /// Bytes from the start of one vertex to the next within a stream.
+    pub fn stream_stride(&self, stream: usize) -> usize {
+        self.elements.iter()
+            .filter(|elem| elem.stream == stream)
+            .map(|elem| elem.vtype.get_size_of())
+            .sum()
+    }
+
+    pub fn element(&self, name: &str) -> Option<&VertexElement> {
+        self.elements.iter().find(|elem| elem.name == name)
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.elements.iter().map(|elem| elem.stream + 1).max().unwrap_or(0)
+    }
+
+    /// Where `elem` of the `vertex`th vertex starts in a buffer holding
+    /// `vertex_count` vertices. Streams follow each other in the buffer.
+    pub fn element_offset(&self, elem: &VertexElement, vertex: usize, vertex_count: usize) -> usize {
+        let stream_start: usize = (0..elem.stream).map(|stream| self.stream_stride(stream) * vertex_count).sum();
+        stream_start + vertex * self.stream_stride(elem.stream) + elem.offset
+    }
+
+    /// Adds an element after the others, interleaved with them in stream 0.
+    pub fn add_element(&mut self, name: String, vtype: VertexElementType) -> Result<(), String> {
+        self.add_element_to_stream(0, name, vtype)
+    }
+
+    /// Adds an element after the others of its stream. Each stream is a
+    /// block of the vertex data holding its elements for every vertex, one
+    /// after the other, so attributes such as positions can be kept apart
+    /// from the rest.
+    ///
+    /// Fails if the type has a number of components other than 1 to 4.
+    pub fn add_element_to_stream(&mut self, stream: usize, name: String, vtype: VertexElementType) -> Result<(), String> {
+        let num_components = vtype.get_num_components();
+        if num_components < 1 || num_components > 4 {
+            return Err(format!("vertex element {} has {} components, it needs 1 to 4", name, num_components));
         }
 
+        let offset = self.stream_stride(stream);
+
         self.elements.push(VertexElement {
             vtype: vtype,
             name: name,
             offset: offset,
+            stream: stream,
         });
+
+        Ok(())
+    }
+}
+
+/// An attribute read by a vertex shader.
+#[derive(Clone, PartialEq, Debug)]
+pub struct VertexInput {
+    pub name: String,
+    /// Whether it is an `int`/`uint` vector rather than a float one.
+    pub integer: bool,
+}
+
+/// Checks that the elements of `layouts` between them feed every input of a
+/// vertex shader, with integer elements for integer inputs and the rest for
+/// float inputs. Otherwise the error lists the inputs that aren't fed
+/// properly along with the elements the shader doesn't read, which are fine
+/// on their own as a mesh may be drawn with materials reading less of it.
+pub fn check_vertex_inputs(inputs: &[VertexInput], layouts: &[&VertexLayoutDescription]) -> Result<(), String> {
+    let mut missing: Vec<&str> = Vec::new();
+    let mut mismatched: Vec<String> = Vec::new();
+
+    for input in inputs.iter() {
+        match layouts.iter().filter_map(|layout| layout.element(&input.name)).next() {
+            Some(elem) => {
+                if elem.vtype.is_integer() != input.integer {
+                    mismatched.push(format!("{} ({:?} for {} input)", input.name, elem.vtype, if input.integer { "an integer" } else { "a float" }));
+                }
+            },
+            None => missing.push(&input.name),
+        }
+    }
+
+    if missing.is_empty() && mismatched.is_empty() {
+        return Ok(());
+    }
+
+    let mut problems: Vec<String> = Vec::new();
+
+    if !missing.is_empty() {
+        problems.push(format!("shader inputs missing from the vertex layout: {}", missing.join(", ")));
+    }
+
+    if !mismatched.is_empty() {
+        problems.push(format!("shader inputs with the wrong element type: {}", mismatched.join(", ")));
     }
+
+    let unused: Vec<&str> = layouts.iter()
+        .flat_map(|layout| layout.elements.iter())
+        .filter(|elem| !inputs.iter().any(|input| input.name == elem.name))
+        .map(|elem| &elem.name[..])
+        .collect();
+
+    if !unused.is_empty() {
+        problems.push(format!("vertex layout elements the shader doesn't read: {}", unused.join(", ")));
+    }
+
+    Err(problems.join("; "))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_element_rejects_bad_component_counts() {
+        let mut layout = VertexLayoutDescription::new();
+
+        assert!(layout.add_element("weights".to_string(), VertexElementType::U8Norm(0)).is_err());
+        assert!(layout.add_element("weights".to_string(), VertexElementType::U8Norm(5)).is_err());
+        assert!(layout.add_element("weights".to_string(), VertexElementType::U8Norm(4)).is_ok());
+        assert_eq!(layout.stride(), 4);
+    }
+
+    fn input(name: &str, integer: bool) -> VertexInput {
+        VertexInput {
+            name: name.to_string(),
+            integer: integer,
+        }
+    }
+
+    fn layout(elements: &[(&str, VertexElementType)]) -> VertexLayoutDescription {
+        let mut layout = VertexLayoutDescription::new();
+        for &(name, vtype) in elements {
+            layout.add_element(name.to_string(), vtype).unwrap();
+        }
+        layout
+    }
+
+    #[test]
+    fn inputs_may_be_fed_by_any_layout() {
+        let vertices = layout(&[("position", VertexElementType::F32F32F32), ("bone_ids", VertexElementType::U8(4))]);
+        let instances = layout(&[("tint", VertexElementType::U8Norm(4))]);
+        let inputs = [input("position", false), input("bone_ids", true), input("tint", false)];
+
+        assert!(check_vertex_inputs(&inputs, &[&vertices, &instances]).is_ok());
+        assert!(check_vertex_inputs(&inputs[..2], &[&vertices, &instances]).is_ok());
+    }
+
+    #[test]
+    fn reports_missing_inputs_and_unused_elements() {
+        let vertices = layout(&[("position", VertexElementType::F32F32F32), ("color", VertexElementType::U8Norm(4))]);
+        let inputs = [input("position", false), input("normal", false)];
+
+        let error = check_vertex_inputs(&inputs, &[&vertices]).unwrap_err();
+        assert!(error.contains("missing from the vertex layout: normal"), "{}", error);
+        assert!(error.contains("doesn't read: color"), "{}", error);
+    }
+
+    #[test]
+    fn reports_integer_mismatches() {
+        let vertices = layout(&[("bone_ids", VertexElementType::U8Norm(4)), ("weights", VertexElementType::U16(4))]);
+        let inputs = [input("bone_ids", true), input("weights", false)];
+
+        let error = check_vertex_inputs(&inputs, &[&vertices]).unwrap_err();
+        assert!(error.contains("bone_ids (U8Norm(4) for an integer input)"), "{}", error);
+        assert!(error.contains("weights (U16(4) for a float input)"), "{}", error);
+    }
+}
@@ -2,20 +2,26 @@ mod component;
 mod node;
 mod transform;
 mod model;
+mod model_batch;
 mod camera;
 
 use common::*;
 use renderer::Renderer;
 use renderer::geometry::Geometry;
 use renderer::IndexType;
+use renderer::{Material, MaterialHandle, MaterialDescription, RendererError, FrameData, ViewData};
 use renderer::util::mesh::{load_meshes_from_file, MeshData, MeshOptions};
+use renderer::util::file_watcher::FileWatcher;
 
 pub use self::node::{Node, NodeRef, WeakNodeRef};
 pub use self::component::SceneComponent;
 pub use self::model::Model;
+pub use self::model_batch::{ModelBatch, ModelBatchRef, ModelInstance};
 pub use self::camera::Camera;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 use std::rc::{Rc, Weak};
 use std::cell::{RefCell};
 
@@ -23,6 +29,16 @@ pub struct Scene {
     renderer: Box<Renderer>,
     root_node: Rc<RefCell<Node>>,
     camera: Camera,
+    materials: Vec<Material>,
+    error_material: Option<MaterialHandle>,
+    /// Watches the shaders of loaded materials so they can be edited while
+    /// the game is running.
+    shader_watcher: FileWatcher,
+    /// Batches of instanced models by file and material. Ordered, so that
+    /// they are drawn in the same order every frame.
+    model_batches: BTreeMap<(PathBuf, MaterialHandle), ModelBatchRef>,
+    start_time: Instant,
+    last_frame_time: Instant,
 }
 
 impl Scene {
@@ -31,6 +47,12 @@ impl Scene {
             renderer: renderer,
             root_node: Rc::new(RefCell::new(Node::new("_root", None))),
             camera: Camera::new(deg(45f32), aspect, 0.1f32, 1000f32),
+            materials: Vec::new(),
+            error_material: None,
+            shader_watcher: FileWatcher::new(Duration::from_millis(500)),
+            model_batches: BTreeMap::new(),
+            start_time: Instant::now(),
+            last_frame_time: Instant::now(),
         }
     }
     
@@ -43,57 +65,86 @@ impl Scene {
         self.root_node.borrow_mut().attach_child(child)
     }
     
-    pub fn attach_model_component_from_file(&mut self, node: &NodeRef, path: &Path) {
-        let mut geometries: Vec<Box<Geometry>> = Vec::new();
-        
-        let mesh_data: Vec<MeshData> = load_meshes_from_file(path, &MeshOptions::default()).unwrap();
-
-        // this lameness will have to suffice until a proper material
-        // abstraction is in place.        
-        let vert_src = r#"
-#version 400
-
-uniform Matrices {
-    mat4 model_view_proj;
-};
-        
-in vec3 position;
-in vec2 tex_coord;
-out vec2 frag_tex_coord;
+    /// Loads a material file, keeping the material alive as long as the scene.
+    pub fn load_material(&mut self, path: &Path) -> Result<MaterialHandle, RendererError> {
+        let material = try!(Material::load_from_file(&mut self.renderer, path));
+        let handle = material.handle();
 
-void main() {
-    gl_Position = model_view_proj * vec4(position, 1.0);
-}
-"#;
+        if let Some((vert_path, frag_path)) = material.shader_paths() {
+            self.shader_watcher.watch(vert_path);
+            self.shader_watcher.watch(frag_path);
 
-let frag_src = r#"
-#version 400
+            for path in self.renderer.material_includes(handle) {
+                self.shader_watcher.watch(&path);
+            }
+        }
 
-uniform sampler2D tex;
-in vec2 frag_tex_coord;
-out vec4 color;
+        self.materials.push(material);
+        Ok(handle)
+    }
+    
+    /// The built-in magenta material, for when `load_material` fails.
+    pub fn error_material(&mut self) -> MaterialHandle {
+        if let Some(handle) = self.error_material {
+            return handle;
+        }
 
-void main() {
-    color = texture(tex, frag_tex_coord);
-}
-"#;
+        let handle = self.renderer.create_material(&MaterialDescription::error())
+            .ok().expect("built-in error material failed to compile");
+        self.error_material = Some(handle);
+        handle
+    }
+    
+    fn create_model_geometries(&mut self, path: &Path, material: MaterialHandle) -> Vec<Box<Geometry>> {
+        let mut geometries: Vec<Box<Geometry>> = Vec::new();
         
+        let mesh_data: Vec<MeshData> = load_meshes_from_file(path, &MeshOptions::default()).unwrap();
+
         for mesh_datum in mesh_data {
             let geometry = self.renderer.create_geometry(
                 &mesh_datum.vertex_data,
                 &mesh_datum.index_data,
                 &mesh_datum.layout,
                 IndexType::U32,
-                vert_src,
-                frag_src);
+                material);
 
-            geometries.push(geometry);
+            match geometry {
+                Ok(geometry) => geometries.push(geometry),
+                Err(e)       => println!("Failed to create geometry for {}: {}", path.display(), e),
+            }
         }
+
+        geometries
+    }
+    
+    pub fn attach_model_component_from_file(&mut self, node: &NodeRef, path: &Path, material: MaterialHandle) {
+        let geometries = self.create_model_geometries(path, material);
         
         let model = Box::new(Model::new(node.borrow().transform(), geometries));
         node.borrow_mut().attach_component(model);
     }
     
+    /// Like `attach_model_component_from_file`, but every node showing the
+    /// same file with the same material is drawn in a single batch. The
+    /// material has to read its transform from the instance attributes, see
+    /// `ModelBatch`.
+    pub fn attach_instanced_model_from_file(&mut self, node: &NodeRef, path: &Path, material: MaterialHandle, tint: Vec4f) {
+        let key = (path.to_path_buf(), material);
+
+        let batch = match self.model_batches.get(&key).cloned() {
+            Some(batch) => batch,
+            None        => {
+                let geometries = self.create_model_geometries(path, material);
+                let batch = Rc::new(RefCell::new(ModelBatch::new(geometries)));
+                self.model_batches.insert(key, batch.clone());
+                batch
+            },
+        };
+
+        let instance = Box::new(ModelInstance::new(batch, node.borrow().transform(), tint));
+        node.borrow_mut().attach_component(instance);
+    }
+    
     fn render_nodes_recursive(&mut self, node: NodeRef) {
         for component in node.borrow_mut().components_mut().iter_mut() {
             component.render(&mut self.renderer, &self.camera)
@@ -114,10 +165,208 @@ void main() {
         }
     }
     
+    /// Reloads every material using a changed shader or included file,
+    /// once however many of its files changed.
+    fn reload_changed_shaders(&mut self) {
+        let changed = self.shader_watcher.poll();
+
+        if changed.is_empty() {
+            return;
+        }
+
+        for material in self.materials.iter() {
+            let (vert_path, frag_path) = match material.shader_paths() {
+                Some(x) => x,
+                None    => continue,
+            };
+
+            let includes = self.renderer.material_includes(material.handle());
+            let uses_changed = changed.iter().any(|path| path == vert_path || path == frag_path || includes.contains(path));
+
+            if !uses_changed {
+                continue;
+            }
+
+            // Keep drawing with the old shaders until the error is fixed.
+            match material.reload_shaders(&mut self.renderer) {
+                Ok(_)  => println!("Reloaded {} and {}", vert_path.display(), frag_path.display()),
+                Err(e) => println!("{}", e),
+            }
+
+            // The shaders may include other files now.
+            for path in self.renderer.material_includes(material.handle()) {
+                self.shader_watcher.watch(&path);
+            }
+        }
+    }
+    
     pub fn frame(&mut self) {
+        self.reload_changed_shaders();
+
+        let now = Instant::now();
+        let frame_data = FrameData {
+            time: seconds(now.duration_since(self.start_time)),
+            delta_time: seconds(now.duration_since(self.last_frame_time)),
+        };
+        self.last_frame_time = now;
+
+        let view_data = ViewData::new(self.camera.view(), self.camera.projection(), self.camera.transform().position);
+
+        self.renderer.set_frame_data(&frame_data);
+        self.renderer.set_view_data(&view_data);
+        
         self.renderer.clear(1.0, 0.3, 0.3, 1.0);
+        self.renderer.clear_depth(1.0);
         
         let root = self.root_node.clone();
         self.render_nodes_recursive(root);
+
+        for batch in self.model_batches.values() {
+            batch.borrow_mut().render(&mut self.renderer);
+        }
+    }
+}
+
+fn seconds(duration: Duration) -> f32 {
+    duration.as_secs() as f32 + duration.subsec_nanos() as f32 / 1_000_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use renderer::{BufferData, VertexLayoutDescription, VertexElementType, ParamValue};
+    use renderer::backends::trace::{TraceRenderer, TraceCommand, TraceLog};
+
+    const VERT_SRC: &'static str = "#version 400
+uniform mat4 model_view_proj;
+in vec3 position;
+void main() {
+    gl_Position = model_view_proj * vec4(position, 1.0);
+}
+";
+
+    const FRAG_SRC: &'static str = "#version 400
+out vec4 color;
+void main() {
+    color = vec4(1.0);
+}
+";
+
+    const INSTANCED_VERT_SRC: &'static str = "#version 400
+uniform mat4 view_projection;
+in vec3 position;
+in vec4 instance_model_0;
+in vec4 instance_model_1;
+in vec4 instance_model_2;
+in vec4 instance_model_3;
+in vec4 instance_tint;
+out vec4 frag_tint;
+void main() {
+    mat4 model = mat4(instance_model_0, instance_model_1, instance_model_2, instance_model_3);
+    frag_tint = instance_tint;
+    gl_Position = view_projection * model * vec4(position, 1.0);
+}
+";
+
+    #[test]
+    fn frame_draws_models_with_their_transform() {
+        let trace = TraceRenderer::new();
+        let log = trace.log();
+        let mut renderer: Box<Renderer> = trace;
+
+        let material = renderer.create_material(&MaterialDescription::new(VERT_SRC, FRAG_SRC)).unwrap();
+
+        let mut layout = VertexLayoutDescription::new();
+        layout.add_element("position".to_string(), VertexElementType::F32F32F32).unwrap();
+
+        let vertices = BufferData::new_initialized(vec![0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+        let indices = BufferData::new_initialized(vec![0u32, 1, 2]);
+        let geometry = renderer.create_geometry(&vertices, &indices, &layout, IndexType::U32, material).unwrap();
+
+        let mut scene = Scene::new(renderer, 1.0);
+        let node = scene.new_child_node("triangle");
+        let model = Box::new(Model::new(node.borrow().transform(), vec![geometry]));
+        node.borrow_mut().attach_component(model);
+
+        log.borrow_mut().clear();
+        scene.frame();
+
+        let log = log.borrow();
+        assert_eq!(log.len(), 5);
+
+        match log[0] { TraceCommand::SetFrameData { .. } => (), ref c => panic!("unexpected {:?}", c) }
+        match log[1] { TraceCommand::SetViewData { .. }  => (), ref c => panic!("unexpected {:?}", c) }
+        match log[2] { TraceCommand::Clear { .. }        => (), ref c => panic!("unexpected {:?}", c) }
+        match log[3] { TraceCommand::ClearDepth { .. }   => (), ref c => panic!("unexpected {:?}", c) }
+
+        let expected_mvp = scene.camera.projection() * scene.camera.view();
+
+        match log[4] {
+            TraceCommand::DrawGeometry { geometry, ref params, .. } => {
+                assert_eq!(geometry, 0);
+                assert!(params.contains(&("model_view_proj".to_string(), ParamValue::Mat4(expected_mvp))));
+            },
+            ref c => panic!("unexpected {:?}", c),
+        }
+    }
+
+    /// Runs a frame, returning the count of each instanced draw and the size
+    /// of each instance upload. Fails on non-instanced draws.
+    fn instanced_frame(scene: &mut Scene, log: &TraceLog) -> (Vec<usize>, Vec<usize>) {
+        log.borrow_mut().clear();
+        scene.frame();
+
+        let mut draws = Vec::new();
+        let mut uploads = Vec::new();
+
+        for command in log.borrow().iter() {
+            match *command {
+                TraceCommand::DrawGeometryInstanced { count, .. } => draws.push(count),
+                TraceCommand::CreateInstanceBuffer { bytes, .. } |
+                TraceCommand::SetInstanceData { bytes, .. }       => uploads.push(bytes),
+                TraceCommand::DrawGeometry { .. }                 => panic!("batched model drawn without instancing"),
+                _                                                 => (),
+            }
+        }
+
+        (draws, uploads)
+    }
+
+    #[test]
+    fn frame_draws_nodes_sharing_a_model_in_one_batch() {
+        let trace = TraceRenderer::new();
+        let log = trace.log();
+        let mut renderer: Box<Renderer> = trace;
+
+        let material = renderer.create_material(&MaterialDescription::new(INSTANCED_VERT_SRC, FRAG_SRC)).unwrap();
+
+        let mut layout = VertexLayoutDescription::new();
+        layout.add_element("position".to_string(), VertexElementType::F32F32F32).unwrap();
+
+        let vertices = BufferData::new_initialized(vec![0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+        let indices = BufferData::new_initialized(vec![0u32, 1, 2]);
+        let geometry = renderer.create_geometry(&vertices, &indices, &layout, IndexType::U32, material).unwrap();
+
+        let mut scene = Scene::new(renderer, 1.0);
+
+        // Stands in for the geometries loaded from the file.
+        let path = Path::new("triangle.obj");
+        scene.model_batches.insert((path.to_path_buf(), material), Rc::new(RefCell::new(ModelBatch::new(vec![geometry]))));
+
+        let first = scene.new_child_node("first");
+        let second = scene.new_child_node("second");
+        scene.attach_instanced_model_from_file(&first, path, material, Vec4f::new(1.0, 1.0, 1.0, 1.0));
+        scene.attach_instanced_model_from_file(&second, path, material, Vec4f::new(1.0, 0.0, 0.0, 1.0));
+        assert_eq!(scene.model_batches.len(), 1);
+
+        // A model matrix and a tint per instance.
+        let instance_size = 20 * 4;
+
+        // Instances are uploaded once, then only when they change.
+        assert_eq!(instanced_frame(&mut scene, &log), (vec![2], vec![2 * instance_size]));
+        assert_eq!(instanced_frame(&mut scene, &log), (vec![2], vec![]));
+
+        second.borrow_mut().components_mut().clear();
+        assert_eq!(instanced_frame(&mut scene, &log), (vec![1], vec![instance_size]));
     }
 }
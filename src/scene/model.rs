@@ -1,14 +1,18 @@
 use renderer::Renderer;
-use renderer::shader_params::ParamValue;
+use renderer::shader_params::{ParamValue, ParamHandle, ParamError};
 use renderer::geometry::Geometry;
 use super::transform::Transform;
 use super::component::SceneComponent;
 use super::camera::Camera;
 
+use std::cell::Cell;
+
 pub struct Model {
     global_transform: Transform,
     local_transform: Transform,
     geometries: Vec<Box<Geometry>>,
+    /// `model_view_proj` of each geometry, resolved on first draw.
+    mvp_handles: Vec<Option<ParamHandle>>,
 }
 
 impl Model {
@@ -16,6 +20,7 @@ impl Model {
         Model {
             global_transform: global_transform.clone(),
             local_transform: Transform::identity(),
+            mvp_handles: vec![None; geometries.len()],
             geometries: geometries,
         }
     }
@@ -40,12 +45,30 @@ impl SceneComponent for Model {
         let model = self.global_transform.to_matrix();
         let mvp = proj * view * model;
         
-        for geometry in self.geometries.iter_mut() {
+        for (geometry, mvp_handle) in self.geometries.iter_mut().zip(self.mvp_handles.iter_mut()) {
+            let handle = Cell::new(*mvp_handle);
+
             geometry.update_params(&|params| {
-                params.set("model_view_proj", ParamValue::Mat4(mvp)); 
+                if let Some(h) = handle.get() {
+                    match params.set_by_handle(h, ParamValue::Mat4(mvp)) {
+                        // The params were reflected again after a reload.
+                        Err(ParamError::InvalidHandle(_)) => (),
+                        _ => return,
+                    }
+                }
+
+                handle.set(params.handle("model_view_proj").ok());
+
+                if let Some(h) = handle.get() {
+                    let _ = params.set_by_handle(h, ParamValue::Mat4(mvp));
+                }
             });
+
+            *mvp_handle = handle.get();
             
-            renderer.draw_geometry(geometry);
+            if let Err(e) = renderer.draw_geometry(geometry) {
+                println!("Failed to draw geometry: {}", e);
+            }
         }
     }
 }
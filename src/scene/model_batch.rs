@@ -0,0 +1,204 @@
+use common::*;
+use renderer::{Renderer, BufferData, BufferUsage, InstanceBuffer, VertexLayoutDescription, VertexElementType};
+use renderer::geometry::Geometry;
+use renderer::util::handle_pool::{HandlePool, PoolHandle};
+use super::transform::Transform;
+use super::component::SceneComponent;
+use super::camera::Camera;
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+struct BatchInstance {
+    model: Mat4f,
+    tint: Vec4f,
+}
+
+type InstanceHandle = PoolHandle<BatchInstance>;
+
+pub type ModelBatchRef = Rc<RefCell<ModelBatch>>;
+
+/// Every copy of a model, drawn with one instanced draw per geometry
+/// instead of one draw per copy. The scene shares a batch between all the
+/// nodes showing the same file with the same material, each of which has
+/// a `ModelInstance` keeping its transform in the batch up to date.
+///
+/// The material's vertex shader gets each instance's model matrix as the
+/// `instance_model_0` to `instance_model_3` columns and its tint as
+/// `instance_tint`, see `data/shaders/instanced.vert`.
+pub struct ModelBatch {
+    geometries: Vec<Box<Geometry>>,
+    instances: HandlePool<BatchInstance>,
+    instance_buffer: Option<Box<InstanceBuffer>>,
+    /// Whether the instance buffer is out of date.
+    dirty: bool,
+    /// Set once a failure has been logged, so that a batch failing every
+    /// frame only logs until it draws again.
+    error_logged: bool,
+}
+
+impl ModelBatch {
+    pub fn new(geometries: Vec<Box<Geometry>>) -> ModelBatch {
+        ModelBatch {
+            geometries: geometries,
+            instances: HandlePool::new(),
+            instance_buffer: None,
+            dirty: true,
+            error_logged: false,
+        }
+    }
+
+    pub fn instance_layout() -> VertexLayoutDescription {
+        let mut layout = VertexLayoutDescription::new_per_instance(1);
+
+        // Vec4s always have a valid number of components.
+        for i in 0..4 {
+            layout.add_element(format!("instance_model_{}", i), VertexElementType::F32F32F32F32).unwrap();
+        }
+
+        layout.add_element("instance_tint".to_string(), VertexElementType::F32F32F32F32).unwrap();
+        layout
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.instances.len()
+    }
+
+    fn add_instance(&mut self, model: Mat4f, tint: Vec4f) -> InstanceHandle {
+        self.dirty = true;
+        self.instances.insert(BatchInstance {
+            model: model,
+            tint: tint,
+        })
+    }
+
+    fn remove_instance(&mut self, handle: InstanceHandle) {
+        self.dirty = true;
+        self.instances.remove(handle);
+    }
+
+    fn set_model(&mut self, handle: InstanceHandle, model: Mat4f) {
+        self.dirty = true;
+        self.instances[handle].model = model;
+    }
+
+    fn set_tint(&mut self, handle: InstanceHandle, tint: Vec4f) {
+        self.dirty = true;
+        self.instances[handle].tint = tint;
+    }
+
+    fn instance_data(&self) -> BufferData {
+        let mut floats: Vec<f32> = Vec::with_capacity(self.instances.len() * 20);
+
+        for instance in self.instances.values() {
+            let m = &instance.model;
+            for column in [m.x, m.y, m.z, m.w].iter() {
+                floats.extend([column.x, column.y, column.z, column.w].iter().cloned());
+            }
+
+            let t = &instance.tint;
+            floats.extend([t.x, t.y, t.z, t.w].iter().cloned());
+        }
+
+        BufferData::new_initialized(floats)
+    }
+
+    /// Uploads the instances if they have changed, then draws them all.
+    pub fn render(&mut self, renderer: &mut Box<Renderer>) {
+        let count = self.instances.len();
+
+        if count == 0 {
+            return;
+        }
+
+        if self.dirty {
+            let data = self.instance_data();
+
+            // A buffer that failed to update is dropped and created again
+            // next frame.
+            let result = match self.instance_buffer.take() {
+                Some(mut buffer) => renderer.set_instance_data(&mut buffer, &data).map(|_| buffer),
+                None             => renderer.create_instance_buffer(&data, &ModelBatch::instance_layout(), BufferUsage::Stream),
+            };
+
+            match result {
+                Ok(buffer) => self.instance_buffer = Some(buffer),
+                Err(e)     => {
+                    if !self.error_logged {
+                        println!("Failed to update instances: {}", e);
+                        self.error_logged = true;
+                    }
+                    return;
+                },
+            }
+
+            self.dirty = false;
+        }
+
+        let mut failed = false;
+
+        if let Some(ref instance_buffer) = self.instance_buffer {
+            for geometry in self.geometries.iter_mut() {
+                if let Err(e) = renderer.draw_geometry_instanced(geometry, &**instance_buffer, count) {
+                    if !self.error_logged {
+                        println!("Failed to draw geometry: {}", e);
+                    }
+                    failed = true;
+                }
+            }
+        }
+
+        self.error_logged = failed;
+    }
+}
+
+/// A node's copy of a batched model. Drawn by the scene along with the rest
+/// of its batch, and removed from it when dropped.
+pub struct ModelInstance {
+    batch: ModelBatchRef,
+    handle: InstanceHandle,
+    global_transform: Transform,
+    local_transform: Transform,
+}
+
+impl ModelInstance {
+    pub fn new(batch: ModelBatchRef, global_transform: &Transform, tint: Vec4f) -> ModelInstance {
+        let handle = batch.borrow_mut().add_instance(global_transform.to_matrix(), tint);
+
+        ModelInstance {
+            batch: batch,
+            handle: handle,
+            global_transform: global_transform.clone(),
+            local_transform: Transform::identity(),
+        }
+    }
+
+    pub fn set_tint(&mut self, tint: Vec4f) {
+        self.batch.borrow_mut().set_tint(self.handle, tint);
+    }
+}
+
+impl Drop for ModelInstance {
+    fn drop(&mut self) {
+        self.batch.borrow_mut().remove_instance(self.handle);
+    }
+}
+
+impl SceneComponent for ModelInstance {
+    fn global_transform_change(&mut self, transform: &Transform) {
+        self.global_transform = transform.clone() + self.local_transform.clone();
+        self.batch.borrow_mut().set_model(self.handle, self.global_transform.to_matrix());
+    }
+
+    fn local_transform(&self) -> &Transform {
+        &self.local_transform
+    }
+
+    fn local_transform_mut(&mut self) -> &mut Transform {
+        &mut self.local_transform
+    }
+
+    // The scene draws the whole batch at once after the nodes.
+    fn render(&mut self, _: &mut Box<Renderer>, _: &Camera) {
+    }
+}